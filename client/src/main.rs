@@ -1,75 +1,439 @@
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
+use mqtt_common::config::{self, ConfigFile};
+use mqtt_common::integrity::{self, SharedSecret, Signed};
+use mqtt_common::shutdown;
 use mqtt_common::{
-    DataPacket, DataPayload, DataResponse, NodeInfo, NodeStatus, NodeType, ProcessingStatus,
-    RoutingRequest, RoutingResponse, RoutingStatus, ClientConfiguration,
+    Backoff, DataPacket, DataPayload, DataResponse, FulfillmentSummary, NodeInfo, NodeStatus,
+    NodeType, ProcessingStatus, RoutingRequest, RoutingResponse, RoutingStatus,
+    ClientConfiguration, mqtt_client_id, check_version,
+    MqttSettings, decode_or_log, RoutingIssuer, DATA_TYPE_CATALOG, PROTOCOL_VERSION,
+    DedupWindow, DEFAULT_DEDUP_MEMORY_BUDGET_MB, FEATURE_COMPRESSION, MAX_COMPRESSION_LEVEL,
+    topics, format_sensor_reading, set_offline_will, DataRequest, Fulfillment,
+    PoolError, RateLimiter, health, MqttTransport, RoutingAck, QosPolicy, WireFormat,
+    WIRE_FORMATS, WIRE_FORMATS_METADATA_KEY,
 };
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::error::Error;
-use std::sync::atomic::{AtomicU32, Ordering};
+use rumqttc::{AsyncClient, ClientError, EventLoop, QoS};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::signal;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::time;
 use uuid::Uuid;
 
-type BoxError = Box<dyn Error + Send + Sync>;
-type DynError = Box<dyn Error + Send + Sync>;
-
 #[derive(Debug)]
 struct NodeConfig {
     mqtt_host: String,
     mqtt_port: u16,
+    /// Broker credentials, keep-alive and packet size
+    mqtt: MqttSettings,
+    /// Prepended to the MQTT client id so broker logs show the host or pod
+    client_id_prefix: Option<String>,
     node_capacity: u32,
     data_request_interval: u64,
+    /// Nodes to spread data requests across
+    fan_out: u32,
+    /// Resends of an unanswered data request before it is given up on
+    max_request_retries: u32,
+    /// Data types asked for in both routing and data requests
+    data_types: Vec<String>,
+    /// Memory the received-packet dedup window may use, in MB
+    dedup_memory_budget_mb: usize,
+    /// Deflate level asked for on data responses, when the nodes support it
+    compression_level: u32,
+    /// Namespaces every topic so several pools can share a broker
+    topic_prefix: String,
+    /// Most data requests sent per second, new and resent together; also
+    /// the largest burst allowed
+    max_requests_per_sec: u32,
+    /// Port serving the `/health` readiness probe; no server when unset
+    health_port: Option<u16>,
+    /// Signs outgoing and verifies incoming messages when set
+    shared_secret: Option<SharedSecret>,
+    /// QoS of routing traffic and of everything else
+    qos: QosPolicy,
+    /// Identity kept across restarts, so the orchestrator can route this
+    /// client back to the nodes it used before
+    logical_id: Option<String>,
 }
-async fn cleanup(slave: &SlaveNode) -> Result<(), BoxError> {
-    // Publish offline status before shutdown
-    if let Some(master_id) = slave.master_id.read().await.as_ref() {
-        let mut final_heartbeat = slave.node_info.clone();
-        final_heartbeat.status = NodeStatus::Offline;
-        if let Ok(payload) = serde_json::to_string(&final_heartbeat) {
-            slave
-                .client
-                .publish(
-                    format!("heartbeat/slave/{}", final_heartbeat.node_id),
-                    QoS::AtLeastOnce,
-                    false,
-                    payload,
-                )
-                .await?;
+
+impl NodeConfig {
+    /// Builds the config from `var` lookups, falling back to the `[client]`
+    /// table of the config file and then to the defaults. Invalid variables
+    /// are ignored; invalid file values are an error.
+    fn load(
+        file: &config::ClientConfig,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, PoolError> {
+        let data_types = config::list(
+            &var,
+            "CLIENT_DATA_TYPES",
+            file.client_data_types.as_deref(),
+            catalog_type,
+        )?;
+        Ok(NodeConfig {
+            mqtt_host: config::setting(&var, "MQTT_HOST", file.mqtt_host.clone())
+                .unwrap_or_else(|| "localhost".to_string()),
+            mqtt_port: config::setting(&var, "MQTT_PORT", file.mqtt_port).unwrap_or(1883),
+            mqtt: MqttSettings::load(&file.mqtt(), &var)?,
+            client_id_prefix: config::setting(
+                &var,
+                "CLIENT_ID_PREFIX",
+                file.client_id_prefix.clone(),
+            )
+            .filter(|prefix| !prefix.is_empty()),
+            node_capacity: config::setting(&var, "NODE_CAPACITY", file.node_capacity)
+                .unwrap_or(100),
+            // `time::interval` panics on zero
+            data_request_interval: config::bounded(
+                &var,
+                "DATA_REQUEST_INTERVAL",
+                file.data_request_interval,
+                1..=u64::MAX,
+            )?
+            .unwrap_or(10),
+            fan_out: config::setting(&var, "FAN_OUT", file.fan_out).unwrap_or(1),
+            max_request_retries: config::setting(
+                &var,
+                "MAX_REQUEST_RETRIES",
+                file.max_request_retries,
+            )
+            .unwrap_or(3),
+            data_types: dedup_data_types(data_types.unwrap_or_default()),
+            dedup_memory_budget_mb: config::setting(
+                &var,
+                "DEDUP_MEMORY_BUDGET_MB",
+                file.dedup_memory_budget_mb,
+            )
+            .unwrap_or(DEFAULT_DEDUP_MEMORY_BUDGET_MB),
+            compression_level: config::setting(&var, "COMPRESSION_LEVEL", file.compression_level)
+                .unwrap_or(0),
+            topic_prefix: config::setting(&var, "TOPIC_PREFIX", file.topic_prefix.clone())
+                .unwrap_or_default()
+                .trim_matches('/')
+                .to_string(),
+            max_requests_per_sec: config::bounded(
+                &var,
+                "MAX_REQUESTS_PER_SEC",
+                file.max_requests_per_sec,
+                1..=u32::MAX,
+            )?
+            .unwrap_or(5),
+            health_port: config::setting(&var, "HEALTH_PORT", file.health_port),
+            shared_secret: SharedSecret::load(file.shared_secret.as_deref(), &var),
+            qos: QosPolicy::load(file.mqtt_qos, file.routing_qos, &var)?,
+            logical_id: config::setting(&var, "CLIENT_LOGICAL_ID", file.client_logical_id.clone())
+                .filter(|id| !id.is_empty()),
+        })
+    }
+}
+
+/// Requested when `CLIENT_DATA_TYPES` is unset
+const DEFAULT_DATA_TYPES: [&str; 2] = ["text", "sensor"];
+
+/// A `CLIENT_DATA_TYPES` entry (e.g. `text`), if it is in the data type catalog
+fn catalog_type(entry: &str) -> Option<String> {
+    DATA_TYPE_CATALOG
+        .contains(&entry)
+        .then(|| entry.to_string())
+}
+
+/// Drops repeated data types, keeping the first of each.
+/// `DEFAULT_DATA_TYPES` is used when none are left.
+fn dedup_data_types(requested: Vec<String>) -> Vec<String> {
+    let mut types: Vec<String> = Vec::new();
+    for data_type in requested {
+        if !types.contains(&data_type) {
+            types.push(data_type);
         }
     }
+    if types.is_empty() {
+        return DEFAULT_DATA_TYPES.iter().map(|t| t.to_string()).collect();
+    }
+    types
+}
+
+async fn cleanup(slave: &SlaveNode, reason: &str) -> Result<(), PoolError> {
+    // Publish offline status before shutdown
+    if slave.master_id.read().await.is_some() {
+        shutdown::announce_offline(
+            &slave.client,
+            topics::heartbeat_slave(&slave.topic_prefix, &slave.node_info.node_id),
+            slave.qos.default,
+            &slave.node_info,
+            NodeStatus::Offline,
+            reason,
+        )
+        .await?;
+    }
     Ok(())
 }
 
 struct SlaveNode {
     node_info: NodeInfo,
-    client: AsyncClient,
+    /// Signs everything published when the pool shares a secret
+    client: Signed<AsyncClient>,
     current_load: Arc<AtomicU32>,
     master_id: Arc<tokio::sync::RwLock<Option<String>>>,
-    config: Arc<tokio::sync::RwLock<Option<ClientConfiguration>>>,
+    /// Every node we were routed to, starting with `master_id`
+    assigned_nodes: Arc<tokio::sync::RwLock<Vec<String>>>,
+    config: Arc<tokio::sync::RwLock<RoutingConfig>>,
+    /// Request id of the routing attempt we're waiting on, if any
+    pending_routing: Arc<tokio::sync::RwLock<Option<String>>>,
+    /// No routing requests are sent before this, after a `Pending` answer
+    retry_routing_at: Arc<tokio::sync::RwLock<Option<Instant>>>,
+    /// Data requests still waiting for a response
+    pending_requests: Arc<tokio::sync::Mutex<PendingRequests>>,
     data_request_interval: Duration,
+    /// Shared by routing and data requests so the two can't diverge
+    data_types: Arc<Vec<String>>,
+    /// Deflate level wanted on data responses; only requested from nodes
+    /// advertising compression
+    compression_level: u32,
+    /// Ids of recently received data packets, so redeliveries are handled once
+    seen_packets: Arc<tokio::sync::Mutex<DedupWindow>>,
+    /// Subscribes on our behalf, once per topic
+    subscriber: Subscriber,
+    /// Put in front of every topic; see `topics`
+    topic_prefix: String,
+    /// Caps outgoing data requests, new and resent alike
+    request_limiter: Arc<tokio::sync::Mutex<RateLimiter>>,
+    /// Whether the broker connection is up, as reported on `/health`
+    connected: Arc<AtomicBool>,
+    /// QoS of routing traffic and of everything else
+    qos: QosPolicy,
+}
+
+/// The subscribe half of an MQTT client, so tests can record what is subscribed
+trait SubscribeClient {
+    async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), ClientError>;
+}
+
+impl SubscribeClient for AsyncClient {
+    async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+        AsyncClient::subscribe(self, topic, qos).await
+    }
+}
+
+/// Holds routing acks back until the broker has acknowledged (SUBACK) every
+/// subscription queued before them, so the orchestrator only hears that a
+/// routing was taken up once its topics are live. Subscriptions are
+/// numbered in the order they are queued, which is the order the event loop
+/// sends them in.
+#[derive(Debug, Default)]
+struct AckGate {
+    /// Number of the last subscription queued
+    queued: u64,
+    /// Subscriptions queued but not yet sent to the broker, oldest first
+    unsent: VecDeque<u64>,
+    /// Subscriptions sent but not yet acknowledged, by packet id
+    unacked: HashMap<u16, u64>,
+    /// Acks waiting on every subscription up to the number given
+    waiting: Vec<(RoutingAck, u64)>,
+}
+
+impl AckGate {
+    fn queued(&mut self) {
+        self.queued += 1;
+        self.unsent.push_back(self.queued);
+    }
+
+    /// The subscription just numbered couldn't be queued after all
+    fn withdraw(&mut self) {
+        self.unsent.pop_back();
+    }
+
+    /// Holds `ack` behind every subscription queued so far
+    fn hold(&mut self, ack: RoutingAck) -> Vec<RoutingAck> {
+        self.waiting.push((ack, self.queued));
+        self.release()
+    }
+
+    /// The event loop sent the oldest queued subscription as `pkid`
+    fn sent(&mut self, pkid: u16) {
+        if let Some(number) = self.unsent.pop_front() {
+            self.unacked.insert(pkid, number);
+        }
+    }
+
+    /// The broker acknowledged `pkid`; acks waiting on a refused
+    /// subscription report the routing as not taken up
+    fn acked(&mut self, pkid: u16, refused: bool) -> Vec<RoutingAck> {
+        if let Some(number) = self.unacked.remove(&pkid) {
+            if refused {
+                for (ack, upto) in &mut self.waiting {
+                    if *upto >= number {
+                        ack.accepted = false;
+                    }
+                }
+            }
+        }
+        self.release()
+    }
+
+    /// Subscriptions in flight are lost with the connection, so every
+    /// waiting ack reports the routing as not taken up
+    fn connection_lost(&mut self) -> Vec<RoutingAck> {
+        self.unsent.clear();
+        self.unacked.clear();
+        self.waiting
+            .drain(..)
+            .map(|(ack, _)| RoutingAck {
+                accepted: false,
+                ..ack
+            })
+            .collect()
+    }
+
+    /// Acks whose subscriptions are all acknowledged
+    fn release(&mut self) -> Vec<RoutingAck> {
+        let outstanding = self
+            .unsent
+            .iter()
+            .chain(self.unacked.values())
+            .min()
+            .copied();
+        let (ready, waiting) = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .partition(|(_, upto)| outstanding.is_none_or(|first| first > *upto));
+        self.waiting = waiting;
+        ready.into_iter().map(|(ack, _)| ack).collect()
+    }
+}
+
+/// Subscribes through `client`, remembering every topic subscribed so that
+/// re-routing to the same nodes doesn't subscribe to it again
+#[derive(Clone)]
+struct Subscriber<C = AsyncClient> {
+    client: C,
+    /// Put in front of every topic; see `topics`
+    topic_prefix: String,
+    qos: QosPolicy,
+    /// Every topic subscribed, with the QoS it was subscribed at
+    topics: Arc<tokio::sync::Mutex<HashMap<String, QoS>>>,
+    /// Routing acks waiting for their subscriptions to be acknowledged
+    acks: Arc<std::sync::Mutex<AckGate>>,
+}
+
+impl<C: SubscribeClient> Subscriber<C> {
+    fn new(client: C, topic_prefix: &str, qos: QosPolicy) -> Self {
+        Subscriber {
+            client,
+            topic_prefix: topic_prefix.to_string(),
+            qos,
+            topics: Arc::default(),
+            acks: Arc::default(),
+        }
+    }
+
+    /// Subscribes to `topic` at the default QoS unless already subscribed,
+    /// returning whether it is. Failed subscriptions are logged and tried
+    /// again on the next call.
+    async fn ensure(&self, topic: &str) -> bool {
+        self.ensure_at(topic, self.qos.default).await
+    }
+
+    /// `ensure` at `qos`
+    async fn ensure_at(&self, topic: &str, qos: QoS) -> bool {
+        let mut topics = self.topics.lock().await;
+        if topics.contains_key(topic) {
+            return true;
+        }
+        // Numbered before it is queued, as the event loop may send it
+        // straight away; the topics lock keeps the numbers in queue order
+        self.acks.lock().unwrap().queued();
+        match self.client.subscribe(topic, qos).await {
+            Ok(()) => {
+                topics.insert(topic.to_string(), qos);
+                true
+            }
+            Err(e) => {
+                self.acks.lock().unwrap().withdraw();
+                eprintln!("Error subscribing to topic {}: {:?}", topic, e);
+                false
+            }
+        }
+    }
+
+    /// Forgets what was subscribed and subscribes to all of it again. A
+    /// clean-session reconnect drops every subscription on the broker side.
+    async fn resubscribe(&self) {
+        let previous = std::mem::take(&mut *self.topics.lock().await);
+        for (topic, qos) in previous {
+            self.ensure_at(&topic, qos).await;
+        }
+    }
+
+    /// Topics every client needs before its first routing request
+    async fn subscribe_at_startup(&self, node_id: &str) {
+        // Routing answers are addressed to our node id
+        self.ensure_at(
+            &topics::routing_response(&self.topic_prefix, node_id),
+            self.qos.routing,
+        )
+        .await;
+    }
 }
 
 impl SlaveNode {
-    async fn new(capacity: u32, data_request_interval: Duration) -> Result<Self, DynError> {
-        let node_info = NodeInfo::new(NodeType::Client, capacity);
+    async fn new(settings: &NodeConfig) -> Result<Self, PoolError> {
+        let fan_out = settings.fan_out;
+        let max_request_retries = settings.max_request_retries;
+        let topic_prefix = settings.topic_prefix.clone();
+        let mut node_info = NodeInfo::new(NodeType::Client, settings.node_capacity);
+        // Data responses may come in any format we can decode
+        node_info.metadata.insert(
+            WIRE_FORMATS_METADATA_KEY.to_string(),
+            WireFormat::join(&WIRE_FORMATS),
+        );
         let node_id = node_info.node_id.clone();
 
-        let mut mqtt_options = MqttOptions::new(node_id.clone(), "localhost", 1883);
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
+        let connected = Arc::new(AtomicBool::new(false));
+        if let Some(port) = settings.health_port {
+            health::start(port, Arc::clone(&connected)).await?;
+        }
 
-        let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
+        let client_id = mqtt_client_id(settings.client_id_prefix.as_deref(), &node_id);
+        let mut mqtt_options =
+            settings
+                .mqtt
+                .options(client_id, &settings.mqtt_host, settings.mqtt_port);
+        set_offline_will(
+            &mut mqtt_options,
+            &topic_prefix,
+            settings.shared_secret.as_ref(),
+            &NodeType::Client,
+            &node_id,
+        );
+        let channel_cap = mqtt_options.request_channel_capacity();
+        let (client, eventloop) = AsyncClient::new(mqtt_options, channel_cap);
+        let subscriber = Subscriber::new(client.clone(), &topic_prefix, settings.qos);
+        subscriber.subscribe_at_startup(&node_id).await;
+        let client = Signed::new(client, settings.shared_secret.clone());
 
         let node = SlaveNode {
             node_info,
             client: client.clone(),
             current_load: Arc::new(AtomicU32::new(0)),
             master_id: Arc::new(tokio::sync::RwLock::new(None)),
-            config: Arc::new(tokio::sync::RwLock::new(None)),
-            data_request_interval,
+            assigned_nodes: Arc::new(tokio::sync::RwLock::new(Vec::new())),
+            config: Arc::new(tokio::sync::RwLock::new(RoutingConfig::default())),
+            pending_routing: Arc::new(tokio::sync::RwLock::new(None)),
+            retry_routing_at: Arc::new(tokio::sync::RwLock::new(None)),
+            pending_requests: Arc::new(tokio::sync::Mutex::new(PendingRequests::default())),
+            data_request_interval: Duration::from_secs(settings.data_request_interval),
+            data_types: Arc::new(settings.data_types.clone()),
+            compression_level: settings.compression_level,
+            seen_packets: Arc::new(tokio::sync::Mutex::new(DedupWindow::with_budget_mb(
+                settings.dedup_memory_budget_mb,
+            ))),
+            subscriber,
+            topic_prefix,
+            request_limiter: Arc::new(tokio::sync::Mutex::new(RateLimiter::new(
+                f64::from(settings.max_requests_per_sec),
+                settings.max_requests_per_sec,
+            ))),
+            connected,
+            qos: settings.qos,
         };
 
         // Start heartbeat sender
@@ -77,6 +441,12 @@ impl SlaveNode {
         let client_clone = client.clone();
         let current_load = node.current_load.clone();
         let master_id = node.master_id.clone();
+        let pending_routing = node.pending_routing.clone();
+        let retry_routing_at = node.retry_routing_at.clone();
+        let data_types = node.data_types.clone();
+        let topic_prefix = node.topic_prefix.clone();
+        let logical_id = settings.logical_id.clone();
+        let qos = node.qos;
 
         tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(5));
@@ -93,8 +463,8 @@ impl SlaveNode {
                     if let Ok(payload) = serde_json::to_string(&heartbeat) {
                         if let Err(e) = client_clone
                             .publish(
-                                format!("heartbeat/slave/{}", heartbeat.node_id),
-                                QoS::AtLeastOnce,
+                                topics::heartbeat_slave(&topic_prefix, &heartbeat.node_id),
+                                qos.default,
                                 false,
                                 payload,
                             )
@@ -104,45 +474,151 @@ impl SlaveNode {
                             heartbeat.status = NodeStatus::Error;
                         }
                     }
+                } else if retry_routing_at
+                    .read()
+                    .await
+                    .is_some_and(|retry_at| Instant::now() < retry_at)
+                {
+                    println!("Waiting before asking for routing again");
                 } else {
                     // If no master is assigned, send routing request
                     node_info_clone.status = NodeStatus::Inactive;
-                    Self::request_routing(&client_clone, &heartbeat).await;
+                    Self::request_routing(
+                        &client_clone,
+                        &topic_prefix,
+                        &heartbeat,
+                        &pending_routing,
+                        fan_out,
+                        &data_types,
+                        logical_id.as_deref(),
+                        qos.routing,
+                    )
+                    .await;
                 }
             }
         });
 
-        // Start data requester
+        // Start data requester, cycling through the assigned nodes
         let client_clone = client.clone();
-        let master_id = node.master_id.clone();
+        let assigned_nodes = node.assigned_nodes.clone();
+        let pending_requests = node.pending_requests.clone();
         let node_id = node.node_info.node_id.clone();
         let data_request_interval = node.data_request_interval;
+        let data_types = node.data_types.clone();
+        let config = node.config.clone();
+        let compression_level = node.compression_level;
+        let topic_prefix = node.topic_prefix.clone();
+        let request_limiter = node.request_limiter.clone();
 
         tokio::spawn(async move {
             let mut interval = time::interval(data_request_interval);
+            let mut cursor = 0;
             loop {
                 interval.tick().await;
-                if let Some(master) = master_id.read().await.as_ref() {
-                    Self::request_data(&client_clone, master, &node_id).await;
+                let nodes = assigned_nodes.read().await;
+                if let Some(master) = next_node(&nodes, &mut cursor) {
+                    if !may_send_request(&request_limiter).await {
+                        continue;
+                    }
+                    let mut request = data_request(&node_id, &data_types);
+                    request.compression_level = negotiated_compression(
+                        compression_level,
+                        config.read().await.merged().as_ref(),
+                    );
+                    pending_requests
+                        .lock()
+                        .await
+                        .track(&request, master, Instant::now());
+                    Self::request_data(&client_clone, &topic_prefix, master, &request, qos.default)
+                        .await;
+                }
+            }
+        });
+
+        // Report data request round trips
+        let pending_requests = node.pending_requests.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(LATENCY_REPORT_INTERVAL);
+            loop {
+                interval.tick().await;
+                for line in pending_requests.lock().await.latency.report() {
+                    println!("Round trip {}", line);
+                }
+            }
+        });
+
+        // Resend data requests nobody answered within the processing timeout
+        let client_clone = client.clone();
+        let config = node.config.clone();
+        let pending_requests = node.pending_requests.clone();
+        let topic_prefix = node.topic_prefix.clone();
+        let request_limiter = node.request_limiter.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(RETRY_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let timeout = match config.read().await.merged() {
+                    Some(cfg) => Duration::from_millis(cfg.processing_timeout_ms),
+                    None => continue,
+                };
+                let resends = pending_requests.lock().await.sweep(
+                    Instant::now(),
+                    timeout,
+                    max_request_retries,
+                );
+                for (master, request) in resends {
+                    // Requests left unsent stay due for the next sweep
+                    if !may_send_request(&request_limiter).await {
+                        break;
+                    }
+                    let sent = Self::request_data(
+                        &client_clone,
+                        &topic_prefix,
+                        &master,
+                        &request,
+                        qos.default,
+                    )
+                    .await;
+                    if sent {
+                        pending_requests
+                            .lock()
+                            .await
+                            .resent(&request.request_id, Instant::now());
+                    }
                 }
             }
         });
 
         // Event loop handler
         let node_info_clone = node.node_info.clone();
-        let client_clone = client.clone();
-        let current_load_clone = node.current_load.clone();
+        let subscriber = node.subscriber.clone();
         let master_id = node.master_id.clone();
+        let assigned_nodes = node.assigned_nodes.clone();
         let config = node.config.clone();
+        let pending_routing = node.pending_routing.clone();
+        let retry_routing_at = node.retry_routing_at.clone();
+        let pending_requests = node.pending_requests.clone();
+        let seen_packets = node.seen_packets.clone();
+        let connected = node.connected.clone();
+        let secret = settings.shared_secret.clone();
 
         tokio::spawn(async move {
             handle_events(
                 eventloop,
                 node_info_clone,
-                client_clone,
-                current_load_clone,
+                subscriber,
+                client,
                 master_id,
+                assigned_nodes,
                 config,
+                pending_routing,
+                retry_routing_at,
+                pending_requests,
+                seen_packets,
+                connected,
+                secret,
             )
             .await;
         });
@@ -150,152 +626,653 @@ impl SlaveNode {
         Ok(node)
     }
 
-    async fn request_routing(client: &AsyncClient, node_info: &NodeInfo) {
+    #[allow(clippy::too_many_arguments)]
+    async fn request_routing(
+        client: &impl MqttTransport,
+        topic_prefix: &str,
+        node_info: &NodeInfo,
+        pending_routing: &Arc<tokio::sync::RwLock<Option<String>>>,
+        fan_out: u32,
+        data_types: &[String],
+        logical_id: Option<&str>,
+        qos: QoS,
+    ) {
+        // Each attempt gets a fresh id so responses to older attempts are ignored
+        let request_id = Uuid::new_v4().to_string();
+        *pending_routing.write().await = Some(request_id.clone());
+
         let request = RoutingRequest {
             client_id: node_info.node_id.clone(),
-            data_type: vec!["text".to_string(), "sensor".to_string()],
+            data_type: data_types.to_vec(),
             node_info: node_info.clone(),
             preferred_node: None,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs(),
+            request_id: Some(request_id),
+            fan_out: (fan_out > 1).then_some(fan_out),
+            schema_version: PROTOCOL_VERSION,
+            logical_id: logical_id.map(str::to_string),
         };
 
         if let Ok(payload) = serde_json::to_string(&request) {
             if let Err(e) = client
-                .publish("routing/request", QoS::AtLeastOnce, false, payload)
+                .publish(topics::routing_request(topic_prefix), qos, false, payload)
                 .await
             {
                 eprintln!("Error publishing routing request: {:?}", e);
             }
         }
     }
-    async fn request_data(client: &AsyncClient, master_id: &str, node_id: &str) {
-        let data_request = DataRequest {
-            request_id: Uuid::new_v4().to_string(),
-            slave_id: node_id.to_string(),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-            data_types: vec!["text".to_string(), "sensor".to_string()],
-            max_items: 10,
-        };
 
-        // Publish to the specific master-slave data request topic
-        let topic = format!("data/request/{}/{}", master_id, node_id);
-        if let Ok(payload) = serde_json::to_string(&data_request) {
+    /// Tells the orchestrator whether we took up the routing in `ack`
+    async fn send_routing_ack(
+        client: &impl MqttTransport,
+        topic_prefix: &str,
+        ack: &RoutingAck,
+        qos: QoS,
+    ) {
+        if let Ok(payload) = serde_json::to_string(ack) {
             if let Err(e) = client
-                .publish(&topic, QoS::AtLeastOnce, false, payload)
+                .publish(
+                    topics::routing_ack(topic_prefix, &ack.node_id),
+                    qos,
+                    false,
+                    payload,
+                )
                 .await
             {
-                eprintln!("Error publishing data request: {:?}", e);
-            } else {
-                println!(
-                    "Sent data request to node {} on topic {}",
-                    master_id, topic
+                eprintln!("Error publishing routing ack: {:?}", e);
+            }
+        }
+    }
+
+    async fn request_data(
+        client: &impl MqttTransport,
+        topic_prefix: &str,
+        master_id: &str,
+        data_request: &DataRequest,
+        qos: QoS,
+    ) -> bool {
+        // Publish to the specific master-slave data request topic
+        let topic = topics::data_request(topic_prefix, master_id, &data_request.client_id);
+        let Ok(payload) = serde_json::to_string(data_request) else {
+            return false;
+        };
+        if let Err(e) = client.publish(&topic, qos, false, payload).await {
+            eprintln!("Error publishing data request: {:?}", e);
+            return false;
+        }
+        println!("Sent data request to node {} on topic {}", master_id, topic);
+        true
+    }
+}
+
+/// The compression level to ask for, or `None` (leave it to the node) unless
+/// every assigned node advertised the compression feature
+fn negotiated_compression(wanted: u32, config: Option<&ClientConfiguration>) -> Option<u32> {
+    let supported = config.is_some_and(|config| config.supports(FEATURE_COMPRESSION));
+    (wanted > 0 && supported).then_some(wanted.min(MAX_COMPRESSION_LEVEL))
+}
+
+/// Spends a token from `limiter`, warning when the request has to be dropped
+async fn may_send_request(limiter: &tokio::sync::Mutex<RateLimiter>) -> bool {
+    let allowed = limiter.lock().await.try_acquire();
+    if !allowed {
+        warn!("Data request rate over MAX_REQUESTS_PER_SEC; dropping request");
+    }
+    allowed
+}
+
+/// How often unanswered data requests are checked for a resend
+const RETRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most packets asked for in one data request
+const MAX_ITEMS_PER_REQUEST: u32 = 10;
+
+fn data_request(node_id: &str, data_types: &[String]) -> DataRequest {
+    DataRequest {
+        request_id: Uuid::new_v4().to_string(),
+        client_id: node_id.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        data_types: data_types.to_vec(),
+        only_if_changed: None,
+        idempotency_key: None,
+        compression_level: None,
+        fulfillment: Fulfillment::BestEffort,
+        max_items: Some(MAX_ITEMS_PER_REQUEST),
+        priority: 0,
+    }
+}
+
+struct PendingRequest {
+    request: DataRequest,
+    node_id: String,
+    sent_at: Instant,
+    retries: u32,
+}
+
+/// Round trips kept per data type for percentiles
+const LATENCY_WINDOW: usize = 1000;
+
+/// How often round-trip latency stats are printed
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Aggregate round trips for one data type
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LatencySummary {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+}
+
+#[derive(Debug, Default)]
+struct TypeLatencies {
+    count: u64,
+    min: Option<Duration>,
+    max: Duration,
+    /// The last `LATENCY_WINDOW` samples, oldest first
+    recent: VecDeque<Duration>,
+}
+
+/// Data request round trips per data type. Count, min and max cover every
+/// sample; percentiles are exact over the most recent `LATENCY_WINDOW`.
+#[derive(Debug, Default)]
+struct LatencyStats {
+    by_type: BTreeMap<String, TypeLatencies>,
+}
+
+impl LatencyStats {
+    fn record(&mut self, data_type: &str, latency: Duration) {
+        let stats = self.by_type.entry(data_type.to_string()).or_default();
+        stats.count += 1;
+        stats.min = Some(stats.min.map_or(latency, |min| min.min(latency)));
+        stats.max = stats.max.max(latency);
+        if stats.recent.len() == LATENCY_WINDOW {
+            stats.recent.pop_front();
+        }
+        stats.recent.push_back(latency);
+    }
+
+    fn summary(&self, data_type: &str) -> Option<LatencySummary> {
+        let stats = self.by_type.get(data_type)?;
+        let mut sorted: Vec<Duration> = stats.recent.iter().copied().collect();
+        sorted.sort();
+        // Nearest-rank percentile
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+        Some(LatencySummary {
+            count: stats.count,
+            min: stats.min?,
+            max: stats.max,
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+        })
+    }
+
+    /// One line per data type, for the periodic report
+    fn report(&self) -> Vec<String> {
+        self.by_type
+            .keys()
+            .filter_map(|data_type| {
+                let s = self.summary(data_type)?;
+                Some(format!(
+                    "{}: count={} min={:?} p50={:?} p95={:?} p99={:?} max={:?}",
+                    data_type, s.count, s.min, s.p50, s.p95, s.p99, s.max
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Data requests sent but not yet answered, keyed by request id
+#[derive(Default)]
+struct PendingRequests {
+    requests: HashMap<String, PendingRequest>,
+    /// Round trips of answered requests, by the data type that answered first
+    latency: LatencyStats,
+}
+
+impl PendingRequests {
+    fn track(&mut self, request: &DataRequest, node_id: &str, now: Instant) {
+        self.requests.insert(
+            request.request_id.clone(),
+            PendingRequest {
+                request: request.clone(),
+                node_id: node_id.to_string(),
+                sent_at: now,
+                retries: 0,
+            },
+        );
+    }
+
+    /// Marks the request answered; false if it wasn't pending
+    fn acknowledge(&mut self, request_id: &str) -> bool {
+        self.requests.remove(request_id).is_some()
+    }
+
+    /// Marks the request answered by a `data_type` packet and records the
+    /// round trip since it was (last) sent
+    fn answer(&mut self, request_id: &str, data_type: &str, now: Instant) -> bool {
+        match self.requests.remove(request_id) {
+            Some(pending) => {
+                self.latency
+                    .record(data_type, now.duration_since(pending.sent_at));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the requests (with their node) to send again because nothing
+    /// answered them within `timeout`. Requests already resent `max_retries`
+    /// times are dropped instead. Nothing counts as a retry until `resent`
+    /// reports it sent.
+    fn sweep(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+        max_retries: u32,
+    ) -> Vec<(String, DataRequest)> {
+        let mut resends = Vec::new();
+        self.requests.retain(|request_id, pending| {
+            if now.duration_since(pending.sent_at) < timeout {
+                return true;
+            }
+            if pending.retries >= max_retries {
+                error!(
+                    "Data request {} to node {} unanswered after {} retries; giving up",
+                    request_id, pending.node_id, pending.retries
                 );
+                return false;
             }
+            println!(
+                "Resending data request {} to node {} (retry {}/{})",
+                request_id,
+                pending.node_id,
+                pending.retries + 1,
+                max_retries
+            );
+            resends.push((pending.node_id.clone(), pending.request.clone()));
+            true
+        });
+        resends
+    }
+
+    /// Records a resend of `request_id` from `sweep` as published
+    fn resent(&mut self, request_id: &str, now: Instant) {
+        if let Some(pending) = self.requests.get_mut(request_id) {
+            pending.retries += 1;
+            pending.sent_at = now;
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DataRequest {
-    request_id: String,
-    slave_id: String,
-    timestamp: u64,
-    data_types: Vec<String>,
-    max_items: u32,
+/// The node the next data request goes to, cycling through `nodes`
+fn next_node<'a>(nodes: &'a [String], cursor: &mut usize) -> Option<&'a str> {
+    if nodes.is_empty() {
+        return None;
+    }
+    let node = &nodes[*cursor % nodes.len()];
+    *cursor = cursor.wrapping_add(1);
+    Some(node)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_events(
     mut eventloop: EventLoop,
     node_info: NodeInfo,
-    client: AsyncClient,
-    current_load: Arc<AtomicU32>,
+    subscriber: Subscriber,
+    client: Signed<AsyncClient>,
     master_id: Arc<tokio::sync::RwLock<Option<String>>>,
-    config: Arc<tokio::sync::RwLock<Option<ClientConfiguration>>>,
+    assigned_nodes: Arc<tokio::sync::RwLock<Vec<String>>>,
+    config: Arc<tokio::sync::RwLock<RoutingConfig>>,
+    pending_routing: Arc<tokio::sync::RwLock<Option<String>>>,
+    retry_routing_at: Arc<tokio::sync::RwLock<Option<Instant>>>,
+    pending_requests: Arc<tokio::sync::Mutex<PendingRequests>>,
+    seen_packets: Arc<tokio::sync::Mutex<DedupWindow>>,
+    connected: Arc<AtomicBool>,
+    secret: Option<SharedSecret>,
 ) {
+    let mut backoff = Backoff::default();
     loop {
         match eventloop.poll().await {
             Ok(event) => {
-                if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = event {
+                backoff.reset();
+                if let rumqttc::Event::Outgoing(rumqttc::Outgoing::Subscribe(pkid)) = event {
+                    subscriber.acks.lock().unwrap().sent(pkid);
+                } else if let rumqttc::Event::Incoming(rumqttc::Packet::SubAck(suback)) = event {
+                    let refused = suback
+                        .return_codes
+                        .iter()
+                        .any(|code| matches!(code, rumqttc::SubscribeReasonCode::Failure));
+                    let ready = subscriber.acks.lock().unwrap().acked(suback.pkid, refused);
+                    send_routing_acks(&client, &subscriber, ready).await;
+                } else if let rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) = event {
+                    connected.store(true, Ordering::Relaxed);
+                    // Off the poll loop, which must keep running for the
+                    // subscribe requests to go out
+                    let subscriber = subscriber.clone();
+                    tokio::spawn(async move { subscriber.resubscribe().await });
+                } else if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = event {
+                    let Some(payload) =
+                        integrity::unseal_with(secret.as_ref(), &publish.topic, &publish.payload)
+                    else {
+                        warn!(
+                            "Dropping message on {} with a missing or invalid signature",
+                            publish.topic
+                        );
+                        continue;
+                    };
                     // Handle routing response
-                    if publish
-                        .topic
-                        .starts_with(&format!("routing/response/slave-{}", node_info.node_id))
-                    {
-                        if let Ok(response) =
-                            serde_json::from_slice::<RoutingResponse>(&publish.payload)
+                    let prefix = subscriber.topic_prefix.as_str();
+                    if publish.topic == topics::routing_response(prefix, &node_info.node_id) {
+                        if let Some(response) =
+                            decode_or_log::<RoutingResponse>(&publish.topic, payload)
                         {
-                            handle_routing_response(response, &client, &master_id, &config).await;
+                            let ack = handle_routing_response(
+                                response,
+                                &subscriber,
+                                &master_id,
+                                &assigned_nodes,
+                                &config,
+                                &pending_routing,
+                                &retry_routing_at,
+                            )
+                            .await;
+                            // Sent once the broker confirms the subscriptions
+                            if let Some(ack) = ack {
+                                let ready = subscriber.acks.lock().unwrap().hold(ack);
+                                send_routing_acks(&client, &subscriber, ready).await;
+                            }
                         }
                     }
-                    // Handle data response from master
-                    else if let Some(master) = master_id.read().await.as_ref() {
-                        let data_response_topic =
-                            format!("data/response/{}/{}", master, node_info.node_id);
-                        if publish.topic == data_response_topic {
-                            if let Ok(data_packet) =
-                                serde_json::from_slice::<DataPacket>(&publish.payload)
+                    // Handle data responses from any assigned node
+                    else {
+                        let nodes = assigned_nodes.read().await;
+                        let from_assigned = |topic: fn(&str, &str, &str) -> String| {
+                            nodes.iter().any(|node| {
+                                publish.topic == topic(prefix, node, &node_info.node_id)
+                            })
+                        };
+                        if from_assigned(topics::data_response) {
+                            // Responses may be deflate-compressed frames holding
+                            // a single packet or a batch
+                            let data_packets =
+                                mqtt_common::decode_data_packets(payload).unwrap_or_default();
+                            for data_packet in data_packets {
+                                match check_version(data_packet.schema_version) {
+                                    Ok(())
+                                        if !seen_packets.lock().await.insert(&data_packet.id) =>
+                                    {
+                                        info!("Ignoring duplicate packet {}", data_packet.id);
+                                    }
+                                    Ok(()) => {
+                                        if let Some(request_id) =
+                                            data_packet.metadata.get("request_id")
+                                        {
+                                            pending_requests.lock().await.answer(
+                                                request_id,
+                                                &data_packet.data_type,
+                                                Instant::now(),
+                                            );
+                                        }
+                                        handle_data_response(&data_packet).await
+                                    }
+                                    Err(e) => eprintln!("Skipping data packet: {}", e),
+                                }
+                            }
+                        } else if from_assigned(topics::data_summary) {
+                            if let Some(summary) =
+                                decode_or_log::<FulfillmentSummary>(&publish.topic, payload)
                             {
-                                handle_data_response(&data_packet).await;
+                                pending_requests
+                                    .lock()
+                                    .await
+                                    .acknowledge(&summary.request_id);
+                                handle_fulfillment_summary(&summary);
                             }
                         }
                     }
                 }
             }
             Err(e) => {
-                eprintln!("[{}] Event loop error: {:?}", node_info.node_id, e);
-                time::sleep(Duration::from_secs(5)).await;
+                connected.store(false, Ordering::Relaxed);
+                let lost = subscriber.acks.lock().unwrap().connection_lost();
+                send_routing_acks(&client, &subscriber, lost).await;
+                let delay = backoff.next();
+                eprintln!(
+                    "[{}] Event loop error: {:?}; retrying in {:?}",
+                    node_info.node_id, e, delay
+                );
+                time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Sends the routing acks released by the subscriber's `AckGate`
+async fn send_routing_acks(
+    client: &impl MqttTransport,
+    subscriber: &Subscriber,
+    acks: Vec<RoutingAck>,
+) {
+    for ack in acks {
+        SlaveNode::send_routing_ack(
+            client,
+            &subscriber.topic_prefix,
+            &ack,
+            subscriber.qos.routing,
+        )
+        .await;
+    }
+}
+
+/// Configurations received for the current routing. Both the orchestrator
+/// and the assigned node answer a routing request, with different topic
+/// lists; they are merged the same way whichever arrives first.
+#[derive(Debug, Default)]
+struct RoutingConfig {
+    /// Routing attempt the configurations below answer
+    request_id: Option<String>,
+    orchestrator: Option<ClientConfiguration>,
+    node: Option<ClientConfiguration>,
+}
+
+impl RoutingConfig {
+    fn set(&mut self, issuer: RoutingIssuer, configuration: ClientConfiguration) {
+        match issuer {
+            RoutingIssuer::Orchestrator => self.orchestrator = Some(configuration),
+            RoutingIssuer::Node => self.node = Some(configuration),
+        }
+        if let (Some(orchestrator), Some(node)) = (&self.orchestrator, &self.node) {
+            for field in config_conflicts(orchestrator, node) {
+                eprintln!(
+                    "Orchestrator and node disagree on {}; using the orchestrator's",
+                    field
+                );
+            }
+        }
+    }
+
+    /// The orchestrator's configuration with the node's extra topics appended;
+    /// either one alone until both have arrived
+    fn merged(&self) -> Option<ClientConfiguration> {
+        match (&self.orchestrator, &self.node) {
+            (Some(orchestrator), Some(node)) => {
+                let mut merged = orchestrator.clone();
+                for topic in &node.subscribe_topics {
+                    if !merged.subscribe_topics.contains(topic) {
+                        merged.subscribe_topics.push(topic.clone());
+                    }
+                }
+                Some(merged)
             }
+            (Some(only), None) | (None, Some(only)) => Some(only.clone()),
+            (None, None) => None,
         }
     }
 }
 
+/// Settings the two configurations disagree on
+fn config_conflicts(
+    orchestrator: &ClientConfiguration,
+    node: &ClientConfiguration,
+) -> Vec<&'static str> {
+    [
+        (
+            "publish_topic",
+            orchestrator.publish_topic != node.publish_topic,
+        ),
+        ("qos", orchestrator.qos != node.qos),
+        (
+            "max_batch_size",
+            orchestrator.max_batch_size != node.max_batch_size,
+        ),
+        (
+            "processing_timeout_ms",
+            orchestrator.processing_timeout_ms != node.processing_timeout_ms,
+        ),
+        (
+            "compression_level",
+            orchestrator.compression_level != node.compression_level,
+        ),
+        ("wire_format", orchestrator.wire_format != node.wire_format),
+    ]
+    .into_iter()
+    .filter(|(_, differs)| *differs)
+    .map(|(field, _)| field)
+    .collect()
+}
+
+/// Whether a routing response answers our outstanding attempt. Unsolicited
+/// notices (no request id) are only trusted to revoke an assignment.
+fn is_current_routing_response(pending: Option<&str>, response: &RoutingResponse) -> bool {
+    match response.request_id.as_deref() {
+        Some(request_id) => pending == Some(request_id),
+        None => response.status == RoutingStatus::Rejected,
+    }
+}
+
+/// Applies a routing response. Returns the ack owed to the orchestrator
+/// when it is one of its accepted routings.
 async fn handle_routing_response(
     response: RoutingResponse,
-    client: &AsyncClient,
+    subscriber: &Subscriber<impl SubscribeClient>,
     master_id: &Arc<tokio::sync::RwLock<Option<String>>>,
-    config: &Arc<tokio::sync::RwLock<Option<ClientConfiguration>>>,
-) {
+    assigned_nodes: &Arc<tokio::sync::RwLock<Vec<String>>>,
+    config: &Arc<tokio::sync::RwLock<RoutingConfig>>,
+    pending_routing: &Arc<tokio::sync::RwLock<Option<String>>>,
+    retry_routing_at: &Arc<tokio::sync::RwLock<Option<Instant>>>,
+) -> Option<RoutingAck> {
+    {
+        let mut pending = pending_routing.write().await;
+        // The second answer to an accepted attempt only completes its configuration
+        let completes_current = response.status == RoutingStatus::Accepted
+            && response.request_id.is_some()
+            && config.read().await.request_id == response.request_id;
+        if !completes_current && !is_current_routing_response(pending.as_deref(), &response) {
+            println!(
+                "Ignoring stale routing response from node: {}",
+                response.node_id
+            );
+            return None;
+        }
+        if response.request_id.is_some() && response.status != RoutingStatus::Pending {
+            *pending = None;
+        }
+    }
+
     match response.status {
         RoutingStatus::Accepted => {
             println!("Routing accepted by node: {}", response.node_id);
-            *master_id.write().await = Some(response.node_id);
+            let (issuer, client_id, node_id) = (
+                response.issuer,
+                response.client_id.clone(),
+                response.node_id.clone(),
+            );
+            let mut routing = config.write().await;
+            if routing.request_id != response.request_id {
+                *routing = RoutingConfig {
+                    request_id: response.request_id.clone(),
+                    ..RoutingConfig::default()
+                };
+            }
+
+            // The orchestrator's assignment stands over the node's own answer
+            if response.issuer == RoutingIssuer::Orchestrator || routing.orchestrator.is_none() {
+                // Older orchestrators and direct node answers list no assignments
+                let nodes: Vec<String> = if response.assignments.is_empty() {
+                    vec![response.node_id.clone()]
+                } else {
+                    response
+                        .assignments
+                        .iter()
+                        .map(|assignment| assignment.node_id.clone())
+                        .collect()
+                };
+                *master_id.write().await = Some(response.node_id);
+                *assigned_nodes.write().await = nodes;
+            }
+            let nodes = assigned_nodes.read().await.clone();
+
             if let Some(cfg) = response.configuration {
-                *config.write().await = Some(cfg.clone());
+                routing.set(response.issuer, cfg);
+            }
+            let merged = routing.merged();
+            drop(routing);
 
-                // Subscribe to configured topics
-                for topic in cfg.subscribe_topics {
-                    if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
-                        eprintln!("Error subscribing to topic {}: {:?}", topic, e);
-                    }
+            let mut subscribed = true;
+            if let Some(cfg) = merged {
+                // Subscribe to the broadcast and control topics the configuration lists
+                for topic in &cfg.subscribe_topics {
+                    subscribed &= subscriber.ensure(topic).await;
                 }
 
-                // Subscribe to data response topic
-                let master = master_id.read().await;
-                if let Some(master_id) = master.as_ref() {
-                    if let Err(e) = client
-                        .subscribe(format!("data/response/{}/+", master_id), QoS::AtLeastOnce)
-                        .await
-                    {
-                        eprintln!("Error subscribing to data response topic: {:?}", e);
-                    }
+                // Subscribe to the data response topics of every assigned node
+                for node in &nodes {
+                    let prefix = subscriber.topic_prefix.as_str();
+                    subscribed &= subscriber
+                        .ensure(&topics::data_response(prefix, node, "+"))
+                        .await;
+                    subscribed &= subscriber
+                        .ensure(&topics::data_summary(prefix, node, "+"))
+                        .await;
                 }
             }
+            // Only the orchestrator waits for confirmation
+            return (issuer == RoutingIssuer::Orchestrator).then_some(RoutingAck {
+                client_id,
+                node_id,
+                accepted: subscribed,
+            });
         }
         RoutingStatus::Rejected => {
             println!("Routing rejected: {:?}", response.rejection_reason);
             *master_id.write().await = None;
-            *config.write().await = None;
+            assigned_nodes.write().await.clear();
+            *config.write().await = RoutingConfig::default();
+            // Honor the orchestrator's hint instead of retrying on the next heartbeat
+            *retry_routing_at.write().await = response
+                .retry_after_ms
+                .map(|ms| Instant::now() + Duration::from_millis(ms));
         }
         RoutingStatus::Pending => {
-            println!("Routing pending...");
+            println!("Routing pending: {:?}", response.rejection_reason);
+            // The orchestrator has no node for us yet; wait as long as it asks
+            *retry_routing_at.write().await = response
+                .retry_after_ms
+                .map(|ms| Instant::now() + Duration::from_millis(ms));
         }
     }
+    None
 }
 
 async fn handle_data_response(data_packet: &DataPacket) {
@@ -307,18 +1284,34 @@ async fn handle_data_response(data_packet: &DataPacket) {
             temperature,
             humidity,
             pressure,
+            units,
         } => {
             println!(
-                "Sensor {} reading: {}'Celcius {}'Bar {}'Pascal",
-                sensor_id, temperature, humidity, pressure
+                "Sensor {} reading: {}",
+                sensor_id,
+                format_sensor_reading(*temperature, *humidity, *pressure, units.as_ref())
             )
         }
         _ => println!("Other data type received"),
     }
 }
 
+fn handle_fulfillment_summary(summary: &FulfillmentSummary) {
+    match &summary.error {
+        Some(error) => println!("Data request {} rejected: {}", summary.request_id, error),
+        None => {
+            for skipped in &summary.skipped {
+                println!(
+                    "Data request {} skipped {}: {:?}",
+                    summary.request_id, skipped.data_type, skipped.reason
+                );
+            }
+        }
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<(), BoxError> {
+async fn main() -> Result<(), PoolError> {
     /* Initialize logging with timestamp */
     env_logger::Builder::from_default_env()
         .format_timestamp_millis()
@@ -326,63 +1319,724 @@ async fn main() -> Result<(), BoxError> {
         .init();
     info!("Starting MQTT Client Node...");
 
-    /* Load configuration */
-    let config = NodeConfig {
-        mqtt_host: std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string()),
-        mqtt_port: std::env::var("MQTT_PORT")
-            .unwrap_or_else(|_| "1883".to_string())
-            .parse()
-            .unwrap_or(1883),
-        node_capacity: std::env::var("NODE_CAPACITY")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse()
-            .unwrap_or(100),
-        data_request_interval: std::env::var("DATA_REQUEST_INTERVAL")
-            .unwrap_or_else(|_| "10".to_string())
-            .parse()
-            .unwrap_or(10),
-    };
+    /* Load configuration: environment variables, then the config file */
+    let file = ConfigFile::from_env()?;
+    let config = NodeConfig::load(&file.client, config::env_var)?;
     info!("Using configuration: {:?}", config);
 
-    /* Initialize the slave node with error conversion */
-    let slave = SlaveNode::new(
-        config.node_capacity,
-        Duration::from_secs(config.data_request_interval),
-    )
-    .await
-    .map_err(|e| -> BoxError {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            e.to_string(),
-        ))
-    })?;
+    /* Initialize the slave node */
+    let slave = SlaveNode::new(&config).await?;
 
     info!(
         "Client node initialized successfully with ID: {}",
         slave.node_info.node_id
     );
 
-    /* Create a future that completes when a shutdown signal is received */
-    let shutdown = async {
-        match signal::ctrl_c().await {
-            Ok(()) => {
-                info!("Received shutdown signal");
+    /* Run the node until a shutdown signal is received */
+    let reason = shutdown::wait_for_shutdown().await;
+    info!(
+        "Received shutdown signal ({}), initiating shutdown sequence...",
+        reason
+    );
+
+    /* Perform cleanup */
+    cleanup(&slave, &reason).await?;
+    info!("Slave node shut down successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::MqttOptions;
+
+    fn routing_response(node_id: &str, request_id: Option<&str>) -> RoutingResponse {
+        RoutingResponse {
+            node_id: node_id.to_string(),
+            client_id: "client-1".to_string(),
+            status: RoutingStatus::Accepted,
+            rejection_reason: None,
+            configuration: None,
+            timestamp: 0,
+            request_id: request_id.map(str::to_string),
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejection_retry_hint_delays_next_routing_request() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 10);
+        let subscriber = Subscriber::new(client, "", QosPolicy::default());
+        let master_id = Arc::new(tokio::sync::RwLock::new(None));
+        let assigned_nodes = Arc::new(tokio::sync::RwLock::new(Vec::new()));
+        let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+        let pending = Arc::new(tokio::sync::RwLock::new(None));
+        let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+        let reject = |retry_after_ms| RoutingResponse {
+            status: RoutingStatus::Rejected,
+            rejection_reason: Some("No available master nodes".to_string()),
+            retry_after_ms,
+            ..routing_response("none", None)
+        };
+
+        let before = Instant::now();
+        handle_routing_response(
+            reject(Some(3000)),
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        let wait_until = retry_at.read().await.unwrap();
+        assert!(wait_until >= before + Duration::from_millis(3000));
+        assert!(wait_until <= Instant::now() + Duration::from_millis(3000));
+
+        // Without a hint the client retries on its next heartbeat
+        handle_routing_response(
+            reject(None),
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        assert!(retry_at.read().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stale_routing_response_is_ignored() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 10);
+        let subscriber = Subscriber::new(client, "", QosPolicy::default());
+        let master_id = Arc::new(tokio::sync::RwLock::new(Some("node-a".to_string())));
+        let assigned_nodes = Arc::new(tokio::sync::RwLock::new(vec!["node-a".to_string()]));
+        let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+        let pending = Arc::new(tokio::sync::RwLock::new(Some("attempt-2".to_string())));
+        let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+
+        // A late answer to an earlier attempt must not move us
+        for stale in [Some("attempt-1"), None] {
+            handle_routing_response(
+                routing_response("node-b", stale),
+                &subscriber,
+                &master_id,
+                &assigned_nodes,
+                &config,
+                &pending,
+                &retry_at,
+            )
+            .await;
+            assert_eq!(master_id.read().await.as_deref(), Some("node-a"));
+        }
+        assert_eq!(pending.read().await.as_deref(), Some("attempt-2"));
+
+        handle_routing_response(
+            routing_response("node-c", Some("attempt-2")),
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        assert_eq!(master_id.read().await.as_deref(), Some("node-c"));
+        assert_eq!(*assigned_nodes.read().await, vec!["node-c"]);
+        assert!(pending.read().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_orchestrator_routing_is_acknowledged() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 10);
+        let subscriber = Subscriber::new(client, "", QosPolicy::default());
+        let master_id = Arc::new(tokio::sync::RwLock::new(None));
+        let assigned_nodes = Arc::new(tokio::sync::RwLock::new(Vec::new()));
+        let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+        let pending = Arc::new(tokio::sync::RwLock::new(Some("attempt-1".to_string())));
+        let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+        let configuration = configuration(&["data/input/client-1"], 30_000);
+
+        let ack = handle_routing_response(
+            RoutingResponse {
+                configuration: Some(configuration.clone()),
+                ..routing_response("node-a", Some("attempt-1"))
+            },
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        assert_eq!(
+            ack,
+            Some(RoutingAck {
+                client_id: "client-1".to_string(),
+                node_id: "node-a".to_string(),
+                accepted: true,
+            })
+        );
+        // Held until the broker confirms the three subscriptions just queued
+        {
+            let mut gate = subscriber.acks.lock().unwrap();
+            assert!(gate.hold(ack.unwrap()).is_empty());
+            for pkid in 1..=3 {
+                gate.sent(pkid);
+            }
+            assert!(gate.acked(1, false).is_empty());
+            assert!(gate.acked(2, false).is_empty());
+            assert_eq!(gate.acked(3, false).len(), 1);
+        }
+
+        // The node's own answer completes the routing but needs no ack
+        let ack = handle_routing_response(
+            RoutingResponse {
+                configuration: Some(configuration),
+                issuer: RoutingIssuer::Node,
+                ..routing_response("node-a", Some("attempt-1"))
+            },
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        assert_eq!(ack, None);
+    }
+
+    fn routing_ack(node_id: &str) -> RoutingAck {
+        RoutingAck {
+            client_id: "client-1".to_string(),
+            node_id: node_id.to_string(),
+            accepted: true,
+        }
+    }
+
+    #[test]
+    fn test_routing_ack_waits_for_every_suback() {
+        let mut gate = AckGate::default();
+        assert_eq!(
+            gate.hold(routing_ack("node-a")),
+            vec![routing_ack("node-a")]
+        );
+
+        gate.queued();
+        gate.queued();
+        assert!(gate.hold(routing_ack("node-b")).is_empty());
+        // Queued later, so node-b doesn't wait for it
+        gate.queued();
+        gate.sent(1);
+        gate.sent(2);
+        assert!(gate.acked(2, false).is_empty());
+        assert_eq!(gate.acked(1, false), vec![routing_ack("node-b")]);
+        assert!(gate.hold(routing_ack("node-c")).is_empty());
+        gate.sent(3);
+        assert_eq!(gate.acked(3, false), vec![routing_ack("node-c")]);
+    }
+
+    #[test]
+    fn test_refused_subscription_gives_the_routing_up() {
+        let mut gate = AckGate::default();
+        gate.queued();
+        assert!(gate.hold(routing_ack("node-a")).is_empty());
+        gate.sent(7);
+        let acks = gate.acked(7, true);
+        assert_eq!(
+            acks,
+            vec![RoutingAck {
+                accepted: false,
+                ..routing_ack("node-a")
+            }]
+        );
+    }
+
+    #[test]
+    fn test_lost_connection_gives_waiting_routings_up() {
+        let mut gate = AckGate::default();
+        gate.queued();
+        gate.queued();
+        assert!(gate.hold(routing_ack("node-a")).is_empty());
+        gate.sent(1);
+        let acks = gate.connection_lost();
+        assert_eq!(
+            acks,
+            vec![RoutingAck {
+                accepted: false,
+                ..routing_ack("node-a")
+            }]
+        );
+        // Nothing is left outstanding for later routings
+        assert_eq!(
+            gate.hold(routing_ack("node-b")),
+            vec![routing_ack("node-b")]
+        );
+    }
+
+    fn published(eventloop: &mut EventLoop) -> Vec<rumqttc::Publish> {
+        eventloop.clean();
+        std::mem::take(&mut eventloop.pending)
+            .into_iter()
+            .filter_map(|request| match request {
+                rumqttc::Request::Publish(publish) => Some(publish),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_routing_and_data_requests_use_configured_types() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+        let node_info = NodeInfo::new(NodeType::Client, 10);
+        let pending = Arc::new(tokio::sync::RwLock::new(None));
+        let data_types = vec!["image".to_string(), "log".to_string()];
+
+        SlaveNode::request_routing(
+            &client,
+            "",
+            &node_info,
+            &pending,
+            1,
+            &data_types,
+            Some("sensor-gateway-7"),
+            QoS::ExactlyOnce,
+        )
+        .await;
+        SlaveNode::request_data(
+            &client,
+            "",
+            "node-a",
+            &data_request(&node_info.node_id, &data_types),
+            QoS::AtMostOnce,
+        )
+        .await;
+
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 2);
+        let routing: RoutingRequest = serde_json::from_slice(&published[0].payload).unwrap();
+        let data: DataRequest = serde_json::from_slice(&published[1].payload).unwrap();
+        assert_eq!(routing.data_type, vec!["image", "log"]);
+        assert_eq!(routing.logical_id.as_deref(), Some("sensor-gateway-7"));
+        assert_eq!(data.data_types, routing.data_type);
+        assert_eq!(published[0].qos, QoS::ExactlyOnce);
+        assert_eq!(published[1].qos, QoS::AtMostOnce);
+    }
+
+    fn data_types_from_env(spec: &str) -> Vec<String> {
+        let env = |key: &str| (key == "CLIENT_DATA_TYPES").then(|| spec.to_string());
+        NodeConfig::load(&config::ClientConfig::default(), env)
+            .unwrap()
+            .data_types
+    }
+
+    #[test]
+    fn test_parse_data_types_drops_unknown_types() {
+        assert_eq!(
+            data_types_from_env("text,number,image"),
+            vec!["text", "number", "image"]
+        );
+        assert_eq!(data_types_from_env("text, video,text"), vec!["text"]);
+        assert_eq!(data_types_from_env(""), vec!["text", "sensor"]);
+        assert_eq!(data_types_from_env("video"), vec!["text", "sensor"]);
+    }
+
+    #[test]
+    fn test_config_file_values_are_typed_and_checked() {
+        let file = ConfigFile::parse(
+            r#"
+            [client]
+            mqtt_host = "broker.internal"
+            mqtt_port = 8883
+            mqtt_username = "client"
+            mqtt_password = "secret"
+            client_id_prefix = "edge-7"
+            client_data_types = ["image"]
+            "#,
+        )
+        .unwrap();
+        let config = NodeConfig::load(&file.client, |_| None).unwrap();
+        assert_eq!(config.mqtt_host, "broker.internal");
+        assert_eq!(config.mqtt_port, 8883);
+        assert_eq!(config.mqtt.username.as_deref(), Some("client"));
+        assert_eq!(config.client_id_prefix.as_deref(), Some("edge-7"));
+        assert_eq!(config.data_types, vec!["image"]);
+
+        for table in [
+            "client_data_types = [\"video\"]",
+            "max_requests_per_sec = 0",
+            "data_request_interval = 0",
+            "routing_qos = 7",
+        ] {
+            let file = ConfigFile::parse(&format!("[client]\n{}", table)).unwrap();
+            assert!(
+                NodeConfig::load(&file.client, |_| None).is_err(),
+                "{}",
+                table
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing_response_topic_matches_orchestrator_reply() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+        let node_info = NodeInfo::new(NodeType::Client, 10);
+        let pending = Arc::new(tokio::sync::RwLock::new(None));
+
+        SlaveNode::request_routing(
+            &client,
+            "pool-a",
+            &node_info,
+            &pending,
+            1,
+            &[],
+            None,
+            QoS::AtLeastOnce,
+        )
+        .await;
+        let published = published(&mut eventloop);
+        assert_eq!(published[0].topic, "pool-a/routing/request");
+        let request: RoutingRequest = serde_json::from_slice(&published[0].payload).unwrap();
+
+        // The orchestrator and nodes reply on `routing/response/{client_id}`
+        let reply_topic = format!("pool-a/routing/response/{}", request.client_id);
+        assert_eq!(
+            topics::routing_response("pool-a", &node_info.node_id),
+            reply_topic
+        );
+        assert!(!reply_topic.contains("slave-"));
+    }
+
+    #[test]
+    fn test_unanswered_request_is_resent_until_retries_run_out() {
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let mut pending = PendingRequests::default();
+        let request = data_request("client-1", &["text".to_string()]);
+        pending.track(&request, "node-a", start);
+        assert!(pending.sweep(start, timeout, 3).is_empty());
+
+        // Resends that didn't go out, e.g. over the rate limit, spend no retries
+        for tick in 1..10 {
+            assert_eq!(pending.sweep(start + timeout * tick, timeout, 3).len(), 1);
+        }
+
+        let mut resends = 0;
+        for tick in 1..10 {
+            let now = start + timeout * tick;
+            let swept = pending.sweep(now, timeout, 3);
+            for (node_id, resent) in &swept {
+                assert_eq!(node_id, "node-a");
+                assert_eq!(resent.request_id, request.request_id);
+                pending.resent(&resent.request_id, now);
             }
-            Err(err) => {
-                error!("Failed to listen for shutdown signal: {}", err);
+            resends += swept.len();
+        }
+        assert_eq!(resends, 3);
+        assert!(!pending.acknowledge(&request.request_id));
+
+        // An answered request is never resent
+        let answered = data_request("client-1", &["text".to_string()]);
+        pending.track(&answered, "node-a", start);
+        assert!(pending.acknowledge(&answered.request_id));
+        assert!(pending.sweep(start + timeout, timeout, 3).is_empty());
+    }
+
+    #[test]
+    fn test_compression_only_requested_from_nodes_supporting_it() {
+        let without = configuration(&[], 5000);
+        assert_eq!(negotiated_compression(6, Some(&without)), None);
+        assert_eq!(negotiated_compression(6, None), None);
+
+        let with = ClientConfiguration {
+            node_features: vec![FEATURE_COMPRESSION.to_string()],
+            ..configuration(&[], 5000)
+        };
+        assert_eq!(negotiated_compression(6, Some(&with)), Some(6));
+        assert_eq!(negotiated_compression(0, Some(&with)), None);
+
+        // Nothing is sent that a node could read as a compression request
+        let mut request = data_request("client-1", &["text".to_string()]);
+        request.compression_level = negotiated_compression(6, Some(&without));
+        let sent: DataRequest =
+            serde_json::from_slice(&serde_json::to_vec(&request).unwrap()).unwrap();
+        assert_eq!(sent.compression_level, None);
+    }
+
+    #[test]
+    fn test_latency_percentiles_per_data_type() {
+        let mut stats = LatencyStats::default();
+        // Slow samples that fall out of the percentile window
+        for _ in 0..500 {
+            stats.record("text", Duration::from_secs(10));
+        }
+        for ms in 1..=1000 {
+            stats.record("text", Duration::from_millis(ms));
+        }
+
+        let text = stats.summary("text").unwrap();
+        assert_eq!(text.count, 1500);
+        assert_eq!(text.min, Duration::from_millis(1));
+        assert_eq!(text.max, Duration::from_secs(10));
+        let close = |actual: Duration, expected_ms: u64| {
+            actual.as_millis().abs_diff(u128::from(expected_ms)) <= 1
+        };
+        assert!(close(text.p50, 500), "p50 {:?}", text.p50);
+        assert!(close(text.p95, 950), "p95 {:?}", text.p95);
+        assert!(close(text.p99, 990), "p99 {:?}", text.p99);
+        assert!(stats.summary("sensor").is_none());
+
+        // Answers are timed from when the request was sent
+        let start = Instant::now();
+        let mut pending = PendingRequests::default();
+        let request = data_request("client-1", &["sensor".to_string()]);
+        pending.track(&request, "node-a", start);
+        assert!(pending.answer(
+            &request.request_id,
+            "sensor",
+            start + Duration::from_millis(40)
+        ));
+        let sensor = pending.latency.summary("sensor").unwrap();
+        assert_eq!(sensor.count, 1);
+        assert_eq!(sensor.p99, Duration::from_millis(40));
+    }
+
+    fn configuration(topics: &[&str], processing_timeout_ms: u64) -> ClientConfiguration {
+        ClientConfiguration {
+            subscribe_topics: topics.iter().map(|t| t.to_string()).collect(),
+            publish_topic: "data/processed/client-1".to_string(),
+            qos: 1,
+            max_batch_size: 100,
+            processing_timeout_ms,
+            compression_level: 0,
+            wire_format: WireFormat::Json,
+            node_features: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_orchestrator_and_node_configurations_merge_in_either_order() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 100);
+        let subscriber = Subscriber::new(client, "", QosPolicy::default());
+
+        let mut from_orchestrator = routing_response("node-a", Some("attempt-1"));
+        from_orchestrator.configuration = Some(configuration(
+            &["data/input/client-1", "control/client-1"],
+            30000,
+        ));
+        let mut from_node = routing_response("node-a", Some("attempt-1"));
+        from_node.issuer = RoutingIssuer::Node;
+        from_node.configuration = Some(configuration(
+            &["data/response/node-a/client-1", "data/broadcast/#"],
+            5000,
+        ));
+
+        let mut merged = Vec::new();
+        for node_first in [false, true] {
+            let master_id = Arc::new(tokio::sync::RwLock::new(None));
+            let assigned_nodes = Arc::new(tokio::sync::RwLock::new(Vec::new()));
+            let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+            let pending = Arc::new(tokio::sync::RwLock::new(Some("attempt-1".to_string())));
+            let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+
+            let mut responses = vec![from_orchestrator.clone(), from_node.clone()];
+            if node_first {
+                responses.reverse();
             }
+            for response in responses {
+                handle_routing_response(
+                    response,
+                    &subscriber,
+                    &master_id,
+                    &assigned_nodes,
+                    &config,
+                    &pending,
+                    &retry_at,
+                )
+                .await;
+            }
+            assert_eq!(master_id.read().await.as_deref(), Some("node-a"));
+            merged.push(config.read().await.merged().unwrap());
         }
-    };
 
-    /* Run the node until shutdown */
-    tokio::select! {
-        _ = shutdown => {
-            info!("Initiating shutdown sequence...");
+        for cfg in &merged {
+            assert_eq!(
+                cfg.subscribe_topics,
+                vec![
+                    "data/input/client-1",
+                    "control/client-1",
+                    "data/response/node-a/client-1",
+                    "data/broadcast/#",
+                ]
+            );
+            // Conflicting settings come from the orchestrator
+            assert_eq!(cfg.processing_timeout_ms, 30000);
         }
     }
 
-    /* Perform cleanup */
-    cleanup(&slave).await?;
-    info!("Slave node shut down successfully");
-    Ok(())
+    /// Records subscribe calls instead of sending them
+    #[derive(Clone, Default)]
+    struct RecordingClient {
+        subscribed: Arc<std::sync::Mutex<Vec<String>>>,
+        levels: Arc<std::sync::Mutex<HashMap<String, QoS>>>,
+    }
+
+    impl SubscribeClient for RecordingClient {
+        async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+            self.subscribed.lock().unwrap().push(topic.to_string());
+            self.levels.lock().unwrap().insert(topic.to_string(), qos);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_use_the_qos_policy() {
+        let recorder = RecordingClient::default();
+        let qos = QosPolicy {
+            default: QoS::AtMostOnce,
+            routing: QoS::ExactlyOnce,
+        };
+        let subscriber = Subscriber::new(recorder.clone(), "", qos);
+        subscriber.subscribe_at_startup("client-1").await;
+        subscriber.ensure("data/response/node-a/+").await;
+        subscriber.resubscribe().await;
+
+        let levels = recorder.levels.lock().unwrap().clone();
+        assert_eq!(levels["routing/response/client-1"], QoS::ExactlyOnce);
+        assert_eq!(levels["data/response/node-a/+"], QoS::AtMostOnce);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_subscribes_again() {
+        let recorder = RecordingClient::default();
+        let subscriber = Subscriber::new(recorder.clone(), "", QosPolicy::default());
+        subscriber.subscribe_at_startup("client-1").await;
+        subscriber.ensure("data/response/node-a/+").await;
+
+        // The broker forgot both on reconnect
+        subscriber.resubscribe().await;
+        let mut subscribed = recorder.subscribed.lock().unwrap().clone();
+        subscribed.sort();
+        assert_eq!(
+            subscribed,
+            vec![
+                "data/response/node-a/+",
+                "data/response/node-a/+",
+                "routing/response/client-1",
+                "routing/response/client-1",
+            ]
+        );
+
+        // And they are remembered again afterwards
+        assert!(subscriber.ensure("data/response/node-a/+").await);
+        assert_eq!(recorder.subscribed.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_made_once_across_reroutes() {
+        let recorder = RecordingClient::default();
+        let subscriber = Subscriber::new(recorder.clone(), "", QosPolicy::default());
+        subscriber.subscribe_at_startup("client-1").await;
+        assert_eq!(
+            *recorder.subscribed.lock().unwrap(),
+            vec!["routing/response/client-1"]
+        );
+
+        let master_id = Arc::new(tokio::sync::RwLock::new(None));
+        let assigned_nodes = Arc::new(tokio::sync::RwLock::new(Vec::new()));
+        let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+        let pending = Arc::new(tokio::sync::RwLock::new(None));
+        let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+        for attempt in ["attempt-1", "attempt-2"] {
+            *pending.write().await = Some(attempt.to_string());
+            let mut response = routing_response("node-a", Some(attempt));
+            response.configuration = Some(configuration(
+                &["data/response/node-a/client-1", "data/broadcast/#"],
+                5000,
+            ));
+            handle_routing_response(
+                response,
+                &subscriber,
+                &master_id,
+                &assigned_nodes,
+                &config,
+                &pending,
+                &retry_at,
+            )
+            .await;
+        }
+
+        assert_eq!(
+            *recorder.subscribed.lock().unwrap(),
+            vec![
+                "routing/response/client-1",
+                "data/response/node-a/client-1",
+                "data/broadcast/#",
+                "data/response/node-a/+",
+                "data/summary/node-a/+",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_requests_cycle_through_assigned_nodes() {
+        let mqtt_options = MqttOptions::new("client-1", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 10);
+        let subscriber = Subscriber::new(client, "", QosPolicy::default());
+        let master_id = Arc::new(tokio::sync::RwLock::new(None));
+        let assigned_nodes = Arc::new(tokio::sync::RwLock::new(Vec::new()));
+        let config = Arc::new(tokio::sync::RwLock::new(RoutingConfig::default()));
+        let pending = Arc::new(tokio::sync::RwLock::new(Some("attempt-1".to_string())));
+        let retry_at = Arc::new(tokio::sync::RwLock::new(None));
+
+        let mut response = routing_response("node-a", Some("attempt-1"));
+        response.assignments = ["node-a", "node-b", "node-c"]
+            .iter()
+            .map(|node_id| mqtt_common::NodeAssignment {
+                node_id: node_id.to_string(),
+                configuration: ClientConfiguration {
+                    subscribe_topics: Vec::new(),
+                    publish_topic: String::new(),
+                    qos: 1,
+                    max_batch_size: 100,
+                    processing_timeout_ms: 30000,
+                    compression_level: 0,
+                    wire_format: WireFormat::Json,
+                    node_features: Vec::new(),
+                },
+            })
+            .collect();
+        handle_routing_response(
+            response,
+            &subscriber,
+            &master_id,
+            &assigned_nodes,
+            &config,
+            &pending,
+            &retry_at,
+        )
+        .await;
+        assert_eq!(master_id.read().await.as_deref(), Some("node-a"));
+
+        let nodes = assigned_nodes.read().await;
+        let mut cursor = 0;
+        let targets: Vec<&str> = (0..4)
+            .filter_map(|_| next_node(&nodes, &mut cursor))
+            .collect();
+        assert_eq!(targets, vec!["node-a", "node-b", "node-c", "node-a"]);
+    }
 }
@@ -1,25 +1,61 @@
 pub mod common {
-    use serde::{Deserialize, Serialize};
+    use crate::config;
+    use crate::integrity::{self, SharedSecret};
+    use flate2::{
+        read::{DeflateDecoder, GzDecoder},
+        write::{DeflateEncoder, GzEncoder},
+        Compression,
+    };
+    use log::{debug, warn};
+    use rand::Rng;
+    use rumqttc::{LastWill, MqttOptions, QoS};
+    use serde::{de::DeserializeOwned, ser::Error as _, Deserialize, Serialize, Serializer};
     use std::fmt;
+    use std::io::{Read, Write};
+    use std::time::Duration;
     use std::{
-        collections::HashMap,
-        time::{SystemTime, UNIX_EPOCH},
+        collections::{HashMap, HashSet, VecDeque},
+        time::{Instant, SystemTime, UNIX_EPOCH},
     };
     use uuid::Uuid;
+    /// Refuses to serialize NaN/Inf, which serde_json would otherwise emit as `null`
+    fn serialize_finite<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if value.is_finite() {
+            serializer.serialize_f64(*value)
+        } else {
+            Err(S::Error::custom("non-finite value"))
+        }
+    }
+
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub enum DataPayload {
         Text(String),
-        Number(f64),
+        Number(#[serde(serialize_with = "serialize_finite")] f64),
         Coordinates {
+            #[serde(serialize_with = "serialize_finite")]
             x: f64,
+            #[serde(serialize_with = "serialize_finite")]
             y: f64,
+            #[serde(serialize_with = "serialize_finite")]
             z: f64,
+            /// Reference frame, e.g. `"wgs84"` (x = longitude, y = latitude),
+            /// `"ecef"` or `"local"`; unknown for senders that predate it
+            #[serde(default)]
+            frame: Option<String>,
         },
         SensorData {
             sensor_id: String,
+            #[serde(serialize_with = "serialize_finite")]
             temperature: f64,
+            /// Relative humidity, in percent
+            #[serde(serialize_with = "serialize_finite")]
             humidity: f64,
+            #[serde(serialize_with = "serialize_finite")]
             pressure: f64,
+            /// Units of `temperature` and `pressure`; unknown for senders
+            /// that predate unit annotations
+            #[serde(default)]
+            units: Option<SensorUnits>,
         },
         ImageData {
             width: u32,
@@ -27,13 +63,518 @@ pub mod common {
             format: String,
             data: Vec<u8>,
         },
+        /// A chunk of audio, encoded as `codec` (e.g. `pcm_s16le`)
+        AudioData {
+            sample_rate: u32,
+            channels: u8,
+            codec: String,
+            data: Vec<u8>,
+        },
         LogEntry {
             level: String,
             message: String,
             timestamp: String,
         },
+        /// Several `LogEntry` payloads merged into one message
+        LogBatch {
+            entries: Vec<LogEntry>,
+        },
+        /// Another payload, serialized and compressed; see `DataPayload::compress`
+        Compressed {
+            /// Data type of the wrapped payload
+            inner_type: String,
+            algorithm: String,
+            data: Vec<u8>,
+        },
+        /// Arbitrary JSON for sources that fit none of the variants above
+        Json(#[serde(with = "json_value")] serde_json::Value),
+        /// Several payloads from the same event, e.g. a sensor reading and
+        /// the log line it triggered, processed one after another
+        Batch(Vec<DataPayload>),
+    }
+
+    /// `serde_json::Value` only deserializes from self-describing formats, so
+    /// binary formats such as bincode carry it as a JSON string instead
+    mod json_value {
+        use serde::{
+            de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer,
+        };
+
+        pub fn serialize<S: Serializer>(
+            value: &serde_json::Value,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                value.serialize(serializer)
+            } else {
+                serde_json::to_string(value)
+                    .map_err(S::Error::custom)?
+                    .serialize(serializer)
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<serde_json::Value, D::Error> {
+            if deserializer.is_human_readable() {
+                serde_json::Value::deserialize(deserializer)
+            } else {
+                let text = String::deserialize(deserializer)?;
+                serde_json::from_str(&text).map_err(D::Error::custom)
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    pub enum TemperatureUnit {
+        #[default]
+        Celsius,
+        Fahrenheit,
+        Kelvin,
+    }
+
+    impl TemperatureUnit {
+        pub fn symbol(&self) -> &'static str {
+            match self {
+                TemperatureUnit::Celsius => "°C",
+                TemperatureUnit::Fahrenheit => "°F",
+                TemperatureUnit::Kelvin => "K",
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    pub enum PressureUnit {
+        #[default]
+        Hectopascal,
+        Pascal,
+        Kilopascal,
+        Bar,
+    }
+
+    impl PressureUnit {
+        pub fn symbol(&self) -> &'static str {
+            match self {
+                PressureUnit::Hectopascal => "hPa",
+                PressureUnit::Pascal => "Pa",
+                PressureUnit::Kilopascal => "kPa",
+                PressureUnit::Bar => "bar",
+            }
+        }
+    }
+
+    /// Units a `SensorData` reading was taken in. Humidity is always relative
+    /// humidity in percent.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    pub struct SensorUnits {
+        pub temperature: TemperatureUnit,
+        pub pressure: PressureUnit,
+    }
+
+    /// Human-readable sensor reading, labeled with `units` when known
+    pub fn format_sensor_reading(
+        temperature: f64,
+        humidity: f64,
+        pressure: f64,
+        units: Option<&SensorUnits>,
+    ) -> String {
+        match units {
+            Some(units) => format!(
+                "{}{}, {}% humidity, {} {}",
+                temperature,
+                units.temperature.symbol(),
+                humidity,
+                pressure,
+                units.pressure.symbol()
+            ),
+            None => format!(
+                "temperature {}, {}% humidity, pressure {}",
+                temperature, humidity, pressure
+            ),
+        }
+    }
+
+    /// A single log line inside a `DataPayload::LogBatch`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct LogEntry {
+        pub level: String,
+        pub message: String,
+        pub timestamp: String,
+    }
+
+    /// `DataPayload::Coordinates` frame for GPS positions, range-checked by
+    /// `DataPayload::validate`
+    pub const WGS84_FRAME: &str = "wgs84";
+
+    /// Highest `DataPayload::load_cost` of a single payload: the headroom a
+    /// node needs to be sure it can take one more packet
+    pub const MAX_LOAD_COST: u32 = 5;
+
+    /// Deepest nesting of `DataPayload::Batch` that `validate` accepts
+    pub const MAX_BATCH_DEPTH: usize = 4;
+
+    /// Most payloads a single `DataPayload::Batch` may hold
+    pub const MAX_BATCH_ITEMS: usize = 32;
+
+    impl DataPayload {
+        /// Units of node load this payload occupies while being processed,
+        /// roughly one per 100ms of a node's simulated processing time
+        pub fn load_cost(&self) -> u32 {
+            match self {
+                DataPayload::Text(_) => 1,
+                DataPayload::Number(_) => 1,
+                DataPayload::Coordinates { .. } => 2,
+                DataPayload::SensorData { .. } => 2,
+                DataPayload::AudioData { .. } => 3,
+                DataPayload::ImageData { .. } => MAX_LOAD_COST,
+                DataPayload::LogEntry { .. } => 1,
+                DataPayload::LogBatch { .. } => 1,
+                // Nodes charge the wrapped payload's cost once it is decompressed
+                DataPayload::Compressed { .. } => 1,
+                DataPayload::Json(_) => 1,
+                // Items are processed one at a time, so a batch never holds
+                // more than one packet's worth of capacity
+                DataPayload::Batch(items) => items
+                    .iter()
+                    .map(DataPayload::load_cost)
+                    .fold(0, u32::saturating_add)
+                    .min(MAX_LOAD_COST),
+            }
+        }
+
+        /// How many `Batch` layers the payload is wrapped in; 0 for anything
+        /// that isn't a batch
+        pub fn batch_depth(&self) -> usize {
+            match self {
+                DataPayload::Batch(items) => {
+                    1 + items
+                        .iter()
+                        .map(DataPayload::batch_depth)
+                        .max()
+                        .unwrap_or(0)
+                }
+                _ => 0,
+            }
+        }
+
+        /// Data type name of the payload, as used in `DataRequest::data_types`
+        pub fn type_name(&self) -> &'static str {
+            match self {
+                DataPayload::Text(_) => "text",
+                DataPayload::Number(_) => "number",
+                DataPayload::Coordinates { .. } => "coordinates",
+                DataPayload::SensorData { .. } => "sensor",
+                DataPayload::ImageData { .. } => "image",
+                DataPayload::AudioData { .. } => "audio",
+                DataPayload::LogEntry { .. } | DataPayload::LogBatch { .. } => "log",
+                DataPayload::Compressed { .. } => "compressed",
+                DataPayload::Json(_) => "json",
+                DataPayload::Batch(_) => "batch",
+            }
+        }
+
+        /// Wraps the payload in a gzip-compressed `Compressed` payload. Already
+        /// compressed payloads, and ones that can't be serialized (non-finite
+        /// values), are returned unchanged.
+        pub fn compress(&self) -> DataPayload {
+            if let DataPayload::Compressed { .. } = self {
+                return self.clone();
+            }
+            let Ok(serialized) = serde_json::to_vec(self) else {
+                return self.clone();
+            };
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&serialized).expect("in-memory gzip");
+            DataPayload::Compressed {
+                inner_type: self.type_name().to_string(),
+                algorithm: GZIP_ALGORITHM.to_string(),
+                data: encoder.finish().expect("in-memory gzip"),
+            }
+        }
+
+        /// The payload a `Compressed` payload wraps, with compressed batch
+        /// items unwrapped as well; other payloads are returned as they are
+        pub fn decompress(&self) -> Result<DataPayload, CompressionError> {
+            let mut budget = MAX_DECOMPRESSED_BYTES;
+            self.decompress_within(MAX_BATCH_DEPTH + 1, &mut budget)
+        }
+
+        /// `decompress`, looking at most `depth` batches deep; anything deeper
+        /// is left for `validate` to reject. Everything inflated along the
+        /// way, batch items included, comes out of `budget`.
+        fn decompress_within(
+            &self,
+            depth: usize,
+            budget: &mut usize,
+        ) -> Result<DataPayload, CompressionError> {
+            match self {
+                DataPayload::Compressed {
+                    algorithm, data, ..
+                } => {
+                    if algorithm != GZIP_ALGORITHM {
+                        return Err(CompressionError::UnknownAlgorithm(algorithm.clone()));
+                    }
+                    // One byte past the budget is enough to know it's exceeded
+                    let mut serialized = Vec::new();
+                    GzDecoder::new(data.as_slice())
+                        .take(*budget as u64 + 1)
+                        .read_to_end(&mut serialized)
+                        .map_err(CompressionError::Corrupt)?;
+                    if serialized.len() > *budget {
+                        return Err(CompressionError::TooLarge(MAX_DECOMPRESSED_BYTES));
+                    }
+                    *budget -= serialized.len();
+                    let payload: DataPayload =
+                        serde_json::from_slice(&serialized).map_err(CompressionError::Malformed)?;
+                    // A compressed payload wrapping another stays wrapped
+                    match payload {
+                        DataPayload::Compressed { .. } => Ok(payload),
+                        payload => payload.decompress_within(depth, budget),
+                    }
+                }
+                DataPayload::Batch(items) if depth > 0 => items
+                    .iter()
+                    .map(|item| item.decompress_within(depth - 1, budget))
+                    .collect::<Result<_, _>>()
+                    .map(DataPayload::Batch),
+                _ => Ok(self.clone()),
+            }
+        }
+
+        /// Numeric values used for change detection, if the payload carries any
+        pub fn numeric_values(&self) -> Option<Vec<f64>> {
+            match self {
+                DataPayload::Number(value) => Some(vec![*value]),
+                DataPayload::SensorData {
+                    temperature,
+                    humidity,
+                    pressure,
+                    ..
+                } => Some(vec![*temperature, *humidity, *pressure]),
+                _ => None,
+            }
+        }
+
+        /// Seconds of audio an `AudioData` payload holds, counting its bytes
+        /// as 16-bit samples. For compressed codecs this is only an upper bound.
+        pub fn audio_duration_secs(&self) -> Option<f64> {
+            match self {
+                DataPayload::AudioData {
+                    sample_rate,
+                    channels,
+                    data,
+                    ..
+                } if *sample_rate > 0 && *channels > 0 => {
+                    let samples = data.len() / (AUDIO_BYTES_PER_SAMPLE * usize::from(*channels));
+                    Some(samples as f64 / f64::from(*sample_rate))
+                }
+                _ => None,
+            }
+        }
+
+        /// Whether every float in the payload is finite (no NaN/Inf)
+        pub fn is_finite(&self) -> bool {
+            match self {
+                DataPayload::Coordinates { x, y, z, .. } => {
+                    x.is_finite() && y.is_finite() && z.is_finite()
+                }
+                DataPayload::Batch(items) => items.iter().all(DataPayload::is_finite),
+                payload => payload
+                    .numeric_values()
+                    .unwrap_or_default()
+                    .iter()
+                    .all(|value| value.is_finite()),
+            }
+        }
+
+        /// Checks the payload is internally consistent, collecting every problem found
+        pub fn validate(&self) -> Result<(), ValidationError> {
+            let mut issues = Vec::new();
+            // A batch reports non-finite values against the item holding them
+            if !self.is_finite() && !matches!(self, DataPayload::Batch(_)) {
+                issues.push("non-finite value".to_string());
+            }
+            match self {
+                DataPayload::Text(text) if text.trim().is_empty() => {
+                    issues.push("empty text".to_string());
+                }
+                DataPayload::Coordinates {
+                    x,
+                    y,
+                    frame: Some(frame),
+                    ..
+                } if frame == WGS84_FRAME => {
+                    if !(-180.0..=180.0).contains(x) {
+                        issues.push(format!("longitude out of range: {}", x));
+                    }
+                    if !(-90.0..=90.0).contains(y) {
+                        issues.push(format!("latitude out of range: {}", y));
+                    }
+                }
+                DataPayload::SensorData {
+                    sensor_id,
+                    humidity,
+                    pressure,
+                    ..
+                } => {
+                    if sensor_id.is_empty() {
+                        issues.push("missing sensor id".to_string());
+                    }
+                    if *humidity < 0.0 || *humidity > 100.0 {
+                        issues.push(format!("humidity out of range: {}", humidity));
+                    }
+                    if *pressure <= 0.0 {
+                        issues.push(format!("non-positive pressure: {}", pressure));
+                    }
+                }
+                DataPayload::ImageData {
+                    width,
+                    height,
+                    data,
+                    ..
+                } => {
+                    if data.is_empty() {
+                        issues.push("empty image data".to_string());
+                    }
+                    if *width == 0 || *height == 0 {
+                        issues.push(format!("invalid image dimensions: {}x{}", width, height));
+                    } else if *width > MAX_IMAGE_DIMENSION || *height > MAX_IMAGE_DIMENSION {
+                        issues.push(format!("image dimensions too large: {}x{}", width, height));
+                    } else if data.len() as u64 > u64::from(*width) * u64::from(*height) * 4 {
+                        // Even uncompressed RGBA can't need more than 4 bytes per pixel
+                        issues.push(format!(
+                            "{} bytes of image data exceeds {}x{} RGBA",
+                            data.len(),
+                            width,
+                            height
+                        ));
+                    }
+                }
+                DataPayload::AudioData {
+                    sample_rate,
+                    channels,
+                    data,
+                    ..
+                } => {
+                    if data.is_empty() {
+                        issues.push("empty audio data".to_string());
+                    }
+                    if *sample_rate == 0 {
+                        issues.push("zero sample rate".to_string());
+                    }
+                    if *channels == 0 {
+                        issues.push("zero audio channels".to_string());
+                    }
+                }
+                DataPayload::LogEntry { level, .. } if !is_log_level(level) => {
+                    issues.push(format!("unrecognized log level: {}", level));
+                }
+                DataPayload::LogBatch { entries } => {
+                    if entries.is_empty() {
+                        issues.push("empty log batch".to_string());
+                    }
+                    for entry in entries.iter().filter(|entry| !is_log_level(&entry.level)) {
+                        issues.push(format!("unrecognized log level: {}", entry.level));
+                    }
+                }
+                DataPayload::Compressed { algorithm, .. } if algorithm != GZIP_ALGORITHM => {
+                    issues.push(format!("unknown compression algorithm: {}", algorithm));
+                }
+                DataPayload::Batch(_) if self.batch_depth() > MAX_BATCH_DEPTH => {
+                    issues.push(format!("batch nested deeper than {}", MAX_BATCH_DEPTH));
+                }
+                DataPayload::Batch(items) if items.len() > MAX_BATCH_ITEMS => {
+                    issues.push(format!(
+                        "batch of {} items exceeds {}",
+                        items.len(),
+                        MAX_BATCH_ITEMS
+                    ));
+                }
+                DataPayload::Batch(items) => {
+                    if items.is_empty() {
+                        issues.push("empty batch".to_string());
+                    }
+                    for (index, item) in items.iter().enumerate() {
+                        if let Err(e) = item.validate() {
+                            issues.extend(
+                                e.issues
+                                    .into_iter()
+                                    .map(|issue| format!("batch item {}: {}", index, issue)),
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if issues.is_empty() {
+                Ok(())
+            } else {
+                Err(ValidationError { issues })
+            }
+        }
+    }
+
+    /// Algorithm name `DataPayload::compress` writes
+    pub const GZIP_ALGORITHM: &str = "gzip";
+
+    /// Most bytes `DataPayload::decompress` inflates for one payload, batch
+    /// items together, so a small packet can't expand without bound
+    pub const MAX_DECOMPRESSED_BYTES: usize = 16 * 1024 * 1024;
+
+    #[derive(Debug)]
+    pub enum CompressionError {
+        UnknownAlgorithm(String),
+        Corrupt(std::io::Error),
+        /// Inflates to more than the given number of bytes
+        TooLarge(usize),
+        /// Decompressed fine, but isn't a serialized payload
+        Malformed(serde_json::Error),
+    }
+
+    impl fmt::Display for CompressionError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                CompressionError::UnknownAlgorithm(algorithm) => {
+                    write!(f, "unknown compression algorithm: {}", algorithm)
+                }
+                CompressionError::Corrupt(e) => write!(f, "corrupt compressed data: {}", e),
+                CompressionError::TooLarge(limit) => {
+                    write!(f, "compressed data inflates past {} bytes", limit)
+                }
+                CompressionError::Malformed(e) => write!(f, "malformed compressed payload: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for CompressionError {}
+
+    /// Largest width or height accepted for `DataPayload::ImageData`
+    pub const MAX_IMAGE_DIMENSION: u32 = 16384;
+
+    /// Sample width `DataPayload::audio_duration_secs` assumes
+    pub const AUDIO_BYTES_PER_SAMPLE: usize = 2;
+
+    fn is_log_level(level: &str) -> bool {
+        ["TRACE", "DEBUG", "INFO", "WARN", "ERROR"]
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(level))
+    }
+
+    /// Problems found by `DataPayload::validate`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ValidationError {
+        pub issues: Vec<String>,
     }
 
+    impl fmt::Display for ValidationError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "invalid payload: {}", self.issues.join("; "))
+        }
+    }
+
+    impl std::error::Error for ValidationError {}
+
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct DataPacket {
         pub id: String,
@@ -41,15 +582,118 @@ pub mod common {
         pub data_type: String,
         pub payload: DataPayload,
         pub metadata: HashMap<String, String>,
+        /// `PROTOCOL_VERSION` of the sender; 0 for peers that predate versioning
+        #[serde(default)]
+        pub schema_version: u16,
+    }
+
+    /// Several packets answering one data request, published as a single
+    /// message of at most the client's `max_batch_size` packets
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct DataBatch {
+        pub packets: Vec<DataPacket>,
+        /// `PROTOCOL_VERSION` of the sender; 0 for peers that predate versioning
+        #[serde(default)]
+        pub schema_version: u16,
+    }
+
+    impl DataBatch {
+        /// Splits `packets`, in order, into batches of at most
+        /// `max_batch_size` packets (a size of 0 is treated as 1)
+        pub fn split(packets: Vec<DataPacket>, max_batch_size: u32) -> Vec<DataBatch> {
+            let max_batch_size = (max_batch_size as usize).max(1);
+            let mut batches = Vec::new();
+            let mut packets = packets.into_iter().peekable();
+            while packets.peek().is_some() {
+                batches.push(DataBatch {
+                    packets: packets.by_ref().take(max_batch_size).collect(),
+                    schema_version: PROTOCOL_VERSION,
+                });
+            }
+            batches
+        }
     }
-    #[derive(Debug, Serialize, Deserialize)]
+
+    /// Every data type a `DataRequest` can ask for
+    pub const DATA_TYPE_CATALOG: [&str; 7] = [
+        "sensor",
+        "text",
+        "number",
+        "coordinates",
+        "image",
+        "audio",
+        "log",
+    ];
+
+    /// Data asked of a node on `data/request/{node_id}/{client_id}`
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
     pub struct DataRequest {
         pub request_id: String,
+        /// Older clients sent this as `slave_id`
+        #[serde(alias = "slave_id")]
         pub client_id: String,
+        /// Unix timestamp the client created the request at
+        #[serde(default)]
+        pub timestamp: u64,
         pub data_types: Vec<String>,
+        /// Only send numeric/sensor values that moved by more than this threshold
+        #[serde(default)]
+        pub only_if_changed: Option<f64>,
+        /// Retries with the same key receive the originally generated packets
+        #[serde(default)]
+        pub idempotency_key: Option<String>,
+        /// Overrides the compression level from the client's configuration
+        #[serde(default)]
+        pub compression_level: Option<u32>,
+        /// Whether a partially fulfillable request is served or rejected
+        #[serde(default)]
+        pub fulfillment: Fulfillment,
+        /// Most packets the client wants back for this request
+        #[serde(default)]
+        pub max_items: Option<u32>,
+        /// Urgency of the request; nodes serve higher values first (0 = lowest)
+        #[serde(default)]
+        pub priority: u8,
+    }
+
+    /// How a node handles requests for types it can't all serve
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    pub enum Fulfillment {
+        /// Serve what can be served and report the rest as skipped
+        #[default]
+        BestEffort,
+        /// Serve nothing unless every requested type can be served
+        Strict,
+    }
+
+    /// Why a requested data type wasn't served
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+    pub enum SkipReason {
+        /// The node can't produce this type
+        Unsupported,
+        /// The client used up its quota for this type
+        OverQuota,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct SkippedType {
+        pub data_type: String,
+        pub reason: SkipReason,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    /// Outcome of a data request that couldn't be served in full, published
+    /// on `data/summary/{node_id}/{client_id}`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct FulfillmentSummary {
+        pub request_id: String,
+        pub fulfilled: Vec<String>,
+        pub skipped: Vec<SkippedType>,
+        /// Set when the request was refused as a whole, e.g. a strict
+        /// request that couldn't be met or one that was too large
+        pub error: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct DataResponse {
         /// ID of the processed packet
         pub packet_id: String,
@@ -79,11 +723,35 @@ pub mod common {
         pub capacity: u32,
         /// Current number of operations being processed
         pub current_load: u32,
+        /// Packet load the node last reported, kept by the orchestrator,
+        /// which tracks routed clients in `current_load` instead
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reported_load: Option<u32>,
         /// Version of the node software
         pub version: String,
         /// Optional metadata as key-value pairs
         #[serde(default)]
         pub metadata: std::collections::HashMap<String, String>,
+        /// Data types this node can serve
+        #[serde(default)]
+        pub supported_data_types: Vec<String>,
+        /// Optional features (see `NODE_FEATURE_CATALOG`) this node supports
+        #[serde(default)]
+        pub features: Vec<String>,
+        /// Set only on the final offline heartbeat: why the process exited
+        /// (`SIGINT`, `SIGTERM` or `error: ...`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub shutdown_reason: Option<String>,
+        /// Packets processed since the node started
+        #[serde(default)]
+        pub processed_total: u64,
+        /// Packets processed since the previous heartbeat
+        #[serde(default)]
+        pub processed_since_last_heartbeat: u32,
+        /// Smoothed time the broker takes to acknowledge the node's
+        /// publishes; unset until the first acknowledgement
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub avg_publish_latency_ms: Option<u32>,
     }
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -98,9 +766,22 @@ pub mod common {
         pub preferred_node: Option<String>,
         /// Timestamp of the request
         pub timestamp: u64,
+        /// Identifies this routing attempt; echoed back in the response
+        #[serde(default)]
+        pub request_id: Option<String>,
+        /// Number of nodes to spread data requests across; `None` means one
+        #[serde(default)]
+        pub fan_out: Option<u32>,
+        /// `PROTOCOL_VERSION` of the sender; 0 for peers that predate versioning
+        #[serde(default)]
+        pub schema_version: u16,
+        /// Identity the client keeps across restarts (`CLIENT_LOGICAL_ID`), so
+        /// it can be routed back to the nodes it used before
+        #[serde(default)]
+        pub logical_id: Option<String>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct RoutingResponse {
         /// ID of the master node accepting/rejecting the request
         pub node_id: String,
@@ -108,12 +789,131 @@ pub mod common {
         pub client_id: String,
         /// Whether the routing request was accepted
         pub status: RoutingStatus,
-        /// If rejected, the reason why
+        /// If rejected or pending, the reason why
         pub rejection_reason: Option<String>,
         /// Configuration for the slave if accepted
         pub configuration: Option<ClientConfiguration>,
         /// Timestamp of the response
         pub timestamp: u64,
+        /// `RoutingRequest::request_id` this answers; `None` for unsolicited notices
+        #[serde(default)]
+        pub request_id: Option<String>,
+        /// Every node assigned to the slave, starting with `node_id`. Holds
+        /// more than one entry only for fan-out requests.
+        #[serde(default)]
+        pub assignments: Vec<NodeAssignment>,
+        /// Who answered; decides how the configuration is merged
+        #[serde(default)]
+        pub issuer: RoutingIssuer,
+        /// `PROTOCOL_VERSION` of the sender; 0 for peers that predate versioning
+        #[serde(default)]
+        pub schema_version: u16,
+        /// How long to wait before asking again. Always set on `Pending`
+        /// answers; set on rejections the sender expects to clear up by then.
+        #[serde(default)]
+        pub retry_after_ms: Option<u64>,
+    }
+
+    /// Answer to `orchestrator/query/routings`: the orchestrator's view of the
+    /// pool, published on `orchestrator/response/routings`
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct RoutingTableSnapshot {
+        /// Known nodes, ordered by node id
+        pub nodes: Vec<NodeInfo>,
+        /// Client id -> ids of the nodes it is routed to (several with fan-out)
+        pub routings: std::collections::HashMap<String, Vec<String>>,
+        /// Unix timestamp the snapshot was taken at
+        pub generated_at: u64,
+    }
+
+    /// Aggregate capacity a regional orchestrator reports to its parent
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct RegionSummary {
+        /// Region managed by the reporting orchestrator
+        pub region: String,
+        /// Number of active nodes in the region
+        pub node_count: u32,
+        /// Sum of the capacity of the active nodes
+        pub total_capacity: u32,
+        /// Sum of the current load of the active nodes
+        pub total_load: u32,
+        /// Timestamp of the summary
+        pub timestamp: u64,
+    }
+
+    impl RegionSummary {
+        /// Capacity still free in the region
+        pub fn available_capacity(&self) -> u32 {
+            self.total_capacity.saturating_sub(self.total_load)
+        }
+    }
+
+    /// Health probe the orchestrator sends a node on `probe/{node_id}`
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct ProbeRequest {
+        pub probe_id: String,
+        /// Sender's clock when the probe was sent, in milliseconds
+        pub sent_at_ms: u64,
+    }
+
+    /// Operator command on `control/{node_id}` taking a node in or out of
+    /// maintenance. A node in maintenance refuses new routings but finishes
+    /// work already in flight.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct MaintenanceControl {
+        pub maintenance: bool,
+    }
+
+    /// Operator command on `control/{node_id}/capacity` retuning how much load
+    /// a node advertises, without restarting it
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct CapacityControl {
+        pub capacity: u32,
+    }
+
+    /// A node's reply to a probe, published on `probe/ack/{node_id}`
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct ProbeAck {
+        pub probe_id: String,
+        pub node_id: String,
+        pub status: NodeStatus,
+        pub current_load: u32,
+        /// Echo of `ProbeRequest::sent_at_ms` for round-trip measurement
+        pub sent_at_ms: u64,
+        /// Node's clock when the ack was sent, in milliseconds
+        pub acked_at_ms: u64,
+    }
+
+    /// A client's confirmation, published on `routing/ack/{node_id}`, that it
+    /// took up an accepted routing and subscribed to its topics
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct RoutingAck {
+        pub client_id: String,
+        /// The primary node of the routing being acknowledged
+        pub node_id: String,
+        /// False when the client could not subscribe and gives the routing up
+        pub accepted: bool,
+    }
+
+    /// Pool-wide maintenance commands published on `control/pool`
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub enum PoolControl {
+        /// Reject all new routing; with `drain_nodes`, also ask every node to drain
+        PoolDrain {
+            #[serde(default)]
+            drain_nodes: bool,
+        },
+        /// Resume normal routing
+        PoolResume,
+    }
+
+    impl PoolControl {
+        /// What the orchestrator tells each drained node on `control/{node_id}`
+        pub fn node_control(&self) -> MaintenanceControl {
+            MaintenanceControl {
+                maintenance: matches!(self, PoolControl::PoolDrain { .. }),
+            }
+        }
     }
 
     /// Represents the status of a node in the system
@@ -164,9 +964,37 @@ pub mod common {
                 status: NodeStatus::Active,
                 capacity,
                 current_load: 0,
+                reported_load: None,
                 version: env!("CARGO_PKG_VERSION").to_string(),
                 metadata: std::collections::HashMap::new(),
+                supported_data_types: Vec::new(),
+                features: Vec::new(),
+                shutdown_reason: None,
+                processed_total: 0,
+                processed_since_last_heartbeat: 0,
+                avg_publish_latency_ms: None,
+            }
+        }
+
+        /// Whether the node can serve every one of `data_types`
+        pub fn supports_all(&self, data_types: &[String]) -> bool {
+            data_types
+                .iter()
+                .all(|data_type| self.supported_data_types.contains(data_type))
+        }
+
+        /// Fraction of capacity in use: 0.0 idle, 1.0 full. A node without
+        /// any capacity counts as full rather than dividing by zero.
+        pub fn utilization(&self) -> f32 {
+            if self.capacity == 0 {
+                return 1.0;
             }
+            self.current_load as f32 / self.capacity as f32
+        }
+
+        /// Whether `cost` more units of load fit within capacity
+        pub fn has_capacity(&self, cost: u32) -> bool {
+            self.current_load.saturating_add(cost) <= self.capacity
         }
     }
 
@@ -191,10 +1019,50 @@ pub mod common {
         pub max_batch_size: u32,
         /// Processing timeout in milliseconds
         pub processing_timeout_ms: u64,
+        /// Deflate level (0-9) used for data responses; 0 sends them uncompressed
+        #[serde(default)]
+        pub compression_level: u32,
+        /// Encoding of data responses; bincode only when the client
+        /// advertised it under `WIRE_FORMATS_METADATA_KEY`
+        #[serde(default)]
+        pub wire_format: WireFormat,
+        /// Optional features supported by every assigned node; clients only
+        /// use the ones listed here
+        #[serde(default)]
+        pub node_features: Vec<String>,
+    }
+
+    impl ClientConfiguration {
+        pub fn supports(&self, feature: &str) -> bool {
+            self.node_features.iter().any(|f| f == feature)
+        }
+    }
+
+    /// Node feature: deflate-compressed data responses
+    pub const FEATURE_COMPRESSION: &str = "compression";
+
+    /// Every optional feature a node can advertise in `NodeInfo::features`
+    pub const NODE_FEATURE_CATALOG: [&str; 1] = [FEATURE_COMPRESSION];
+
+    /// Sender of a `RoutingResponse`. Both the orchestrator and the chosen node
+    /// answer a routing request; the orchestrator's configuration is
+    /// authoritative and the node's only adds to it.
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+    pub enum RoutingIssuer {
+        #[default]
+        Orchestrator,
+        Node,
+    }
+
+    /// One of the nodes a slave was routed to
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct NodeAssignment {
+        pub node_id: String,
+        pub configuration: ClientConfiguration,
     }
 
     /// Status of data processing
-    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
     pub enum ProcessingStatus {
         Processed,
         Failed,
@@ -207,4 +1075,2489 @@ pub mod common {
             ProcessingStatus::Processed
         }
     }
+
+    /// `NodeInfo.metadata` key listing the wire formats a node accepts, e.g. `json,bincode`
+    pub const WIRE_FORMATS_METADATA_KEY: &str = "wire_formats";
+
+    /// Encoding of an MQTT payload, identified by the payload's first byte
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub enum WireFormat {
+        #[default]
+        Json,
+        /// Compact binary encoding, much smaller for `ImageData` bytes
+        Bincode,
+    }
+
+    impl WireFormat {
+        pub fn tag(self) -> u8 {
+            match self {
+                WireFormat::Json => 1,
+                WireFormat::Bincode => 2,
+            }
+        }
+
+        pub fn from_tag(tag: u8) -> Option<Self> {
+            match tag {
+                1 => Some(WireFormat::Json),
+                2 => Some(WireFormat::Bincode),
+                _ => None,
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                WireFormat::Json => "json",
+                WireFormat::Bincode => "bincode",
+            }
+        }
+
+        /// Parses a comma separated list as advertised in `NodeInfo.metadata`,
+        /// skipping unknown names
+        pub fn parse_list(value: &str) -> Vec<Self> {
+            value
+                .split(',')
+                .filter_map(|name| match name.trim().to_ascii_lowercase().as_str() {
+                    "json" => Some(WireFormat::Json),
+                    "bincode" => Some(WireFormat::Bincode),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Formats a peer advertised under `WIRE_FORMATS_METADATA_KEY`;
+        /// peers that don't advertise any only speak JSON
+        pub fn advertised_by(info: &NodeInfo) -> Vec<Self> {
+            info.metadata
+                .get(WIRE_FORMATS_METADATA_KEY)
+                .map(|value| Self::parse_list(value))
+                .unwrap_or_else(|| vec![WireFormat::Json])
+        }
+
+        /// Inverse of `parse_list`
+        pub fn join(formats: &[WireFormat]) -> String {
+            formats
+                .iter()
+                .map(|format| format.name())
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+
+        /// Format for data sent between peers accepting `ours` and
+        /// `theirs`: bincode when both take it, JSON otherwise
+        pub fn shared(ours: &[WireFormat], theirs: &[WireFormat]) -> Self {
+            if ours.contains(&WireFormat::Bincode) && theirs.contains(&WireFormat::Bincode) {
+                WireFormat::Bincode
+            } else {
+                WireFormat::Json
+            }
+        }
+    }
+
+    /// Formats this build reads and writes, as advertised by nodes and clients
+    pub const WIRE_FORMATS: [WireFormat; 2] = [WireFormat::Json, WireFormat::Bincode];
+
+    /// Node heartbeat `NodeInfo.metadata` key listing the clients the node has
+    /// accepted, comma separated, so the orchestrator can cross-check its routing table
+    pub const ROUTED_CLIENTS_METADATA_KEY: &str = "routed_clients";
+
+    /// Client `NodeInfo.metadata` key with the compression level it wants (0-9)
+    pub const COMPRESSION_METADATA_KEY: &str = "compression_level";
+
+    /// Node `NodeInfo.metadata` key with the time, in milliseconds, the node
+    /// allows for processing a packet before reporting a timeout
+    pub const PROCESSING_TIMEOUT_METADATA_KEY: &str = "processing_timeout_ms";
+
+    /// Processing timeout a node advertised in its metadata, if any
+    pub fn advertised_processing_timeout(info: &NodeInfo) -> Option<u64> {
+        info.metadata
+            .get(PROCESSING_TIMEOUT_METADATA_KEY)
+            .and_then(|value| value.parse::<u64>().ok())
+    }
+
+    /// Highest deflate level; higher requests are clamped to it
+    pub const MAX_COMPRESSION_LEVEL: u32 = 9;
+
+    /// Tag byte of a deflate-compressed frame; the inflated bytes are another frame
+    pub const DEFLATE_TAG: u8 = 0x10;
+
+    /// Most deflate frames `decode_frame` unwraps around one payload;
+    /// `compress_frame` never nests them
+    pub const MAX_DEFLATE_DEPTH: usize = 1;
+
+    /// Compression level a client asked for in its metadata, clamped to the valid range
+    pub fn requested_compression(info: &NodeInfo) -> u32 {
+        info.metadata
+            .get(COMPRESSION_METADATA_KEY)
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(0)
+            .min(MAX_COMPRESSION_LEVEL)
+    }
+
+    /// Wraps a frame in a deflate frame at `level`; level 0 returns it unchanged
+    pub fn compress_frame(frame: &[u8], level: u32) -> Vec<u8> {
+        if level == 0 {
+            return frame.to_vec();
+        }
+        let mut encoder = DeflateEncoder::new(
+            vec![DEFLATE_TAG],
+            Compression::new(level.min(MAX_COMPRESSION_LEVEL)),
+        );
+        // Writing into a Vec can't fail
+        encoder.write_all(frame).expect("in-memory deflate");
+        encoder.finish().expect("in-memory deflate")
+    }
+
+    #[derive(Debug)]
+    pub enum WireError {
+        Empty,
+        Inflate(std::io::Error),
+        /// Inflates to more than the given number of bytes
+        TooLarge(usize),
+        /// Deflate frames nested deeper than `MAX_DEFLATE_DEPTH`
+        TooDeep,
+        UnknownFormat(u8),
+        /// The payload's tag didn't match the format the caller expected
+        UnexpectedFormat(WireFormat),
+        Json(serde_json::Error),
+        Bincode(bincode::Error),
+    }
+
+    impl fmt::Display for WireError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                WireError::Empty => write!(f, "empty payload"),
+                WireError::Inflate(e) => write!(f, "inflate: {}", e),
+                WireError::TooLarge(limit) => write!(f, "frame inflates past {} bytes", limit),
+                WireError::TooDeep => {
+                    write!(f, "deflate frames nested deeper than {}", MAX_DEFLATE_DEPTH)
+                }
+                WireError::UnknownFormat(tag) => write!(f, "unknown wire format tag: {}", tag),
+                WireError::UnexpectedFormat(format) => {
+                    write!(f, "unexpected wire format: {}", format.name())
+                }
+                WireError::Json(e) => write!(f, "json: {}", e),
+                WireError::Bincode(e) => write!(f, "bincode: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for WireError {}
+
+    /// Failures surfaced by the node, client and orchestrator entry points, so
+    /// callers can tell a bad configuration from a transient broker problem
+    #[derive(Debug, thiserror::Error)]
+    pub enum PoolError {
+        // Boxed, as both carry whole packets and would bloat every `Result`
+        #[error("mqtt client: {0}")]
+        Mqtt(Box<rumqttc::ClientError>),
+        #[error("mqtt connection: {0}")]
+        Connection(Box<rumqttc::ConnectionError>),
+        #[error("serialization: {0}")]
+        Serde(#[from] serde_json::Error),
+        #[error("wire format: {0}")]
+        Wire(#[from] WireError),
+        #[error("io: {0}")]
+        Io(#[from] std::io::Error),
+        #[error("configuration: {0}")]
+        Config(String),
+        #[error("timed out")]
+        Timeout,
+        #[error("payload of {size} bytes exceeds the {limit} byte limit")]
+        PayloadTooLarge { size: usize, limit: usize },
+        #[error("{0}")]
+        Other(String),
+    }
+
+    impl From<rumqttc::ClientError> for PoolError {
+        fn from(e: rumqttc::ClientError) -> Self {
+            PoolError::Mqtt(Box::new(e))
+        }
+    }
+
+    impl From<rumqttc::ConnectionError> for PoolError {
+        fn from(e: rumqttc::ConnectionError) -> Self {
+            PoolError::Connection(Box::new(e))
+        }
+    }
+
+    impl PoolError {
+        /// Whether retrying later might succeed, as opposed to a problem that
+        /// needs an operator
+        pub fn is_transient(&self) -> bool {
+            matches!(
+                self,
+                PoolError::Mqtt(_) | PoolError::Connection(_) | PoolError::Timeout
+            )
+        }
+    }
+
+    /// Encodes `value` as a tagged payload: one format byte followed by the body
+    pub fn encode<T: Serialize>(format: WireFormat, value: &T) -> Result<Vec<u8>, WireError> {
+        let mut payload = vec![format.tag()];
+        match format {
+            WireFormat::Json => {
+                serde_json::to_writer(&mut payload, value).map_err(WireError::Json)?
+            }
+            WireFormat::Bincode => {
+                bincode::serialize_into(&mut payload, value).map_err(WireError::Bincode)?
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Decodes a tagged payload that must be in `format`
+    pub fn decode<T: DeserializeOwned>(format: WireFormat, payload: &[u8]) -> Result<T, WireError> {
+        let (tag, body) = payload.split_first().ok_or(WireError::Empty)?;
+        let found = WireFormat::from_tag(*tag).ok_or(WireError::UnknownFormat(*tag))?;
+        if found != format {
+            return Err(WireError::UnexpectedFormat(found));
+        }
+        match format {
+            WireFormat::Json => serde_json::from_slice(body).map_err(WireError::Json),
+            WireFormat::Bincode => bincode::deserialize(body).map_err(WireError::Bincode),
+        }
+    }
+
+    /// Decodes a tagged payload in whichever format its first byte names,
+    /// inflating compressed frames first, to at most `MAX_DECOMPRESSED_BYTES`.
+    /// Untagged JSON objects from peers that predate framing are accepted too.
+    pub fn decode_frame<T: DeserializeOwned>(payload: &[u8]) -> Result<T, WireError> {
+        decode_frame_within(payload, MAX_DEFLATE_DEPTH)
+    }
+
+    /// `decode_frame`, unwrapping at most `depth` more deflate frames
+    fn decode_frame_within<T: DeserializeOwned>(
+        payload: &[u8],
+        depth: usize,
+    ) -> Result<T, WireError> {
+        match payload.first() {
+            None => Err(WireError::Empty),
+            Some(b'{') => serde_json::from_slice(payload).map_err(WireError::Json),
+            Some(&DEFLATE_TAG) => {
+                if depth == 0 {
+                    return Err(WireError::TooDeep);
+                }
+                // One byte past the cap is enough to know it's exceeded
+                let mut inflated = Vec::new();
+                DeflateDecoder::new(&payload[1..])
+                    .take(MAX_DECOMPRESSED_BYTES as u64 + 1)
+                    .read_to_end(&mut inflated)
+                    .map_err(WireError::Inflate)?;
+                if inflated.len() > MAX_DECOMPRESSED_BYTES {
+                    return Err(WireError::TooLarge(MAX_DECOMPRESSED_BYTES));
+                }
+                decode_frame_within(&inflated, depth - 1)
+            }
+            Some(tag) => {
+                let format = WireFormat::from_tag(*tag).ok_or(WireError::UnknownFormat(*tag))?;
+                decode(format, payload)
+            }
+        }
+    }
+
+    /// Decodes a data response frame holding either a `DataBatch` or, from
+    /// nodes that send packets one at a time, a single `DataPacket`
+    pub fn decode_data_packets(payload: &[u8]) -> Result<Vec<DataPacket>, WireError> {
+        match decode_frame::<DataBatch>(payload) {
+            Ok(batch) => Ok(batch.packets),
+            Err(_) => decode_frame::<DataPacket>(payload).map(|packet| vec![packet]),
+        }
+    }
+
+    /// Version of the message schema this build speaks. Bump it whenever a
+    /// message changes in a way older receivers would misread.
+    pub const PROTOCOL_VERSION: u16 = 1;
+
+    /// Just the version field of an otherwise unknown message
+    #[derive(Deserialize)]
+    struct VersionProbe {
+        #[serde(default)]
+        schema_version: u16,
+    }
+
+    #[derive(Debug)]
+    pub enum MessageError {
+        /// Sent by a peer speaking a newer protocol than `PROTOCOL_VERSION`
+        UnsupportedVersion(u16),
+        Malformed(serde_json::Error),
+    }
+
+    impl fmt::Display for MessageError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                MessageError::UnsupportedVersion(version) => write!(
+                    f,
+                    "schema version {} is newer than supported version {}",
+                    version, PROTOCOL_VERSION
+                ),
+                MessageError::Malformed(e) => write!(f, "malformed message: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for MessageError {}
+
+    /// Fails if `version` is newer than this build understands
+    pub fn check_version(version: u16) -> Result<(), MessageError> {
+        if version > PROTOCOL_VERSION {
+            Err(MessageError::UnsupportedVersion(version))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Parses a JSON message, checking its `schema_version` first so that a
+    /// message from a newer peer is reported as such rather than as a parse
+    /// failure when its shape has changed.
+    pub fn parse_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T, MessageError> {
+        if let Ok(probe) = serde_json::from_slice::<VersionProbe>(payload) {
+            check_version(probe.schema_version)?;
+        }
+        serde_json::from_slice(payload).map_err(MessageError::Malformed)
+    }
+
+    /// Most payload bytes quoted when a message fails to decode
+    const PAYLOAD_PREVIEW_BYTES: usize = 96;
+
+    /// Start of `payload` as text, for logs, noting the full size when cut short
+    pub fn payload_preview(payload: &[u8]) -> String {
+        let shown = &payload[..payload.len().min(PAYLOAD_PREVIEW_BYTES)];
+        let mut preview = String::from_utf8_lossy(shown).into_owned();
+        if shown.len() < payload.len() {
+            preview.push_str(&format!("... ({} bytes)", payload.len()));
+        }
+        preview
+    }
+
+    /// Parses a message received on `topic` like `parse_message`, logging the
+    /// error and a preview of the payload at warn level instead of failing
+    pub fn decode_or_log<T: DeserializeOwned>(topic: &str, payload: &[u8]) -> Option<T> {
+        match parse_message(payload) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                warn!(
+                    "Skipping message on {}: {}; payload: {}",
+                    topic,
+                    e,
+                    payload_preview(payload)
+                );
+                None
+            }
+        }
+    }
+
+    /// What to do with metadata that exceeds `MetadataLimits`
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+    pub enum OversizePolicy {
+        /// Shorten long keys/values and drop entries beyond the limit,
+        /// refusing the map if two keys would be shortened to the same one
+        Truncate,
+        /// Refuse the whole map
+        Reject,
+    }
+
+    impl OversizePolicy {
+        /// `truncate` or `reject`, in any case
+        pub fn parse(value: &str) -> Option<Self> {
+            match value.to_ascii_lowercase().as_str() {
+                "truncate" => Some(OversizePolicy::Truncate),
+                "reject" => Some(OversizePolicy::Reject),
+                _ => None,
+            }
+        }
+    }
+
+    /// Metadata map exceeding `MetadataLimits` under `OversizePolicy::Reject`,
+    /// or that `OversizePolicy::Truncate` can't shorten without losing an entry
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum MetadataError {
+        KeyTooLong(String),
+        ValueTooLong(String),
+        TooManyEntries(usize),
+        KeyCollision(String),
+    }
+
+    impl fmt::Display for MetadataError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                MetadataError::KeyTooLong(key) => write!(f, "metadata key too long: {}", key),
+                MetadataError::ValueTooLong(key) => {
+                    write!(f, "metadata value too long for key: {}", key)
+                }
+                MetadataError::TooManyEntries(count) => {
+                    write!(f, "too many metadata entries: {}", count)
+                }
+                MetadataError::KeyCollision(key) => {
+                    write!(f, "metadata keys collide when truncated: {}", key)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for MetadataError {}
+
+    /// Bounds on `metadata` maps so a single huge entry can't bloat every message
+    #[derive(Debug, Clone)]
+    pub struct MetadataLimits {
+        /// Maximum key length in bytes
+        pub max_key_len: usize,
+        /// Maximum value length in bytes
+        pub max_value_len: usize,
+        /// Maximum number of entries
+        pub max_entries: usize,
+        pub policy: OversizePolicy,
+    }
+
+    impl Default for MetadataLimits {
+        fn default() -> Self {
+            MetadataLimits {
+                max_key_len: 64,
+                max_value_len: 1024,
+                max_entries: 32,
+                policy: OversizePolicy::Truncate,
+            }
+        }
+    }
+
+    /// Longest prefix of `value` within `max_len` bytes that ends on a char boundary
+    fn truncate_str(value: &str, max_len: usize) -> String {
+        let mut end = max_len.min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value[..end].to_string()
+    }
+
+    impl MetadataLimits {
+        /// Enforces the limits on `metadata` in place, truncating or rejecting per the policy
+        pub fn apply(&self, metadata: &mut HashMap<String, String>) -> Result<(), MetadataError> {
+            if self.policy == OversizePolicy::Reject {
+                if metadata.len() > self.max_entries {
+                    return Err(MetadataError::TooManyEntries(metadata.len()));
+                }
+                for (key, value) in metadata.iter() {
+                    if key.len() > self.max_key_len {
+                        return Err(MetadataError::KeyTooLong(truncate_str(
+                            key,
+                            self.max_key_len,
+                        )));
+                    }
+                    if value.len() > self.max_value_len {
+                        return Err(MetadataError::ValueTooLong(key.clone()));
+                    }
+                }
+                return Ok(());
+            }
+
+            let within_limits = metadata.len() <= self.max_entries
+                && metadata
+                    .iter()
+                    .all(|(k, v)| k.len() <= self.max_key_len && v.len() <= self.max_value_len);
+            if within_limits {
+                return Ok(());
+            }
+
+            // Keep the first entries by key so truncation is deterministic
+            let mut entries: Vec<(&String, &String)> = metadata.iter().collect();
+            entries.sort();
+            entries.truncate(self.max_entries);
+            let mut limited = HashMap::with_capacity(entries.len());
+            for (key, value) in entries {
+                let key = truncate_str(key, self.max_key_len);
+                // One entry would silently overwrite the other
+                if limited.contains_key(&key) {
+                    return Err(MetadataError::KeyCollision(key));
+                }
+                limited.insert(key, truncate_str(value, self.max_value_len));
+            }
+            *metadata = limited;
+            Ok(())
+        }
+    }
+
+    /// Reads `CLIENT_ID_PREFIX` (e.g. a hostname or pod name), ignoring empty values
+    pub fn client_id_prefix_from_env() -> Option<String> {
+        std::env::var("CLIENT_ID_PREFIX")
+            .ok()
+            .filter(|prefix| !prefix.is_empty())
+    }
+
+    /// Builders for every topic the pool uses, placed under an optional
+    /// prefix (`TOPIC_PREFIX`) so independent pools can share one broker.
+    /// Pass `+` or `#` as an id to get the matching subscription filter.
+    pub mod topics {
+        use super::NodeType;
+
+        /// Reads `TOPIC_PREFIX` (e.g. `pool-a`) without surrounding slashes;
+        /// empty when unset
+        pub fn prefix_from_env() -> String {
+            std::env::var("TOPIC_PREFIX")
+                .unwrap_or_default()
+                .trim_matches('/')
+                .to_string()
+        }
+
+        /// `topic` under `prefix`
+        pub fn prefixed(prefix: &str, topic: &str) -> String {
+            if prefix.is_empty() {
+                topic.to_string()
+            } else {
+                format!("{}/{}", prefix, topic)
+            }
+        }
+
+        /// `topic` with `prefix` removed, or `None` if it belongs to another pool
+        pub fn strip<'a>(prefix: &str, topic: &'a str) -> Option<&'a str> {
+            if prefix.is_empty() {
+                Some(topic)
+            } else {
+                topic.strip_prefix(prefix)?.strip_prefix('/')
+            }
+        }
+
+        /// Where clients ask the orchestrator for a node
+        pub fn routing_request(prefix: &str) -> String {
+            prefixed(prefix, "routing/request")
+        }
+
+        /// Where clients confirm a routing to `node_id`
+        pub fn routing_ack(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("routing/ack/{}", node_id))
+        }
+
+        /// Where a parent orchestrator forwards requests to `region`
+        pub fn regional_routing_request(prefix: &str, region: &str) -> String {
+            prefixed(prefix, &format!("routing/request/{}", region))
+        }
+
+        /// Where the orchestrator and nodes answer `client_id`'s routing requests
+        pub fn routing_response(prefix: &str, client_id: &str) -> String {
+            prefixed(prefix, &format!("routing/response/{}", client_id))
+        }
+
+        /// Where `node_id` publishes heartbeats
+        pub fn heartbeat(prefix: &str, node_type: &NodeType, node_id: &str) -> String {
+            let kind = match node_type {
+                NodeType::Node => "master",
+                NodeType::Client => "slave",
+                NodeType::Monitor => "monitor",
+            };
+            prefixed(prefix, &format!("heartbeat/{}/{}", kind, node_id))
+        }
+
+        pub fn heartbeat_master(prefix: &str, node_id: &str) -> String {
+            heartbeat(prefix, &NodeType::Node, node_id)
+        }
+
+        pub fn heartbeat_slave(prefix: &str, client_id: &str) -> String {
+            heartbeat(prefix, &NodeType::Client, client_id)
+        }
+
+        /// Where `client_id` sends data requests to `node_id`
+        pub fn data_request(prefix: &str, node_id: &str, client_id: &str) -> String {
+            prefixed(prefix, &format!("data/request/{}/{}", node_id, client_id))
+        }
+
+        /// Where `node_id` sends `client_id` its data packets
+        pub fn data_response(prefix: &str, node_id: &str, client_id: &str) -> String {
+            prefixed(prefix, &format!("data/response/{}/{}", node_id, client_id))
+        }
+
+        /// Where `node_id` reports the outcome of processing incoming packets
+        pub fn processing_status(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("data/response/{}", node_id))
+        }
+
+        /// Where `node_id` tells `client_id` which requested types it didn't get
+        pub fn data_summary(prefix: &str, node_id: &str, client_id: &str) -> String {
+            prefixed(prefix, &format!("data/summary/{}/{}", node_id, client_id))
+        }
+
+        /// Where packets from `source` are sent to nodes for processing
+        pub fn data_incoming(prefix: &str, source: &str) -> String {
+            prefixed(prefix, &format!("data/incoming/{}", source))
+        }
+
+        /// Where processed packet `id` (or a client's processed data) is published
+        pub fn data_processed(prefix: &str, id: &str) -> String {
+            prefixed(prefix, &format!("data/processed/{}", id))
+        }
+
+        /// Where data for `client_id` to process is published
+        pub fn data_input(prefix: &str, client_id: &str) -> String {
+            prefixed(prefix, &format!("data/input/{}", client_id))
+        }
+
+        pub fn data_broadcast(prefix: &str, channel: &str) -> String {
+            prefixed(prefix, &format!("data/broadcast/{}", channel))
+        }
+
+        /// Where the orchestrator sends `node_id` a latency probe
+        pub fn probe(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("probe/{}", node_id))
+        }
+
+        /// Where `node_id` acknowledges a probe
+        pub fn probe_ack(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("probe/ack/{}", node_id))
+        }
+
+        /// Where an operator asks the orchestrator to probe `node_id`
+        pub fn admin_probe(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("admin/probe/{}", node_id))
+        }
+
+        /// Operator commands for one node or client
+        pub fn control(prefix: &str, id: &str) -> String {
+            prefixed(prefix, &format!("control/{}", id))
+        }
+
+        /// Capacity changes for `node_id`
+        pub fn capacity_control(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("control/{}/capacity", node_id))
+        }
+
+        /// Pool-wide drain and resume commands
+        pub fn pool_control(prefix: &str) -> String {
+            prefixed(prefix, "control/pool")
+        }
+
+        /// Where a regional orchestrator reports its summary to the parent
+        pub fn region_summary(prefix: &str, region: &str) -> String {
+            prefixed(prefix, &format!("orchestrator/region/{}", region))
+        }
+
+        /// Retained status of `node_id`, cleared once it is removed
+        pub fn master_status(prefix: &str, node_id: &str) -> String {
+            prefixed(prefix, &format!("master/status/{}", node_id))
+        }
+
+        pub fn routing_history_query(prefix: &str) -> String {
+            prefixed(prefix, "orchestrator/query/routing_history")
+        }
+
+        pub fn routing_history_response(prefix: &str) -> String {
+            prefixed(prefix, "orchestrator/query/routing_history/response")
+        }
+
+        pub fn routings_query(prefix: &str) -> String {
+            prefixed(prefix, "orchestrator/query/routings")
+        }
+
+        pub fn routings_response(prefix: &str) -> String {
+            prefixed(prefix, "orchestrator/response/routings")
+        }
+
+        /// Operator command moving a client off its current nodes
+        pub fn reassign(prefix: &str) -> String {
+            prefixed(prefix, "orchestrator/reassign")
+        }
+    }
+
+    /// MQTT client id for a connection, `{prefix}-{base}` when a prefix is set.
+    /// Only the broker sees this; node ids stay unprefixed.
+    pub fn mqtt_client_id(prefix: Option<&str>, base: &str) -> String {
+        match prefix {
+            Some(prefix) => format!("{}-{}", prefix, base),
+            None => base.to_string(),
+        }
+    }
+
+    /// Outgoing request queue length when `MQTT_CHANNEL_CAP` is unset. Publishes
+    /// wait once it is full, so bursty senders want a few hundred to a few
+    /// thousand slots.
+    pub const DEFAULT_MQTT_CHANNEL_CAP: usize = 10;
+
+    /// Inputs outside this range are clamped into it
+    pub const MQTT_CHANNEL_CAP_RANGE: std::ops::RangeInclusive<usize> = 1..=100_000;
+
+    /// Keep-alive interval when `MQTT_KEEPALIVE_SECS` is unset
+    pub const DEFAULT_MQTT_KEEPALIVE_SECS: u64 = 5;
+
+    /// Older rumqttc releases refuse keep-alives under 5 s, and MQTT can't
+    /// express more than 65535 s; inputs outside are clamped
+    pub const MQTT_KEEPALIVE_SECS_RANGE: std::ops::RangeInclusive<u64> = 5..=65_535;
+
+    /// Broker connection settings shared by every binary: credentials from
+    /// `MQTT_USERNAME`/`MQTT_PASSWORD`, used when both are present, the
+    /// keep-alive from `MQTT_KEEPALIVE_SECS`, the request channel capacity
+    /// (read back with `request_channel_capacity()` when creating the client)
+    /// from `MQTT_CHANNEL_CAP` and the packet size from `MAX_PAYLOAD_BYTES`
+    #[derive(Clone, PartialEq)]
+    pub struct MqttSettings {
+        pub username: Option<String>,
+        pub password: Option<String>,
+        pub keep_alive_secs: u64,
+        pub channel_cap: usize,
+        pub payload_limit: PayloadLimit,
+    }
+
+    impl Default for MqttSettings {
+        fn default() -> Self {
+            MqttSettings {
+                username: None,
+                password: None,
+                keep_alive_secs: DEFAULT_MQTT_KEEPALIVE_SECS,
+                channel_cap: DEFAULT_MQTT_CHANNEL_CAP,
+                payload_limit: PayloadLimit::default(),
+            }
+        }
+    }
+
+    /// Leaves the password out of logged configuration
+    impl fmt::Debug for MqttSettings {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("MqttSettings")
+                .field("username", &self.username)
+                .field("keep_alive_secs", &self.keep_alive_secs)
+                .field("channel_cap", &self.channel_cap)
+                .field("payload_limit", &self.payload_limit)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl MqttSettings {
+        /// Reads the settings from `var`, keeping defaults for missing or
+        /// invalid values and clamping out of range ones
+        pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+            Self::load(&config::MqttSection::default(), var).unwrap_or_default()
+        }
+
+        /// `from_vars`, falling back to the config file's values, which
+        /// must be in range
+        pub fn load(
+            file: &config::MqttSection,
+            var: impl Fn(&str) -> Option<String>,
+        ) -> Result<Self, PoolError> {
+            Ok(MqttSettings {
+                username: var("MQTT_USERNAME")
+                    .or_else(|| file.mqtt_username.clone())
+                    .filter(|username| !username.is_empty()),
+                password: var("MQTT_PASSWORD").or_else(|| file.mqtt_password.clone()),
+                keep_alive_secs: config::bounded(
+                    &var,
+                    "MQTT_KEEPALIVE_SECS",
+                    file.mqtt_keepalive_secs,
+                    MQTT_KEEPALIVE_SECS_RANGE,
+                )?
+                .unwrap_or(DEFAULT_MQTT_KEEPALIVE_SECS),
+                channel_cap: config::bounded(
+                    &var,
+                    "MQTT_CHANNEL_CAP",
+                    file.mqtt_channel_cap,
+                    MQTT_CHANNEL_CAP_RANGE,
+                )?
+                .unwrap_or(DEFAULT_MQTT_CHANNEL_CAP),
+                payload_limit: PayloadLimit::load(file.max_payload_bytes, &var)?,
+            })
+        }
+
+        /// Options for connecting to the broker at `host:port`
+        pub fn options(&self, client_id: String, host: &str, port: u16) -> MqttOptions {
+            let mut options = MqttOptions::new(client_id, host, port);
+            if let (Some(username), Some(password)) = (&self.username, &self.password) {
+                debug!("Authenticating to the MQTT broker as {}", username);
+                options.set_credentials(username, password);
+            }
+            options.set_keep_alive(Duration::from_secs(self.keep_alive_secs));
+            options.set_request_channel_capacity(self.channel_cap);
+
+            // rumqttc refuses packets over 10 KiB unless told otherwise
+            let packet_size = self.payload_limit.packet_size();
+            options.set_max_packet_size(packet_size, packet_size);
+            options
+        }
+    }
+
+    /// Broker connection options from the environment; see `MqttSettings`
+    pub fn mqtt_options(client_id: String, host: &str, port: u16) -> MqttOptions {
+        mqtt_options_from_vars(client_id, host, port, |key| std::env::var(key).ok())
+    }
+
+    /// `mqtt_options` with the environment replaced by `var` lookups
+    pub fn mqtt_options_from_vars(
+        client_id: String,
+        host: &str,
+        port: u16,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> MqttOptions {
+        MqttSettings::from_vars(var).options(client_id, host, port)
+    }
+
+    /// QoS used for each class of message. Routing requests and responses
+    /// can be raised to `ExactlyOnce` on their own, since a redelivered one
+    /// can turn into a duplicate node assignment.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct QosPolicy {
+        /// Everything but routing traffic, from `MQTT_QOS`
+        pub default: QoS,
+        /// `routing/request` and `routing/response`, from `ROUTING_QOS`;
+        /// follows `MQTT_QOS` when unset
+        pub routing: QoS,
+    }
+
+    impl Default for QosPolicy {
+        fn default() -> Self {
+            QosPolicy {
+                default: QoS::AtLeastOnce,
+                routing: QoS::AtLeastOnce,
+            }
+        }
+    }
+
+    impl QosPolicy {
+        /// Reads `MQTT_QOS` and `ROUTING_QOS` (`0`, `1` or `2`) from `var`,
+        /// keeping `AtLeastOnce` for missing or invalid levels
+        pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+            Self::load(None, None, var).unwrap_or_default()
+        }
+
+        /// `from_vars`, falling back to the `mqtt_qos` and `routing_qos`
+        /// levels of a config file, which must be valid
+        pub fn load(
+            file_default: Option<u8>,
+            file_routing: Option<u8>,
+            var: impl Fn(&str) -> Option<String>,
+        ) -> Result<Self, PoolError> {
+            let level = |key: &str, file: Option<u8>| match var(key) {
+                Some(level) => Ok(parse_qos(&level)),
+                None => file
+                    .map(|level| qos_level(level).ok_or_else(|| config::invalid(key, level)))
+                    .transpose(),
+            };
+            let default = level("MQTT_QOS", file_default)?.unwrap_or(QoS::AtLeastOnce);
+            Ok(QosPolicy {
+                default,
+                routing: level("ROUTING_QOS", file_routing)?.unwrap_or(default),
+            })
+        }
+    }
+
+    /// Largest message published when `MAX_PAYLOAD_BYTES` is unset
+    pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+
+    /// Room an MQTT packet needs on top of its payload for the fixed header,
+    /// topic and packet id
+    pub const PACKET_HEADROOM_BYTES: usize = 4 * 1024;
+
+    /// Size limit on published messages, checked up front so an oversized
+    /// one is refused with a clear error instead of failing at the broker
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct PayloadLimit {
+        pub max_bytes: usize,
+        /// Bytes appended after the check, i.e. the HMAC seal when signing
+        pub seal_bytes: usize,
+    }
+
+    impl Default for PayloadLimit {
+        fn default() -> Self {
+            PayloadLimit {
+                max_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+                seal_bytes: 0,
+            }
+        }
+    }
+
+    impl PayloadLimit {
+        /// Reads `MAX_PAYLOAD_BYTES` from `var`, keeping the default for
+        /// missing, zero or invalid values
+        pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+            Self::load(None, var).unwrap_or_default()
+        }
+
+        /// `from_vars`, falling back to the `max_payload_bytes` of a config
+        /// file, which must not be zero
+        pub fn load(
+            file: Option<usize>,
+            var: impl Fn(&str) -> Option<String>,
+        ) -> Result<Self, PoolError> {
+            if file == Some(0) {
+                return Err(config::invalid("MAX_PAYLOAD_BYTES", 0));
+            }
+            let max_bytes = config::setting(&var, "MAX_PAYLOAD_BYTES", None)
+                .filter(|bytes| *bytes > 0)
+                .or(file);
+            Ok(max_bytes
+                .map(|max_bytes| PayloadLimit {
+                    max_bytes,
+                    ..PayloadLimit::default()
+                })
+                .unwrap_or_default())
+        }
+
+        /// The limit for payloads that get sealed with the pool's secret,
+        /// when there is one, before going out
+        pub fn sealed(self, secret: Option<&SharedSecret>) -> Self {
+            PayloadLimit {
+                seal_bytes: if secret.is_some() {
+                    integrity::SIGNATURE_LEN
+                } else {
+                    0
+                },
+                ..self
+            }
+        }
+
+        /// Largest MQTT packet carrying a payload within the limit
+        pub fn packet_size(&self) -> usize {
+            self.max_bytes + integrity::SIGNATURE_LEN + PACKET_HEADROOM_BYTES
+        }
+
+        /// Refuses `payload` if it, with its seal, is over the limit
+        pub fn check(&self, payload: &[u8]) -> Result<(), PoolError> {
+            let size = payload.len() + self.seal_bytes;
+            if size > self.max_bytes {
+                return Err(PoolError::PayloadTooLarge {
+                    size,
+                    limit: self.max_bytes,
+                });
+            }
+            Ok(())
+        }
+
+        /// Serializes `message` as JSON, refusing it when over the limit
+        pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, PoolError> {
+            let payload = serde_json::to_vec(message)?;
+            self.check(&payload)?;
+            Ok(payload)
+        }
+    }
+
+    /// Parses an MQTT QoS level, `0`, `1` or `2`, warning about anything else
+    pub fn parse_qos(level: &str) -> Option<QoS> {
+        let qos = level.trim().parse().ok().and_then(qos_level);
+        if qos.is_none() {
+            warn!("Ignoring invalid MQTT QoS level: {}", level.trim());
+        }
+        qos
+    }
+
+    /// The QoS with number `level`
+    pub fn qos_level(level: u8) -> Option<QoS> {
+        match level {
+            0 => Some(QoS::AtMostOnce),
+            1 => Some(QoS::AtLeastOnce),
+            2 => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+
+    /// The client operations the node and orchestrator use, so they can run
+    /// against `rumqttc` or, in tests, the in-memory broker in `testkit`.
+    /// Signatures mirror `rumqttc::AsyncClient`.
+    pub trait MqttTransport: Clone + Send + Sync + 'static {
+        fn publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> impl std::future::Future<Output = Result<(), rumqttc::ClientError>> + Send
+        where
+            S: Into<String> + Send,
+            V: Into<Vec<u8>> + Send;
+
+        /// Like `publish`, but fails instead of waiting when the outgoing
+        /// queue is full
+        fn try_publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> Result<(), rumqttc::ClientError>
+        where
+            S: Into<String>,
+            V: Into<Vec<u8>>;
+
+        fn subscribe<S: Into<String> + Send>(
+            &self,
+            topic: S,
+            qos: QoS,
+        ) -> impl std::future::Future<Output = Result<(), rumqttc::ClientError>> + Send;
+    }
+
+    impl MqttTransport for rumqttc::AsyncClient {
+        async fn publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> Result<(), rumqttc::ClientError>
+        where
+            S: Into<String> + Send,
+            V: Into<Vec<u8>> + Send,
+        {
+            rumqttc::AsyncClient::publish(self, topic, qos, retain, payload).await
+        }
+
+        fn try_publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> Result<(), rumqttc::ClientError>
+        where
+            S: Into<String>,
+            V: Into<Vec<u8>>,
+        {
+            rumqttc::AsyncClient::try_publish(self, topic, qos, retain, payload)
+        }
+
+        async fn subscribe<S: Into<String> + Send>(
+            &self,
+            topic: S,
+            qos: QoS,
+        ) -> Result<(), rumqttc::ClientError> {
+            rumqttc::AsyncClient::subscribe(self, topic, qos).await
+        }
+    }
+
+    /// Last-will body the broker publishes on the heartbeat topic when a node
+    /// or client drops without shutting down cleanly
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+    pub struct OfflineNotice {
+        pub node_id: String,
+        pub status: NodeStatus,
+    }
+
+    /// Anything published on a heartbeat topic: a regular heartbeat, or the
+    /// bare `OfflineNotice` left as a last will
+    #[derive(Debug, Clone)]
+    pub enum HeartbeatMessage {
+        Live(Box<NodeInfo>),
+        Offline(OfflineNotice),
+    }
+
+    impl<'de> Deserialize<'de> for HeartbeatMessage {
+        /// Tells the two apart by `node_type`, which every heartbeat carries
+        /// and the will lacks, so a malformed message reports what is wrong
+        /// with it rather than that it matched neither
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            use serde::de::Error as _;
+            let value = serde_json::Value::deserialize(deserializer)?;
+            if value.get("node_type").is_some() {
+                serde_json::from_value(value)
+                    .map(|info| HeartbeatMessage::Live(Box::new(info)))
+                    .map_err(|e| D::Error::custom(format!("heartbeat: {}", e)))
+            } else {
+                serde_json::from_value(value)
+                    .map(HeartbeatMessage::Offline)
+                    .map_err(|e| D::Error::custom(format!("offline notice: {}", e)))
+            }
+        }
+    }
+
+    impl HeartbeatMessage {
+        pub fn status(&self) -> &NodeStatus {
+            match self {
+                HeartbeatMessage::Live(info) => &info.status,
+                HeartbeatMessage::Offline(notice) => &notice.status,
+            }
+        }
+    }
+
+    /// Registers an `OfflineNotice` for `node_id` as the connection's last
+    /// will, so peers learn of a crash without waiting for heartbeats to lapse.
+    /// The notice is signed like any other publish when `secret` is set.
+    pub fn set_offline_will(
+        options: &mut MqttOptions,
+        topic_prefix: &str,
+        secret: Option<&SharedSecret>,
+        node_type: &NodeType,
+        node_id: &str,
+    ) {
+        let notice = OfflineNotice {
+            node_id: node_id.to_string(),
+            status: NodeStatus::Offline,
+        };
+        if let Ok(payload) = serde_json::to_vec(&notice) {
+            let topic = topics::heartbeat(topic_prefix, node_type, node_id);
+            let payload = integrity::seal_with(secret, &topic, payload);
+            options.set_last_will(LastWill::new(topic, payload, QoS::AtLeastOnce, false));
+        }
+    }
+
+    /// Reconnect delays for an MQTT event loop: doubles from `initial` up to
+    /// `max`, with ±20% jitter so nodes don't retry in lockstep
+    #[derive(Debug, Clone)]
+    pub struct Backoff {
+        initial: Duration,
+        max: Duration,
+        current: Duration,
+    }
+
+    impl Default for Backoff {
+        fn default() -> Self {
+            Self::new(Duration::from_millis(500), Duration::from_secs(30))
+        }
+    }
+
+    impl Backoff {
+        /// Maximum fraction a delay is shifted either way
+        const JITTER: f64 = 0.2;
+
+        pub fn new(initial: Duration, max: Duration) -> Self {
+            Self {
+                initial,
+                max,
+                current: initial,
+            }
+        }
+
+        /// The delay before the next retry; never exceeds `max`
+        #[allow(clippy::should_implement_trait)]
+        pub fn next(&mut self) -> Duration {
+            let base = self.current;
+            self.current = (self.current * 2).min(self.max);
+            let factor = rand::thread_rng().gen_range(1.0 - Self::JITTER..=1.0 + Self::JITTER);
+            base.mul_f64(factor).min(self.max)
+        }
+
+        /// Starts over from `initial`, after a successful poll
+        pub fn reset(&mut self) {
+            self.current = self.initial;
+        }
+    }
+
+    /// Token bucket: holds up to `capacity` tokens, refilled at `per_sec` a
+    /// second, and each acquire spends one. Allows short bursts while capping
+    /// the sustained rate.
+    #[derive(Debug, Clone)]
+    pub struct RateLimiter {
+        capacity: f64,
+        per_sec: f64,
+        tokens: f64,
+        refilled_at: Instant,
+    }
+
+    impl RateLimiter {
+        /// Starts full, so the first `capacity` acquires succeed at once
+        pub fn new(per_sec: f64, capacity: u32) -> Self {
+            RateLimiter {
+                capacity: f64::from(capacity),
+                per_sec,
+                tokens: f64::from(capacity),
+                refilled_at: Instant::now(),
+            }
+        }
+
+        /// Spends a token if one is available
+        pub fn try_acquire(&mut self) -> bool {
+            self.try_acquire_at(Instant::now())
+        }
+
+        pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+            let elapsed = now.saturating_duration_since(self.refilled_at);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.capacity);
+            self.refilled_at = now;
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Simulated network faults for resilience testing, configured by
+    /// `FAULT_INJECTION` as comma-separated `kind:value` pairs: `drop:0.1`
+    /// loses 10% of wrapped publishes and `delay:200` holds each one back by
+    /// 200ms first
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct FaultInjector {
+        drop_probability: f64,
+        delay: Duration,
+    }
+
+    impl FaultInjector {
+        pub fn new(drop_probability: f64, delay: Duration) -> Self {
+            FaultInjector {
+                drop_probability: drop_probability.clamp(0.0, 1.0),
+                delay,
+            }
+        }
+
+        /// Parses a `FAULT_INJECTION` spec; unknown or malformed entries are
+        /// ignored with a warning
+        pub fn parse(spec: &str) -> Self {
+            let mut injector = FaultInjector::default();
+            for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                if !injector.apply(entry) {
+                    warn!("Ignoring unknown fault injection entry: {}", entry);
+                }
+            }
+            injector
+        }
+
+        /// Parses a spec whose entries must all be valid
+        pub fn try_parse(spec: &str) -> Option<Self> {
+            let mut injector = FaultInjector::default();
+            spec.split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .all(|entry| injector.apply(entry))
+                .then_some(injector)
+        }
+
+        /// Applies one `kind:value` entry, returning false if it is unknown
+        /// or malformed
+        fn apply(&mut self, entry: &str) -> bool {
+            match entry.split_once(':') {
+                Some(("drop", value)) => value
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|p| p.is_finite())
+                    .map(|p| self.drop_probability = p.clamp(0.0, 1.0))
+                    .is_some(),
+                Some(("delay", value)) => value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .map(|ms| self.delay = Duration::from_millis(ms))
+                    .is_some(),
+                _ => false,
+            }
+        }
+
+        /// Whether any fault is configured
+        pub fn is_active(&self) -> bool {
+            self.drop_probability > 0.0 || !self.delay.is_zero()
+        }
+
+        /// Runs `publish` after the configured delay, unless the publish is
+        /// picked to be dropped, in which case it reports success without
+        /// sending anything, like a message lost on the way
+        pub async fn publish<F, Fut, E>(&self, publish: F) -> Result<(), E>
+        where
+            F: FnOnce() -> Fut,
+            Fut: std::future::Future<Output = Result<(), E>>,
+        {
+            if !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            if self.drop_probability > 0.0 && rand::thread_rng().gen_bool(self.drop_probability) {
+                debug!("Fault injection dropped a publish");
+                return Ok(());
+            }
+            publish().await
+        }
+    }
+
+    /// Memory budget for each dedup window when `DEDUP_MEMORY_BUDGET_MB` is unset
+    pub const DEFAULT_DEDUP_MEMORY_BUDGET_MB: usize = 16;
+
+    /// Recently seen ids (packet ids, response ids), capped by estimated memory
+    /// rather than entry count so long ids can't grow it unexpectedly. The
+    /// oldest ids are forgotten first once the budget is reached.
+    #[derive(Debug)]
+    pub struct DedupWindow {
+        order: VecDeque<String>,
+        seen: HashSet<String>,
+        budget_bytes: usize,
+        used_bytes: usize,
+    }
+
+    impl DedupWindow {
+        /// Hash table slack and control bytes charged per entry on top of the
+        /// two `String`s holding the id
+        const ENTRY_OVERHEAD: usize = 16;
+
+        pub fn new(budget_bytes: usize) -> Self {
+            DedupWindow {
+                order: VecDeque::new(),
+                seen: HashSet::new(),
+                budget_bytes,
+                used_bytes: 0,
+            }
+        }
+
+        pub fn with_budget_mb(budget_mb: usize) -> Self {
+            Self::new(budget_mb.saturating_mul(1024 * 1024))
+        }
+
+        /// Estimated bytes used by one id: it is stored in both the set and
+        /// the eviction queue
+        fn entry_cost(id: &str) -> usize {
+            2 * (std::mem::size_of::<String>() + id.len()) + Self::ENTRY_OVERHEAD
+        }
+
+        /// Records `id`, returning `false` if it is already in the window. An
+        /// id too large for the whole budget is never remembered.
+        pub fn insert(&mut self, id: &str) -> bool {
+            if self.seen.contains(id) {
+                return false;
+            }
+            let cost = Self::entry_cost(id);
+            if cost > self.budget_bytes {
+                return true;
+            }
+            while self.used_bytes + cost > self.budget_bytes {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.seen.remove(&oldest);
+                self.used_bytes -= Self::entry_cost(&oldest);
+            }
+            self.order.push_back(id.to_string());
+            self.seen.insert(id.to_string());
+            self.used_bytes += cost;
+            true
+        }
+
+        /// Estimated memory held by the remembered ids
+        pub fn estimated_bytes(&self) -> usize {
+            self.used_bytes
+        }
+
+        pub fn len(&self) -> usize {
+            self.order.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.order.is_empty()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn image_packet() -> DataPacket {
+            DataPacket {
+                id: "image-1".to_string(),
+                timestamp: "0".to_string(),
+                data_type: "image".to_string(),
+                payload: DataPayload::ImageData {
+                    width: 16,
+                    height: 16,
+                    format: "PNG".to_string(),
+                    data: (0..=255).collect(),
+                },
+                metadata: HashMap::new(),
+                schema_version: PROTOCOL_VERSION,
+            }
+        }
+
+        fn image_bytes(packet: &DataPacket) -> Vec<u8> {
+            match &packet.payload {
+                DataPayload::ImageData { data, .. } => data.clone(),
+                other => panic!("expected image data, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_data_batches_respect_size_limit_and_round_trip() {
+            let packets: Vec<DataPacket> = (0..5)
+                .map(|i| DataPacket {
+                    id: format!("image-{}", i),
+                    ..image_packet()
+                })
+                .collect();
+
+            let batches = DataBatch::split(packets.clone(), 2);
+            let sizes: Vec<usize> = batches.iter().map(|b| b.packets.len()).collect();
+            assert_eq!(sizes, vec![2, 2, 1]);
+            assert_eq!(DataBatch::split(packets.clone(), 0).len(), 5);
+            assert!(DataBatch::split(Vec::new(), 2).is_empty());
+
+            let frame = compress_frame(&serde_json::to_vec(&batches[0]).unwrap(), 6);
+            let ids: Vec<String> = decode_data_packets(&frame)
+                .unwrap()
+                .into_iter()
+                .map(|packet| packet.id)
+                .collect();
+            assert_eq!(ids, vec!["image-0", "image-1"]);
+
+            // A lone packet decodes as a batch of one
+            let single = decode_data_packets(&serde_json::to_vec(&packets[4]).unwrap()).unwrap();
+            assert_eq!(single.len(), 1);
+            assert_eq!(image_bytes(&single[0]), image_bytes(&packets[4]));
+        }
+
+        #[test]
+        fn test_image_packet_round_trips_in_both_formats() {
+            let packet = image_packet();
+            for format in [WireFormat::Json, WireFormat::Bincode] {
+                let payload = encode(format, &packet).unwrap();
+                assert_eq!(payload[0], format.tag());
+
+                let decoded: DataPacket = decode(format, &payload).unwrap();
+                assert_eq!(decoded.id, packet.id);
+                assert_eq!(image_bytes(&decoded), image_bytes(&packet));
+
+                let framed: DataPacket = decode_frame(&payload).unwrap();
+                assert_eq!(image_bytes(&framed), image_bytes(&packet));
+            }
+
+            let json = encode(WireFormat::Json, &packet).unwrap();
+            let binary = encode(WireFormat::Bincode, &packet).unwrap();
+            assert!(binary.len() < json.len());
+            assert!(matches!(
+                decode::<DataPacket>(WireFormat::Json, &binary),
+                Err(WireError::UnexpectedFormat(WireFormat::Bincode))
+            ));
+        }
+
+        #[test]
+        fn test_json_payload_round_trips_alongside_fixed_variants() {
+            let value = serde_json::json!({
+                "vendor": "acme",
+                "readings": [{"pm25": 12.5, "ok": true}, {"pm25": null}],
+                "location": {"lat": 52.1, "tags": ["roof", "north"]},
+            });
+            let packet = DataPacket {
+                id: "json-1".to_string(),
+                data_type: "json".to_string(),
+                payload: DataPayload::Json(value.clone()),
+                ..image_packet()
+            };
+
+            let text = serde_json::to_string(&packet).unwrap();
+            assert!(text.contains(r#""payload":{"Json":{"#));
+            for format in [WireFormat::Json, WireFormat::Bincode] {
+                let decoded: DataPacket = decode_frame(&encode(format, &packet).unwrap()).unwrap();
+                match decoded.payload {
+                    DataPayload::Json(decoded) => assert_eq!(decoded, value),
+                    other => panic!("expected a JSON payload, got {:?}", other),
+                }
+            }
+
+            // Fixed variants whose shape looks like arbitrary JSON are not swallowed
+            let text: DataPacket = serde_json::from_str(
+                &serde_json::to_string(&DataPacket {
+                    payload: DataPayload::Text("hi".to_string()),
+                    ..packet.clone()
+                })
+                .unwrap(),
+            )
+            .unwrap();
+            assert!(matches!(text.payload, DataPayload::Text(_)));
+            assert_eq!(packet.payload.type_name(), "json");
+            assert!(packet.payload.validate().is_ok());
+        }
+
+        #[test]
+        fn test_pool_error_conversions_pick_matching_variant() {
+            // A client whose event loop is gone fails every request
+            let (client, eventloop) =
+                rumqttc::AsyncClient::new(MqttOptions::new("client-1", "localhost", 1883), 1);
+            drop(eventloop);
+            let client_error = client
+                .try_publish("topic", QoS::AtMostOnce, false, "payload")
+                .unwrap_err();
+            assert!(matches!(PoolError::from(client_error), PoolError::Mqtt(_)));
+
+            assert!(matches!(
+                PoolError::from(rumqttc::ConnectionError::RequestsDone),
+                PoolError::Connection(_)
+            ));
+            let serde_error = serde_json::from_str::<NodeInfo>("{").unwrap_err();
+            assert!(matches!(PoolError::from(serde_error), PoolError::Serde(_)));
+            assert!(matches!(
+                PoolError::from(WireError::Empty),
+                PoolError::Wire(WireError::Empty)
+            ));
+            let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+            assert!(matches!(PoolError::from(io_error), PoolError::Io(_)));
+
+            assert!(PoolError::Timeout.is_transient());
+            assert!(!PoolError::Config("bad port".to_string()).is_transient());
+            assert_eq!(
+                PoolError::Config("bad port".to_string()).to_string(),
+                "configuration: bad port"
+            );
+        }
+
+        #[test]
+        fn test_data_request_round_trips_every_field() {
+            let request = DataRequest {
+                request_id: "req-1".to_string(),
+                client_id: "client-1".to_string(),
+                timestamp: 1_700_000_000,
+                data_types: vec!["text".to_string(), "sensor".to_string()],
+                only_if_changed: Some(0.5),
+                idempotency_key: Some("retry-1".to_string()),
+                compression_level: Some(6),
+                fulfillment: Fulfillment::Strict,
+                max_items: Some(10),
+                priority: 3,
+            };
+            let decoded: DataRequest =
+                serde_json::from_slice(&serde_json::to_vec(&request).unwrap()).unwrap();
+            assert_eq!(decoded, request);
+
+            let legacy: DataRequest = serde_json::from_str(
+                r#"{"request_id":"req-2","slave_id":"client-2","timestamp":5,"data_types":["text"],"max_items":10}"#,
+            )
+            .unwrap();
+            assert_eq!(legacy.client_id, "client-2");
+            assert_eq!(legacy.timestamp, 5);
+            assert_eq!(legacy.max_items, Some(10));
+        }
+
+        #[test]
+        fn test_message_without_schema_version_defaults_to_zero() {
+            let payload = br#"{"node_id":"node-1","client_id":"client-1","status":"Accepted",
+                "rejection_reason":null,"configuration":null,"timestamp":0}"#;
+            let response: RoutingResponse = parse_message(payload).unwrap();
+            assert_eq!(response.schema_version, 0);
+        }
+
+        #[test]
+        fn test_newer_schema_version_is_rejected() {
+            let mut packet = image_packet();
+            packet.schema_version = PROTOCOL_VERSION + 1;
+            let payload = serde_json::to_vec(&packet).unwrap();
+            assert!(matches!(
+                parse_message::<DataPacket>(&payload),
+                Err(MessageError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+            ));
+
+            // Reported as a version mismatch even when the shape has changed
+            let reshaped = format!(r#"{{"schema_version":{},"id":7}}"#, PROTOCOL_VERSION + 1);
+            assert!(matches!(
+                parse_message::<DataPacket>(reshaped.as_bytes()),
+                Err(MessageError::UnsupportedVersion(_))
+            ));
+            assert!(matches!(
+                parse_message::<DataPacket>(br#"{"id":7}"#),
+                Err(MessageError::Malformed(_))
+            ));
+        }
+
+        #[test]
+        fn test_decode_or_log_only_returns_valid_messages() {
+            let notice = OfflineNotice {
+                node_id: "node-1".to_string(),
+                status: NodeStatus::Offline,
+            };
+            let payload = serde_json::to_vec(&notice).unwrap();
+            assert_eq!(
+                decode_or_log::<OfflineNotice>("heartbeat/master/node-1", &payload),
+                Some(notice)
+            );
+            assert_eq!(
+                decode_or_log::<OfflineNotice>("heartbeat/master/node-1", b"{not json"),
+                None
+            );
+            assert_eq!(
+                decode_or_log::<OfflineNotice>("heartbeat/master/node-1", br#"{"id":7}"#),
+                None
+            );
+
+            assert_eq!(payload_preview(b"short"), "short");
+            let long = vec![b'x'; 200];
+            assert_eq!(
+                payload_preview(&long),
+                format!("{}... (200 bytes)", "x".repeat(96))
+            );
+        }
+
+        #[test]
+        fn test_heartbeat_message_tells_live_heartbeats_from_wills() {
+            let live = serde_json::to_vec(&NodeInfo::new(NodeType::Node, 10)).unwrap();
+            assert!(matches!(
+                serde_json::from_slice(&live).unwrap(),
+                HeartbeatMessage::Live(info) if info.capacity == 10
+            ));
+            let will = serde_json::to_vec(&OfflineNotice {
+                node_id: "node-1".to_string(),
+                status: NodeStatus::Offline,
+            })
+            .unwrap();
+            let will: HeartbeatMessage = serde_json::from_slice(&will).unwrap();
+            assert!(matches!(will, HeartbeatMessage::Offline(_)));
+            assert_eq!(will.status(), &NodeStatus::Offline);
+
+            // Decode errors name the field at fault
+            let mut broken = serde_json::to_value(NodeInfo::new(NodeType::Node, 10)).unwrap();
+            broken["capacity"] = serde_json::json!("lots");
+            let err = serde_json::from_value::<HeartbeatMessage>(broken).unwrap_err();
+            assert!(
+                err.to_string().starts_with("heartbeat: invalid type"),
+                "{}",
+                err
+            );
+            let err =
+                serde_json::from_str::<HeartbeatMessage>(r#"{"node_id":"node-1"}"#).unwrap_err();
+            assert_eq!(err.to_string(), "offline notice: missing field `status`");
+        }
+
+        #[test]
+        fn test_mqtt_credentials_only_applied_when_both_present() {
+            let options = |vars: &[(&str, &str)]| {
+                let vars: HashMap<String, String> = vars
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+                mqtt_options_from_vars("client-1".to_string(), "localhost", 1883, |key| {
+                    vars.get(key).cloned()
+                })
+            };
+
+            let with_both = options(&[("MQTT_USERNAME", "pool"), ("MQTT_PASSWORD", "s3cret")]);
+            assert_eq!(
+                with_both.credentials(),
+                Some(("pool".to_string(), "s3cret".to_string()))
+            );
+            assert_eq!(options(&[]).credentials(), None);
+            assert_eq!(options(&[("MQTT_USERNAME", "pool")]).credentials(), None);
+            assert_eq!(options(&[("MQTT_PASSWORD", "s3cret")]).credentials(), None);
+
+            let defaults = options(&[]);
+            assert_eq!(defaults.keep_alive(), Duration::from_secs(5));
+            assert_eq!(defaults.request_channel_capacity(), 10);
+
+            let tuned = options(&[("MQTT_KEEPALIVE_SECS", "30"), ("MQTT_CHANNEL_CAP", "500")]);
+            assert_eq!(tuned.keep_alive(), Duration::from_secs(30));
+            assert_eq!(tuned.request_channel_capacity(), 500);
+
+            let absurd = options(&[("MQTT_KEEPALIVE_SECS", "0"), ("MQTT_CHANNEL_CAP", "0")]);
+            assert_eq!(absurd.keep_alive(), Duration::from_secs(5));
+            assert_eq!(absurd.request_channel_capacity(), 1);
+            let huge = options(&[
+                ("MQTT_CHANNEL_CAP", "99999999"),
+                ("MQTT_KEEPALIVE_SECS", "x"),
+            ]);
+            assert_eq!(huge.request_channel_capacity(), 100_000);
+            assert_eq!(huge.keep_alive(), Duration::from_secs(5));
+        }
+
+        #[test]
+        fn test_payload_limit_covers_the_seal_and_sizes_packets() {
+            let limit = PayloadLimit {
+                max_bytes: 100,
+                ..PayloadLimit::default()
+            };
+            assert!(limit.check(&[0; 100]).is_ok());
+
+            let secret = SharedSecret::new("pool-secret");
+            let sealed = limit.sealed(Some(&secret));
+            assert!(sealed.check(&[0; 68]).is_ok());
+            assert!(matches!(
+                sealed.check(&[0; 69]),
+                Err(PoolError::PayloadTooLarge {
+                    size: 101,
+                    limit: 100
+                })
+            ));
+            assert_eq!(limit.sealed(None), limit);
+
+            // Packets fit the largest sealed payload plus its topic and headers
+            let options =
+                mqtt_options_from_vars("client-1".to_string(), "localhost", 1883, |key| {
+                    (key == "MAX_PAYLOAD_BYTES").then(|| "2000000".to_string())
+                });
+            assert!(options.max_packet_size() > 2_000_000 + integrity::SIGNATURE_LEN);
+            let defaults =
+                mqtt_options_from_vars("client-1".to_string(), "localhost", 1883, |_| None);
+            assert!(defaults.max_packet_size() > DEFAULT_MAX_PAYLOAD_BYTES);
+        }
+
+        #[test]
+        fn test_qos_policy_from_vars() {
+            let policy = |vars: &[(&str, &str)]| {
+                let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+                QosPolicy::from_vars(|key| vars.get(key).map(|value| value.to_string()))
+            };
+            assert_eq!(policy(&[]), QosPolicy::default());
+            assert_eq!(
+                policy(&[("MQTT_QOS", "0")]),
+                QosPolicy {
+                    default: QoS::AtMostOnce,
+                    routing: QoS::AtMostOnce,
+                }
+            );
+            assert_eq!(
+                policy(&[("MQTT_QOS", "1"), ("ROUTING_QOS", "2")]),
+                QosPolicy {
+                    default: QoS::AtLeastOnce,
+                    routing: QoS::ExactlyOnce,
+                }
+            );
+            assert_eq!(policy(&[("MQTT_QOS", "3")]), QosPolicy::default());
+        }
+
+        #[test]
+        fn test_offline_will_targets_heartbeat_topic() {
+            let mut options = MqttOptions::new("node-1", "localhost", 1883);
+            set_offline_will(&mut options, "", None, &NodeType::Node, "node-1");
+            let will = options.last_will().unwrap();
+            assert_eq!(will.topic, "heartbeat/master/node-1");
+            assert_eq!(will.qos, QoS::AtLeastOnce);
+            assert!(!will.retain);
+            let notice: OfflineNotice = serde_json::from_slice(&will.message).unwrap();
+            assert_eq!(
+                notice,
+                OfflineNotice {
+                    node_id: "node-1".to_string(),
+                    status: NodeStatus::Offline,
+                }
+            );
+            // Too little to pass for a regular heartbeat
+            assert!(serde_json::from_slice::<NodeInfo>(&will.message).is_err());
+
+            let mut options = MqttOptions::new("client-1", "localhost", 1883);
+            set_offline_will(&mut options, "pool-a", None, &NodeType::Client, "client-1");
+            assert_eq!(
+                options.last_will().unwrap().topic,
+                "pool-a/heartbeat/slave/client-1"
+            );
+
+            let secret = SharedSecret::new("pool-secret");
+            let mut options = MqttOptions::new("node-1", "localhost", 1883);
+            set_offline_will(&mut options, "", Some(&secret), &NodeType::Node, "node-1");
+            let will = options.last_will().unwrap();
+            let payload = integrity::unseal(secret.as_bytes(), &will.topic, &will.message).unwrap();
+            assert!(serde_json::from_slice::<OfflineNotice>(payload).is_ok());
+        }
+
+        #[test]
+        fn test_topic_builders_with_and_without_prefix() {
+            type Build = fn(&str) -> String;
+            let cases: [(Build, &str); 23] = [
+                (|p| topics::routing_request(p), "routing/request"),
+                (
+                    |p| topics::regional_routing_request(p, "eu"),
+                    "routing/request/eu",
+                ),
+                (|p| topics::routing_response(p, "c1"), "routing/response/c1"),
+                (|p| topics::routing_ack(p, "n1"), "routing/ack/n1"),
+                (|p| topics::heartbeat_master(p, "n1"), "heartbeat/master/n1"),
+                (|p| topics::heartbeat_slave(p, "+"), "heartbeat/slave/+"),
+                (
+                    |p| topics::heartbeat(p, &NodeType::Monitor, "m1"),
+                    "heartbeat/monitor/m1",
+                ),
+                (
+                    |p| topics::data_request(p, "n1", "c1"),
+                    "data/request/n1/c1",
+                ),
+                (
+                    |p| topics::data_response(p, "n1", "c1"),
+                    "data/response/n1/c1",
+                ),
+                (|p| topics::processing_status(p, "n1"), "data/response/n1"),
+                (|p| topics::data_summary(p, "n1", "+"), "data/summary/n1/+"),
+                (|p| topics::data_incoming(p, "#"), "data/incoming/#"),
+                (|p| topics::data_processed(p, "pkt"), "data/processed/pkt"),
+                (|p| topics::data_input(p, "c1"), "data/input/c1"),
+                (|p| topics::data_broadcast(p, "#"), "data/broadcast/#"),
+                (|p| topics::probe(p, "n1"), "probe/n1"),
+                (|p| topics::probe_ack(p, "n1"), "probe/ack/n1"),
+                (|p| topics::admin_probe(p, "n1"), "admin/probe/n1"),
+                (|p| topics::control(p, "n1"), "control/n1"),
+                (|p| topics::capacity_control(p, "n1"), "control/n1/capacity"),
+                (|p| topics::pool_control(p), "control/pool"),
+                (
+                    |p| topics::region_summary(p, "eu"),
+                    "orchestrator/region/eu",
+                ),
+                (|p| topics::master_status(p, "n1"), "master/status/n1"),
+            ];
+            for (build, bare) in cases {
+                assert_eq!(build(""), bare);
+                assert_eq!(build("pool-a"), format!("pool-a/{}", bare));
+            }
+            for (build, bare) in [
+                (
+                    topics::routing_history_query as Build,
+                    "orchestrator/query/routing_history",
+                ),
+                (
+                    topics::routing_history_response,
+                    "orchestrator/query/routing_history/response",
+                ),
+                (topics::routings_query, "orchestrator/query/routings"),
+                (topics::routings_response, "orchestrator/response/routings"),
+                (topics::reassign, "orchestrator/reassign"),
+            ] {
+                assert_eq!(build(""), bare);
+                assert_eq!(build("pool-a"), format!("pool-a/{}", bare));
+            }
+        }
+
+        #[test]
+        fn test_strip_only_accepts_own_pool() {
+            assert_eq!(
+                topics::strip("", "routing/request"),
+                Some("routing/request")
+            );
+            assert_eq!(
+                topics::strip("pool-a", "pool-a/routing/request"),
+                Some("routing/request")
+            );
+            assert_eq!(topics::strip("pool-a", "pool-b/routing/request"), None);
+            assert_eq!(topics::strip("pool-a", "pool-ab/routing/request"), None);
+            assert_eq!(topics::strip("pool-a", "routing/request"), None);
+        }
+
+        #[test]
+        fn test_advertised_formats_default_to_json() {
+            let mut info = NodeInfo::new(NodeType::Node, 10);
+            assert_eq!(WireFormat::advertised_by(&info), vec![WireFormat::Json]);
+
+            info.metadata.insert(
+                WIRE_FORMATS_METADATA_KEY.to_string(),
+                WireFormat::join(&[WireFormat::Json, WireFormat::Bincode]),
+            );
+            assert_eq!(
+                WireFormat::advertised_by(&info),
+                vec![WireFormat::Json, WireFormat::Bincode]
+            );
+
+            // Bincode is only used when both sides take it
+            let json_only = WireFormat::advertised_by(&NodeInfo::new(NodeType::Client, 1));
+            assert_eq!(
+                WireFormat::shared(&WIRE_FORMATS, &json_only),
+                WireFormat::Json
+            );
+            assert_eq!(
+                WireFormat::shared(&WIRE_FORMATS, &WireFormat::advertised_by(&info)),
+                WireFormat::Bincode
+            );
+        }
+
+        #[test]
+        fn test_compressed_frames_decode() {
+            let frame = serde_json::to_vec(&image_packet()).unwrap();
+            assert_eq!(compress_frame(&frame, 0), frame);
+
+            let compressed = compress_frame(&frame, MAX_COMPRESSION_LEVEL);
+            assert_eq!(compressed[0], DEFLATE_TAG);
+            assert!(compressed.len() < frame.len());
+            let decoded: DataPacket = decode_frame(&compressed).unwrap();
+            assert_eq!(decoded.id, "image-1");
+
+            assert!(matches!(
+                decode_frame::<DataPacket>(&compress_frame(&compressed, 1)),
+                Err(WireError::TooDeep)
+            ));
+            let bomb = compress_frame(&vec![b' '; MAX_DECOMPRESSED_BYTES + 1], 1);
+            assert!(matches!(
+                decode_frame::<DataPacket>(&bomb),
+                Err(WireError::TooLarge(MAX_DECOMPRESSED_BYTES))
+            ));
+        }
+
+        #[test]
+        fn test_untagged_json_still_decodes() {
+            let legacy = serde_json::to_vec(&image_packet()).unwrap();
+            let decoded: DataPacket = decode_frame(&legacy).unwrap();
+            assert_eq!(decoded.id, "image-1");
+            assert!(matches!(
+                decode_frame::<DataPacket>(&[9, 1, 2]),
+                Err(WireError::UnknownFormat(9))
+            ));
+        }
+
+        fn limits(policy: OversizePolicy) -> MetadataLimits {
+            MetadataLimits {
+                max_key_len: 8,
+                max_value_len: 16,
+                max_entries: 2,
+                policy,
+            }
+        }
+
+        #[test]
+        fn test_oversized_metadata_is_truncated() {
+            let mut metadata = HashMap::new();
+            metadata.insert("type".to_string(), "é".repeat(100));
+            metadata.insert("a-very-long-key".to_string(), "ok".to_string());
+            metadata.insert("zzz".to_string(), "dropped".to_string());
+
+            limits(OversizePolicy::Truncate)
+                .apply(&mut metadata)
+                .unwrap();
+
+            assert_eq!(metadata.len(), 2);
+            assert_eq!(metadata["type"], "é".repeat(8));
+            assert_eq!(metadata["a-very-l"], "ok");
+            assert!(!metadata.contains_key("zzz"));
+        }
+
+        #[test]
+        fn test_keys_colliding_when_truncated_are_refused() {
+            let mut metadata = HashMap::new();
+            metadata.insert("sensor-id-a".to_string(), "1".to_string());
+            metadata.insert("sensor-id-b".to_string(), "2".to_string());
+
+            let result = limits(OversizePolicy::Truncate).apply(&mut metadata);
+            assert_eq!(
+                result,
+                Err(MetadataError::KeyCollision("sensor-i".to_string()))
+            );
+            // Neither entry is lost
+            assert_eq!(metadata.len(), 2);
+            assert_eq!(metadata["sensor-id-b"], "2");
+        }
+
+        #[test]
+        fn test_oversized_metadata_is_rejected() {
+            let mut metadata = HashMap::new();
+            metadata.insert("type".to_string(), "x".repeat(100));
+            let result = limits(OversizePolicy::Reject).apply(&mut metadata);
+            assert_eq!(result, Err(MetadataError::ValueTooLong("type".to_string())));
+            // Rejection leaves the map untouched
+            assert_eq!(metadata["type"].len(), 100);
+
+            let mut small = HashMap::new();
+            small.insert("type".to_string(), "log".to_string());
+            assert!(limits(OversizePolicy::Reject).apply(&mut small).is_ok());
+        }
+
+        #[test]
+        fn test_non_finite_values_fail_to_serialize() {
+            assert!(serde_json::to_string(&DataPayload::Number(f64::NAN)).is_err());
+            assert!(serde_json::to_string(&DataPayload::Number(f64::INFINITY)).is_err());
+            let coordinates = DataPayload::Coordinates {
+                x: 1.0,
+                y: f64::NEG_INFINITY,
+                z: 3.0,
+                frame: None,
+            };
+            assert!(serde_json::to_string(&coordinates).is_err());
+            assert!(serde_json::to_string(&DataPayload::Number(42.5)).is_ok());
+        }
+
+        #[test]
+        fn test_is_finite_detects_nan_and_inf() {
+            let sensor = |pressure: f64| DataPayload::SensorData {
+                sensor_id: "temp-1".to_string(),
+                temperature: 23.5,
+                humidity: 45.0,
+                pressure,
+                units: None,
+            };
+            assert!(sensor(1013.2).is_finite());
+            assert!(!sensor(f64::NAN).is_finite());
+            assert!(!sensor(f64::INFINITY).is_finite());
+            assert!(!DataPayload::Number(f64::NAN).is_finite());
+            assert!(DataPayload::Text("ok".to_string()).is_finite());
+        }
+
+        fn issues(payload: DataPayload) -> Vec<String> {
+            payload.validate().unwrap_err().issues
+        }
+
+        #[test]
+        fn test_compressed_payloads_round_trip() {
+            let text = DataPayload::Text("sample text ".repeat(50));
+            let log = DataPayload::LogEntry {
+                level: "INFO".to_string(),
+                message: "node started".to_string(),
+                timestamp: "0".to_string(),
+            };
+            for payload in [text, log] {
+                let compressed = payload.compress();
+                match &compressed {
+                    DataPayload::Compressed {
+                        inner_type,
+                        algorithm,
+                        ..
+                    } => {
+                        assert_eq!(inner_type, payload.type_name());
+                        assert_eq!(algorithm, GZIP_ALGORITHM);
+                    }
+                    other => panic!("expected compressed payload, got {:?}", other),
+                }
+                assert_eq!(
+                    serde_json::to_value(compressed.decompress().unwrap()).unwrap(),
+                    serde_json::to_value(&payload).unwrap()
+                );
+            }
+        }
+
+        #[test]
+        fn test_decompress_stops_at_size_cap() {
+            let bomb = DataPayload::Text("a".repeat(MAX_DECOMPRESSED_BYTES)).compress();
+            assert!(matches!(
+                bomb.decompress(),
+                Err(CompressionError::TooLarge(MAX_DECOMPRESSED_BYTES))
+            ));
+
+            // Batch items share one budget
+            let half = DataPayload::Text("a".repeat(MAX_DECOMPRESSED_BYTES / 2)).compress();
+            assert!(DataPayload::Batch(vec![half.clone()]).decompress().is_ok());
+            assert!(matches!(
+                DataPayload::Batch(vec![half.clone(), half]).decompress(),
+                Err(CompressionError::TooLarge(_))
+            ));
+        }
+
+        #[test]
+        fn test_decompress_rejects_unknown_algorithm() {
+            let payload = DataPayload::Compressed {
+                inner_type: "text".to_string(),
+                algorithm: "zstd".to_string(),
+                data: vec![1, 2, 3],
+            };
+            assert!(matches!(
+                payload.decompress(),
+                Err(CompressionError::UnknownAlgorithm(algorithm)) if algorithm == "zstd"
+            ));
+            assert_eq!(
+                payload.validate().unwrap_err().issues,
+                vec!["unknown compression algorithm: zstd".to_string()]
+            );
+        }
+
+        #[test]
+        fn test_validate_accepts_well_formed_payloads() {
+            let valid = [
+                DataPayload::Text("hello".to_string()),
+                DataPayload::Number(42.0),
+                DataPayload::Coordinates {
+                    x: 1.0,
+                    y: 2.0,
+                    z: 3.0,
+                    frame: None,
+                },
+                DataPayload::SensorData {
+                    sensor_id: "temp-1".to_string(),
+                    temperature: -12.5,
+                    humidity: 45.0,
+                    pressure: 1013.2,
+                    units: Some(SensorUnits::default()),
+                },
+                image_packet().payload,
+                DataPayload::LogEntry {
+                    level: "warn".to_string(),
+                    message: "disk filling up".to_string(),
+                    timestamp: "0".to_string(),
+                },
+            ];
+            for payload in valid {
+                assert_eq!(payload.validate(), Ok(()), "{:?}", payload);
+            }
+        }
+
+        #[test]
+        fn test_validate_rejects_empty_text_and_non_finite_numbers() {
+            assert_eq!(
+                issues(DataPayload::Text("  ".to_string())),
+                vec!["empty text"]
+            );
+            assert_eq!(
+                issues(DataPayload::Number(f64::NAN)),
+                vec!["non-finite value"]
+            );
+            assert_eq!(
+                issues(DataPayload::Coordinates {
+                    x: 0.0,
+                    y: f64::INFINITY,
+                    z: 0.0,
+                    frame: None,
+                }),
+                vec!["non-finite value"]
+            );
+        }
+
+        #[test]
+        fn test_validate_checks_wgs84_coordinate_ranges() {
+            let coordinates = |x, y, frame: Option<&str>| DataPayload::Coordinates {
+                x,
+                y,
+                z: 0.0,
+                frame: frame.map(str::to_string),
+            };
+            assert!(coordinates(-122.4, 37.8, Some("wgs84")).validate().is_ok());
+            assert!(coordinates(180.0, -90.0, Some("wgs84")).validate().is_ok());
+            assert_eq!(
+                issues(coordinates(200.0, 95.0, Some("wgs84"))),
+                vec!["longitude out of range: 200", "latitude out of range: 95"]
+            );
+            // Other frames have no fixed range
+            assert!(coordinates(200.0, 95.0, Some("local")).validate().is_ok());
+        }
+
+        #[test]
+        fn test_batch_sums_cost_and_validates_items() {
+            let batch = DataPayload::Batch(vec![
+                DataPayload::Number(1.0),
+                DataPayload::Text(" ".to_string()),
+            ]);
+            assert_eq!(batch.load_cost(), 2);
+            assert_eq!(batch.type_name(), "batch");
+            assert_eq!(issues(batch), vec!["batch item 1: empty text"]);
+            assert_eq!(issues(DataPayload::Batch(Vec::new())), vec!["empty batch"]);
+
+            let mut nested = DataPayload::Number(1.0);
+            for _ in 0..MAX_BATCH_DEPTH {
+                nested = DataPayload::Batch(vec![nested]);
+            }
+            assert_eq!(nested.batch_depth(), MAX_BATCH_DEPTH);
+            assert!(nested.validate().is_ok());
+            assert_eq!(
+                issues(DataPayload::Batch(vec![nested])),
+                vec![format!("batch nested deeper than {}", MAX_BATCH_DEPTH)]
+            );
+
+            let wide = DataPayload::Batch(vec![DataPayload::Number(1.0); MAX_BATCH_ITEMS + 1]);
+            assert_eq!(wide.load_cost(), MAX_LOAD_COST);
+            assert_eq!(
+                issues(wide),
+                vec![format!(
+                    "batch of {} items exceeds {}",
+                    MAX_BATCH_ITEMS + 1,
+                    MAX_BATCH_ITEMS
+                )]
+            );
+        }
+
+        #[test]
+        fn test_decompress_unwraps_compressed_batch_items() {
+            let batch = DataPayload::Batch(vec![
+                DataPayload::Number(1.0).compress(),
+                DataPayload::Batch(vec![DataPayload::Text("hi".to_string()).compress()]).compress(),
+            ]);
+            assert_eq!(
+                serde_json::to_value(batch.compress().decompress().unwrap()).unwrap(),
+                serde_json::to_value(DataPayload::Batch(vec![
+                    DataPayload::Number(1.0),
+                    DataPayload::Batch(vec![DataPayload::Text("hi".to_string())]),
+                ]))
+                .unwrap()
+            );
+
+            // Doubly compressed payloads are left for validation to turn away
+            let twice = DataPayload::Compressed {
+                inner_type: "compressed".to_string(),
+                algorithm: GZIP_ALGORITHM.to_string(),
+                data: {
+                    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                    let inner = DataPayload::Number(1.0).compress();
+                    encoder
+                        .write_all(&serde_json::to_vec(&inner).unwrap())
+                        .unwrap();
+                    encoder.finish().unwrap()
+                },
+            };
+            assert_eq!(twice.decompress().unwrap().type_name(), "compressed");
+        }
+
+        #[test]
+        fn test_coordinates_without_frame_still_parse() {
+            let legacy = r#"{"Coordinates":{"x":200.0,"y":95.0,"z":1.0}}"#;
+            let payload: DataPayload = serde_json::from_str(legacy).unwrap();
+            assert!(matches!(
+                &payload,
+                DataPayload::Coordinates { frame: None, .. }
+            ));
+            // Without a frame the values can't be range-checked
+            assert!(payload.validate().is_ok());
+        }
+
+        #[test]
+        fn test_validate_rejects_bad_sensor_readings() {
+            let sensor = |sensor_id: &str, humidity, pressure| DataPayload::SensorData {
+                sensor_id: sensor_id.to_string(),
+                temperature: 20.0,
+                humidity,
+                pressure,
+                units: None,
+            };
+            assert!(sensor("temp-1", 0.0, 1013.2).validate().is_ok());
+            assert!(sensor("temp-1", 100.0, 0.5).validate().is_ok());
+            assert_eq!(
+                issues(sensor("temp-1", 45.0, -1.0)),
+                vec!["non-positive pressure: -1"]
+            );
+            assert_eq!(
+                issues(sensor("temp-1", 45.0, 0.0)),
+                vec!["non-positive pressure: 0"]
+            );
+            assert_eq!(
+                issues(sensor("temp-1", -0.5, 1000.0)),
+                vec!["humidity out of range: -0.5"]
+            );
+            assert_eq!(
+                issues(sensor("temp-1", 45.0, f64::NAN)),
+                vec!["non-finite value"]
+            );
+            assert_eq!(
+                issues(sensor("", 120.0, 1000.0)),
+                vec!["missing sensor id", "humidity out of range: 120"]
+            );
+        }
+
+        #[test]
+        fn test_sensor_units_are_optional_and_used_for_display() {
+            let legacy: DataPayload = serde_json::from_str(
+                r#"{"SensorData":{"sensor_id":"temp-1","temperature":20.0,"humidity":40.0,"pressure":1000.0}}"#,
+            )
+            .unwrap();
+            let DataPayload::SensorData { units, .. } = &legacy else {
+                panic!("expected sensor data, got {:?}", legacy);
+            };
+            assert_eq!(*units, None);
+
+            let units = SensorUnits {
+                temperature: TemperatureUnit::Fahrenheit,
+                pressure: PressureUnit::Kilopascal,
+            };
+            assert_eq!(
+                format_sensor_reading(68.0, 40.0, 101.3, Some(&units)),
+                "68°F, 40% humidity, 101.3 kPa"
+            );
+            assert_eq!(
+                format_sensor_reading(20.0, 40.0, 1000.0, None),
+                "temperature 20, 40% humidity, pressure 1000"
+            );
+        }
+
+        #[test]
+        fn test_validate_rejects_inconsistent_images() {
+            let image = |width, height, data: Vec<u8>| DataPayload::ImageData {
+                width,
+                height,
+                format: "raw".to_string(),
+                data,
+            };
+            assert_eq!(
+                issues(image(0, 10, vec![1])),
+                vec!["invalid image dimensions: 0x10"]
+            );
+            assert_eq!(
+                issues(image(100_000, 1, vec![1])),
+                vec!["image dimensions too large: 100000x1"]
+            );
+            assert_eq!(issues(image(2, 2, Vec::new())), vec!["empty image data"]);
+            assert_eq!(
+                issues(image(2, 2, vec![0; 17])),
+                vec!["17 bytes of image data exceeds 2x2 RGBA"]
+            );
+        }
+
+        fn audio(sample_rate: u32, channels: u8, data: Vec<u8>) -> DataPayload {
+            DataPayload::AudioData {
+                sample_rate,
+                channels,
+                codec: "pcm_s16le".to_string(),
+                data,
+            }
+        }
+
+        #[test]
+        fn test_audio_round_trips_through_both_wire_formats() {
+            let payload = audio(16_000, 2, (0..=255).collect());
+            for format in [WireFormat::Json, WireFormat::Bincode] {
+                let decoded: DataPayload =
+                    decode(format, &encode(format, &payload).unwrap()).unwrap();
+                match decoded {
+                    DataPayload::AudioData {
+                        sample_rate,
+                        channels,
+                        codec,
+                        data,
+                    } => {
+                        assert_eq!((sample_rate, channels), (16_000, 2));
+                        assert_eq!(codec, "pcm_s16le");
+                        assert_eq!(data, (0..=255).collect::<Vec<u8>>());
+                    }
+                    other => panic!("expected audio data, got {:?}", other),
+                }
+            }
+            assert_eq!(payload.type_name(), "audio");
+            assert!(
+                payload.load_cost()
+                    > DataPayload::SensorData {
+                        sensor_id: "s".to_string(),
+                        temperature: 0.0,
+                        humidity: 0.0,
+                        pressure: 1.0,
+                        units: None,
+                    }
+                    .load_cost()
+            );
+            assert!(payload.load_cost() < MAX_LOAD_COST);
+        }
+
+        #[test]
+        fn test_audio_duration_and_validation() {
+            // One second of 8 kHz stereo 16-bit audio
+            assert_eq!(
+                audio(8_000, 2, vec![0; 32_000]).audio_duration_secs(),
+                Some(1.0)
+            );
+            assert_eq!(audio(0, 1, vec![0; 2]).audio_duration_secs(), None);
+            assert!(audio(8_000, 1, vec![0; 2]).validate().is_ok());
+            assert_eq!(
+                issues(audio(0, 0, Vec::new())),
+                vec![
+                    "empty audio data",
+                    "zero sample rate",
+                    "zero audio channels"
+                ]
+            );
+        }
+
+        #[test]
+        fn test_validate_rejects_unknown_log_levels() {
+            let entry = |level: &str| LogEntry {
+                level: level.to_string(),
+                message: "m".to_string(),
+                timestamp: "0".to_string(),
+            };
+            assert_eq!(
+                issues(DataPayload::LogEntry {
+                    level: "LOUD".to_string(),
+                    message: "m".to_string(),
+                    timestamp: "0".to_string(),
+                }),
+                vec!["unrecognized log level: LOUD"]
+            );
+            assert_eq!(
+                issues(DataPayload::LogBatch {
+                    entries: vec![entry("INFO"), entry("")],
+                }),
+                vec!["unrecognized log level: "]
+            );
+            assert_eq!(
+                issues(DataPayload::LogBatch {
+                    entries: Vec::new()
+                }),
+                vec!["empty log batch"]
+            );
+        }
+
+        #[test]
+        fn test_utilization_and_capacity_checks() {
+            let node = |capacity, current_load| NodeInfo {
+                current_load,
+                ..NodeInfo::new(NodeType::Node, capacity)
+            };
+
+            let zero = node(0, 0);
+            assert_eq!(zero.utilization(), 1.0);
+            assert!(!zero.has_capacity(1));
+            assert!(zero.has_capacity(0));
+
+            let partial = node(10, 4);
+            assert_eq!(partial.utilization(), 0.4);
+            assert!(partial.has_capacity(6));
+            assert!(!partial.has_capacity(7));
+
+            let full = node(10, 10);
+            assert_eq!(full.utilization(), 1.0);
+            assert!(!full.has_capacity(1));
+            assert!(!node(10, u32::MAX).has_capacity(u32::MAX));
+        }
+
+        #[test]
+        fn test_mqtt_client_id_includes_prefix() {
+            assert_eq!(
+                mqtt_client_id(Some("pod-7"), "node-1234"),
+                "pod-7-node-1234"
+            );
+            assert_eq!(mqtt_client_id(None, "node-1234"), "node-1234");
+        }
+
+        #[test]
+        fn test_rate_limiter_rejects_bursts_and_refills() {
+            let mut limiter = RateLimiter::new(2.0, 3);
+            let start = Instant::now();
+            for _ in 0..3 {
+                assert!(limiter.try_acquire_at(start));
+            }
+            assert!(!limiter.try_acquire_at(start));
+
+            // Half a second at 2/s buys exactly one more
+            let later = start + Duration::from_millis(500);
+            assert!(limiter.try_acquire_at(later));
+            assert!(!limiter.try_acquire_at(later));
+
+            // A long pause refills only up to capacity
+            let much_later = later + Duration::from_secs(60);
+            for _ in 0..3 {
+                assert!(limiter.try_acquire_at(much_later));
+            }
+            assert!(!limiter.try_acquire_at(much_later));
+        }
+
+        #[test]
+        fn test_fault_injection_spec_parses() {
+            assert_eq!(
+                FaultInjector::parse("drop:0.1, delay:200"),
+                FaultInjector::new(0.1, Duration::from_millis(200))
+            );
+            assert_eq!(
+                FaultInjector::parse("drop:7,jitter:5"),
+                FaultInjector::new(1.0, Duration::ZERO)
+            );
+            assert!(!FaultInjector::parse("").is_active());
+        }
+
+        #[tokio::test]
+        async fn test_fault_injection_drops_by_probability() {
+            async fn published(injector: &FaultInjector) -> u32 {
+                let count = std::sync::atomic::AtomicU32::new(0);
+                for _ in 0..50 {
+                    injector
+                        .publish(|| async {
+                            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            Ok::<(), ()>(())
+                        })
+                        .await
+                        .unwrap();
+                }
+                count.into_inner()
+            }
+            assert_eq!(published(&FaultInjector::new(1.0, Duration::ZERO)).await, 0);
+            assert_eq!(
+                published(&FaultInjector::new(0.0, Duration::ZERO)).await,
+                50
+            );
+        }
+
+        #[test]
+        fn test_backoff_grows_to_cap_within_jitter() {
+            let mut backoff = Backoff::default();
+            let mut base = Duration::from_millis(500);
+            let mut previous = Duration::ZERO;
+
+            while base < Duration::from_secs(30) {
+                let delay = backoff.next();
+                assert!(delay > previous, "{:?} after {:?}", delay, previous);
+                assert!(delay >= base.mul_f64(0.8) && delay <= base.mul_f64(1.2));
+                previous = delay;
+                base *= 2;
+            }
+            for _ in 0..20 {
+                let delay = backoff.next();
+                assert!(delay >= Duration::from_secs(24) && delay <= Duration::from_secs(30));
+            }
+
+            backoff.reset();
+            let delay = backoff.next();
+            assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(600));
+        }
+
+        #[test]
+        fn test_dedup_window_stays_under_budget() {
+            let budget = 64 * 1024;
+            let mut window = DedupWindow::new(budget);
+            for i in 0..50_000 {
+                // Ids of varying length, some far longer than a uuid
+                let id = format!("{}-{}", "x".repeat(i % 300), i);
+                assert!(window.insert(&id));
+                assert!(window.estimated_bytes() <= budget);
+            }
+            assert!(!window.is_empty());
+
+            // The newest id is still remembered; the oldest were evicted
+            assert!(!window.insert(&format!("{}-{}", "x".repeat(49_999 % 300), 49_999)));
+            assert!(window.insert("-0"));
+
+            let mut tiny = DedupWindow::new(8);
+            assert!(tiny.insert("too-long-to-remember"));
+            assert!(tiny.insert("too-long-to-remember"));
+            assert!(tiny.is_empty());
+        }
+    }
 }
@@ -0,0 +1,426 @@
+//! Optional TOML config file (path in `CONFIG_FILE`) as an alternative to
+//! environment variables. Each binary reads its own table:
+//!
+//! ```toml
+//! [node]
+//! node_capacity = 50
+//! supported_types = ["text", "sensor"]
+//!
+//! [orchestrator]
+//! heartbeat_timeout_secs = 30
+//! embedded_broker = true
+//! ```
+//!
+//! Keys are the environment variable names in lower case, and an
+//! environment variable that is set always wins over the file. Lists stand
+//! for the comma-separated form of the variable and booleans for `1`/`0`.
+//!
+//! Each binary reads the typed values of its table directly. A value it can't
+//! use, like an unknown balancer or a QoS level of 3, fails startup, where an
+//! invalid environment variable is only warned about and ignored.
+
+use crate::PoolError;
+use log::warn;
+use serde::Deserialize;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Settings read by the node, from the `[node]` table
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub mqtt_host: Option<String>,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_keepalive_secs: Option<u64>,
+    pub mqtt_channel_cap: Option<usize>,
+    pub node_capacity: Option<u32>,
+    pub processing_concurrency: Option<u32>,
+    pub log_batch_window_ms: Option<u64>,
+    pub response_delay_ms: Option<u64>,
+    pub response_delay_jitter_ms: Option<u64>,
+    /// `type:limit` pairs, as in `TYPE_QUOTAS`
+    pub type_quotas: Option<Vec<String>>,
+    pub timestamp_order: Option<String>,
+    pub metadata_max_key_len: Option<usize>,
+    pub metadata_max_value_len: Option<usize>,
+    pub metadata_max_entries: Option<usize>,
+    pub metadata_policy: Option<String>,
+    pub startup_retries: Option<u32>,
+    pub startup_backoff_ms: Option<u64>,
+    pub client_id_prefix: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub drain_timeout_ms: Option<u64>,
+    pub max_request_types: Option<usize>,
+    pub max_request_items: Option<u32>,
+    pub max_queued_requests: Option<usize>,
+    pub max_batch_size: Option<u32>,
+    pub processing_timeout_ms: Option<u64>,
+    pub dedup_memory_budget_mb: Option<usize>,
+    pub supported_types: Option<Vec<String>>,
+    pub node_features: Option<Vec<String>>,
+    pub health_port: Option<u16>,
+    pub shared_secret: Option<String>,
+    pub mqtt_qos: Option<u8>,
+    pub routing_qos: Option<u8>,
+    pub fault_injection: Option<String>,
+    /// `type:weight` pairs, as in `GENERATOR_WEIGHTS`
+    pub generator_weights: Option<Vec<String>>,
+    /// `name:start..end` pairs, as in `GENERATOR_RANGES`
+    pub generator_ranges: Option<Vec<String>>,
+    pub generator_seed: Option<u64>,
+    pub max_payload_bytes: Option<usize>,
+}
+
+/// Settings read by the client, from the `[client]` table
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub mqtt_host: Option<String>,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_keepalive_secs: Option<u64>,
+    pub mqtt_channel_cap: Option<usize>,
+    pub client_id_prefix: Option<String>,
+    pub node_capacity: Option<u32>,
+    pub data_request_interval: Option<u64>,
+    pub fan_out: Option<u32>,
+    pub max_request_retries: Option<u32>,
+    pub client_data_types: Option<Vec<String>>,
+    pub dedup_memory_budget_mb: Option<usize>,
+    pub compression_level: Option<u32>,
+    pub topic_prefix: Option<String>,
+    pub max_requests_per_sec: Option<u32>,
+    pub health_port: Option<u16>,
+    pub shared_secret: Option<String>,
+    pub mqtt_qos: Option<u8>,
+    pub routing_qos: Option<u8>,
+    pub max_payload_bytes: Option<usize>,
+    pub client_logical_id: Option<String>,
+}
+
+/// Settings read by the orchestrator, from the `[orchestrator]` table
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrchestratorConfig {
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_keepalive_secs: Option<u64>,
+    pub mqtt_channel_cap: Option<usize>,
+    pub orchestrator_mode: Option<String>,
+    pub orchestrator_region: Option<String>,
+    pub balancer: Option<String>,
+    pub routing_seed: Option<u64>,
+    pub heartbeat_timeout_secs: Option<u64>,
+    pub cleanup_interval_secs: Option<u64>,
+    pub status_print_interval_secs: Option<u64>,
+    pub webhook_url: Option<String>,
+    pub webhook_interval_secs: Option<u64>,
+    pub metrics_port: Option<u16>,
+    pub health_port: Option<u16>,
+    pub client_id_prefix: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub routing_history_size: Option<usize>,
+    pub routing_audit_topic: Option<String>,
+    pub embedded_broker: Option<bool>,
+    pub embedded_broker_addr: Option<String>,
+    pub correct_routing_divergence: Option<bool>,
+    pub unavailable_retry_after_ms: Option<u64>,
+    pub record_file: Option<String>,
+    pub shared_secret: Option<String>,
+    pub mqtt_qos: Option<u8>,
+    pub routing_qos: Option<u8>,
+    pub rebalance_threshold: Option<f32>,
+    pub rebalance_max_moves: Option<usize>,
+    pub rebalance_cooldown_secs: Option<u64>,
+    pub routing_ack_timeout_secs: Option<u64>,
+    pub max_payload_bytes: Option<usize>,
+}
+
+/// The broker connection keys every table accepts
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MqttSection {
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_keepalive_secs: Option<u64>,
+    pub mqtt_channel_cap: Option<usize>,
+    pub max_payload_bytes: Option<usize>,
+}
+
+impl NodeConfig {
+    pub fn mqtt(&self) -> MqttSection {
+        MqttSection {
+            mqtt_username: self.mqtt_username.clone(),
+            mqtt_password: self.mqtt_password.clone(),
+            mqtt_keepalive_secs: self.mqtt_keepalive_secs,
+            mqtt_channel_cap: self.mqtt_channel_cap,
+            max_payload_bytes: self.max_payload_bytes,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn mqtt(&self) -> MqttSection {
+        MqttSection {
+            mqtt_username: self.mqtt_username.clone(),
+            mqtt_password: self.mqtt_password.clone(),
+            mqtt_keepalive_secs: self.mqtt_keepalive_secs,
+            mqtt_channel_cap: self.mqtt_channel_cap,
+            max_payload_bytes: self.max_payload_bytes,
+        }
+    }
+}
+
+impl OrchestratorConfig {
+    pub fn mqtt(&self) -> MqttSection {
+        MqttSection {
+            mqtt_username: self.mqtt_username.clone(),
+            mqtt_password: self.mqtt_password.clone(),
+            mqtt_keepalive_secs: self.mqtt_keepalive_secs,
+            mqtt_channel_cap: self.mqtt_channel_cap,
+            max_payload_bytes: self.max_payload_bytes,
+        }
+    }
+}
+
+/// Contents of a config file; missing tables are left empty
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub node: NodeConfig,
+    pub client: ClientConfig,
+    pub orchestrator: OrchestratorConfig,
+}
+
+impl ConfigFile {
+    pub fn parse(text: &str) -> Result<Self, PoolError> {
+        toml::from_str(text).map_err(|e| PoolError::Config(format!("config file: {}", e)))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PoolError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            PoolError::Config(format!("reading config file {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text)
+    }
+
+    /// The file named by `CONFIG_FILE`, or an empty one when unset
+    pub fn from_env() -> Result<Self, PoolError> {
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => Self::load(path),
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+/// Reads a process environment variable
+pub fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+/// The config file value of `key` can't be used
+pub fn invalid(key: &str, value: impl fmt::Display) -> PoolError {
+    PoolError::Config(format!(
+        "config file: invalid {}: {}",
+        key.to_ascii_lowercase(),
+        value
+    ))
+}
+
+/// The environment variable `key` parsed by `parse`; unset when missing or
+/// empty, and ignored with a warning when `parse` rejects it
+fn from_env<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<T> {
+    let value = var(key).filter(|value| !value.trim().is_empty())?;
+    let parsed = parse(value.trim());
+    if parsed.is_none() {
+        warn!("Ignoring invalid {}: {}", key, value);
+    }
+    parsed
+}
+
+/// The setting for `key`: its environment variable, else the file's value
+pub fn setting<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    file: Option<T>,
+) -> Option<T> {
+    from_env(var, key, |value| value.parse().ok()).or(file)
+}
+
+/// `setting` for a flag, `1` or `0` in the environment
+pub fn flag(var: &impl Fn(&str) -> Option<String>, key: &str, file: Option<bool>) -> Option<bool> {
+    from_env(var, key, |value| match value {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    })
+    .or(file)
+}
+
+/// `setting` limited to `range`: environment values are clamped into it,
+/// file values outside it are an error
+pub fn bounded<T: FromStr + PartialOrd + Copy + fmt::Display>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    file: Option<T>,
+    range: RangeInclusive<T>,
+) -> Result<Option<T>, PoolError> {
+    if let Some(value) = from_env(var, key, |value| value.parse::<T>().ok()) {
+        let clamped = if value < *range.start() {
+            *range.start()
+        } else if value > *range.end() {
+            *range.end()
+        } else {
+            value
+        };
+        return Ok(Some(clamped));
+    }
+    match file {
+        Some(value) if !range.contains(&value) => Err(invalid(key, value)),
+        file => Ok(file),
+    }
+}
+
+/// A setting `parse` turns into a `T`, from the environment or else the
+/// file, whose value must parse
+pub fn parsed<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    file: Option<&str>,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Option<T>, PoolError> {
+    if let Some(value) = from_env(var, key, &parse) {
+        return Ok(Some(value));
+    }
+    file.map(|value| parse(value.trim()).ok_or_else(|| invalid(key, value)))
+        .transpose()
+}
+
+/// A list setting: the comma-separated environment variable, whose invalid
+/// entries are skipped with a warning, or else the file's list, whose
+/// entries must all parse
+pub fn list<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    file: Option<&[String]>,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Option<Vec<T>>, PoolError> {
+    if let Some(spec) = var(key) {
+        let entries = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let parsed = parse(entry);
+                if parsed.is_none() {
+                    warn!("Ignoring invalid {} entry: {}", key, entry);
+                }
+                parsed
+            })
+            .collect();
+        return Ok(Some(entries));
+    }
+    file.map(|entries| {
+        entries
+            .iter()
+            .map(|entry| parse(entry.trim()).ok_or_else(|| invalid(key, entry)))
+            .collect()
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SAMPLE: &str = r#"
+        [node]
+        mqtt_host = "broker.local"
+        node_capacity = 50
+        supported_types = ["text", "sensor"]
+
+        [orchestrator]
+        heartbeat_timeout_secs = 30
+        embedded_broker = true
+    "#;
+
+    #[test]
+    fn test_sample_file_parses_into_typed_sections() {
+        let file = ConfigFile::parse(SAMPLE).unwrap();
+        assert_eq!(
+            file.node,
+            NodeConfig {
+                mqtt_host: Some("broker.local".to_string()),
+                node_capacity: Some(50),
+                supported_types: Some(vec!["text".to_string(), "sensor".to_string()]),
+                ..NodeConfig::default()
+            }
+        );
+        assert_eq!(file.client, ClientConfig::default());
+        assert_eq!(file.orchestrator.heartbeat_timeout_secs, Some(30));
+        assert_eq!(file.orchestrator.embedded_broker, Some(true));
+
+        // Typos and wrongly typed values are reported, not ignored
+        assert!(ConfigFile::parse("[node]\nnode_capacty = 5").is_err());
+        assert!(ConfigFile::parse("[node]\nnode_capacity = \"lots\"").is_err());
+    }
+
+    #[test]
+    fn test_environment_overrides_file_values() {
+        let file = ConfigFile::parse(SAMPLE).unwrap();
+        let env = HashMap::from([("NODE_CAPACITY", "75"), ("NODE_FEATURES", "x")]);
+        let var = |key: &str| env.get(key).map(|v| v.to_string());
+        assert_eq!(
+            setting(&var, "NODE_CAPACITY", file.node.node_capacity),
+            Some(75)
+        );
+        assert_eq!(
+            setting(&var, "MQTT_HOST", file.node.mqtt_host.clone()).as_deref(),
+            Some("broker.local")
+        );
+        assert_eq!(setting(&var, "MQTT_PORT", file.node.mqtt_port), None);
+        let known = |t: &str| (t == "text" || t == "sensor").then(|| t.to_string());
+        assert_eq!(
+            list(&var, "SUPPORTED_TYPES", file.node.supported_types.as_deref(), known).unwrap(),
+            Some(vec!["text".to_string(), "sensor".to_string()])
+        );
+        // Bad environment entries are dropped
+        assert_eq!(list(&var, "NODE_FEATURES", None, known).unwrap(), Some(vec![]));
+        assert_eq!(
+            flag(&var, "EMBEDDED_BROKER", file.orchestrator.embedded_broker),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_unusable_file_values_are_errors() {
+        let none = |_: &str| None;
+        let qos = 0..=2u8;
+        assert_eq!(bounded(&none, "MQTT_QOS", Some(2), qos.clone()).unwrap(), Some(2));
+        let err = bounded(&none, "MQTT_QOS", Some(3), qos.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "configuration: config file: invalid mqtt_qos: 3");
+        // The environment is clamped instead
+        let env = |_: &str| Some("7".to_string());
+        assert_eq!(bounded(&env, "MQTT_QOS", Some(3), qos).unwrap(), Some(2));
+
+        let policy = |value: &str| (value == "reject").then_some(());
+        assert!(parsed(&none, "METADATA_POLICY", Some("rejct"), policy).is_err());
+        let known = |t: &str| (t == "text").then(|| t.to_string());
+        let types = ["text".to_string(), "video".to_string()];
+        assert!(list(&none, "SUPPORTED_TYPES", Some(&types), known).is_err());
+
+        // Every table takes the connection keys, but only its own others
+        assert!(ConfigFile::parse("[client]\nmqtt_username = \"pool\"").is_ok());
+        assert!(ConfigFile::parse("[client]\nbalancer = \"random\"").is_err());
+    }
+}
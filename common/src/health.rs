@@ -0,0 +1,116 @@
+//! Readiness probe for container orchestration: `GET /health` answers 200
+//! once the MQTT connection is established and 503 until then (or while it
+//! is down).
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head read from a prober before giving up on it
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Pause after a failed `accept`, so e.g. running out of file descriptors
+/// doesn't turn into a busy loop
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Binds `0.0.0.0:port` and serves `/health` in the background
+pub async fn start(port: u16, connected: Arc<AtomicBool>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    log::info!("Serving health checks on port {}", port);
+    tokio::spawn(serve(listener, connected));
+    Ok(())
+}
+
+/// Serves `GET /health` on `listener` until the process exits. A failed
+/// `accept` is logged and the next connection awaited.
+pub async fn serve(listener: TcpListener, connected: Arc<AtomicBool>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::warn!("Error accepting health check connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let connected = Arc::clone(&connected);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &connected).await {
+                log::warn!("Error answering health check: {}", e);
+            }
+        });
+    }
+}
+
+/// Status line `/health` answers with for the given connection state
+pub fn status(connected: bool) -> &'static str {
+    if connected {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    }
+}
+
+/// Answers one probe, or a 404 for anything other than `GET /health`
+async fn respond(mut stream: TcpStream, connected: &AtomicBool) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut parts = request_line.split_whitespace();
+    let status = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/health")) => status(connected.load(Ordering::Relaxed)),
+        _ => "404 Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_health_follows_connected_flag() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connected = Arc::new(AtomicBool::new(false));
+        tokio::spawn(serve(listener, Arc::clone(&connected)));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        assert!(get("/health").await.starts_with("HTTP/1.1 503"));
+        connected.store(true, Ordering::Relaxed);
+        assert!(get("/health").await.starts_with("HTTP/1.1 200"));
+        connected.store(false, Ordering::Relaxed);
+        assert!(get("/health").await.starts_with("HTTP/1.1 503"));
+        assert!(get("/other").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Optional HMAC-SHA256 message signing. When the pool shares a secret
+//! (`SHARED_SECRET`), every payload is published with a signature over its
+//! topic and payload appended, and receivers drop anything whose signature is
+//! missing or wrong before deserializing it, so a participant without the
+//! secret can't forge traffic or replay a signed message on another topic.
+
+use crate::MqttTransport;
+use hmac::{Hmac, Mac};
+use rumqttc::{ClientError, QoS};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes appended to every signed payload
+pub const SIGNATURE_LEN: usize = 32;
+
+/// HMAC-SHA256 state keyed with `secret` over `topic || payload`. The topic
+/// goes in length-prefixed so no two topic and payload pairs sign alike.
+fn mac(secret: &[u8], topic: &str, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&(topic.len() as u64).to_be_bytes());
+    mac.update(topic.as_bytes());
+    mac.update(payload);
+    mac
+}
+
+/// HMAC-SHA256 of `payload` published on `topic`, under `secret`
+pub fn sign(secret: &[u8], topic: &str, payload: &[u8]) -> [u8; SIGNATURE_LEN] {
+    mac(secret, topic, payload).finalize().into_bytes().into()
+}
+
+/// Whether `sig` is the signature of `payload` on `topic` under `secret`,
+/// compared in constant time
+pub fn verify(secret: &[u8], topic: &str, payload: &[u8], sig: &[u8]) -> bool {
+    mac(secret, topic, payload).verify_slice(sig).is_ok()
+}
+
+/// `payload` followed by its signature for `topic`
+pub fn seal(secret: &[u8], topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(payload.len() + SIGNATURE_LEN);
+    sealed.extend_from_slice(payload);
+    sealed.extend_from_slice(&sign(secret, topic, payload));
+    sealed
+}
+
+/// The payload of a message sealed for `topic`, or `None` if it is too short
+/// to carry a signature or the signature doesn't match
+pub fn unseal<'a>(secret: &[u8], topic: &str, message: &'a [u8]) -> Option<&'a [u8]> {
+    let split = message.len().checked_sub(SIGNATURE_LEN)?;
+    let (payload, sig) = message.split_at(split);
+    verify(secret, topic, payload, sig).then_some(payload)
+}
+
+/// Key shared by every participant of a pool. Kept out of `Debug` output so
+/// it never ends up in logged configuration.
+#[derive(Clone, PartialEq)]
+pub struct SharedSecret(Arc<[u8]>);
+
+impl SharedSecret {
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        SharedSecret(Arc::from(secret.as_ref()))
+    }
+
+    /// `SHARED_SECRET`, or `None` when unset or empty
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// `from_env` with the environment replaced by `var` lookups
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Self::load(None, var)
+    }
+
+    /// `from_vars`, falling back to the `shared_secret` of a config file
+    pub fn load(file: Option<&str>, var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        var("SHARED_SECRET")
+            .filter(|secret| !secret.is_empty())
+            .or_else(|| file.filter(|secret| !secret.is_empty()).map(str::to_string))
+            .map(SharedSecret::new)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedSecret(..)")
+    }
+}
+
+/// Signs `payload` for `topic` when a secret is configured; passes it
+/// through otherwise
+pub fn seal_with(secret: Option<&SharedSecret>, topic: &str, payload: Vec<u8>) -> Vec<u8> {
+    match secret {
+        Some(secret) => seal(secret.as_bytes(), topic, &payload),
+        None => payload,
+    }
+}
+
+/// Checks and strips the signature of a message received on `topic` when a
+/// secret is configured; passes the message through otherwise
+pub fn unseal_with<'a>(
+    secret: Option<&SharedSecret>,
+    topic: &str,
+    message: &'a [u8],
+) -> Option<&'a [u8]> {
+    match secret {
+        Some(secret) => unseal(secret.as_bytes(), topic, message),
+        None => Some(message),
+    }
+}
+
+/// Transport that signs every payload it publishes with the pool's secret,
+/// if there is one
+#[derive(Clone)]
+pub struct Signed<T> {
+    inner: T,
+    secret: Option<SharedSecret>,
+}
+
+impl<T> Signed<T> {
+    pub fn new(inner: T, secret: Option<SharedSecret>) -> Self {
+        Signed { inner, secret }
+    }
+
+    /// The unsigned transport underneath
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The payload of a message received on `topic`, or `None` if it must
+    /// be dropped for a missing or invalid signature
+    pub fn open<'a>(&self, topic: &str, message: &'a [u8]) -> Option<&'a [u8]> {
+        unseal_with(self.secret.as_ref(), topic, message)
+    }
+}
+
+impl<T: MqttTransport> MqttTransport for Signed<T> {
+    async fn publish<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        let topic = topic.into();
+        let payload = seal_with(self.secret.as_ref(), &topic, payload.into());
+        self.inner.publish(topic, qos, retain, payload).await
+    }
+
+    fn try_publish<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        let payload = seal_with(self.secret.as_ref(), &topic, payload.into());
+        self.inner.try_publish(topic, qos, retain, payload)
+    }
+
+    async fn subscribe<S: Into<String> + Send>(
+        &self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<(), ClientError> {
+        self.inner.subscribe(topic, qos).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_signature_passes_and_tampering_fails() {
+        let secret = b"pool-secret";
+        let topic = "routing/response/client-1";
+        let payload = br#"{"node_id":"node-a","status":"Accepted"}"#;
+        let sig = sign(secret, topic, payload);
+        assert!(verify(secret, topic, payload, &sig));
+
+        let forged = br#"{"node_id":"node-x","status":"Accepted"}"#;
+        assert!(!verify(secret, topic, forged, &sig));
+        assert!(!verify(b"other-secret", topic, payload, &sig));
+
+        let mut sealed = seal(secret, topic, payload);
+        assert_eq!(unseal(secret, topic, &sealed), Some(&payload[..]));
+        // Replayed on another topic
+        assert_eq!(unseal(secret, "routing/response/client-2", &sealed), None);
+        sealed[10] ^= 1;
+        assert_eq!(unseal(secret, topic, &sealed), None);
+        // Unsigned traffic is dropped rather than parsed
+        assert_eq!(unseal(secret, topic, payload), None);
+        assert_eq!(unseal(secret, topic, b"short"), None);
+
+        // Bytes can't move between the topic and the payload
+        let sig = sign(secret, "data/a", b"bc");
+        assert!(!verify(secret, "data/ab", b"c", &sig));
+    }
+
+    #[tokio::test]
+    async fn test_signed_transport_round_trips_through_the_broker() {
+        let broker = crate::testkit::MemoryBroker::new();
+        let (raw, _) = broker.connect();
+        let (listener, mut events) = broker.connect();
+        listener
+            .subscribe("routing/response/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let secret = Some(SharedSecret::new("pool-secret"));
+        let signed = Signed::new(raw.clone(), secret.clone());
+        let receiver = Signed::new(listener, secret);
+
+        let wait = std::time::Duration::from_secs(1);
+        signed
+            .publish("routing/response/client-1", QoS::AtLeastOnce, false, "ok")
+            .await
+            .unwrap();
+        let publish = events.expect("routing/response/client-1", wait).await;
+        assert_eq!(
+            receiver.open(&publish.topic, &publish.payload),
+            Some(&b"ok"[..])
+        );
+
+        raw.publish(
+            "routing/response/client-1",
+            QoS::AtLeastOnce,
+            false,
+            "forged",
+        )
+        .await
+        .unwrap();
+        let publish = events.expect("routing/response/client-1", wait).await;
+        assert_eq!(receiver.open(&publish.topic, &publish.payload), None);
+    }
+}
@@ -1,2 +1,8 @@
 mod common;
+pub mod config;
+pub mod health;
+pub mod integrity;
+pub mod shutdown;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
 pub use common::common::*;
\ No newline at end of file
@@ -0,0 +1,118 @@
+//! Graceful shutdown shared by nodes and clients: waiting for the signal to
+//! stop, and the final offline heartbeat telling the pool why.
+
+use crate::{MqttTransport, NodeInfo, NodeStatus};
+use rumqttc::{ClientError, QoS};
+use std::time::Duration;
+use tokio::signal;
+
+/// How long the final heartbeat is given to leave before the process exits
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// Waits for SIGINT (ctrl-c) or, on unix, SIGTERM, returning the reason
+/// reported in the final offline heartbeat. If signals can't be listened
+/// for at all, shuts down right away with a "fatal error" reason.
+pub async fn wait_for_shutdown() -> String {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                log::error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = signal::ctrl_c() => match result {
+            Ok(()) => "SIGINT".to_string(),
+            Err(err) => {
+                let reason = format!("fatal error: {}", err);
+                log::error!("Failed to listen for shutdown signal; shutting down ({})", reason);
+                reason
+            }
+        },
+        _ = terminate => "SIGTERM".to_string(),
+    }
+}
+
+/// Publishes `node_info` on `topic` as the final heartbeat, with `status`
+/// and the shutdown `reason`, then gives it a moment to reach the broker
+pub async fn announce_offline<T: MqttTransport>(
+    client: &T,
+    topic: String,
+    qos: QoS,
+    node_info: &NodeInfo,
+    status: NodeStatus,
+    reason: &str,
+) -> Result<(), ClientError> {
+    let mut final_heartbeat = node_info.clone();
+    final_heartbeat.status = status;
+    final_heartbeat.shutdown_reason = Some(reason.to_string());
+    if let Ok(payload) = serde_json::to_string(&final_heartbeat) {
+        client.publish(topic, qos, false, payload).await?;
+        log::info!("Published offline status ({})", reason);
+    }
+
+    // Allow time for final messages to be sent
+    tokio::time::sleep(FLUSH_DELAY).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::MemoryBroker;
+    use crate::NodeType;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigterm_shuts_down_with_offline_heartbeat() {
+        // Keeps a SIGTERM handler installed throughout, so a signal sent
+        // before `wait_for_shutdown` listens can't kill the test process
+        let _installed = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+        let mut waiting = tokio::spawn(wait_for_shutdown());
+        let reason = loop {
+            let sent = std::process::Command::new("kill")
+                .args(["-TERM", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(sent.success());
+            if let Ok(reason) = tokio::time::timeout(Duration::from_millis(50), &mut waiting).await
+            {
+                break reason.unwrap();
+            }
+        };
+        assert_eq!(reason, "SIGTERM");
+
+        let broker = MemoryBroker::new();
+        let (client, _) = broker.connect();
+        let (observer, mut events) = broker.connect();
+        observer
+            .subscribe("heartbeat/slave/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let node_info = NodeInfo::new(NodeType::Client, 10);
+        let topic = format!("heartbeat/slave/{}", node_info.node_id);
+        announce_offline(
+            &client,
+            topic.clone(),
+            QoS::AtLeastOnce,
+            &node_info,
+            NodeStatus::Offline,
+            &reason,
+        )
+        .await
+        .unwrap();
+
+        let publish = events.expect(&topic, Duration::from_secs(1)).await;
+        let heartbeat: NodeInfo = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(heartbeat.status, NodeStatus::Offline);
+        assert_eq!(heartbeat.shutdown_reason.as_deref(), Some("SIGTERM"));
+    }
+}
@@ -0,0 +1,177 @@
+//! In-memory stand-in for an MQTT broker, so components can be wired together
+//! in tests without a live one. Every publish is delivered straight to the
+//! clients whose filters match, with no QoS handshakes or retained messages.
+
+use crate::MqttTransport;
+use rumqttc::{matches, ClientError, Publish, QoS};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// One connected client and the filters it subscribed to
+struct Session {
+    filters: Vec<String>,
+    outgoing: mpsc::UnboundedSender<Publish>,
+}
+
+/// Shared pub/sub hub; clone it to hand out to several components
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    sessions: Arc<Mutex<Vec<Session>>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects a new client, returning it and the stream of publishes
+    /// matching its subscriptions
+    pub fn connect(&self) -> (MemoryClient, MemoryEventLoop) {
+        let (outgoing, incoming) = mpsc::unbounded_channel();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.push(Session {
+            filters: Vec::new(),
+            outgoing,
+        });
+        let client = MemoryClient {
+            broker: self.clone(),
+            session: sessions.len() - 1,
+        };
+        (client, MemoryEventLoop { incoming })
+    }
+
+    fn route(&self, topic: String, qos: QoS, payload: Vec<u8>) {
+        let publish = Publish::new(topic, qos, payload);
+        for session in self.sessions.lock().unwrap().iter() {
+            if session
+                .filters
+                .iter()
+                .any(|filter| matches(&publish.topic, filter))
+            {
+                // A dropped event loop just stops receiving
+                let _ = session.outgoing.send(publish.clone());
+            }
+        }
+    }
+}
+
+/// Client half of a `MemoryBroker` connection
+#[derive(Clone)]
+pub struct MemoryClient {
+    broker: MemoryBroker,
+    session: usize,
+}
+
+impl MqttTransport for MemoryClient {
+    async fn publish<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String> + Send,
+        V: Into<Vec<u8>> + Send,
+    {
+        self.try_publish(topic, qos, retain, payload)
+    }
+
+    fn try_publish<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        _retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        self.broker.route(topic.into(), qos, payload.into());
+        Ok(())
+    }
+
+    async fn subscribe<S: Into<String> + Send>(
+        &self,
+        topic: S,
+        _qos: QoS,
+    ) -> Result<(), ClientError> {
+        let filter = topic.into();
+        let mut sessions = self.broker.sessions.lock().unwrap();
+        let filters = &mut sessions[self.session].filters;
+        if !filters.contains(&filter) {
+            filters.push(filter);
+        }
+        Ok(())
+    }
+}
+
+/// Publishes delivered to one `MemoryClient`, in order
+pub struct MemoryEventLoop {
+    incoming: mpsc::UnboundedReceiver<Publish>,
+}
+
+impl MemoryEventLoop {
+    /// Next delivered publish; `None` once the broker is gone
+    pub async fn recv(&mut self) -> Option<Publish> {
+        self.incoming.recv().await
+    }
+
+    /// Next delivered publish on `topic`, skipping others; panics after `wait`
+    pub async fn expect(&mut self, topic: &str, wait: Duration) -> Publish {
+        tokio::time::timeout(wait, async {
+            loop {
+                match self.recv().await {
+                    Some(publish) if publish.topic == topic => return publish,
+                    Some(_) => {}
+                    None => panic!("broker closed while waiting for {}", topic),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("nothing published on {} within {:?}", topic, wait))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publishes_reach_matching_subscribers_only() {
+        let broker = MemoryBroker::new();
+        let (publisher, _) = broker.connect();
+        let (wildcard, mut wildcard_loop) = broker.connect();
+        let (exact, mut exact_loop) = broker.connect();
+        wildcard
+            .subscribe("heartbeat/master/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        exact
+            .subscribe("heartbeat/master/node-b", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        for node in ["node-a", "node-b"] {
+            publisher
+                .publish(
+                    format!("heartbeat/master/{}", node),
+                    QoS::AtLeastOnce,
+                    false,
+                    node,
+                )
+                .await
+                .unwrap();
+        }
+
+        let wait = Duration::from_secs(1);
+        let first = wildcard_loop.expect("heartbeat/master/node-a", wait).await;
+        assert_eq!(&first.payload[..], b"node-a");
+        wildcard_loop.expect("heartbeat/master/node-b", wait).await;
+        let only = exact_loop.expect("heartbeat/master/node-b", wait).await;
+        assert_eq!(&only.payload[..], b"node-b");
+        assert!(exact_loop.incoming.try_recv().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use log::{error, info, warn, LevelFilter};
+use mqtt_common::config;
+use mqtt_common::integrity::{SharedSecret, Signed};
+use mqtt_common::{
+    Backoff, DataPacket, HeartbeatMessage, NodeInfo, NodeStatus, NodeType, RoutingResponse, RoutingStatus,
+    client_id_prefix_from_env, decode_or_log, mqtt_client_id, mqtt_options, topics, MqttTransport,
+};
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::signal;
+use tokio::sync::Mutex;
+use tokio::time;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Nodes not heard from for this long are shown as stale
+const STALE_AFTER_SECS: u64 = 15;
+
+/// What the monitor has observed about one node or client
+#[derive(Debug, Clone)]
+pub struct NodeView {
+    pub node_type: NodeType,
+    pub status: NodeStatus,
+    pub capacity: u32,
+    pub current_load: u32,
+    /// When the last heartbeat from this node was observed
+    pub last_seen: u64,
+    pub heartbeats: u64,
+    /// Clients the orchestrator (or node) accepted onto this node
+    pub routed_clients: u64,
+}
+
+/// Aggregated view of the pool, built purely from observed traffic
+#[derive(Debug, Default)]
+pub struct MonitorState {
+    pub nodes: HashMap<String, NodeView>,
+    pub processed_packets: u64,
+    pub routing_accepted: u64,
+    pub routing_rejected: u64,
+}
+
+impl MonitorState {
+    /// Folds one observed message into the view. `topic` is relative to the
+    /// pool's topic prefix. Unknown topics and unparseable payloads are ignored.
+    pub fn observe(&mut self, topic: &str, payload: &[u8], now: u64) {
+        if topic.starts_with("heartbeat/") {
+            if let Some(HeartbeatMessage::Live(info)) = decode_or_log(topic, payload) {
+                self.record_heartbeat(*info, now);
+            }
+        } else if topic.starts_with("data/processed/") {
+            if decode_or_log::<DataPacket>(topic, payload).is_some() {
+                self.processed_packets += 1;
+            }
+        } else if topic.starts_with("routing/response/") {
+            if let Some(response) = decode_or_log::<RoutingResponse>(topic, payload) {
+                self.record_routing(&response);
+            }
+        }
+    }
+
+    fn record_heartbeat(&mut self, info: NodeInfo, now: u64) {
+        let view = self
+            .nodes
+            .entry(info.node_id.clone())
+            .or_insert_with(|| NodeView {
+                node_type: info.node_type.clone(),
+                status: info.status.clone(),
+                capacity: info.capacity,
+                current_load: info.current_load,
+                last_seen: now,
+                heartbeats: 0,
+                routed_clients: 0,
+            });
+        view.node_type = info.node_type;
+        view.status = info.status;
+        view.capacity = info.capacity;
+        view.current_load = info.current_load;
+        view.last_seen = now;
+        view.heartbeats += 1;
+    }
+
+    fn record_routing(&mut self, response: &RoutingResponse) {
+        match response.status {
+            RoutingStatus::Accepted => {
+                self.routing_accepted += 1;
+                if let Some(view) = self.nodes.get_mut(&response.node_id) {
+                    view.routed_clients += 1;
+                }
+            }
+            RoutingStatus::Rejected => self.routing_rejected += 1,
+            RoutingStatus::Pending => {}
+        }
+    }
+
+    /// Renders the dashboard as printable text
+    pub fn render(&self, now: u64) -> String {
+        let mut lines = vec![format!(
+            "=== Pool Monitor === nodes: {} | processed: {} | routed: {} accepted, {} rejected",
+            self.nodes.len(),
+            self.processed_packets,
+            self.routing_accepted,
+            self.routing_rejected
+        )];
+
+        let mut node_ids: Vec<&String> = self.nodes.keys().collect();
+        node_ids.sort();
+        for node_id in node_ids {
+            let view = &self.nodes[node_id];
+            let age = now.saturating_sub(view.last_seen);
+            lines.push(format!(
+                "{} [{}] {:?} load {}/{} clients {} heartbeats {} last seen {}s ago{}",
+                node_id,
+                view.node_type,
+                view.status,
+                view.current_load,
+                view.capacity,
+                view.routed_clients,
+                view.heartbeats,
+                age,
+                if age > STALE_AFTER_SECS {
+                    " (stale)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Passive observer of the pool: only ever publishes its own heartbeat
+struct Monitor {
+    node_info: NodeInfo,
+    /// Signs the heartbeat and checks observed traffic when the pool shares
+    /// a secret
+    client: Signed<AsyncClient>,
+    /// Put in front of every topic; see `topics`
+    topic_prefix: String,
+    state: Arc<Mutex<MonitorState>>,
+}
+
+impl Monitor {
+    async fn new(mqtt_host: &str, mqtt_port: u16) -> Result<Self, BoxError> {
+        let node_info = NodeInfo::new(NodeType::Monitor, 0);
+
+        let client_id = mqtt_client_id(client_id_prefix_from_env().as_deref(), &node_info.node_id);
+        let mqtt_options = mqtt_options(client_id, mqtt_host, mqtt_port);
+        let channel_cap = mqtt_options.request_channel_capacity();
+        let (client, eventloop) = AsyncClient::new(mqtt_options, channel_cap);
+        let client = Signed::new(client, SharedSecret::from_env());
+
+        let topic_prefix = topics::prefix_from_env();
+        for topic in [
+            topics::heartbeat_master(&topic_prefix, "+"),
+            topics::heartbeat_slave(&topic_prefix, "+"),
+            topics::data_processed(&topic_prefix, "#"),
+            topics::routing_response(&topic_prefix, "+"),
+        ] {
+            client.subscribe(topic, QoS::AtMostOnce).await?;
+        }
+
+        let monitor = Monitor {
+            node_info,
+            client,
+            topic_prefix,
+            state: Arc::new(Mutex::new(MonitorState::default())),
+        };
+        monitor.start_heartbeat();
+        monitor.start_event_loop(eventloop);
+
+        Ok(monitor)
+    }
+
+    fn start_heartbeat(&self) {
+        let node_info = self.node_info.clone();
+        let client = self.client.clone();
+        let topic_prefix = self.topic_prefix.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let mut heartbeat = node_info.clone();
+                heartbeat.last_heartbeat = now_secs();
+
+                if let Ok(payload) = serde_json::to_string(&heartbeat) {
+                    if let Err(e) = client
+                        .publish(
+                            topics::heartbeat(
+                                &topic_prefix,
+                                &NodeType::Monitor,
+                                &heartbeat.node_id,
+                            ),
+                            QoS::AtLeastOnce,
+                            false,
+                            payload,
+                        )
+                        .await
+                    {
+                        eprintln!("Error publishing heartbeat: {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    fn start_event_loop(&self, mut eventloop: EventLoop) {
+        let state = Arc::clone(&self.state);
+        let topic_prefix = self.topic_prefix.clone();
+        let client = self.client.clone();
+
+        tokio::spawn(async move {
+            let mut backoff = Backoff::default();
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        backoff.reset();
+                        let Some(topic) = topics::strip(&topic_prefix, &publish.topic) else {
+                            continue;
+                        };
+                        match client.open(&publish.topic, &publish.payload) {
+                            Some(payload) => state.lock().await.observe(topic, payload, now_secs()),
+                            None => warn!(
+                                "Dropping message on {} with a missing or invalid signature",
+                                topic
+                            ),
+                        }
+                    }
+                    Ok(_) => backoff.reset(),
+                    Err(e) => {
+                        let delay = backoff.next();
+                        eprintln!("Event loop error: {:?}; retrying in {:?}", e, delay);
+                        time::sleep(delay).await;
+                    }
+                }
+            }
+        });
+    }
+
+    fn start_dashboard(&self, every: Duration) {
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(every);
+            loop {
+                interval.tick().await;
+                println!("{}", state.lock().await.render(now_secs()));
+            }
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BoxError> {
+    env_logger::Builder::from_default_env()
+        .format_timestamp_millis()
+        .filter_level(LevelFilter::Info)
+        .init();
+    info!("Starting MQTT Monitor...");
+
+    let mqtt_host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+    let mqtt_port = std::env::var("MQTT_PORT")
+        .unwrap_or_else(|_| "1883".to_string())
+        .parse()
+        .unwrap_or(1883);
+    // `time::interval` panics on zero, so 0 is raised to 1
+    let dashboard_interval =
+        config::bounded(&config::env_var, "DASHBOARD_INTERVAL", None, 1..=u64::MAX)?.unwrap_or(5);
+
+    let monitor = Monitor::new(&mqtt_host, mqtt_port).await?;
+    monitor.start_dashboard(Duration::from_secs(dashboard_interval));
+    info!(
+        "Monitor initialized successfully with ID: {}",
+        monitor.node_info.node_id
+    );
+
+    match signal::ctrl_c().await {
+        Ok(()) => info!("Received shutdown signal"),
+        Err(err) => error!("Failed to listen for shutdown signal: {}", err),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_payload_is_aggregated() {
+        let payload = r#"{
+            "node_id": "node-1",
+            "node_type": "Node",
+            "last_heartbeat": 1700000000,
+            "status": "Active",
+            "capacity": 100,
+            "current_load": 42,
+            "version": "0.1.0",
+            "metadata": {}
+        }"#;
+
+        let mut state = MonitorState::default();
+        state.observe("heartbeat/master/node-1", payload.as_bytes(), 1000);
+        state.observe("heartbeat/master/node-1", payload.as_bytes(), 1005);
+
+        let view = &state.nodes["node-1"];
+        assert_eq!(view.node_type, NodeType::Node);
+        assert_eq!(view.status, NodeStatus::Active);
+        assert_eq!(view.current_load, 42);
+        assert_eq!(view.capacity, 100);
+        assert_eq!(view.heartbeats, 2);
+        assert_eq!(view.last_seen, 1005);
+
+        assert!(state
+            .render(1005)
+            .contains("node-1 [Node] Active load 42/100"));
+        assert!(state.render(1100).contains("(stale)"));
+    }
+}
@@ -0,0 +1,379 @@
+//! Payloads the node hands out for data requests. The node asks its
+//! generator for one payload per requested type, so load tests can swap the
+//! fixed samples for randomized or reproducible ones.
+
+use mqtt_common::config::{self, NodeConfig};
+use mqtt_common::{DataPayload, PoolError, SensorUnits};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Produces the payloads of generated data packets
+pub trait DataGenerator: Send {
+    /// The next payload of `data_type`, or `None` when this generator
+    /// doesn't produce that type
+    fn next(&mut self, data_type: &str) -> Option<DataPayload>;
+}
+
+/// The same sample payload for every request
+pub struct FixedGenerator;
+
+impl DataGenerator for FixedGenerator {
+    fn next(&mut self, data_type: &str) -> Option<DataPayload> {
+        Some(match data_type {
+            "sensor" => DataPayload::SensorData {
+                sensor_id: "temp-1".to_string(),
+                temperature: 23.5,
+                humidity: 45.0,
+                pressure: 1013.2,
+                units: Some(SensorUnits::default()),
+            },
+            "text" => DataPayload::Text("Sample text data".to_string()),
+            "number" => DataPayload::Number(42.5),
+            "coordinates" => DataPayload::Coordinates {
+                x: 10.0,
+                y: 20.0,
+                z: 30.0,
+                frame: None,
+            },
+            "image" => DataPayload::ImageData {
+                width: 640,
+                height: 480,
+                format: "jpeg".to_string(),
+                data: vec![0; 100], // Sample image data
+            },
+            "audio" => DataPayload::AudioData {
+                sample_rate: 16_000,
+                channels: 1,
+                codec: "pcm_s16le".to_string(),
+                data: vec![0; 3200], // 100ms of silence
+            },
+            "log" => DataPayload::LogEntry {
+                level: "INFO".to_string(),
+                message: "Sample log entry".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Bounds of the randomized numeric values
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueRanges {
+    pub number: Range<f64>,
+    pub temperature: Range<f64>,
+    pub humidity: Range<f64>,
+    pub pressure: Range<f64>,
+    pub coordinate: Range<f64>,
+}
+
+impl Default for ValueRanges {
+    fn default() -> Self {
+        ValueRanges {
+            number: 0.0..100.0,
+            temperature: -20.0..40.0,
+            humidity: 0.0..100.0,
+            pressure: 950.0..1050.0,
+            coordinate: -100.0..100.0,
+        }
+    }
+}
+
+impl ValueRanges {
+    /// The defaults with each `(name, range)` entry applied
+    fn with(entries: Vec<(String, Range<f64>)>) -> Self {
+        let mut ranges = ValueRanges::default();
+        for (name, range) in entries {
+            ranges.set(&name, range);
+        }
+        ranges
+    }
+
+    /// Replaces the range of the value called `name`, if there is one
+    fn set(&mut self, name: &str, range: Range<f64>) -> bool {
+        let slot = match name {
+            "number" => &mut self.number,
+            "temperature" => &mut self.temperature,
+            "humidity" => &mut self.humidity,
+            "pressure" => &mut self.pressure,
+            "coordinate" => &mut self.coordinate,
+            _ => return false,
+        };
+        *slot = range;
+        true
+    }
+}
+
+/// Splits `name:start..end` into its name and a non-empty range that
+/// `gen_range` can sample, i.e. with finite bounds and a finite width
+fn parse_range(entry: &str) -> Option<(&str, Range<f64>)> {
+    let (name, range) = entry.split_once(':')?;
+    let (start, end) = range.split_once("..")?;
+    let range: Range<f64> = start.trim().parse().ok()?..end.trim().parse().ok()?;
+    let usable = !range.is_empty() && (range.end - range.start).is_finite();
+    usable.then_some((name.trim(), range))
+}
+
+/// Parses one `name:min..max` entry for a value the generator has, e.g.
+/// `number:0..10`
+fn parse_known_range(entry: &str) -> Option<(String, Range<f64>)> {
+    let (name, range) = parse_range(entry)?;
+    ValueRanges::default()
+        .set(name, range.clone())
+        .then(|| (name.to_string(), range))
+}
+
+/// Parses one `type:weight` entry, e.g. `sensor:3`; a weight of 0 excludes
+/// the type
+fn parse_weight(entry: &str) -> Option<(String, u32)> {
+    let (data_type, weight) = entry.split_once(':')?;
+    Some((data_type.trim().to_string(), weight.trim().parse().ok()?))
+}
+
+/// Random values within `ValueRanges`. Each requested type is produced with
+/// a probability of its weight over the largest weight, so a type weighted
+/// 0 is never produced; types without a weight count as 1.
+pub struct WeightedRandomGenerator {
+    weights: HashMap<String, u32>,
+    ranges: ValueRanges,
+    rng: StdRng,
+}
+
+impl WeightedRandomGenerator {
+    pub fn new(weights: HashMap<String, u32>, ranges: ValueRanges) -> Self {
+        Self::with_rng(weights, ranges, StdRng::from_entropy())
+    }
+
+    fn with_rng(weights: HashMap<String, u32>, ranges: ValueRanges, rng: StdRng) -> Self {
+        WeightedRandomGenerator {
+            weights,
+            ranges,
+            rng,
+        }
+    }
+
+    fn included(&mut self, data_type: &str) -> bool {
+        let weight = self.weights.get(data_type).copied().unwrap_or(1);
+        let heaviest = self.weights.values().copied().max().unwrap_or(1).max(1);
+        weight > 0 && self.rng.gen_ratio(weight.min(heaviest), heaviest)
+    }
+}
+
+impl DataGenerator for WeightedRandomGenerator {
+    fn next(&mut self, data_type: &str) -> Option<DataPayload> {
+        if !self.included(data_type) {
+            return None;
+        }
+        let ranges = &self.ranges;
+        let rng = &mut self.rng;
+        Some(match data_type {
+            "sensor" => DataPayload::SensorData {
+                sensor_id: format!("temp-{}", rng.gen_range(1..=8)),
+                temperature: rng.gen_range(ranges.temperature.clone()),
+                humidity: rng.gen_range(ranges.humidity.clone()),
+                pressure: rng.gen_range(ranges.pressure.clone()),
+                units: Some(SensorUnits::default()),
+            },
+            "text" => DataPayload::Text(format!("Sample text data {}", rng.gen::<u32>())),
+            "number" => DataPayload::Number(rng.gen_range(ranges.number.clone())),
+            "coordinates" => DataPayload::Coordinates {
+                x: rng.gen_range(ranges.coordinate.clone()),
+                y: rng.gen_range(ranges.coordinate.clone()),
+                z: rng.gen_range(ranges.coordinate.clone()),
+                frame: None,
+            },
+            "image" => {
+                let (width, height) = [(320, 240), (640, 480), (1280, 720)][rng.gen_range(0..3)];
+                DataPayload::ImageData {
+                    width,
+                    height,
+                    format: "jpeg".to_string(),
+                    data: vec![0; rng.gen_range(100..1000)],
+                }
+            }
+            "audio" => DataPayload::AudioData {
+                sample_rate: 16_000,
+                channels: 1,
+                codec: "pcm_s16le".to_string(),
+                // 50-500ms of silence
+                data: vec![0; 2 * rng.gen_range(800..8000)],
+            },
+            "log" => DataPayload::LogEntry {
+                level: ["DEBUG", "INFO", "WARN", "ERROR"][rng.gen_range(0..4)].to_string(),
+                message: format!("Sample log entry {}", rng.gen::<u32>()),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// A `WeightedRandomGenerator` drawing from a fixed seed, so a load test
+/// gets the same sequence of payloads on every run
+pub struct SeededGenerator(WeightedRandomGenerator);
+
+impl SeededGenerator {
+    pub fn new(seed: u64, weights: HashMap<String, u32>, ranges: ValueRanges) -> Self {
+        SeededGenerator(WeightedRandomGenerator::with_rng(
+            weights,
+            ranges,
+            StdRng::seed_from_u64(seed),
+        ))
+    }
+}
+
+impl DataGenerator for SeededGenerator {
+    fn next(&mut self, data_type: &str) -> Option<DataPayload> {
+        self.0.next(data_type)
+    }
+}
+
+/// Which generator the node uses, from `GENERATOR_WEIGHTS`,
+/// `GENERATOR_RANGES` and `GENERATOR_SEED`. Fixed samples unless one of them
+/// is set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeneratorConfig {
+    pub weights: HashMap<String, u32>,
+    pub ranges: Option<ValueRanges>,
+    pub seed: Option<u64>,
+}
+
+impl GeneratorConfig {
+    /// Reads the generator settings from `var`, else the `[node]` table of
+    /// the config file, whose entries must all be valid
+    pub fn load(
+        file: &NodeConfig,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, PoolError> {
+        let weights = config::list(
+            &var,
+            "GENERATOR_WEIGHTS",
+            file.generator_weights.as_deref(),
+            parse_weight,
+        )?;
+        let ranges = config::list(
+            &var,
+            "GENERATOR_RANGES",
+            file.generator_ranges.as_deref(),
+            parse_known_range,
+        )?;
+        Ok(GeneratorConfig {
+            weights: weights.unwrap_or_default().into_iter().collect(),
+            ranges: ranges.map(ValueRanges::with),
+            seed: config::setting(&var, "GENERATOR_SEED", file.generator_seed),
+        })
+    }
+
+    pub fn build(&self) -> Box<dyn DataGenerator> {
+        let ranges = self.ranges.clone().unwrap_or_default();
+        match self.seed {
+            Some(seed) => Box::new(SeededGenerator::new(seed, self.weights.clone(), ranges)),
+            None if self.weights.is_empty() && self.ranges.is_none() => Box::new(FixedGenerator),
+            None => Box::new(WeightedRandomGenerator::new(self.weights.clone(), ranges)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [&str; 4] = ["sensor", "number", "coordinates", "text"];
+
+    fn sequence(generator: &mut dyn DataGenerator) -> Vec<Option<String>> {
+        (0..20)
+            .flat_map(|_| TYPES)
+            .map(|data_type| {
+                let payload = generator.next(data_type)?;
+                serde_json::to_string(&payload).ok()
+            })
+            .collect()
+    }
+
+    fn from_env(vars: &[(&str, &str)]) -> GeneratorConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        GeneratorConfig::load(&NodeConfig::default(), |key| vars.get(key).cloned()).unwrap()
+    }
+
+    #[test]
+    fn test_seeded_generator_repeats_its_sequence() {
+        let config = from_env(&[
+            ("GENERATOR_WEIGHTS", "sensor:2,number:1"),
+            ("GENERATOR_RANGES", "number:5..6"),
+        ]);
+        let (weights, ranges) = (config.weights, config.ranges.unwrap());
+        let first = sequence(&mut SeededGenerator::new(
+            7,
+            weights.clone(),
+            ranges.clone(),
+        ));
+        let second = sequence(&mut SeededGenerator::new(
+            7,
+            weights.clone(),
+            ranges.clone(),
+        ));
+        assert_eq!(first, second);
+        assert_ne!(
+            first,
+            sequence(&mut SeededGenerator::new(8, weights, ranges.clone()))
+        );
+
+        let mut generator = SeededGenerator::new(7, HashMap::new(), ranges);
+        for _ in 0..20 {
+            match generator.next("number") {
+                Some(DataPayload::Number(value)) => assert!((5.0..6.0).contains(&value)),
+                other => panic!("expected a number, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_finite_ranges_are_ignored() {
+        for spec in [
+            "number:NaN..1",
+            "number:0..NaN",
+            "number:0..inf",
+            "number:-inf..0",
+            "number:-1e308..1e308",
+        ] {
+            let config = from_env(&[("GENERATOR_RANGES", spec)]);
+            assert_eq!(config.ranges, Some(ValueRanges::default()), "{}", spec);
+
+            let file = NodeConfig {
+                generator_ranges: Some(vec![spec.to_string()]),
+                ..NodeConfig::default()
+            };
+            assert!(GeneratorConfig::load(&file, |_| None).is_err(), "{}", spec);
+        }
+
+        let mut generator = WeightedRandomGenerator::new(
+            HashMap::new(),
+            from_env(&[("GENERATOR_RANGES", "number:-1e308..1e308")])
+                .ranges
+                .unwrap(),
+        );
+        assert!(matches!(
+            generator.next("number"),
+            Some(DataPayload::Number(_))
+        ));
+    }
+
+    #[test]
+    fn test_zero_weight_excludes_a_type() {
+        let config = from_env(&[("GENERATOR_WEIGHTS", "text:0,number:1")]);
+        let mut generator = WeightedRandomGenerator::new(config.weights, ValueRanges::default());
+        for _ in 0..100 {
+            assert!(generator.next("text").is_none());
+            assert!(matches!(
+                generator.next("number"),
+                Some(DataPayload::Number(_))
+            ));
+        }
+        assert!(generator.next("unknown").is_none());
+    }
+}
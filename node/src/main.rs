@@ -1,580 +1,4307 @@
-use log::{error, info, warn, LevelFilter};
+mod generator;
+mod handlers;
+
+use generator::{DataGenerator, GeneratorConfig};
+use handlers::{HandlerRegistry, PayloadHandler};
+use mqtt_common::config::{self, ConfigFile};
+use mqtt_common::integrity::{SharedSecret, Signed};
+use mqtt_common::shutdown;
 use mqtt_common::{
-    DataPacket, DataPayload, DataRequest, NodeInfo, NodeStatus, NodeType, RoutingRequest,
-    RoutingResponse, RoutingStatus, ClientConfiguration,
+    Backoff, DataPacket, DataPayload, DataRequest, DataResponse, Fulfillment,
+    FulfillmentSummary, LogEntry, MetadataLimits, NodeInfo, NodeStatus, NodeType, OversizePolicy, ProbeAck,
+    ProbeRequest, ProcessingStatus, RoutingRequest, RoutingResponse, RoutingStatus,
+    ClientConfiguration, SkipReason, SkippedType, WireFormat, WIRE_FORMATS,
+    MAX_COMPRESSION_LEVEL, WIRE_FORMATS_METADATA_KEY, PROCESSING_TIMEOUT_METADATA_KEY, compress_frame, requested_compression,
+    mqtt_client_id, decode_or_log, DATA_TYPE_CATALOG,
+    RoutingIssuer, PROTOCOL_VERSION, DedupWindow, DEFAULT_DEDUP_MEMORY_BUDGET_MB,
+    MqttTransport, ROUTED_CLIENTS_METADATA_KEY, FEATURE_COMPRESSION, NODE_FEATURE_CATALOG, MaintenanceControl, CapacityControl, DataBatch,
+    topics, HeartbeatMessage, QosPolicy, FaultInjector, set_offline_will, PoolError, health, PayloadLimit, MqttSettings,
+};
+use rand::Rng;
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, Outgoing, Packet, QoS, SubscribeReasonCode,
 };
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
-use std::collections::HashMap;
-use std::error::Error;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::signal;
+use tokio::sync::{mpsc, Mutex, Notify, RwLock, Semaphore};
 use tokio::time;
+use tracing::{debug, error, info, instrument, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-type DynError = Box<dyn Error + Send + Sync>;
+/// Upper bound for the delay between startup connection attempts
+const MAX_STARTUP_BACKOFF: Duration = Duration::from_secs(10);
 
-pub struct Node {
-    node_info: NodeInfo,
-    client: AsyncClient,
-    current_load: Arc<AtomicU32>,
-}
+/// Data types `generate_packets` knows how to produce
+const GENERATED_TYPES: [&str; 7] = DATA_TYPE_CATALOG;
 
-impl Node {
-    pub async fn new(capacity: u32, mqtt_host: &str, mqtt_port: u16) -> Result<Self, DynError> {
-        let node_info = NodeInfo::new(NodeType::Node, capacity);
-        let node_id = node_info.node_id.clone();
+/// A `SUPPORTED_TYPES` entry (e.g. `text`), if this node can produce it
+fn generated_type(entry: &str) -> Option<String> {
+    GENERATED_TYPES.contains(&entry).then(|| entry.to_string())
+}
 
-        let mut mqtt_options = MqttOptions::new(node_id.clone(), mqtt_host, mqtt_port);
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
+/// A `NODE_FEATURES` entry (e.g. `compression`), if this node implements it
+fn node_feature(entry: &str) -> Option<String> {
+    NODE_FEATURE_CATALOG
+        .contains(&entry)
+        .then(|| entry.to_string())
+}
 
-        let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
+/// How often a draining node checks whether its load has reached zero
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
 
-        // Subscribe to all relevant topics
-        client.subscribe("data/request/#", QoS::AtLeastOnce).await?;
-        client
-            .subscribe("routing/request/#", QoS::AtLeastOnce)
-            .await?;
-        client
-            .subscribe("data/incoming/#", QoS::AtLeastOnce)
-            .await?;
+/// Routed clients silent on `heartbeat/slave/{id}` for this long lose their slot
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
 
-        let node = Node {
-            node_info,
-            client: client.clone(),
-            current_load: Arc::new(AtomicU32::new(0)),
-        };
+/// Non-blocking attempts for a data publish while the outgoing queue is full
+const FULL_QUEUE_RETRIES: u32 = 5;
 
-        // Start heartbeat sender
-        node.start_heartbeat().await;
+/// Initial delay between those attempts, doubled after each one
+const FULL_QUEUE_BACKOFF: Duration = Duration::from_millis(50);
 
-        // Start event loop handler
-        node.start_event_loop(eventloop).await;
+/// Why a publish couldn't be handed to the event loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PublishErrorKind {
+    /// The outgoing queue is at its limit because the broker isn't taking
+    /// messages as fast as we produce them; worth retrying after a pause
+    BrokerFull,
+    /// The event loop is gone, so retrying can't help
+    Disconnected,
+}
 
-        Ok(node)
+/// Tells a full outgoing queue apart from a dead connection. A failed
+/// `try_publish` can't say which of the two it was, so it counts as full
+/// and only a blocking `publish` reports a closed event loop.
+pub fn classify_publish_error(e: &ClientError) -> PublishErrorKind {
+    match e {
+        ClientError::TryRequest(_) => PublishErrorKind::BrokerFull,
+        ClientError::Request(_) => PublishErrorKind::Disconnected,
     }
+}
 
-    async fn start_heartbeat(&self) {
-        let node_info_clone = self.node_info.clone();
-        let client_clone = self.client.clone();
-        let current_load = self.current_load.clone();
-
-        tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(5));
-            loop {
-                interval.tick().await;
-                let mut heartbeat = node_info_clone.clone();
-                heartbeat.last_heartbeat = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs();
-                heartbeat.current_load = current_load.load(Ordering::Relaxed);
-
-                if let Ok(payload) = serde_json::to_string(&heartbeat) {
-                    let topic = format!("heartbeat/master/{}", heartbeat.node_id);
-                    if let Err(e) = client_clone
-                        .publish(&topic, QoS::AtLeastOnce, false, payload)
-                        .await
+/// Subscribes to `subscriptions`, polling the event loop until the broker
+/// has acknowledged every one. If the connection drops first, the event loop
+/// reconnects and they are all sent again, up to `retries` times with a
+/// doubling delay starting at `initial_backoff`. A subscription the broker
+/// refuses fails right away. Returns the publishes that arrived meanwhile.
+async fn subscribe_all(
+    client: &AsyncClient,
+    eventloop: &mut EventLoop,
+    subscriptions: &[(String, QoS)],
+    retries: u32,
+    initial_backoff: Duration,
+) -> Result<Vec<rumqttc::Publish>, PoolError> {
+    let mut delay = initial_backoff;
+    let mut attempt = 0;
+    let mut received = Vec::new();
+    'attempts: loop {
+        // A new session has none of the earlier subscriptions
+        for (topic, qos) in subscriptions {
+            client.subscribe(topic.as_str(), *qos).await?;
+        }
+        let mut unacked = subscriptions.len();
+        while unacked > 0 {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::SubAck(ack))) => {
+                    if ack
+                        .return_codes
+                        .iter()
+                        .any(|code| matches!(code, SubscribeReasonCode::Failure))
                     {
-                        eprintln!("Error publishing heartbeat: {:?}", e);
-                    } else {
-                        println!("Heartbeat sent on topic: {}", topic);
+                        return Err(PoolError::Other(format!(
+                            "broker refused subscription {}",
+                            ack.pkid
+                        )));
                     }
+                    unacked -= 1;
                 }
+                Ok(Event::Incoming(Packet::Publish(publish))) => received.push(publish),
+                Ok(_) => {}
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    warn!(
+                        "Connection lost while subscribing ({}), retrying in {:?} ({}/{})",
+                        e, delay, attempt, retries
+                    );
+                    time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_STARTUP_BACKOFF);
+                    continue 'attempts;
+                }
+                Err(e) => return Err(e.into()),
             }
-        });
+        }
+        return Ok(received);
     }
+}
 
-    async fn start_event_loop(&self, eventloop: EventLoop) {
-        let node_info_clone = self.node_info.clone();
-        let client_clone = self.client.clone();
-        let current_load_clone = self.current_load.clone();
+/// Polls the event loop until the broker accepts the connection, so a node
+/// started alongside its broker waits for it instead of exiting
+async fn wait_for_broker(
+    eventloop: &mut EventLoop,
+    retries: u32,
+    initial_backoff: Duration,
+) -> Result<(), PoolError> {
+    let mut delay = initial_backoff;
+    let mut attempt = 0;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Broker not ready ({}), retrying in {:?} ({}/{})",
+                    e, delay, attempt, retries
+                );
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_STARTUP_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            let mut eventloop = eventloop;
+/// How long a suppressed value may go unsent before it is re-sent anyway
+const CHANGE_KEEPALIVE: Duration = Duration::from_secs(60);
 
-            loop {
-                match eventloop.poll().await {
-                    Ok(event) => {
-                        if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) = event {
-                            println!("Received message on topic: {}", publish.topic);
-
-                            match publish.topic.as_str() {
-                                topic if topic.starts_with("routing/request") => {
-                                    if let Ok(request) =
-                                        serde_json::from_slice::<RoutingRequest>(&publish.payload)
-                                    {
-                                        println!(
-                                            "Processing routing request from slave: {}",
-                                            request.client_id
-                                        );
-                                        Node::handle_routing_request(
-                                            &request,
-                                            &node_info_clone,
-                                            &client_clone,
-                                            &current_load_clone,
-                                        )
-                                        .await;
-                                    }
-                                }
-                                topic if topic.starts_with("data/request") => {
-                                    if let Ok(request) =
-                                        serde_json::from_slice::<DataRequest>(&publish.payload)
-                                    {
-                                        println!("Processing data request: {}", request.request_id);
-                                        Node::handle_data_request(
-                                            &request,
-                                            &node_info_clone,
-                                            &client_clone,
-                                        )
-                                        .await;
-                                    }
-                                }
-                                topic if topic.starts_with("data/incoming") => {
-                                    if let Ok(packet) =
-                                        serde_json::from_slice::<DataPacket>(&publish.payload)
-                                    {
-                                        println!("Processing incoming data packet: {}", packet.id);
-                                        Node::handle_data_packet(
-                                            &packet,
-                                            &node_info_clone,
-                                            &client_clone,
-                                            &current_load_clone,
-                                        )
-                                        .await;
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Event loop error: {:?}", e);
-                        time::sleep(Duration::from_secs(5)).await;
-                    }
-                }
+/// Tracks the last numeric values sent per client and data type
+pub struct ChangeTracker {
+    last_sent: HashMap<(String, String), (Vec<f64>, Instant)>,
+    keepalive: Duration,
+}
+
+impl ChangeTracker {
+    pub fn new(keepalive: Duration) -> Self {
+        ChangeTracker {
+            last_sent: HashMap::new(),
+            keepalive,
+        }
+    }
+
+    /// Returns true (and records the values) if any value moved by more than
+    /// `threshold` since the last send, or the keepalive has elapsed
+    pub fn should_send(
+        &mut self,
+        client_id: &str,
+        data_type: &str,
+        values: &[f64],
+        threshold: f64,
+        now: Instant,
+    ) -> bool {
+        let key = (client_id.to_string(), data_type.to_string());
+        let send = match self.last_sent.get(&key) {
+            Some((last, sent_at)) => {
+                now.duration_since(*sent_at) >= self.keepalive
+                    || last.len() != values.len()
+                    || last
+                        .iter()
+                        .zip(values)
+                        .any(|(prev, value)| (prev - value).abs() > threshold)
             }
-        });
+            None => true,
+        };
+
+        if send {
+            self.last_sent.insert(key, (values.to_vec(), now));
+        }
+        send
     }
+}
 
-    async fn handle_routing_request(
-        request: &RoutingRequest,
-        node_info: &NodeInfo,
-        client: &AsyncClient,
-        current_load: &Arc<AtomicU32>,
-    ) {
-        let current_load_val = current_load.load(Ordering::Relaxed);
+/// How long responses are kept for requests carrying an idempotency key
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
 
-        let (status, rejection_reason) = if current_load_val >= node_info.capacity {
-            (
-                RoutingStatus::Rejected,
-                Some("Capacity limit reached".to_string()),
-            )
-        } else if request.preferred_node.is_some()
-            && request.preferred_node.as_ref() != Some(&node_info.node_id)
-        {
-            (
-                RoutingStatus::Rejected,
-                Some("Not preferred master".to_string()),
-            )
-        } else {
-            (RoutingStatus::Accepted, None)
-        };
+/// Packets for a data request, plus a summary when not every type was served
+#[derive(Debug, Clone, Default)]
+pub struct PreparedResponse {
+    packets: Vec<DataPacket>,
+    summary: Option<FulfillmentSummary>,
+}
 
-        let response = RoutingResponse {
-            node_id: node_info.node_id.clone(),
-            client_id: request.client_id.clone(),
-            status,
-            rejection_reason,
-            configuration: if status == RoutingStatus::Accepted {
-                Some(ClientConfiguration {
-                    subscribe_topics: vec![
-                        format!("data/response/{}/{}", node_info.node_id, request.client_id),
-                        "data/broadcast/#".to_string(),
-                    ],
-                    publish_topic: format!(
-                        "data/request/{}/{}",
-                        node_info.node_id, request.client_id
-                    ),
-                    qos: 1,
-                    max_batch_size: 100,
-                    processing_timeout_ms: 5000,
-                })
-            } else {
-                None
-            },
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        };
+/// Responses generated for idempotent data requests, keyed by client and key
+pub struct ResponseCache {
+    entries: HashMap<String, (PreparedResponse, Instant)>,
+    ttl: Duration,
+}
 
-        if let Ok(response_payload) = serde_json::to_string(&response) {
-            let topic = format!("routing/response/{}", request.client_id);
-            if let Err(e) = client
-                .publish(&topic, QoS::AtLeastOnce, false, response_payload)
-                .await
-            {
-                eprintln!("Error publishing routing response: {:?}", e);
-            } else {
-                println!("Routing response sent on topic: {}", topic);
-            }
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            entries: HashMap::new(),
+            ttl,
         }
     }
 
-    async fn handle_data_request(
-        request: &DataRequest,
-        node_info: &NodeInfo,
-        client: &AsyncClient,
-    ) {
-        println!("Processing data request from slave {}", request.client_id);
+    pub fn get(&self, key: &str, now: Instant) -> Option<PreparedResponse> {
+        self.entries
+            .get(key)
+            .filter(|(_, cached_at)| now.duration_since(*cached_at) < self.ttl)
+            .map(|(response, _)| response.clone())
+    }
 
-        // Generate sample data packets with expanded types
-        let data_packets = request
-            .data_types
-            .iter()
-            .filter_map(|data_type| {
-                let packet = match data_type.as_str() {
-                    "sensor" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("source".to_string(), "sensor-1".to_string());
-
-                        Some(DataPacket {
-                            data_type: data_type.clone(),
-                            metadata,
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            payload: DataPayload::SensorData {
-                                sensor_id: "temp-1".to_string(),
-                                temperature: 23.5,
-                                humidity: 45.0,
-                                pressure: 1013.2,
-                            },
-                        })
-                    }
-                    "text" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("type".to_string(), "text".to_string());
-
-                        Some(DataPacket {
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            data_type: data_type.clone(),
-                            payload: DataPayload::Text(format!(
-                                "Sample text data for request {}",
-                                request.request_id
-                            )),
-                            metadata,
-                        })
-                    }
-                    "number" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("type".to_string(), "number".to_string());
-
-                        Some(DataPacket {
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            data_type: data_type.clone(),
-                            payload: DataPayload::Number(42.5),
-                            metadata,
-                        })
-                    }
-                    "coordinates" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("type".to_string(), "coordinates".to_string());
-
-                        Some(DataPacket {
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            data_type: data_type.clone(),
-                            payload: DataPayload::Coordinates {
-                                x: 10.0,
-                                y: 20.0,
-                                z: 30.0,
-                            },
-                            metadata,
-                        })
-                    }
-                    "image" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("type".to_string(), "image".to_string());
-
-                        Some(DataPacket {
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            data_type: data_type.clone(),
-                            payload: DataPayload::ImageData {
-                                width: 640,
-                                height: 480,
-                                format: "jpeg".to_string(),
-                                data: vec![0; 100], // Sample image data
-                            },
-                            metadata,
-                        })
-                    }
-                    "log" => {
-                        let mut metadata = HashMap::new();
-                        metadata.insert("type".to_string(), "log".to_string());
-
-                        Some(DataPacket {
-                            id: Uuid::new_v4().to_string(),
-                            timestamp: SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs()
-                                .to_string(),
-                            data_type: data_type.clone(),
-                            payload: DataPayload::LogEntry {
-                                level: "INFO".to_string(),
-                                message: "Sample log entry".to_string(),
-                                timestamp: chrono::Utc::now().to_rfc3339(),
-                            },
-                            metadata,
-                        })
-                    }
-                    _ => None,
-                };
-                packet
-            })
-            .collect::<Vec<_>>();
+    pub fn insert(&mut self, key: String, response: PreparedResponse, now: Instant) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (_, cached_at)| now.duration_since(*cached_at) < ttl);
+        self.entries.insert(key, (response, now));
+    }
+}
 
-        // Send data packets
-        let response_topic = format!("data/response/{}/{}", node_info.node_id, request.client_id);
+/// Window over which per-type quotas are counted
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
 
-        for packet in data_packets {
-            if let Ok(payload) = serde_json::to_string(&packet) {
-                if let Err(e) = client
-                    .publish(&response_topic, QoS::AtLeastOnce, false, payload)
-                    .await
-                {
-                    eprintln!("Error publishing data response: {:?}", e);
-                } else {
-                    println!("Data packet sent on topic: {}", response_topic);
-                }
-            }
+/// Metadata key prefix clients use to request per-type quotas, e.g. `quota.image = 5`
+const QUOTA_METADATA_PREFIX: &str = "quota.";
+
+/// Parses one `TYPE_QUOTAS` entry like `image:5` into a per-type limit
+fn parse_type_quota(entry: &str) -> Option<(String, u32)> {
+    let (data_type, limit) = entry.split_once(':')?;
+    Some((data_type.trim().to_string(), limit.trim().parse().ok()?))
+}
+
+/// Per-client, per-data-type packet quotas over a fixed window. Types without
+/// a limit are unlimited.
+pub struct TypeQuotas {
+    defaults: HashMap<String, u32>,
+    client_limits: HashMap<String, HashMap<String, u32>>,
+    usage: HashMap<(String, String), (Instant, u32)>,
+    window: Duration,
+}
+
+impl TypeQuotas {
+    pub fn new(defaults: HashMap<String, u32>, window: Duration) -> Self {
+        TypeQuotas {
+            defaults,
+            client_limits: HashMap::new(),
+            usage: HashMap::new(),
+            window,
         }
     }
 
-    async fn handle_data_packet(
-        packet: &DataPacket,
-        node_info: &NodeInfo,
-        client: &AsyncClient,
-        current_load: &Arc<AtomicU32>,
-    ) {
-        current_load.fetch_add(1, Ordering::Relaxed);
+    /// Sets limits for one client. They can only tighten the defaults: a
+    /// limit above the default for its type leaves the default in force.
+    pub fn set_client_limits(&mut self, client_id: &str, limits: HashMap<String, u32>) {
+        if limits.is_empty() {
+            self.client_limits.remove(client_id);
+        } else {
+            self.client_limits.insert(client_id.to_string(), limits);
+        }
+    }
 
-        // Process the data packet based on type
-        match &packet.payload {
-            DataPayload::Text(text) => {
-                println!("Processing text data: {}", text);
-            }
-            DataPayload::Number(num) => {
-                println!("Processing number data: {}", num);
-            }
-            DataPayload::Coordinates { x, y, z } => {
-                println!("Processing coordinates: x={}, y={}, z={}", x, y, z);
-            }
-            DataPayload::SensorData {
-                sensor_id,
-                temperature,
-                humidity,
-                pressure,
-            } => {
-                println!(
-                    "Processing sensor data - Sensor: {}, Temp: {}°C, Humidity: {}%, Pressure: {}hPa",
-                    sensor_id, temperature, humidity, pressure
-                );
-            }
-            DataPayload::ImageData {
-                width,
-                height,
-                format,
-                data,
-            } => {
-                println!(
-                    "Processing image data: {}x{} {}, {} bytes",
-                    width,
-                    height,
-                    format,
-                    data.len()
-                );
-            }
-            DataPayload::LogEntry {
-                level,
-                message,
-                timestamp,
-            } => {
-                println!(
-                    "Processing log entry: [{}] {} at {}",
-                    level, message, timestamp
-                );
+    fn limit_for(&self, client_id: &str, data_type: &str) -> Option<u32> {
+        let requested = self
+            .client_limits
+            .get(client_id)
+            .and_then(|limits| limits.get(data_type))
+            .copied();
+        let default = self.defaults.get(data_type).copied();
+        match (requested, default) {
+            (Some(requested), Some(default)) => Some(requested.min(default)),
+            (requested, default) => requested.or(default),
+        }
+    }
+
+    /// Whether one more packet would fit in the client's quota, without counting it
+    pub fn has_room(&self, client_id: &str, data_type: &str, now: Instant) -> bool {
+        let Some(limit) = self.limit_for(client_id, data_type) else {
+            return true;
+        };
+        match self
+            .usage
+            .get(&(client_id.to_string(), data_type.to_string()))
+        {
+            Some((window_start, used)) => {
+                now.duration_since(*window_start) >= self.window || *used < limit
             }
+            None => limit > 0,
         }
+    }
 
-        // Simulate processing time based on data type
-        let processing_time = match &packet.payload {
-            DataPayload::Text(_) => 100,
-            DataPayload::Number(_) => 50,
-            DataPayload::Coordinates { .. } => 150,
-            DataPayload::SensorData { .. } => 200,
-            DataPayload::ImageData { .. } => 500,
-            DataPayload::LogEntry { .. } => 75,
+    /// Counts one packet against the client's quota, returning false if over it
+    pub fn try_consume(&mut self, client_id: &str, data_type: &str, now: Instant) -> bool {
+        let Some(limit) = self.limit_for(client_id, data_type) else {
+            return true;
         };
 
-        time::sleep(Duration::from_millis(processing_time)).await;
+        let window = self.window;
+        let (window_start, used) = self
+            .usage
+            .entry((client_id.to_string(), data_type.to_string()))
+            .or_insert((now, 0));
+        if now.duration_since(*window_start) >= window {
+            *window_start = now;
+            *used = 0;
+        }
 
-        // Send processed notification
-        let processed_topic = format!("data/processed/{}", packet.id);
-        if let Ok(payload) = serde_json::to_string(&packet) {
-            if let Err(e) = client
-                .publish(&processed_topic, QoS::AtLeastOnce, false, payload)
-                .await
-            {
-                eprintln!("Error publishing processed data: {:?}", e);
-            } else {
-                println!("Processed data sent on topic: {}", processed_topic);
-            }
+        if *used >= limit {
+            return false;
         }
+        *used += 1;
+        true
+    }
+}
+
+/// How the node treats packets whose timestamp goes backwards within a stream
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimestampOrder {
+    /// No ordering checks
+    #[default]
+    Off,
+    /// Regressing timestamps are rejected as invalid input
+    Strict,
+    /// Regressing timestamps are logged and tagged, then processed as usual
+    Lenient,
+}
 
-        current_load.fetch_sub(1, Ordering::Relaxed);
+impl TimestampOrder {
+    /// Parses `off`, `strict` or `lenient`, in any case
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "off" => Some(TimestampOrder::Off),
+            "strict" => Some(TimestampOrder::Strict),
+            "lenient" => Some(TimestampOrder::Lenient),
+            _ => None,
+        }
     }
 }
 
-type BoxError = Box<dyn Error>;
+/// Last packet timestamp seen per (client, data type) stream
+#[derive(Default)]
+pub struct StreamClock {
+    last_seen: HashMap<(String, String), i64>,
+}
 
-#[tokio::main]
-async fn main() -> Result<(), BoxError> {
-    /* Initialize logging with timestamp */
-    env_logger::Builder::from_default_env()
-        .format_timestamp_millis()
-        .filter_level(LevelFilter::Info)
-        .init();
-    info!("Starting MQTT Node...");
+impl StreamClock {
+    /// Records `timestamp`, returning false if it is older than the last one
+    /// seen on the stream
+    pub fn observe(&mut self, client_id: &str, data_type: &str, timestamp: i64) -> bool {
+        let last = self
+            .last_seen
+            .entry((client_id.to_string(), data_type.to_string()))
+            .or_insert(timestamp);
+        if timestamp < *last {
+            false
+        } else {
+            *last = timestamp;
+            true
+        }
+    }
+}
 
-    /* Load configuration */
-    let config = NodeConfig {
-        mqtt_host: std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string()),
-        mqtt_port: std::env::var("MQTT_PORT")
-            .unwrap_or_else(|_| "1883".to_string())
-            .parse()
-            .unwrap_or(1883),
-        node_capacity: std::env::var("NODE_CAPACITY")
-            .unwrap_or_else(|_| "100".to_string())
-            .parse()
-            .unwrap_or(100),
-    };
-    info!("Using configuration: {:?}", config);
+/// Packet timestamps are unix seconds, but accept RFC 3339 as well
+fn parse_packet_timestamp(timestamp: &str) -> Option<i64> {
+    timestamp.parse().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .map(|parsed| parsed.timestamp())
+    })
+}
 
-    /* Initialize the master node with error conversion */
-    let node = Node::new(config.node_capacity, &config.mqtt_host, config.mqtt_port)
-        .await
-        .map_err(|e| -> BoxError {
-            Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            ))
-        })?;
+/// Artificial latency added before publishing responses, used to exercise
+/// client timeout and retry paths. Independent of the simulated processing time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResponseDelay {
+    base_ms: u64,
+    jitter_ms: u64,
+}
 
-    info!(
-        "Node initialized successfully with ID: {}",
-        node.node_info.node_id
-    );
+impl ResponseDelay {
+    pub fn new(base_ms: u64, jitter_ms: u64) -> Self {
+        ResponseDelay { base_ms, jitter_ms }
+    }
 
-    /* Create a future that completes when a shutdown signal is received */
-    let shutdown = async {
-        match signal::ctrl_c().await {
-            Ok(()) => {
-                info!("Received shutdown signal");
-            }
-            Err(err) => {
-                error!("Failed to listen for shutdown signal: {}", err);
-            }
+    /// Picks a delay in `[base, base + jitter]`
+    pub fn sample(&self) -> Duration {
+        let jitter = if self.jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=self.jitter_ms)
+        } else {
+            0
+        };
+        Duration::from_millis(self.base_ms + jitter)
+    }
+
+    pub async fn apply(&self) {
+        let delay = self.sample();
+        if !delay.is_zero() {
+            time::sleep(delay).await;
         }
-    };
+    }
+}
+
+/// Weight of the newest sample in the publish latency average
+const PUBLISH_LATENCY_SMOOTHING: f64 = 0.2;
 
-    /* Run the node until shutdown */
-    tokio::select! {
-        _ = shutdown => {
-            info!("Initiating shutdown sequence...");
+/// Exponentially weighted moving average of publish latency
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyEwma {
+    alpha: f64,
+    average_ms: Option<f64>,
+}
+
+impl LatencyEwma {
+    pub fn new(alpha: f64) -> Self {
+        LatencyEwma {
+            alpha,
+            average_ms: None,
         }
     }
 
-    /* Perform cleanup */
-    cleanup(&node).await; // Note: Added ? operator here
-    info!("Node shut down successfully");
-    Ok(())
+    /// Folds in one sample; the first one is taken as is
+    pub fn record(&mut self, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        self.average_ms = Some(match self.average_ms {
+            Some(average) => average + self.alpha * (sample - average),
+            None => sample,
+        });
+    }
+
+    /// The average rounded to whole milliseconds, `None` before any sample
+    pub fn average_ms(&self) -> Option<u32> {
+        self.average_ms.map(|average| average.round() as u32)
+    }
 }
 
+/// Times publishes from going out to the broker until it acknowledges them,
+/// advertised to the orchestrator as `avg_publish_latency_ms`. QoS 0
+/// publishes are never acknowledged, so they aren't timed.
 #[derive(Debug)]
-struct NodeConfig {
-    mqtt_host: String,
-    mqtt_port: u16,
-    node_capacity: u32,
+pub struct AckLatency {
+    /// When each unacknowledged publish went out, by packet id
+    in_flight: HashMap<u16, Instant>,
+    average: LatencyEwma,
 }
 
-async fn cleanup(node: &Node) {
-    info!("Starting cleanup process...");
+impl AckLatency {
+    pub fn new(alpha: f64) -> Self {
+        AckLatency {
+            in_flight: HashMap::new(),
+            average: LatencyEwma::new(alpha),
+        }
+    }
 
-    // Create final heartbeat message
-    let mut final_heartbeat = node.node_info.clone();
-    final_heartbeat.status = NodeStatus::Inactive;
+    /// Notes a publish written to the broker; packet id 0 is QoS 0
+    pub fn sent(&mut self, pkid: u16, at: Instant) {
+        if pkid != 0 {
+            self.in_flight.insert(pkid, at);
+        }
+    }
 
-    // Publish offline status
-    if let Ok(payload) = serde_json::to_string(&final_heartbeat) {
-        match node
-            .client
-            .publish(
-                format!("heartbeat/node/{}", final_heartbeat.node_id),
-                QoS::AtLeastOnce,
-                false,
-                payload,
-            )
-            .await
-        {
-            Ok(_) => info!("Published offline status successfully"),
-            Err(e) => warn!("Failed to publish offline status: {}", e),
+    /// Times the publish the broker acknowledged with `pkid`
+    pub fn acked(&mut self, pkid: u16, at: Instant) {
+        if let Some(sent) = self.in_flight.remove(&pkid) {
+            self.average.record(at.duration_since(sent));
         }
     }
 
-    // Allow time for final messages to be sent
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    info!("Cleanup completed");
+    /// Forgets publishes in flight on a new connection; any resent ones are
+    /// timed again from when they go out
+    pub fn reconnected(&mut self) {
+        self.in_flight.clear();
+    }
+
+    pub fn average_ms(&self) -> Option<u32> {
+        self.average.average_ms()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Buffers outgoing log entries per client so a window's worth can be sent
+/// as a single `LogBatch` packet
+#[derive(Default)]
+pub struct LogBatcher {
+    pending: HashMap<String, Vec<LogEntry>>,
+}
 
-    #[tokio::test]
-    async fn test_node_config() {
-        let config = NodeConfig {
-            mqtt_host: "localhost".to_string(),
-            mqtt_port: 1883,
-            node_capacity: 100,
-        };
-        assert_eq!(config.mqtt_host, "localhost");
-        assert_eq!(config.mqtt_port, 1883);
-        assert_eq!(config.node_capacity, 100);
+impl LogBatcher {
+    /// Buffers an entry; returns true when it opened a new window for the client
+    pub fn push(&mut self, client_id: &str, entry: LogEntry) -> bool {
+        let entries = self.pending.entry(client_id.to_string()).or_default();
+        entries.push(entry);
+        entries.len() == 1
+    }
+
+    /// Takes everything buffered for the client, closing its window
+    pub fn take(&mut self, client_id: &str) -> Vec<LogEntry> {
+        self.pending.remove(client_id).unwrap_or_default()
+    }
+}
+
+/// Counts a packet's cost against the node's load for as long as it lives, so
+/// the load is released however processing ends: normally, early, by panic or
+/// by cancellation.
+pub struct LoadGuard {
+    load: Arc<AtomicU32>,
+    cost: u32,
+}
+
+impl LoadGuard {
+    pub fn acquire(load: &Arc<AtomicU32>, cost: u32) -> Self {
+        load.fetch_add(cost, Ordering::Relaxed);
+        LoadGuard {
+            load: Arc::clone(load),
+            cost,
+        }
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.load.fetch_sub(self.cost, Ordering::Relaxed);
+    }
+}
+
+/// A data request waiting in the `RequestQueue`
+struct QueuedRequest {
+    /// Arrival order, so equal priorities are served first come, first served
+    sequence: u64,
+    request: DataRequest,
+}
+
+impl Ord for QueuedRequest {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.request
+            .priority
+            .cmp(&other.request.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for QueuedRequest {}
+
+/// Requests queued by default before the lowest-priority ones are turned away
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 256;
+
+/// Most requests one client may have queued at once, so a client flooding
+/// requests at the highest priority can't crowd out everyone else
+pub const MAX_QUEUED_PER_CLIENT: usize = 16;
+
+/// Data requests waiting to be served, highest priority first so a burst of
+/// low-priority requests can't starve an urgent one. Holds at most
+/// `capacity` requests, and `MAX_QUEUED_PER_CLIENT` from any one client.
+pub struct RequestQueue {
+    pending: BinaryHeap<QueuedRequest>,
+    next_sequence: u64,
+    capacity: usize,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        RequestQueue::new(DEFAULT_MAX_QUEUED_REQUESTS)
+    }
+}
+
+impl RequestQueue {
+    pub fn new(capacity: usize) -> Self {
+        RequestQueue {
+            pending: BinaryHeap::new(),
+            next_sequence: 0,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Queues `request`, returning the request turned away if there is no
+    /// room: `request` itself when its client already has its share queued,
+    /// otherwise the lowest-priority, most recent request once the queue is
+    /// over capacity
+    pub fn push(&mut self, request: DataRequest) -> Option<DataRequest> {
+        let queued_by_client = self
+            .pending
+            .iter()
+            .filter(|queued| queued.request.client_id == request.client_id)
+            .count();
+        if queued_by_client >= MAX_QUEUED_PER_CLIENT {
+            return Some(request);
+        }
+        self.pending.push(QueuedRequest {
+            sequence: self.next_sequence,
+            request,
+        });
+        self.next_sequence += 1;
+        if self.pending.len() <= self.capacity {
+            return None;
+        }
+        let mut pending = std::mem::take(&mut self.pending).into_vec();
+        let lowest = pending
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(index, _)| index)?;
+        let shed = pending.swap_remove(lowest);
+        self.pending = pending.into();
+        Some(shed.request)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn pop(&mut self) -> Option<DataRequest> {
+        self.pending.pop().map(|queued| queued.request)
+    }
+}
+
+#[derive(Clone)]
+pub struct Node<T = AsyncClient> {
+    node_info: NodeInfo,
+    /// Signs everything published when the pool shares a secret
+    client: Signed<T>,
+    /// Put in front of every topic; see `topics`
+    topic_prefix: String,
+    current_load: Arc<AtomicU32>,
+    /// Packets processed since startup, reported in heartbeats
+    processed_total: Arc<AtomicU64>,
+    /// Packets processed since the last heartbeat; reset by each one
+    processed_since_heartbeat: Arc<AtomicU32>,
+    /// Advertised capacity; starts at `node_info.capacity` and can be retuned
+    /// over the control channel
+    capacity: Arc<AtomicU32>,
+    /// Set on shutdown; new routings are refused while in-flight work finishes
+    draining: Arc<AtomicBool>,
+    /// Set by an operator; like draining, but reversible and advertised in heartbeats
+    maintenance: Arc<AtomicBool>,
+    change_tracker: Arc<Mutex<ChangeTracker>>,
+    response_cache: Arc<Mutex<ResponseCache>>,
+    type_quotas: Arc<Mutex<TypeQuotas>>,
+    timestamp_order: TimestampOrder,
+    stream_clock: Arc<Mutex<StreamClock>>,
+    response_delay: ResponseDelay,
+    processing_slots: Arc<Semaphore>,
+    /// How long log entries are buffered per client before being sent as one batch
+    log_batch_window: Option<Duration>,
+    log_batcher: Arc<Mutex<LogBatcher>>,
+    metadata_limits: MetadataLimits,
+    /// Caps on a single data request's `data_types` length and `max_items`
+    max_request_types: usize,
+    max_request_items: u32,
+    /// Most packets sent in one response message; also told to routed clients
+    max_batch_size: u32,
+    /// Processing budget per packet; advertised to routed clients as
+    /// `processing_timeout_ms`
+    processing_timeout: Duration,
+    /// Compression level each routed client asked for in its metadata
+    client_compression: Arc<Mutex<HashMap<String, u32>>>,
+    /// Encoding of each routed client's data responses, per the formats it
+    /// advertised
+    client_wire_formats: Arc<Mutex<HashMap<String, WireFormat>>>,
+    /// Clients this node accepted a routing for, with when each was last
+    /// heard from; reported in heartbeats
+    routed_clients: Arc<Mutex<BTreeMap<String, Instant>>>,
+    /// Slots held by `routed_clients`, counted against capacity at routing
+    /// time rather than when their data starts arriving
+    reserved_slots: Arc<AtomicU32>,
+    /// Optional in-process sink receiving a copy of every `DataResponse`
+    results: Option<mpsc::Sender<DataResponse>>,
+    /// Ids of recently received packets, so broker redeliveries are processed once
+    seen_packets: Arc<Mutex<DedupWindow>>,
+    /// Data requests received but not yet served
+    request_queue: Arc<Mutex<RequestQueue>>,
+    /// Wakes the request worker when `request_queue` gains an entry
+    request_ready: Arc<Notify>,
+    /// Whether the broker connection is up, as reported on `/health`
+    connected: Arc<AtomicBool>,
+    /// Processing for each data type
+    handlers: Arc<RwLock<HandlerRegistry>>,
+    /// QoS of everything published
+    qos: QosPolicy,
+    /// Simulated faults applied to `data/processed` publishes
+    faults: FaultInjector,
+    /// How long data publishes take, advertised in heartbeats
+    publish_latency: Arc<std::sync::Mutex<AckLatency>>,
+    /// Payloads of the packets handed out for data requests
+    generator: Arc<std::sync::Mutex<Box<dyn DataGenerator>>>,
+    /// Largest data message published
+    payload_limit: PayloadLimit,
+}
+
+impl Node {
+    /// Creates and connects a node. When `results` is given, every
+    /// `DataResponse` the node produces is also sent on it (waiting for room
+    /// if the receiver falls behind).
+    pub async fn new(
+        config: &NodeConfig,
+        results: Option<mpsc::Sender<DataResponse>>,
+    ) -> Result<Self, PoolError> {
+        let mut node_info = NodeInfo::new(NodeType::Node, config.node_capacity);
+        node_info.metadata.insert(
+            WIRE_FORMATS_METADATA_KEY.to_string(),
+            WireFormat::join(&WIRE_FORMATS),
+        );
+        // Lets the orchestrator hand clients the timeout enforced here
+        node_info.metadata.insert(
+            PROCESSING_TIMEOUT_METADATA_KEY.to_string(),
+            config.processing_timeout_ms.to_string(),
+        );
+        let node_id = node_info.node_id.clone();
+
+        let mut mqtt_options = config.mqtt.options(
+            mqtt_client_id(config.client_id_prefix.as_deref(), &node_id),
+            &config.mqtt_host,
+            config.mqtt_port,
+        );
+        set_offline_will(
+            &mut mqtt_options,
+            &config.topic_prefix,
+            config.shared_secret.as_ref(),
+            &NodeType::Node,
+            &node_id,
+        );
+        let channel_cap = mqtt_options.request_channel_capacity();
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, channel_cap);
+
+        // Serve 503 while still connecting, so probes don't see a refused port
+        let connected = Arc::new(AtomicBool::new(false));
+        if let Some(port) = config.health_port {
+            health::start(port, Arc::clone(&connected)).await?;
+        }
+
+        let backoff = Duration::from_millis(config.startup_backoff_ms);
+        wait_for_broker(&mut eventloop, config.startup_retries, backoff).await?;
+        connected.store(true, Ordering::Relaxed);
+
+        // Subscribe to all relevant topics
+        let prefix = config.topic_prefix.as_str();
+        let qos = config.qos;
+        let subscriptions = [
+            (topics::data_request(prefix, "+", "+"), qos.default),
+            (topics::regional_routing_request(prefix, "#"), qos.routing),
+            (topics::routing_response(prefix, "+"), qos.routing),
+            (topics::data_incoming(prefix, "#"), qos.default),
+            (topics::heartbeat_slave(prefix, "+"), qos.default),
+            (topics::probe(prefix, &node_id), qos.default),
+            (topics::control(prefix, &node_id), qos.default),
+            (topics::capacity_control(prefix, &node_id), qos.default),
+        ];
+        let received = subscribe_all(
+            &client,
+            &mut eventloop,
+            &subscriptions,
+            config.startup_retries,
+            backoff,
+        )
+        .await?;
+
+        let node = Node {
+            connected,
+            ..Node::build(node_info, client, config, results)
+        };
+        for publish in received {
+            node.handle_publish(&publish.topic, &publish.payload).await;
+        }
+
+        // Start heartbeat sender
+        node.start_heartbeat().await;
+
+        // Serve queued data requests by priority
+        node.start_request_worker();
+
+        // Start event loop handler
+        node.start_event_loop(eventloop).await;
+
+        Ok(node)
+    }
+
+    async fn start_event_loop(&self, eventloop: EventLoop) {
+        let node = self.clone();
+
+        tokio::spawn(async move {
+            let mut eventloop = eventloop;
+            let mut backoff = Backoff::default();
+
+            loop {
+                match eventloop.poll().await {
+                    Ok(event) => {
+                        backoff.reset();
+                        match event {
+                            Event::Incoming(Packet::ConnAck(_)) => {
+                                node.connected.store(true, Ordering::Relaxed);
+                                node.publish_latency.lock().unwrap().reconnected();
+                            }
+                            Event::Outgoing(Outgoing::Publish(pkid)) => {
+                                node.publish_latency
+                                    .lock()
+                                    .unwrap()
+                                    .sent(pkid, Instant::now());
+                            }
+                            // QoS 1 publishes are acknowledged with a PubAck,
+                            // QoS 2 ones first with a PubRec
+                            Event::Incoming(Packet::PubAck(rumqttc::PubAck { pkid }))
+                            | Event::Incoming(Packet::PubRec(rumqttc::PubRec { pkid })) => {
+                                node.publish_latency
+                                    .lock()
+                                    .unwrap()
+                                    .acked(pkid, Instant::now());
+                            }
+                            Event::Incoming(Packet::Publish(publish)) => {
+                                info!("Received message on topic: {}", publish.topic);
+
+                                node.handle_publish(&publish.topic, &publish.payload).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                    Err(e) => {
+                        node.connected.store(false, Ordering::Relaxed);
+                        let delay = backoff.next();
+                        error!("Event loop error: {:?}; retrying in {:?}", e, delay);
+                        time::sleep(delay).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl<T: MqttTransport> Node<T> {
+    fn build(
+        mut node_info: NodeInfo,
+        client: T,
+        config: &NodeConfig,
+        results: Option<mpsc::Sender<DataResponse>>,
+    ) -> Self {
+        node_info.supported_data_types = config.supported_types.clone();
+        node_info.features = config.features.clone();
+        Node {
+            capacity: Arc::new(AtomicU32::new(node_info.capacity)),
+            node_info,
+            client: Signed::new(client, config.shared_secret.clone()),
+            topic_prefix: config.topic_prefix.clone(),
+            current_load: Arc::new(AtomicU32::new(0)),
+            processed_total: Arc::new(AtomicU64::new(0)),
+            processed_since_heartbeat: Arc::new(AtomicU32::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            change_tracker: Arc::new(Mutex::new(ChangeTracker::new(CHANGE_KEEPALIVE))),
+            response_cache: Arc::new(Mutex::new(ResponseCache::new(IDEMPOTENCY_TTL))),
+            type_quotas: Arc::new(Mutex::new(TypeQuotas::new(
+                config.type_quotas.clone(),
+                QUOTA_WINDOW,
+            ))),
+            response_delay: ResponseDelay::new(
+                config.response_delay_ms,
+                config.response_delay_jitter_ms,
+            ),
+            timestamp_order: config.timestamp_order,
+            stream_clock: Arc::new(Mutex::new(StreamClock::default())),
+            processing_slots: Arc::new(Semaphore::new(config.processing_concurrency as usize)),
+            log_batch_window: (config.log_batch_window_ms > 0)
+                .then(|| Duration::from_millis(config.log_batch_window_ms)),
+            log_batcher: Arc::new(Mutex::new(LogBatcher::default())),
+            metadata_limits: config.metadata_limits.clone(),
+            max_request_types: config.max_request_types,
+            max_request_items: config.max_request_items,
+            max_batch_size: config.max_batch_size,
+            processing_timeout: Duration::from_millis(config.processing_timeout_ms),
+            client_compression: Arc::new(Mutex::new(HashMap::new())),
+            client_wire_formats: Arc::new(Mutex::new(HashMap::new())),
+            routed_clients: Arc::new(Mutex::new(BTreeMap::new())),
+            reserved_slots: Arc::new(AtomicU32::new(0)),
+            results,
+            seen_packets: Arc::new(Mutex::new(DedupWindow::with_budget_mb(
+                config.dedup_memory_budget_mb,
+            ))),
+            request_queue: Arc::new(Mutex::new(RequestQueue::new(config.max_queued_requests))),
+            request_ready: Arc::new(Notify::new()),
+            connected: Arc::new(AtomicBool::new(false)),
+            handlers: Arc::new(RwLock::new(HandlerRegistry::with_defaults())),
+            qos: config.qos,
+            faults: config.fault_injection.clone(),
+            publish_latency: Arc::new(std::sync::Mutex::new(AckLatency::new(
+                PUBLISH_LATENCY_SMOOTHING,
+            ))),
+            generator: Arc::new(std::sync::Mutex::new(config.generator.build())),
+            payload_limit: config
+                .mqtt
+                .payload_limit
+                .sealed(config.shared_secret.as_ref()),
+        }
+    }
+
+    /// Processes packets of `handler`'s data type with it from now on,
+    /// replacing the handler registered before
+    pub async fn register_handler(&self, handler: Box<dyn PayloadHandler>) {
+        info!("Registered handler for {} data", handler.data_type());
+        self.handlers.write().await.register(handler);
+    }
+
+    /// Takes the node in or out of maintenance. New routings are refused while
+    /// in maintenance; requests from clients already routed here are still served.
+    pub fn set_maintenance(&self, maintenance: bool) {
+        if self.maintenance.swap(maintenance, Ordering::Relaxed) != maintenance {
+            info!(
+                "Maintenance mode {}",
+                if maintenance { "entered" } else { "left" }
+            );
+        }
+    }
+
+    /// Retunes the advertised capacity. Refused, with a warning, when it is
+    /// below the load already in flight.
+    pub fn set_capacity(&self, capacity: u32) -> bool {
+        let load = self.current_load.load(Ordering::Relaxed);
+        if capacity < load {
+            warn!("Ignoring capacity {} below current load {}", capacity, load);
+            return false;
+        }
+        let previous = self.capacity.swap(capacity, Ordering::Relaxed);
+        info!("Capacity changed from {} to {}", previous, capacity);
+        true
+    }
+
+    /// Applies an operator command received on `control/{node_id}[/capacity]`
+    fn handle_control(&self, topic: &str, payload: &[u8]) {
+        if topic.ends_with("/capacity") {
+            if let Some(control) = decode_or_log::<CapacityControl>(topic, payload) {
+                self.set_capacity(control.capacity);
+            }
+        } else if let Some(control) = decode_or_log::<MaintenanceControl>(topic, payload) {
+            self.set_maintenance(control.maintenance);
+        }
+    }
+
+    /// Status advertised in heartbeats and probe acks
+    fn status(&self) -> NodeStatus {
+        if self.maintenance.load(Ordering::Relaxed) {
+            NodeStatus::Maintenance
+        } else {
+            self.node_info.status.clone()
+        }
+    }
+
+    /// The node's info with its live load, capacity and status
+    fn current_info(&self) -> NodeInfo {
+        let mut info = self.node_info.clone();
+        info.last_heartbeat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        info.current_load = self.current_load.load(Ordering::Relaxed);
+        info.processed_total = self.processed_total.load(Ordering::Relaxed);
+        info.capacity = self.capacity.load(Ordering::Relaxed);
+        info.status = self.status();
+        info.avg_publish_latency_ms = self.publish_latency.lock().unwrap().average_ms();
+        self.limit_metadata(&mut info);
+        info
+    }
+
+    /// Holds the metadata published in `info` to `metadata_limits`, leaving
+    /// it out altogether when it can't be made to fit
+    fn limit_metadata(&self, info: &mut NodeInfo) {
+        if let Err(e) = self.metadata_limits.apply(&mut info.metadata) {
+            warn!("Publishing node info without metadata: {}", e);
+            info.metadata.clear();
+        }
+    }
+
+    /// Current state for the next heartbeat, including the clients this node
+    /// believes are routed to it. Starts a new throughput interval.
+    async fn heartbeat(&self) -> NodeInfo {
+        let mut heartbeat = self.current_info();
+        heartbeat.processed_since_last_heartbeat =
+            self.processed_since_heartbeat.swap(0, Ordering::Relaxed);
+        // Added past the metadata limits: a cut-short list would have the
+        // orchestrator drop live routings, and its length is bounded by capacity
+        let routed_clients = self.routed_clients.lock().await;
+        heartbeat.metadata.insert(
+            ROUTED_CLIENTS_METADATA_KEY.to_string(),
+            routed_clients
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        heartbeat
+    }
+
+    /// Where live heartbeats, the shutdown heartbeat and the last will all
+    /// go; the orchestrator only listens on this one
+    fn heartbeat_topic(&self) -> String {
+        topics::heartbeat(
+            &self.topic_prefix,
+            &self.node_info.node_type,
+            &self.node_info.node_id,
+        )
+    }
+
+    async fn start_heartbeat(&self) {
+        let node = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                node.expire_reservations(CLIENT_TIMEOUT).await;
+                let heartbeat = node.heartbeat().await;
+
+                if let Ok(payload) = serde_json::to_string(&heartbeat) {
+                    let topic = node.heartbeat_topic();
+                    if let Err(e) = node
+                        .client
+                        .publish(&topic, node.qos.default, false, payload)
+                        .await
+                    {
+                        error!("Error publishing heartbeat: {:?}", e);
+                    } else {
+                        info!("Heartbeat sent on topic: {}", topic);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Queues a data request for the request worker. Oversized requests are
+    /// refused before taking up room, and a full queue turns away its
+    /// lowest-priority request.
+    async fn enqueue_data_request(&self, request: DataRequest) {
+        // Refuse requests big enough to amplify the work a single message causes
+        if request.data_types.len() > self.max_request_types
+            || request.max_items.unwrap_or(0) > self.max_request_items
+        {
+            warn!(
+                "Rejecting request {} from {}: {} types, max_items {:?}",
+                request.request_id,
+                request.client_id,
+                request.data_types.len(),
+                request.max_items
+            );
+            self.reject_data_request(&request, "request too large")
+                .await;
+            return;
+        }
+
+        let shed = self.request_queue.lock().await.push(request);
+        self.request_ready.notify_one();
+        if let Some(shed) = shed {
+            warn!(
+                "Request queue full; turning away request {} from {}",
+                shed.request_id, shed.client_id
+            );
+            self.reject_data_request(&shed, "request queue full").await;
+        }
+    }
+
+    /// Tells the client its data request won't be served
+    async fn reject_data_request(&self, request: &DataRequest, reason: &str) {
+        let summary = FulfillmentSummary {
+            request_id: request.request_id.clone(),
+            fulfilled: Vec::new(),
+            skipped: Vec::new(),
+            error: Some(reason.to_string()),
+        };
+        self.publish_summary(&request.client_id, &summary).await;
+    }
+
+    /// Serves queued data requests one at a time, highest priority first
+    fn start_request_worker(&self) {
+        let node = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let next = node.request_queue.lock().await.pop();
+                match next {
+                    Some(request) => node.handle_data_request(&request).await,
+                    None => node.request_ready.notified().await,
+                }
+            }
+        });
+    }
+
+    /// Dispatches a message received on one of the subscribed topics
+    async fn handle_publish(&self, topic: &str, payload: &[u8]) {
+        let Some(payload) = self.client.open(topic, payload) else {
+            warn!(
+                "Dropping message on {} with a missing or invalid signature",
+                topic
+            );
+            return;
+        };
+        let Some(topic) = topics::strip(&self.topic_prefix, topic) else {
+            return;
+        };
+        match topic {
+            topic if topic.starts_with("routing/request") => {
+                if let Some(request) = decode_or_log::<RoutingRequest>(topic, payload) {
+                    info!(
+                        "Processing routing request from slave: {}",
+                        request.client_id
+                    );
+                    self.handle_routing_request(&request).await;
+                }
+            }
+            topic if topic.starts_with("routing/response/") => {
+                if let Some(response) = decode_or_log::<RoutingResponse>(topic, payload) {
+                    self.handle_assignment(&response).await;
+                }
+            }
+            topic if topic.starts_with("data/request") => {
+                if let Some(request) = decode_or_log::<DataRequest>(topic, payload) {
+                    info!(
+                        "Queueing data request: {} (priority {})",
+                        request.request_id, request.priority
+                    );
+                    self.enqueue_data_request(request).await;
+                }
+            }
+            topic if topic.starts_with("data/incoming") => {
+                if let Some(packet) = decode_or_log::<DataPacket>(topic, payload) {
+                    info!("Processing incoming data packet: {}", packet.id);
+                    let source = topic
+                        .strip_prefix("data/incoming/")
+                        .unwrap_or_default()
+                        .to_string();
+                    // Bounded by the processing semaphore, not the event loop
+                    let node = self.clone();
+                    tokio::spawn(async move {
+                        node.handle_data_packet(&source, &packet).await;
+                    });
+                }
+            }
+            topic if topic.starts_with("control/") => {
+                self.handle_control(topic, payload);
+            }
+            topic if topic.starts_with("heartbeat/slave/") => {
+                // A bare `OfflineNotice` is the client's last will
+                if let Some(heartbeat) = decode_or_log::<HeartbeatMessage>(topic, payload) {
+                    let client_id = topic.rsplit('/').next().unwrap_or_default();
+                    self.handle_client_heartbeat(client_id, heartbeat.status())
+                        .await;
+                }
+            }
+            topic if topic.starts_with("probe/") => {
+                if let Some(probe) = decode_or_log::<ProbeRequest>(topic, payload) {
+                    self.handle_probe(&probe).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Stops accepting routings and waits for the current load to reach zero.
+    /// Returns false if `timeout` passed with work still in flight.
+    pub async fn begin_drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let load = self.current_load.load(Ordering::Relaxed);
+            if load == 0 {
+                info!("Drained all in-flight work");
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!("Drain timed out with {} packets still in flight", load);
+                return false;
+            }
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Holds a slot for `client_id` until it disconnects, goes quiet or is
+    /// routed elsewhere, so routings accepted in quick succession cannot
+    /// outnumber capacity before any of their data arrives. A client already
+    /// holding a slot keeps it.
+    async fn reserve_slot(&self, client_id: &str) -> bool {
+        let mut routed_clients = self.routed_clients.lock().await;
+        if let Some(last_seen) = routed_clients.get_mut(client_id) {
+            *last_seen = Instant::now();
+            return true;
+        }
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let reserved =
+            self.reserved_slots
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+                    (reserved < capacity).then_some(reserved + 1)
+                });
+        if reserved.is_err() {
+            return false;
+        }
+        routed_clients.insert(client_id.to_string(), Instant::now());
+        true
+    }
+
+    /// Frees the slot held by `client_id`, if any
+    async fn release_slot(&self, client_id: &str) {
+        if self.routed_clients.lock().await.remove(client_id).is_some() {
+            self.reserved_slots.fetch_sub(1, Ordering::SeqCst);
+            info!("Released slot held by client {}", client_id);
+        }
+    }
+
+    /// Frees the slots of routed clients not heard from within `timeout`
+    async fn expire_reservations(&self, timeout: Duration) {
+        let mut routed_clients = self.routed_clients.lock().await;
+        let before = routed_clients.len();
+        routed_clients.retain(|client_id, last_seen| {
+            let alive = last_seen.elapsed() < timeout;
+            if !alive {
+                info!("Client {} timed out; releasing its slot", client_id);
+            }
+            alive
+        });
+        let expired = (before - routed_clients.len()) as u32;
+        self.reserved_slots.fetch_sub(expired, Ordering::SeqCst);
+    }
+
+    /// Tracks a routed client's heartbeat, releasing its slot once it goes offline
+    async fn handle_client_heartbeat(&self, client_id: &str, status: &NodeStatus) {
+        if *status == NodeStatus::Offline {
+            self.release_slot(client_id).await;
+        } else if let Some(last_seen) = self.routed_clients.lock().await.get_mut(client_id) {
+            *last_seen = Instant::now();
+        }
+    }
+
+    #[instrument(skip_all, fields(
+        client_id = %request.client_id,
+        request_id = %request.request_id.as_deref().unwrap_or_default(),
+    ))]
+    async fn handle_routing_request(&self, request: &RoutingRequest) {
+        let node_info = &self.current_info();
+        // Every node hears every request; one meant for another node is
+        // left to that node and the orchestrator
+        let preferred_here = request.preferred_node.as_ref() == Some(&node_info.node_id);
+        if request.preferred_node.is_some() && !preferred_here {
+            return;
+        }
+
+        let (mut status, mut rejection_reason) = if self.draining.load(Ordering::Relaxed) {
+            (RoutingStatus::Rejected, Some("draining".to_string()))
+        } else if self.maintenance.load(Ordering::Relaxed) {
+            (RoutingStatus::Rejected, Some("in maintenance".to_string()))
+        } else if !node_info.has_capacity(1) {
+            (
+                RoutingStatus::Rejected,
+                Some("Capacity limit reached".to_string()),
+            )
+        } else {
+            (RoutingStatus::Accepted, None)
+        };
+        // Only the preferred node holds a slot up front; others wait to see
+        // whether the orchestrator assigns them the client
+        if status == RoutingStatus::Accepted
+            && preferred_here
+            && !self.reserve_slot(&request.client_id).await
+        {
+            status = RoutingStatus::Rejected;
+            rejection_reason = Some("Capacity limit reached".to_string());
+        }
+        // A refusal from a node the client didn't ask for would only race the
+        // orchestrator's answer
+        if status == RoutingStatus::Rejected && !preferred_here {
+            return;
+        }
+
+        let wire_format = WireFormat::shared(
+            &WIRE_FORMATS,
+            &WireFormat::advertised_by(&request.node_info),
+        );
+        if status == RoutingStatus::Accepted {
+            // Clients may ask for tighter per-type quotas via their metadata
+            let limits = request
+                .node_info
+                .metadata
+                .iter()
+                .filter_map(|(key, value)| {
+                    let data_type = key.strip_prefix(QUOTA_METADATA_PREFIX)?;
+                    Some((data_type.to_string(), value.parse().ok()?))
+                })
+                .collect::<HashMap<_, _>>();
+            self.type_quotas
+                .lock()
+                .await
+                .set_client_limits(&request.client_id, limits);
+            self.client_compression.lock().await.insert(
+                request.client_id.clone(),
+                requested_compression(&request.node_info),
+            );
+            self.client_wire_formats
+                .lock()
+                .await
+                .insert(request.client_id.clone(), wire_format);
+        }
+
+        let response = RoutingResponse {
+            node_id: node_info.node_id.clone(),
+            client_id: request.client_id.clone(),
+            status,
+            rejection_reason,
+            configuration: if status == RoutingStatus::Accepted {
+                Some(ClientConfiguration {
+                    subscribe_topics: vec![
+                        topics::data_response(
+                            &self.topic_prefix,
+                            &node_info.node_id,
+                            &request.client_id,
+                        ),
+                        topics::data_broadcast(&self.topic_prefix, "#"),
+                    ],
+                    publish_topic: topics::data_request(
+                        &self.topic_prefix,
+                        &node_info.node_id,
+                        &request.client_id,
+                    ),
+                    qos: 1,
+                    max_batch_size: self.max_batch_size,
+                    processing_timeout_ms: self.processing_timeout.as_millis() as u64,
+                    compression_level: self
+                        .compression_level(requested_compression(&request.node_info)),
+                    wire_format,
+                    node_features: node_info.features.clone(),
+                })
+            } else {
+                None
+            },
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            request_id: request.request_id.clone(),
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Node,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        };
+
+        self.response_delay.apply().await;
+
+        if let Ok(response_payload) = serde_json::to_string(&response) {
+            let topic = topics::routing_response(&self.topic_prefix, &request.client_id);
+            if let Err(e) = self
+                .client
+                .publish(&topic, self.qos.routing, false, response_payload)
+                .await
+            {
+                error!("Error publishing routing response: {:?}", e);
+            } else {
+                info!("Routing response sent on topic: {}", topic);
+            }
+        }
+    }
+
+    /// Follows the orchestrator's routing decisions: holds a slot for a client
+    /// assigned here and frees it once the client is routed elsewhere or its
+    /// routing is revoked
+    async fn handle_assignment(&self, response: &RoutingResponse) {
+        if response.issuer != RoutingIssuer::Orchestrator
+            || response.status == RoutingStatus::Pending
+        {
+            return;
+        }
+        let node_id = &self.node_info.node_id;
+        let assigned = response.status == RoutingStatus::Accepted
+            && (&response.node_id == node_id
+                || response
+                    .assignments
+                    .iter()
+                    .any(|assignment| &assignment.node_id == node_id));
+        if !assigned {
+            self.release_slot(&response.client_id).await;
+        } else if !self.reserve_slot(&response.client_id).await {
+            warn!(
+                "Assigned client {} with no slot left; serving it anyway",
+                response.client_id
+            );
+        }
+    }
+
+    /// Answers an orchestrator health probe with the node's current state
+    async fn handle_probe(&self, probe: &ProbeRequest) {
+        let ack = ProbeAck {
+            probe_id: probe.probe_id.clone(),
+            node_id: self.node_info.node_id.clone(),
+            status: self.status(),
+            current_load: self.current_load.load(Ordering::Relaxed),
+            sent_at_ms: probe.sent_at_ms,
+            acked_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+
+        let topic = topics::probe_ack(&self.topic_prefix, &self.node_info.node_id);
+        if let Ok(payload) = serde_json::to_string(&ack) {
+            if let Err(e) = self
+                .client
+                .publish(&topic, self.qos.default, false, payload)
+                .await
+            {
+                error!("Error publishing probe ack: {:?}", e);
+            }
+        }
+    }
+
+    /// The compression level actually used for a `requested` one; always 0
+    /// when this node doesn't advertise the compression feature
+    fn compression_level(&self, requested: u32) -> u32 {
+        if self
+            .node_info
+            .features
+            .iter()
+            .any(|f| f == FEATURE_COMPRESSION)
+        {
+            requested.min(MAX_COMPRESSION_LEVEL)
+        } else {
+            0
+        }
+    }
+
+    #[instrument(skip_all, fields(
+        client_id = %request.client_id,
+        request_id = %request.request_id,
+    ))]
+    async fn handle_data_request(&self, request: &DataRequest) {
+        info!("Processing data request from slave {}", request.client_id);
+
+        // Retries carrying the same idempotency key get the originally generated packets
+        let prepared = match &request.idempotency_key {
+            Some(key) => {
+                let cache_key = format!("{}/{}", request.client_id, key);
+                let mut cache = self.response_cache.lock().await;
+                let now = Instant::now();
+                match cache.get(&cache_key, now) {
+                    Some(prepared) => {
+                        info!("Replaying cached response for idempotency key {}", key);
+                        prepared
+                    }
+                    None => {
+                        let prepared = self.prepare_packets(request).await;
+                        cache.insert(cache_key, prepared.clone(), now);
+                        prepared
+                    }
+                }
+            }
+            None => self.prepare_packets(request).await,
+        };
+        if let Some(summary) = &prepared.summary {
+            self.publish_summary(&request.client_id, summary).await;
+        }
+        let mut data_packets = self
+            .batch_log_entries(&request.client_id, prepared.packets)
+            .await;
+        if let Some(max_items) = request.max_items {
+            data_packets.truncate(max_items as usize);
+        }
+
+        // Send data packets
+        let response_topic = topics::data_response(
+            &self.topic_prefix,
+            &self.node_info.node_id,
+            &request.client_id,
+        );
+
+        if !data_packets.is_empty() {
+            self.response_delay.apply().await;
+        }
+
+        // Bandwidth-constrained clients trade our CPU for smaller responses
+        let compression_level = self.compression_level(match request.compression_level {
+            Some(level) => level,
+            None => self
+                .client_compression
+                .lock()
+                .await
+                .get(&request.client_id)
+                .copied()
+                .unwrap_or(0),
+        });
+        let wire_format = self
+            .client_wire_formats
+            .lock()
+            .await
+            .get(&request.client_id)
+            .copied()
+            .unwrap_or_default();
+
+        for packet in &mut data_packets {
+            // Lets the client match packets to the request they answer
+            packet
+                .metadata
+                .insert("request_id".to_string(), request.request_id.clone());
+            if compression_level > 0 {
+                packet
+                    .metadata
+                    .insert("encoding".to_string(), "deflate".to_string());
+                packet.metadata.insert(
+                    "compression_level".to_string(),
+                    compression_level.to_string(),
+                );
+            }
+        }
+
+        for mut batch in DataBatch::split(data_packets, self.max_batch_size) {
+            let packet_ids: Vec<String> = batch.packets.iter().map(|p| p.id.clone()).collect();
+            let encoded = match wire_format {
+                // Clients taking bincode also take batches, so a lone packet
+                // needn't go out on its own
+                WireFormat::Bincode => mqtt_common::encode(wire_format, &batch).ok(),
+                // A lone packet goes out as-is, readable by clients predating batches
+                WireFormat::Json if batch.packets.len() == 1 => {
+                    serde_json::to_vec(&batch.packets.remove(0)).ok()
+                }
+                WireFormat::Json => serde_json::to_vec(&batch).ok(),
+            };
+            if let Some(payload) = encoded {
+                let payload = compress_frame(&payload, compression_level);
+                if let Err(e) = self.payload_limit.check(&payload) {
+                    error!("Refusing to publish packets {:?}: {}", packet_ids, e);
+                    self.reject_data_request(request, &e.to_string()).await;
+                } else if let Err(e) = self.publish_data(&response_topic, payload).await {
+                    error!("Error publishing data response: {:?}", e);
+                } else {
+                    info!("Data response sent on topic: {}", response_topic);
+                }
+            }
+        }
+    }
+
+    /// Moves log entries into the client's batch when batching is enabled,
+    /// returning the packets that should still be sent right away
+    async fn batch_log_entries(
+        &self,
+        client_id: &str,
+        packets: Vec<DataPacket>,
+    ) -> Vec<DataPacket> {
+        let Some(window) = self.log_batch_window else {
+            return packets;
+        };
+
+        let mut batcher = self.log_batcher.lock().await;
+        let mut remaining = Vec::new();
+        for packet in packets {
+            match packet.payload {
+                DataPayload::LogEntry {
+                    level,
+                    message,
+                    timestamp,
+                } => {
+                    let entry = LogEntry {
+                        level,
+                        message,
+                        timestamp,
+                    };
+                    if batcher.push(client_id, entry) {
+                        let node = self.clone();
+                        let client_id = client_id.to_string();
+                        tokio::spawn(
+                            async move {
+                                time::sleep(window).await;
+                                node.flush_log_batch(&client_id).await;
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
+                _ => remaining.push(packet),
+            }
+        }
+        remaining
+    }
+
+    /// Sends the client's buffered log entries as a single `LogBatch` packet
+    async fn flush_log_batch(&self, client_id: &str) {
+        let entries = self.log_batcher.lock().await.take(client_id);
+        if entries.is_empty() {
+            return;
+        }
+
+        let mut metadata = HashMap::new();
+        metadata.insert("type".to_string(), "log".to_string());
+        metadata.insert("batch_size".to_string(), entries.len().to_string());
+
+        let packet = DataPacket {
+            id: Uuid::new_v4().to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string(),
+            data_type: "log".to_string(),
+            payload: DataPayload::LogBatch { entries },
+            metadata,
+            schema_version: PROTOCOL_VERSION,
+        };
+
+        let response_topic =
+            topics::data_response(&self.topic_prefix, &self.node_info.node_id, client_id);
+        match self.payload_limit.encode(&packet) {
+            Ok(payload) => {
+                if let Err(e) = self.publish_data(&response_topic, payload).await {
+                    error!("Error publishing log batch: {:?}", e);
+                } else {
+                    info!("Log batch sent on topic: {}", response_topic);
+                }
+            }
+            Err(e) => {
+                error!("Refusing to publish log batch {}: {}", packet.id, e);
+                let summary = FulfillmentSummary {
+                    request_id: packet.id,
+                    fulfilled: Vec::new(),
+                    skipped: Vec::new(),
+                    error: Some(e.to_string()),
+                };
+                self.publish_summary(client_id, &summary).await;
+            }
+        }
+    }
+
+    /// Publishes data so that a backed-up broker doesn't lose it: while the
+    /// outgoing queue is full the publish is retried with a growing delay,
+    /// then it waits in line for the queue. Only a closed event loop drops it.
+    async fn publish_data(&self, topic: &str, payload: Vec<u8>) -> Result<(), ClientError> {
+        let mut delay = FULL_QUEUE_BACKOFF;
+        for attempt in 1..=FULL_QUEUE_RETRIES {
+            match self
+                .client
+                .try_publish(topic, self.qos.default, false, payload.clone())
+            {
+                Ok(()) => return Ok(()),
+                Err(e) if classify_publish_error(&e) == PublishErrorKind::BrokerFull => {
+                    warn!(
+                        "Outgoing queue full publishing to {} (attempt {}/{}), retrying in {:?}",
+                        topic, attempt, FULL_QUEUE_RETRIES, delay
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.client
+            .publish(topic, self.qos.default, false, payload)
+            .await
+    }
+
+    /// Tells the client which requested types it didn't get
+    async fn publish_summary(&self, client_id: &str, summary: &FulfillmentSummary) {
+        let topic = topics::data_summary(&self.topic_prefix, &self.node_info.node_id, client_id);
+        if let Ok(payload) = serde_json::to_string(summary) {
+            if let Err(e) = self
+                .client
+                .publish(&topic, self.qos.default, false, payload)
+                .await
+            {
+                error!("Error publishing fulfillment summary: {:?}", e);
+            }
+        }
+    }
+
+    /// Generates the packets for a request, dropping unchanged numeric values
+    /// and summarizing any requested types that couldn't be served
+    async fn prepare_packets(&self, request: &DataRequest) -> PreparedResponse {
+        let mut data_packets = self.generate_packets(request);
+        let mut skipped: Vec<SkippedType> = request
+            .data_types
+            .iter()
+            .filter(|data_type| !data_packets.iter().any(|p| &p.data_type == *data_type))
+            .map(|data_type| SkippedType {
+                data_type: data_type.clone(),
+                reason: SkipReason::Unsupported,
+            })
+            .collect();
+
+        // Throttle only the types the client is over quota on
+        {
+            let mut quotas = self.type_quotas.lock().await;
+            let now = Instant::now();
+
+            // Strict requests are refused before any quota is spent
+            if request.fulfillment == Fulfillment::Strict {
+                skipped.extend(
+                    data_packets
+                        .iter()
+                        .filter(|p| !quotas.has_room(&request.client_id, &p.data_type, now))
+                        .map(|p| SkippedType {
+                            data_type: p.data_type.clone(),
+                            reason: SkipReason::OverQuota,
+                        }),
+                );
+                if !skipped.is_empty() {
+                    let unavailable: Vec<String> = skipped
+                        .iter()
+                        .map(|s| format!("{} ({:?})", s.data_type, s.reason))
+                        .collect();
+                    warn!(
+                        "Rejecting strict request {} from {}: {}",
+                        request.request_id,
+                        request.client_id,
+                        unavailable.join(", ")
+                    );
+                    return PreparedResponse {
+                        packets: Vec::new(),
+                        summary: Some(FulfillmentSummary {
+                            request_id: request.request_id.clone(),
+                            fulfilled: Vec::new(),
+                            skipped,
+                            error: Some(format!(
+                                "cannot fulfill all requested types: {}",
+                                unavailable.join(", ")
+                            )),
+                        }),
+                    };
+                }
+            }
+
+            data_packets.retain(|packet| {
+                let allowed = quotas.try_consume(&request.client_id, &packet.data_type, now);
+                if !allowed {
+                    warn!(
+                        "Client {} over its {} quota, skipping",
+                        request.client_id, packet.data_type
+                    );
+                    skipped.push(SkippedType {
+                        data_type: packet.data_type.clone(),
+                        reason: SkipReason::OverQuota,
+                    });
+                }
+                allowed
+            });
+        }
+
+        let summary = (!skipped.is_empty()).then(|| FulfillmentSummary {
+            request_id: request.request_id.clone(),
+            fulfilled: data_packets.iter().map(|p| p.data_type.clone()).collect(),
+            skipped,
+            error: None,
+        });
+
+        // Drop numeric values that haven't moved enough since the last response
+        if let Some(threshold) = request.only_if_changed {
+            let mut tracker = self.change_tracker.lock().await;
+            let now = Instant::now();
+            data_packets.retain(|packet| match packet.payload.numeric_values() {
+                Some(values) => tracker.should_send(
+                    &request.client_id,
+                    &packet.data_type,
+                    &values,
+                    threshold,
+                    now,
+                ),
+                None => true,
+            });
+        }
+
+        PreparedResponse {
+            packets: data_packets,
+            summary,
+        }
+    }
+
+    fn generate_packets(&self, request: &DataRequest) -> Vec<DataPacket> {
+        let mut generator = self.generator.lock().unwrap();
+        request
+            .data_types
+            .iter()
+            .filter(|data_type| self.node_info.supported_data_types.contains(data_type))
+            .filter_map(|data_type| {
+                let payload = generator.next(data_type)?;
+                let mut metadata = HashMap::new();
+                if data_type == "sensor" {
+                    metadata.insert("source".to_string(), "sensor-1".to_string());
+                } else {
+                    metadata.insert("type".to_string(), data_type.clone());
+                }
+                Some(DataPacket {
+                    id: Uuid::new_v4().to_string(),
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                        .to_string(),
+                    data_type: data_type.clone(),
+                    payload,
+                    metadata,
+                    schema_version: PROTOCOL_VERSION,
+                })
+            })
+            // Never hand out NaN/Inf, which would break consumers' math
+            .filter_map(
+                |mut packet| match self.metadata_limits.apply(&mut packet.metadata) {
+                    Ok(()) => Some(packet),
+                    Err(e) => {
+                        warn!("Dropping generated {} packet: {}", packet.data_type, e);
+                        None
+                    }
+                },
+            )
+            .filter(|packet| {
+                let finite = packet.payload.is_finite();
+                if !finite {
+                    warn!(
+                        "Dropping generated {} packet with non-finite value",
+                        packet.data_type
+                    );
+                }
+                finite
+            })
+            .collect::<Vec<_>>()
+    }
+
+    fn data_response(
+        &self,
+        packet_id: &str,
+        status: ProcessingStatus,
+        processing_time_ms: u64,
+        errors: Vec<String>,
+    ) -> DataResponse {
+        let mut processor_info = self.node_info.clone();
+        processor_info.current_load = self.current_load.load(Ordering::Relaxed);
+
+        DataResponse {
+            packet_id: packet_id.to_string(),
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string(),
+            status,
+            processing_time_ms,
+            errors,
+            processor_info,
+        }
+    }
+
+    /// Whether the packet's timestamp is in order for its stream. Always true
+    /// when ordering isn't enforced or the timestamp can't be parsed.
+    async fn check_timestamp_order(&self, source: &str, packet: &DataPacket) -> bool {
+        if self.timestamp_order == TimestampOrder::Off {
+            return true;
+        }
+        match parse_packet_timestamp(&packet.timestamp) {
+            Some(timestamp) => {
+                self.stream_clock
+                    .lock()
+                    .await
+                    .observe(source, &packet.data_type, timestamp)
+            }
+            None => true,
+        }
+    }
+
+    /// Publishes a `DataResponse` and forwards it to the results channel, if any
+    async fn emit_data_response(&self, response: &DataResponse) {
+        let topic = topics::processing_status(&self.topic_prefix, &self.node_info.node_id);
+        if let Ok(payload) = serde_json::to_string(response) {
+            if let Err(e) = self
+                .client
+                .publish(&topic, self.qos.default, false, payload)
+                .await
+            {
+                error!("Error publishing data response: {:?}", e);
+            }
+        }
+
+        if let Some(results) = &self.results {
+            if results.send(response.clone()).await.is_err() {
+                warn!("Results channel closed, dropping response");
+            }
+        }
+    }
+
+    /// Processes a packet published by `source` (the client id from the
+    /// `data/incoming/{client_id}` topic)
+    async fn handle_data_packet(&self, source: &str, packet: &DataPacket) {
+        if !self.seen_packets.lock().await.insert(&packet.id) {
+            debug!("Ignoring duplicate packet {}", packet.id);
+            return;
+        }
+
+        // Compressed payloads, and compressed batch items, are handled as
+        // the payload they wrap
+        let mut processed = packet.clone();
+        if let DataPayload::Compressed { .. } | DataPayload::Batch(_) = &packet.payload {
+            match packet.payload.decompress() {
+                Ok(payload) => processed.payload = payload,
+                Err(e) => {
+                    warn!("Rejecting packet {}: {}", packet.id, e);
+                    let response = self.data_response(
+                        &packet.id,
+                        ProcessingStatus::InvalidInput,
+                        0,
+                        vec![e.to_string()],
+                    );
+                    self.emit_data_response(&response).await;
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = processed.payload.validate() {
+            warn!("Rejecting packet {}: {}", packet.id, e);
+            let response =
+                self.data_response(&packet.id, ProcessingStatus::InvalidInput, 0, e.issues);
+            self.emit_data_response(&response).await;
+            return;
+        }
+
+        if !self.check_timestamp_order(source, packet).await {
+            if self.timestamp_order == TimestampOrder::Strict {
+                warn!("Rejecting packet {} with regressing timestamp", packet.id);
+                let response = self.data_response(
+                    &packet.id,
+                    ProcessingStatus::InvalidInput,
+                    0,
+                    vec!["timestamp regression".to_string()],
+                );
+                self.emit_data_response(&response).await;
+                return;
+            }
+            // Lenient ordering processes a tagged copy of out-of-order packets
+            warn!("Packet {} has a regressing timestamp", packet.id);
+            processed
+                .metadata
+                .insert("out_of_order".to_string(), "true".to_string());
+        }
+
+        if let Err(e) = self.metadata_limits.apply(&mut processed.metadata) {
+            warn!("Rejecting packet {}: {}", packet.id, e);
+            let response = self.data_response(
+                &packet.id,
+                ProcessingStatus::InvalidInput,
+                0,
+                vec![e.to_string()],
+            );
+            self.emit_data_response(&response).await;
+            return;
+        }
+        let packet = &processed;
+
+        // Resolved up front so a handler being registered isn't held up
+        // while this packet is processed
+        let resolved = self.handlers.read().await.resolve(packet);
+        let steps = match resolved {
+            Ok(steps) => steps,
+            Err(data_type) => {
+                warn!(
+                    "Rejecting packet {}: no handler for {} data",
+                    packet.id, data_type
+                );
+                let response = self.data_response(
+                    &packet.id,
+                    ProcessingStatus::InvalidInput,
+                    0,
+                    vec![format!("unsupported data type: {}", data_type)],
+                );
+                self.emit_data_response(&response).await;
+                return;
+            }
+        };
+
+        // Expensive payloads take up more of the node's capacity
+        let _load = LoadGuard::acquire(&self.current_load, packet.payload.load_cost());
+
+        // Wait for a free processing slot; queued packets still count as load
+        let Ok(_permit) = self.processing_slots.acquire().await else {
+            return;
+        };
+        let started = Instant::now();
+
+        // Packets taking longer than the advertised timeout are abandoned
+        let outcome = time::timeout(self.processing_timeout, handlers::process(&steps)).await;
+        if let Ok(Err(e)) = outcome {
+            warn!("Packet {} failed: {}", packet.id, e);
+            let response = self.data_response(
+                &packet.id,
+                ProcessingStatus::Failed,
+                started.elapsed().as_millis() as u64,
+                vec![e.0],
+            );
+            self.emit_data_response(&response).await;
+            return;
+        }
+        if outcome.is_err() {
+            warn!(
+                "Packet {} timed out after {:?}",
+                packet.id, self.processing_timeout
+            );
+            let response = self.data_response(
+                &packet.id,
+                ProcessingStatus::Timeout,
+                started.elapsed().as_millis() as u64,
+                vec![format!(
+                    "processing exceeded {} ms",
+                    self.processing_timeout.as_millis()
+                )],
+            );
+            self.emit_data_response(&response).await;
+            return;
+        }
+        // Injected response delay isn't processing time
+        let processing_time_ms = started.elapsed().as_millis() as u64;
+        self.response_delay.apply().await;
+
+        // Send processed notification
+        let processed_topic = topics::data_processed(&self.topic_prefix, &packet.id);
+        match self.payload_limit.encode(&packet) {
+            Ok(payload) => {
+                // Injected faults stand in for a lossy or slow network
+                let published = self
+                    .faults
+                    .publish(|| self.publish_data(&processed_topic, payload))
+                    .await;
+                if let Err(e) = published {
+                    error!("Error publishing processed data: {:?}", e);
+                } else {
+                    info!("Processed data sent on topic: {}", processed_topic);
+                }
+            }
+            // Reported as invalid input, e.g. for being over `MAX_PAYLOAD_BYTES`
+            Err(e) => {
+                error!("Refusing to publish packet {}: {}", packet.id, e);
+                let response = self.data_response(
+                    &packet.id,
+                    ProcessingStatus::InvalidInput,
+                    0,
+                    vec![e.to_string()],
+                );
+                self.emit_data_response(&response).await;
+                return;
+            }
+        }
+
+        self.processed_total.fetch_add(1, Ordering::Relaxed);
+        self.processed_since_heartbeat
+            .fetch_add(1, Ordering::Relaxed);
+
+        let response = self.data_response(
+            &packet.id,
+            ProcessingStatus::Processed,
+            processing_time_ms,
+            Vec::new(),
+        );
+        self.emit_data_response(&response).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), PoolError> {
+    /* Initialize logging: RUST_LOG filters, at info by default */
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+    info!("Starting MQTT Node...");
+
+    /* Load configuration: environment variables, then the config file */
+    let file = ConfigFile::from_env()?;
+    let config = NodeConfig::load(&file.node, config::env_var)?;
+    info!("Using configuration: {:?}", config);
+    if config.fault_injection.is_active() {
+        warn!(
+            "Fault injection enabled: {:?}; processed data will be dropped or delayed",
+            config.fault_injection
+        );
+    }
+
+    /* Initialize the master node */
+    let node = Node::new(&config, None).await.inspect_err(|e| {
+        if e.is_transient() {
+            error!(
+                "Broker still unreachable after {} retries: {}",
+                config.startup_retries, e
+            );
+        } else {
+            error!("Node failed to start: {}", e);
+        }
+    })?;
+
+    info!(
+        "Node initialized successfully with ID: {}",
+        node.node_info.node_id
+    );
+
+    /* SIGUSR1 toggles maintenance mode */
+    #[cfg(unix)]
+    {
+        let node = node.clone();
+        tokio::spawn(async move {
+            let mut toggles = match signal::unix::signal(signal::unix::SignalKind::user_defined1())
+            {
+                Ok(toggles) => toggles,
+                Err(err) => {
+                    error!("Failed to listen for SIGUSR1: {}", err);
+                    return;
+                }
+            };
+            while toggles.recv().await.is_some() {
+                node.set_maintenance(!node.maintenance.load(Ordering::Relaxed));
+            }
+        });
+    }
+
+    /* Run the node until a shutdown signal is received */
+    let reason = shutdown::wait_for_shutdown().await;
+    info!(
+        "Received shutdown signal ({}), initiating shutdown sequence...",
+        reason
+    );
+
+    /* Perform cleanup */
+    cleanup(
+        &node,
+        Duration::from_millis(config.drain_timeout_ms),
+        &reason,
+    )
+    .await;
+    info!("Node shut down successfully");
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct NodeConfig {
+    mqtt_host: String,
+    mqtt_port: u16,
+    /// Broker credentials, keep-alive and packet size. Data messages over
+    /// `MAX_PAYLOAD_BYTES` are refused rather than published.
+    mqtt: MqttSettings,
+    /// Capacity advertised to the orchestrator, used for routing and admission
+    node_capacity: u32,
+    /// Number of packets processed at once locally. Independent of
+    /// `node_capacity`: a node may advertise less than it can process, or more
+    /// (queueing the excess behind the processing semaphore). At least 1, as
+    /// a semaphore without permits would never process anything.
+    processing_concurrency: u32,
+    /// Window for merging outgoing log entries per client; 0 sends each one immediately
+    log_batch_window_ms: u64,
+    /// Artificial delay before publishing responses (testing only)
+    response_delay_ms: u64,
+    /// Random extra delay in `[0, jitter]` added on top of `response_delay_ms`
+    response_delay_jitter_ms: u64,
+    /// Default per-client packet limits per data type, per minute
+    type_quotas: HashMap<String, u32>,
+    /// Whether packet timestamps must increase per (client, data type)
+    timestamp_order: TimestampOrder,
+    /// Size limits for packet metadata and what to do when they're exceeded
+    metadata_limits: MetadataLimits,
+    /// Connection/subscribe attempts retried at startup before giving up
+    startup_retries: u32,
+    /// Initial delay between startup attempts, doubled after each failure
+    startup_backoff_ms: u64,
+    /// Prepended to the MQTT client id so broker logs show the host or pod
+    client_id_prefix: Option<String>,
+    /// Namespaces every topic so several pools can share a broker
+    topic_prefix: String,
+    /// How long shutdown waits for in-flight packets before going offline anyway
+    drain_timeout_ms: u64,
+    /// Data types advertised to the orchestrator and served to clients
+    supported_types: Vec<String>,
+    /// Most data types a single request may ask for
+    max_request_types: usize,
+    /// Largest `max_items` a single request may ask for
+    max_request_items: u32,
+    /// Most data requests waiting to be served before the lowest-priority
+    /// ones are turned away
+    max_queued_requests: usize,
+    /// Most packets batched into one response message
+    max_batch_size: u32,
+    /// How long a packet may take to process before it is answered with `Timeout`
+    processing_timeout_ms: u64,
+    /// Memory the received-packet dedup window may use, in MB
+    dedup_memory_budget_mb: usize,
+    /// Optional features advertised to the orchestrator and honored for clients
+    features: Vec<String>,
+    /// Port serving the `/health` readiness probe; no server when unset
+    health_port: Option<u16>,
+    /// Signs outgoing and verifies incoming messages when set
+    shared_secret: Option<SharedSecret>,
+    /// QoS of routing traffic and of everything else
+    qos: QosPolicy,
+    /// Simulated drops and latency on `data/processed` publishes
+    fault_injection: FaultInjector,
+    /// Generator of the payloads served for data requests
+    generator: GeneratorConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            mqtt_host: "localhost".to_string(),
+            mqtt_port: 1883,
+            mqtt: MqttSettings::default(),
+            node_capacity: 100,
+            processing_concurrency: 100,
+            log_batch_window_ms: 0,
+            response_delay_ms: 0,
+            response_delay_jitter_ms: 0,
+            type_quotas: HashMap::new(),
+            timestamp_order: TimestampOrder::Off,
+            metadata_limits: MetadataLimits::default(),
+            startup_retries: 10,
+            startup_backoff_ms: 500,
+            client_id_prefix: None,
+            topic_prefix: String::new(),
+            drain_timeout_ms: 30000,
+            supported_types: GENERATED_TYPES.iter().map(|t| t.to_string()).collect(),
+            max_request_types: 32,
+            max_request_items: 1000,
+            max_queued_requests: DEFAULT_MAX_QUEUED_REQUESTS,
+            max_batch_size: 100,
+            processing_timeout_ms: 5000,
+            dedup_memory_budget_mb: DEFAULT_DEDUP_MEMORY_BUDGET_MB,
+            features: NODE_FEATURE_CATALOG.iter().map(|f| f.to_string()).collect(),
+            health_port: None,
+            shared_secret: None,
+            qos: QosPolicy::default(),
+            fault_injection: FaultInjector::default(),
+            generator: GeneratorConfig::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Builds the config from `var` lookups, falling back to the `[node]`
+    /// table of the config file and then to the defaults. Invalid variables
+    /// are ignored; invalid file values are an error.
+    fn load(
+        file: &config::NodeConfig,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, PoolError> {
+        let defaults = NodeConfig::default();
+        let limits = defaults.metadata_limits;
+        let node_capacity = config::setting(&var, "NODE_CAPACITY", file.node_capacity)
+            .unwrap_or(defaults.node_capacity);
+        let type_quotas = config::list(
+            &var,
+            "TYPE_QUOTAS",
+            file.type_quotas.as_deref(),
+            parse_type_quota,
+        )?;
+        let supported_types = config::list(
+            &var,
+            "SUPPORTED_TYPES",
+            file.supported_types.as_deref(),
+            generated_type,
+        )?;
+        let fault_injection = match var("FAULT_INJECTION") {
+            Some(spec) => FaultInjector::parse(&spec),
+            None => match &file.fault_injection {
+                Some(spec) => FaultInjector::try_parse(spec)
+                    .ok_or_else(|| config::invalid("FAULT_INJECTION", spec))?,
+                None => FaultInjector::default(),
+            },
+        };
+        Ok(NodeConfig {
+            mqtt_host: config::setting(&var, "MQTT_HOST", file.mqtt_host.clone())
+                .unwrap_or(defaults.mqtt_host),
+            mqtt_port: config::setting(&var, "MQTT_PORT", file.mqtt_port)
+                .unwrap_or(defaults.mqtt_port),
+            mqtt: MqttSettings::load(&file.mqtt(), &var)?,
+            node_capacity,
+            // Default to processing as many packets at once as we advertise
+            processing_concurrency: config::bounded(
+                &var,
+                "PROCESSING_CONCURRENCY",
+                file.processing_concurrency,
+                1..=u32::MAX,
+            )?
+            .unwrap_or(node_capacity.max(1)),
+            log_batch_window_ms: config::setting(
+                &var,
+                "LOG_BATCH_WINDOW_MS",
+                file.log_batch_window_ms,
+            )
+            .unwrap_or(defaults.log_batch_window_ms),
+            response_delay_ms: config::setting(&var, "RESPONSE_DELAY_MS", file.response_delay_ms)
+                .unwrap_or(defaults.response_delay_ms),
+            response_delay_jitter_ms: config::setting(
+                &var,
+                "RESPONSE_DELAY_JITTER_MS",
+                file.response_delay_jitter_ms,
+            )
+            .unwrap_or(defaults.response_delay_jitter_ms),
+            type_quotas: type_quotas
+                .map(HashMap::from_iter)
+                .unwrap_or(defaults.type_quotas),
+            timestamp_order: config::parsed(
+                &var,
+                "TIMESTAMP_ORDER",
+                file.timestamp_order.as_deref(),
+                TimestampOrder::parse,
+            )?
+            .unwrap_or(defaults.timestamp_order),
+            metadata_limits: MetadataLimits {
+                max_key_len: config::setting(
+                    &var,
+                    "METADATA_MAX_KEY_LEN",
+                    file.metadata_max_key_len,
+                )
+                .unwrap_or(limits.max_key_len),
+                max_value_len: config::setting(
+                    &var,
+                    "METADATA_MAX_VALUE_LEN",
+                    file.metadata_max_value_len,
+                )
+                .unwrap_or(limits.max_value_len),
+                max_entries: config::setting(
+                    &var,
+                    "METADATA_MAX_ENTRIES",
+                    file.metadata_max_entries,
+                )
+                .unwrap_or(limits.max_entries),
+                policy: config::parsed(
+                    &var,
+                    "METADATA_POLICY",
+                    file.metadata_policy.as_deref(),
+                    OversizePolicy::parse,
+                )?
+                .unwrap_or(limits.policy),
+            },
+            startup_retries: config::setting(&var, "STARTUP_RETRIES", file.startup_retries)
+                .unwrap_or(defaults.startup_retries),
+            startup_backoff_ms: config::setting(
+                &var,
+                "STARTUP_BACKOFF_MS",
+                file.startup_backoff_ms,
+            )
+            .unwrap_or(defaults.startup_backoff_ms),
+            client_id_prefix: config::setting(
+                &var,
+                "CLIENT_ID_PREFIX",
+                file.client_id_prefix.clone(),
+            )
+            .filter(|prefix| !prefix.is_empty()),
+            topic_prefix: config::setting(&var, "TOPIC_PREFIX", file.topic_prefix.clone())
+                .unwrap_or_default()
+                .trim_matches('/')
+                .to_string(),
+            drain_timeout_ms: config::setting(&var, "DRAIN_TIMEOUT_MS", file.drain_timeout_ms)
+                .unwrap_or(defaults.drain_timeout_ms),
+            supported_types: supported_types
+                .map(|requested| {
+                    // An empty list means every type we can produce
+                    GENERATED_TYPES
+                        .iter()
+                        .filter(|t| requested.is_empty() || requested.iter().any(|r| r == *t))
+                        .map(|t| t.to_string())
+                        .collect()
+                })
+                .unwrap_or(defaults.supported_types),
+            max_request_types: config::setting(&var, "MAX_REQUEST_TYPES", file.max_request_types)
+                .unwrap_or(defaults.max_request_types),
+            max_request_items: config::setting(&var, "MAX_REQUEST_ITEMS", file.max_request_items)
+                .unwrap_or(defaults.max_request_items),
+            max_queued_requests: config::bounded(
+                &var,
+                "MAX_QUEUED_REQUESTS",
+                file.max_queued_requests,
+                1..=usize::MAX,
+            )?
+            .unwrap_or(defaults.max_queued_requests),
+            max_batch_size: config::setting(&var, "MAX_BATCH_SIZE", file.max_batch_size)
+                .unwrap_or(defaults.max_batch_size),
+            processing_timeout_ms: config::setting(
+                &var,
+                "PROCESSING_TIMEOUT_MS",
+                file.processing_timeout_ms,
+            )
+            .unwrap_or(defaults.processing_timeout_ms),
+            dedup_memory_budget_mb: config::setting(
+                &var,
+                "DEDUP_MEMORY_BUDGET_MB",
+                file.dedup_memory_budget_mb,
+            )
+            .unwrap_or(defaults.dedup_memory_budget_mb),
+            // Unlike `SUPPORTED_TYPES`, an empty list means no features
+            features: config::list(
+                &var,
+                "NODE_FEATURES",
+                file.node_features.as_deref(),
+                node_feature,
+            )?
+            .unwrap_or(defaults.features),
+            health_port: config::setting(&var, "HEALTH_PORT", file.health_port),
+            shared_secret: SharedSecret::load(file.shared_secret.as_deref(), &var),
+            qos: QosPolicy::load(file.mqtt_qos, file.routing_qos, &var)?,
+            fault_injection,
+            generator: GeneratorConfig::load(file, &var)?,
+        })
+    }
+}
+
+async fn cleanup(node: &Node, drain_timeout: Duration, reason: &str) {
+    info!("Starting cleanup process...");
+    node.begin_drain(drain_timeout).await;
+
+    // Publish offline status where the orchestrator listens for heartbeats
+    if let Err(e) = shutdown::announce_offline(
+        &node.client,
+        node.heartbeat_topic(),
+        node.qos.default,
+        &node.node_info,
+        NodeStatus::Inactive,
+        reason,
+    )
+    .await
+    {
+        warn!("Failed to publish offline status: {}", e);
+    }
+    info!("Cleanup completed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_common::{PoolControl, MAX_BATCH_DEPTH};
+    use rumqttc::{MqttOptions, QoS};
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    async fn test_node_config() {
+        let config = NodeConfig {
+            mqtt_host: "localhost".to_string(),
+            mqtt_port: 1883,
+            node_capacity: 100,
+            ..NodeConfig::default()
+        };
+        assert_eq!(config.mqtt_host, "localhost");
+        assert_eq!(config.mqtt_port, 1883);
+        assert_eq!(config.node_capacity, 100);
+    }
+
+    #[test]
+    fn test_config_file_values_apply_unless_overridden() {
+        let file = ConfigFile::parse(
+            r#"
+            [node]
+            node_capacity = 40
+            supported_types = ["text", "sensor"]
+            type_quotas = ["image:5"]
+            mqtt_username = "node"
+            mqtt_password = "secret"
+            mqtt_keepalive_secs = 20
+            "#,
+        )
+        .unwrap();
+        let env = |key: &str| (key == "NODE_CAPACITY").then(|| "60".to_string());
+        let config = NodeConfig::load(&file.node, env).unwrap();
+        assert_eq!(config.node_capacity, 60);
+        assert_eq!(config.processing_concurrency, 60);
+        assert_eq!(config.supported_types, vec!["sensor", "text"]);
+        assert_eq!(
+            config.type_quotas,
+            HashMap::from([("image".to_string(), 5)])
+        );
+        assert_eq!(config.mqtt_port, 1883);
+        assert_eq!(config.mqtt.username.as_deref(), Some("node"));
+        assert_eq!(config.mqtt.password.as_deref(), Some("secret"));
+        assert_eq!(config.mqtt.keep_alive_secs, 20);
+    }
+
+    #[test]
+    fn test_processing_concurrency_is_at_least_one() {
+        let env = |key: &str| (key == "PROCESSING_CONCURRENCY").then(|| "0".to_string());
+        let config = NodeConfig::load(&config::NodeConfig::default(), env).unwrap();
+        assert_eq!(config.processing_concurrency, 1);
+
+        let env = |key: &str| (key == "NODE_CAPACITY").then(|| "0".to_string());
+        let config = NodeConfig::load(&config::NodeConfig::default(), env).unwrap();
+        assert_eq!(config.processing_concurrency, 1);
+
+        let file = config::NodeConfig {
+            processing_concurrency: Some(0),
+            ..config::NodeConfig::default()
+        };
+        assert!(NodeConfig::load(&file, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config_file_values_are_errors() {
+        for table in [
+            "timestamp_order = \"sometimes\"",
+            "supported_types = [\"text\", \"bogus\"]",
+            "type_quotas = [\"image\"]",
+            "fault_injection = \"drop:often\"",
+            "mqtt_qos = 3",
+            "mqtt_keepalive_secs = 1",
+        ] {
+            let file = ConfigFile::parse(&format!("[node]\n{}", table)).unwrap();
+            assert!(NodeConfig::load(&file.node, |_| None).is_err(), "{}", table);
+        }
+
+        // The same mistakes in the environment fall back to the defaults
+        let env = |key: &str| match key {
+            "TIMESTAMP_ORDER" => Some("sometimes".to_string()),
+            "SUPPORTED_TYPES" => Some(" text, bogus ,sensor".to_string()),
+            _ => None,
+        };
+        let config = NodeConfig::load(&config::NodeConfig::default(), env).unwrap();
+        assert_eq!(config.timestamp_order, TimestampOrder::Off);
+        assert_eq!(config.supported_types, vec!["sensor", "text"]);
+    }
+
+    fn test_config() -> NodeConfig {
+        NodeConfig::default()
+    }
+
+    /// Builds a node whose publishes queue up in the returned event loop
+    fn test_node(config: &NodeConfig) -> (Node, EventLoop) {
+        let mqtt_options = MqttOptions::new("test-node", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 100);
+        let mut node_info = NodeInfo::new(NodeType::Node, config.node_capacity);
+        node_info.node_id = TEST_NODE_ID.to_string();
+        (Node::build(node_info, client, config, None), eventloop)
+    }
+
+    const TEST_NODE_ID: &str = "test-node";
+
+    /// Drains everything the node has published so far
+    fn published(eventloop: &mut EventLoop) -> Vec<rumqttc::Publish> {
+        eventloop.clean();
+        std::mem::take(&mut eventloop.pending)
+            .into_iter()
+            .filter_map(|request| match request {
+                rumqttc::Request::Publish(publish) => Some(publish),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A routing request naming the test node, so it answers and reserves
+    fn routing_request(client_id: &str) -> RoutingRequest {
+        RoutingRequest {
+            client_id: client_id.to_string(),
+            data_type: vec!["text".to_string()],
+            node_info: NodeInfo::new(NodeType::Client, 10),
+            preferred_node: Some(TEST_NODE_ID.to_string()),
+            timestamp: 0,
+            request_id: None,
+            fan_out: None,
+            schema_version: PROTOCOL_VERSION,
+            logical_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_delay_applied_before_publish() {
+        let config = NodeConfig {
+            response_delay_ms: 50,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+
+        let started = Instant::now();
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic, "routing/response/client-1");
+    }
+
+    #[tokio::test]
+    async fn test_configured_qos_used_per_message_class() {
+        let config = NodeConfig {
+            qos: QosPolicy {
+                default: QoS::AtMostOnce,
+                routing: QoS::ExactlyOnce,
+            },
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        node.handle_data_packet("client-1", &packet(DataPayload::Number(1.0)))
+            .await;
+
+        let qos: HashMap<String, QoS> = published(&mut eventloop)
+            .into_iter()
+            .map(|publish| (publish.topic, publish.qos))
+            .collect();
+        assert_eq!(qos["routing/response/client-1"], QoS::ExactlyOnce);
+        let (_, data_qos) = qos
+            .iter()
+            .find(|(topic, _)| topic.starts_with("data/processed/"))
+            .unwrap();
+        assert_eq!(*data_qos, QoS::AtMostOnce);
+        let (_, status_qos) = qos
+            .iter()
+            .find(|(topic, _)| topic.starts_with("data/response/"))
+            .unwrap();
+        assert_eq!(*status_qos, QoS::AtMostOnce);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_reports_accepted_clients() {
+        let (node, _eventloop) = test_node(&test_config());
+        let reported = |heartbeat: NodeInfo| {
+            heartbeat
+                .metadata
+                .get(ROUTED_CLIENTS_METADATA_KEY)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(reported(node.heartbeat().await), "");
+
+        node.handle_routing_request(&routing_request("client-b"))
+            .await;
+        node.handle_routing_request(&routing_request("client-a"))
+            .await;
+        assert_eq!(reported(node.heartbeat().await), "client-a,client-b");
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_metadata_is_held_to_the_limits() {
+        let config = NodeConfig {
+            metadata_limits: MetadataLimits {
+                max_value_len: 10,
+                ..MetadataLimits::default()
+            },
+            ..test_config()
+        };
+        let (mut node, _eventloop) = test_node(&config);
+        node.node_info.metadata.insert(
+            "region".to_string(),
+            "eu-west-1-availability-zone-a".to_string(),
+        );
+        node.handle_routing_request(&routing_request("client-a"))
+            .await;
+        node.handle_routing_request(&routing_request("client-b"))
+            .await;
+        let heartbeat = node.heartbeat().await;
+        assert_eq!(heartbeat.metadata["region"], "eu-west-1-");
+        // The routed client list is never cut short
+        assert_eq!(
+            heartbeat.metadata[ROUTED_CLIENTS_METADATA_KEY],
+            "client-a,client-b"
+        );
+    }
+
+    fn data_request(client_id: &str, data_types: &[&str]) -> DataRequest {
+        DataRequest {
+            request_id: Uuid::new_v4().to_string(),
+            client_id: client_id.to_string(),
+            timestamp: 0,
+            data_types: data_types.iter().map(|t| t.to_string()).collect(),
+            only_if_changed: None,
+            idempotency_key: None,
+            compression_level: None,
+            fulfillment: Fulfillment::BestEffort,
+            max_items: None,
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn test_request_queue_serves_higher_priority_first() {
+        let mut queue = RequestQueue::default();
+        let requests: Vec<DataRequest> = [
+            ("log", 0),
+            ("log", 0),
+            ("sensor", 9),
+            ("text", 3),
+            ("log", 0),
+        ]
+        .into_iter()
+        .map(|(data_type, priority)| DataRequest {
+            priority,
+            ..data_request("client-1", &[data_type])
+        })
+        .collect();
+        let ids: Vec<String> = requests.iter().map(|r| r.request_id.clone()).collect();
+        for request in requests {
+            queue.push(request);
+        }
+
+        let mut order = Vec::new();
+        while let Some(request) = queue.pop() {
+            order.push(request.request_id);
+        }
+        // Urgent sensor first, then text, then the logs in arrival order
+        assert_eq!(
+            order,
+            vec![
+                ids[2].clone(),
+                ids[3].clone(),
+                ids[0].clone(),
+                ids[1].clone(),
+                ids[4].clone(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_request_queue_sheds_the_lowest_priority() {
+        let mut queue = RequestQueue::new(2);
+        let low = data_request("client-1", &["log"]);
+        assert!(queue.push(low.clone()).is_none());
+        assert!(queue
+            .push(DataRequest {
+                priority: 5,
+                ..data_request("client-2", &["text"])
+            })
+            .is_none());
+
+        // A more urgent request displaces the lowest-priority one...
+        let shed = queue.push(DataRequest {
+            priority: 9,
+            ..data_request("client-3", &["sensor"])
+        });
+        assert_eq!(shed.unwrap().request_id, low.request_id);
+        // ...while one no more urgent than anything queued is turned away
+        let late = data_request("client-4", &["log"]);
+        assert_eq!(
+            queue.push(late.clone()).unwrap().request_id,
+            late.request_id
+        );
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn test_one_client_cannot_fill_the_request_queue() {
+        let mut queue = RequestQueue::new(MAX_QUEUED_PER_CLIENT * 2);
+        for _ in 0..MAX_QUEUED_PER_CLIENT {
+            assert!(queue
+                .push(DataRequest {
+                    priority: u8::MAX,
+                    ..data_request("greedy", &["text"])
+                })
+                .is_none());
+        }
+        assert!(queue
+            .push(DataRequest {
+                priority: u8::MAX,
+                ..data_request("greedy", &["text"])
+            })
+            .is_some());
+        assert!(queue.push(data_request("client-1", &["text"])).is_none());
+        assert_eq!(queue.len(), MAX_QUEUED_PER_CLIENT + 1);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_data_request_logs_are_correlated_by_request_span() {
+        let (node, _eventloop) = test_node(&test_config());
+        let request = DataRequest {
+            request_id: "req-42".to_string(),
+            ..data_request("client-1", &["text"])
+        };
+        node.handle_data_request(&request).await;
+
+        let span = "handle_data_request{client_id=client-1 request_id=req-42}";
+        logs_assert(|lines| {
+            lines
+                .iter()
+                .any(|line| line.contains(span) && line.contains("Processing data request"))
+                .then_some(())
+                .ok_or_else(|| format!("no request log within {}", span))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_retries_return_identical_packets() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let request = DataRequest {
+            idempotency_key: Some("retry-1".to_string()),
+            ..data_request("client-1", &["text", "sensor"])
+        };
+
+        node.handle_data_request(&request).await;
+        let first = published(&mut eventloop);
+        node.handle_data_request(&request).await;
+        let second = published(&mut eventloop);
+
+        // Both packets go out in one batch
+        assert_eq!(first.len(), 1);
+        let payloads = |publishes: &[rumqttc::Publish]| {
+            publishes
+                .iter()
+                .map(|p| p.payload.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(payloads(&first), payloads(&second));
+
+        // Without a key, packets are regenerated
+        let fresh = data_request("client-1", &["text"]);
+        node.handle_data_request(&fresh).await;
+        node.handle_data_request(&fresh).await;
+        let regenerated = published(&mut eventloop);
+        assert_ne!(regenerated[0].payload, regenerated[1].payload);
+    }
+
+    fn summary_and_data_types(
+        publishes: &[rumqttc::Publish],
+    ) -> (Option<FulfillmentSummary>, Vec<String>) {
+        let summary = publishes
+            .iter()
+            .find(|p| p.topic.starts_with("data/summary/"))
+            .map(|p| serde_json::from_slice(&p.payload).unwrap());
+        let data_types = publishes
+            .iter()
+            .filter(|p| p.topic.starts_with("data/response/"))
+            .flat_map(|p| mqtt_common::decode_data_packets(&p.payload).unwrap())
+            .map(|packet| packet.data_type)
+            .collect();
+        (summary, data_types)
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_serves_what_it_can_and_reports_skips() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.handle_data_request(&data_request("client-1", &["text", "bogus"]))
+            .await;
+
+        let (summary, data_types) = summary_and_data_types(&published(&mut eventloop));
+        assert_eq!(data_types, vec!["text"]);
+        let summary = summary.expect("skipped types should be summarized");
+        assert_eq!(summary.fulfilled, vec!["text"]);
+        assert_eq!(
+            summary.skipped,
+            vec![SkippedType {
+                data_type: "bogus".to_string(),
+                reason: SkipReason::Unsupported,
+            }]
+        );
+        assert!(summary.error.is_none());
+
+        // Fully served requests don't get a summary
+        node.handle_data_request(&data_request("client-1", &["text"]))
+            .await;
+        let (summary, _) = summary_and_data_types(&published(&mut eventloop));
+        assert!(summary.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_strict_rejects_partially_fulfillable_request() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let request = DataRequest {
+            fulfillment: Fulfillment::Strict,
+            ..data_request("client-1", &["text", "bogus"])
+        };
+        node.handle_data_request(&request).await;
+
+        let (summary, data_types) = summary_and_data_types(&published(&mut eventloop));
+        assert!(data_types.is_empty());
+        let summary = summary.expect("rejection should be summarized");
+        assert!(summary.fulfilled.is_empty());
+        assert_eq!(summary.skipped.len(), 1);
+        assert!(summary.error.unwrap().contains("bogus"));
+    }
+
+    #[tokio::test]
+    async fn test_strict_rejection_spends_no_quota() {
+        let config = NodeConfig {
+            type_quotas: HashMap::from([("image".to_string(), 1)]),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let strict = |types: &[&str]| DataRequest {
+            fulfillment: Fulfillment::Strict,
+            ..data_request("client-1", types)
+        };
+
+        node.handle_data_request(&strict(&["image", "bogus"])).await;
+        let (_, data_types) = summary_and_data_types(&published(&mut eventloop));
+        assert!(data_types.is_empty());
+
+        node.handle_data_request(&strict(&["image"])).await;
+        let (summary, data_types) = summary_and_data_types(&published(&mut eventloop));
+        assert_eq!(data_types, vec!["image"]);
+        assert!(summary.is_none());
+
+        node.handle_data_request(&strict(&["image"])).await;
+        let (summary, _) = summary_and_data_types(&published(&mut eventloop));
+        assert_eq!(summary.unwrap().skipped[0].reason, SkipReason::OverQuota);
+    }
+
+    #[tokio::test]
+    async fn test_log_entries_within_window_are_batched() {
+        let config = NodeConfig {
+            log_batch_window_ms: 50,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+
+        for _ in 0..3 {
+            node.handle_data_request(&data_request("client-1", &["log", "text"]))
+                .await;
+        }
+        // Only the non-log packets go out right away
+        assert_eq!(published(&mut eventloop).len(), 3);
+
+        time::sleep(Duration::from_millis(100)).await;
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let batch: DataPacket = serde_json::from_slice(&published[0].payload).unwrap();
+        match batch.payload {
+            DataPayload::LogBatch { entries } => assert_eq!(entries.len(), 3),
+            other => panic!("expected a log batch, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_packets_are_batched_up_to_max_batch_size() {
+        let config = NodeConfig {
+            max_batch_size: 2,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        node.handle_data_request(&data_request("client-1", &["text", "sensor", "image"]))
+            .await;
+
+        let batches: Vec<Vec<DataPacket>> = published(&mut eventloop)
+            .iter()
+            .map(|p| mqtt_common::decode_data_packets(&p.payload).unwrap())
+            .collect();
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 1]);
+        let data_types: Vec<&str> = batches
+            .iter()
+            .flatten()
+            .map(|packet| packet.data_type.as_str())
+            .collect();
+        assert_eq!(data_types, vec!["text", "sensor", "image"]);
+    }
+
+    #[tokio::test]
+    async fn test_max_compression_sends_smaller_payloads() {
+        let (node, mut eventloop) = test_node(&test_config());
+
+        let mut sizes = Vec::new();
+        for level in [0, MAX_COMPRESSION_LEVEL] {
+            let request = DataRequest {
+                compression_level: Some(level),
+                ..data_request("client-1", &["image", "log"])
+            };
+            node.handle_data_request(&request).await;
+            let published = published(&mut eventloop);
+            sizes.push(published.iter().map(|p| p.payload.len()).sum::<usize>());
+
+            let packets: Vec<DataPacket> = published
+                .iter()
+                .flat_map(|p| mqtt_common::decode_data_packets(&p.payload).unwrap())
+                .collect();
+            assert_eq!(packets.len(), 2);
+            let encoding = packets[0].metadata.get("encoding").map(String::as_str);
+            assert_eq!(encoding, (level > 0).then_some("deflate"));
+        }
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[tokio::test]
+    async fn test_data_responses_use_the_format_the_client_advertised() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let request = data_request("client-1", &["image"]);
+
+        // Clients that advertise nothing only read JSON
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let sent = published(&mut eventloop);
+        let response: RoutingResponse = serde_json::from_slice(&sent[0].payload).unwrap();
+        assert_eq!(
+            response.configuration.unwrap().wire_format,
+            WireFormat::Json
+        );
+        node.handle_data_request(&request).await;
+        assert_eq!(published(&mut eventloop)[0].payload[0], b'{');
+
+        let mut routing = routing_request("client-1");
+        routing.node_info.metadata.insert(
+            WIRE_FORMATS_METADATA_KEY.to_string(),
+            WireFormat::join(&WIRE_FORMATS),
+        );
+        node.handle_routing_request(&routing).await;
+        let sent = published(&mut eventloop);
+        let response: RoutingResponse = serde_json::from_slice(&sent[0].payload).unwrap();
+        assert_eq!(
+            response.configuration.unwrap().wire_format,
+            WireFormat::Bincode
+        );
+        node.handle_data_request(&request).await;
+        let payload = published(&mut eventloop).remove(0).payload;
+        assert_eq!(payload[0], WireFormat::Bincode.tag());
+        let packets = mqtt_common::decode_data_packets(&payload).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data_type, "image");
+    }
+
+    #[tokio::test]
+    async fn test_node_without_compression_feature_sends_uncompressed() {
+        let config = NodeConfig {
+            features: Vec::new(),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        assert!(node.node_info.features.is_empty());
+
+        node.handle_data_request(&DataRequest {
+            compression_level: Some(MAX_COMPRESSION_LEVEL),
+            ..data_request("client-1", &["log"])
+        })
+        .await;
+        let packets = published(&mut eventloop);
+        let packet: DataPacket = mqtt_common::decode_frame(&packets[0].payload).unwrap();
+        assert!(!packet.metadata.contains_key("encoding"));
+
+        // Clients are told the feature is unavailable
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let published = published(&mut eventloop);
+        let response: RoutingResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        let configuration = response.configuration.unwrap();
+        assert!(!configuration.supports(FEATURE_COMPRESSION));
+        assert_eq!(configuration.compression_level, 0);
+    }
+
+    #[tokio::test]
+    async fn test_probe_is_acked_with_current_state() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.current_load.store(7, Ordering::Relaxed);
+
+        let probe = ProbeRequest {
+            probe_id: "probe-1".to_string(),
+            sent_at_ms: 1234,
+        };
+        node.handle_probe(&probe).await;
+
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        assert_eq!(
+            published[0].topic,
+            format!("probe/ack/{}", node.node_info.node_id)
+        );
+        let ack: ProbeAck = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(ack.probe_id, "probe-1");
+        assert_eq!(ack.current_load, 7);
+        assert_eq!(ack.sent_at_ms, 1234);
+    }
+
+    fn packet(payload: DataPayload) -> DataPacket {
+        DataPacket {
+            id: Uuid::new_v4().to_string(),
+            timestamp: "0".to_string(),
+            data_type: "number".to_string(),
+            payload,
+            metadata: HashMap::new(),
+            schema_version: PROTOCOL_VERSION,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_finite_packets_are_rejected() {
+        let (node, mut eventloop) = test_node(&test_config());
+
+        for value in [f64::NAN, f64::INFINITY] {
+            node.handle_data_packet("client-1", &packet(DataPayload::Number(value)))
+                .await;
+
+            let published = published(&mut eventloop);
+            assert_eq!(published.len(), 1);
+            assert!(published[0].topic.starts_with("data/response/"));
+            let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+            assert_eq!(response.status, ProcessingStatus::InvalidInput);
+            assert_eq!(response.errors, vec!["non-finite value".to_string()]);
+        }
+        assert_eq!(node.current_load.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_load_accounting_matches_payload_cost() {
+        let (node, _eventloop) = test_node(&test_config());
+        let payloads = [
+            (DataPayload::Text("hello".to_string()), 1),
+            (DataPayload::Number(1.0), 1),
+            (
+                DataPayload::Coordinates {
+                    x: 1.0,
+                    y: 2.0,
+                    z: 3.0,
+                    frame: None,
+                },
+                2,
+            ),
+            (
+                DataPayload::SensorData {
+                    sensor_id: "temp-1".to_string(),
+                    temperature: 20.0,
+                    humidity: 40.0,
+                    pressure: 1000.0,
+                    units: None,
+                },
+                2,
+            ),
+            (
+                DataPayload::ImageData {
+                    width: 2,
+                    height: 2,
+                    format: "PNG".to_string(),
+                    data: vec![0; 16],
+                },
+                5,
+            ),
+            (
+                DataPayload::LogEntry {
+                    level: "INFO".to_string(),
+                    message: "started".to_string(),
+                    timestamp: "0".to_string(),
+                },
+                1,
+            ),
+            (
+                DataPayload::LogBatch {
+                    entries: vec![LogEntry {
+                        level: "INFO".to_string(),
+                        message: "started".to_string(),
+                        timestamp: "0".to_string(),
+                    }],
+                },
+                1,
+            ),
+        ];
+
+        for (payload, cost) in payloads {
+            assert_eq!(payload.load_cost(), cost, "{:?}", payload);
+            let processing = {
+                let node = node.clone();
+                let packet = packet(payload);
+                tokio::spawn(async move { node.handle_data_packet("client-1", &packet).await })
+            };
+            while node.current_load.load(Ordering::Relaxed) == 0 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(node.current_load.load(Ordering::Relaxed), cost);
+            processing.await.unwrap();
+            assert_eq!(node.current_load.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_is_released_when_processing_panics_or_is_cancelled() {
+        let load = Arc::new(AtomicU32::new(0));
+        let task_load = Arc::clone(&load);
+        let result = tokio::spawn(async move {
+            let _load = LoadGuard::acquire(&task_load, 1);
+            assert_eq!(task_load.load(Ordering::Relaxed), 1);
+            panic!("processing failed");
+        })
+        .await;
+        assert!(result.unwrap_err().is_panic());
+        assert_eq!(load.load(Ordering::Relaxed), 0);
+
+        // Aborting a packet mid-processing releases its load too
+        let (node, _eventloop) = test_node(&test_config());
+        let processing = {
+            let node = node.clone();
+            tokio::spawn(async move {
+                node.handle_data_packet("client-1", &packet(DataPayload::Number(1.0)))
+                    .await;
+            })
+        };
+        while node.current_load.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        processing.abort();
+        assert!(processing.await.unwrap_err().is_cancelled());
+        assert_eq!(node.current_load.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_type_quota_throttles_only_that_type() {
+        let config = NodeConfig {
+            type_quotas: HashMap::from([("image".to_string(), 5)]),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let request = data_request("client-1", &["image", "text"]);
+
+        let data_types = |publishes: Vec<rumqttc::Publish>| {
+            publishes
+                .iter()
+                .filter(|p| p.topic.starts_with("data/response/"))
+                .flat_map(|p| mqtt_common::decode_data_packets(&p.payload).unwrap())
+                .map(|packet| packet.data_type)
+                .collect::<Vec<_>>()
+        };
+
+        for _ in 0..5 {
+            node.handle_data_request(&request).await;
+            assert_eq!(data_types(published(&mut eventloop)), vec!["image", "text"]);
+        }
+        node.handle_data_request(&request).await;
+        assert_eq!(data_types(published(&mut eventloop)), vec!["text"]);
+
+        // Quotas are tracked per client
+        node.handle_data_request(&data_request("client-2", &["image"]))
+            .await;
+        assert_eq!(data_types(published(&mut eventloop)), vec!["image"]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_load_and_rejects_routing() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.current_load.store(2, Ordering::Relaxed);
+
+        let draining = node.clone();
+        let drain = tokio::spawn(async move { draining.begin_drain(Duration::from_secs(5)).await });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!drain.is_finished());
+
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Rejected);
+        assert_eq!(response.rejection_reason.as_deref(), Some("draining"));
+
+        node.current_load.store(0, Ordering::Relaxed);
+        assert!(drain.await.unwrap());
+
+        // Load that never clears is given up on after the timeout
+        node.current_load.store(1, Ordering::Relaxed);
+        assert!(!node.begin_drain(Duration::from_millis(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_rejects_routing_and_is_advertised() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.set_maintenance(true);
+        assert_eq!(node.heartbeat().await.status, NodeStatus::Maintenance);
+
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Rejected);
+        assert_eq!(response.rejection_reason.as_deref(), Some("in maintenance"));
+        assert!(response.configuration.is_none());
+
+        node.set_maintenance(false);
+        assert_eq!(node.heartbeat().await.status, NodeStatus::Active);
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_pool_drain_and_resume_reach_the_node() {
+        let (node, _eventloop) = test_node(&test_config());
+        let topic = topics::control("", TEST_NODE_ID);
+        // The payloads the orchestrator publishes for pool commands
+        let drain =
+            serde_json::to_vec(&PoolControl::PoolDrain { drain_nodes: true }.node_control())
+                .unwrap();
+        let resume = serde_json::to_vec(&PoolControl::PoolResume.node_control()).unwrap();
+
+        node.handle_publish(&topic, &drain).await;
+        assert_eq!(node.status(), NodeStatus::Maintenance);
+        node.handle_publish(&topic, &resume).await;
+        assert_eq!(node.status(), NodeStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_control_changes_routing_limit() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.current_load.store(5, Ordering::Relaxed);
+        let topic = format!("control/{}/capacity", node.node_info.node_id);
+        let route = |client_id: &'static str| {
+            let node = node.clone();
+            async move {
+                node.handle_routing_request(&routing_request(client_id))
+                    .await
+            }
+        };
+
+        node.handle_control(&topic, br#"{"capacity": 5}"#);
+        assert_eq!(node.heartbeat().await.capacity, 5);
+        route("client-1").await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Rejected);
+
+        // Below the current load: ignored
+        node.handle_control(&topic, br#"{"capacity": 2}"#);
+        assert_eq!(node.heartbeat().await.capacity, 5);
+
+        node.handle_control(&topic, br#"{"capacity": 20}"#);
+        assert_eq!(node.heartbeat().await.capacity, 20);
+        route("client-2").await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_only_the_assigned_node_holds_a_clients_slot() {
+        let (first, mut first_events) = test_node(&test_config());
+        let (client, _second_events) =
+            AsyncClient::new(MqttOptions::new("test-node-2", "localhost", 1883), 100);
+        let mut info = NodeInfo::new(NodeType::Node, 10);
+        info.node_id = "test-node-2".to_string();
+        let second = Node::build(info, client, &test_config(), None);
+        let reserved = |node: &Node| node.reserved_slots.load(Ordering::SeqCst);
+
+        // A request naming the other node goes unanswered here
+        let request = RoutingRequest {
+            preferred_node: Some("test-node-2".to_string()),
+            ..routing_request("client-1")
+        };
+        for node in [&first, &second] {
+            node.handle_routing_request(&request).await;
+        }
+        assert!(published(&mut first_events).is_empty());
+        assert_eq!((reserved(&first), reserved(&second)), (0, 1));
+
+        // Without a preference, only the orchestrator's pick reserves
+        let request = RoutingRequest {
+            preferred_node: None,
+            ..routing_request("client-2")
+        };
+        for node in [&first, &second] {
+            node.handle_routing_request(&request).await;
+        }
+        assert_eq!((reserved(&first), reserved(&second)), (0, 1));
+        let assign = |node_id: &str| RoutingResponse {
+            node_id: node_id.to_string(),
+            client_id: "client-2".to_string(),
+            status: RoutingStatus::Accepted,
+            rejection_reason: None,
+            configuration: None,
+            timestamp: 0,
+            request_id: Some("req-1".to_string()),
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        };
+        for node in [&first, &second] {
+            node.handle_assignment(&assign(TEST_NODE_ID)).await;
+        }
+        assert_eq!((reserved(&first), reserved(&second)), (1, 1));
+
+        // Moving the client frees its slot on the node it left
+        for node in [&first, &second] {
+            node.handle_assignment(&assign("test-node-2")).await;
+        }
+        assert_eq!((reserved(&first), reserved(&second)), (0, 2));
+
+        // So does revoking its routing
+        let revoked = RoutingResponse {
+            status: RoutingStatus::Rejected,
+            request_id: None,
+            ..assign("test-node-2")
+        };
+        for node in [&first, &second] {
+            node.handle_assignment(&revoked).await;
+        }
+        assert_eq!((reserved(&first), reserved(&second)), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_routings_never_exceed_capacity() {
+        let config = NodeConfig {
+            node_capacity: 3,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let routings: Vec<_> = (0..10)
+            .map(|i| {
+                let node = node.clone();
+                tokio::spawn(async move {
+                    node.handle_routing_request(&routing_request(&format!("client-{}", i)))
+                        .await
+                })
+            })
+            .collect();
+        for routing in routings {
+            routing.await.unwrap();
+        }
+
+        let accepted = |eventloop: &mut EventLoop| {
+            published(eventloop)
+                .iter()
+                .filter(|publish| {
+                    serde_json::from_slice::<RoutingResponse>(&publish.payload)
+                        .is_ok_and(|response| response.status == RoutingStatus::Accepted)
+                })
+                .count()
+        };
+        assert_eq!(accepted(&mut eventloop), 3);
+        assert_eq!(node.reserved_slots.load(Ordering::SeqCst), 3);
+
+        // A client going offline frees its slot for the next one
+        let routed = node
+            .routed_clients
+            .lock()
+            .await
+            .keys()
+            .next()
+            .cloned()
+            .unwrap();
+        node.handle_client_heartbeat(&routed, &NodeStatus::Offline)
+            .await;
+        assert_eq!(node.reserved_slots.load(Ordering::SeqCst), 2);
+        node.handle_routing_request(&routing_request("client-late"))
+            .await;
+        assert_eq!(accepted(&mut eventloop), 1);
+
+        // Silent clients time out
+        node.expire_reservations(Duration::ZERO).await;
+        assert_eq!(node.reserved_slots.load(Ordering::SeqCst), 0);
+        assert!(node.routed_clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_topics_match_live_heartbeat_topic() {
+        let config = NodeConfig {
+            topic_prefix: "pool-a".to_string(),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let live = node.heartbeat_topic();
+        assert_eq!(
+            live,
+            format!("pool-a/heartbeat/master/{}", node.node_info.node_id)
+        );
+
+        let mut options = MqttOptions::new("test-node", "localhost", 1883);
+        set_offline_will(
+            &mut options,
+            &config.topic_prefix,
+            None,
+            &NodeType::Node,
+            &node.node_info.node_id,
+        );
+        assert_eq!(options.last_will().unwrap().topic, live);
+
+        cleanup(&node, Duration::from_secs(1), "SIGTERM").await;
+        assert_eq!(published(&mut eventloop)[0].topic, live);
+    }
+
+    #[tokio::test]
+    async fn test_offline_heartbeat_carries_shutdown_reason() {
+        let (node, mut eventloop) = test_node(&test_config());
+        cleanup(&node, Duration::from_secs(1), "SIGTERM").await;
+
+        let published = published(&mut eventloop);
+        assert_eq!(
+            published[0].topic,
+            format!("heartbeat/master/{}", node.node_info.node_id)
+        );
+        let heartbeat: NodeInfo = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(heartbeat.status, NodeStatus::Inactive);
+        assert_eq!(heartbeat.shutdown_reason.as_deref(), Some("SIGTERM"));
+
+        // Regular heartbeats leave the field out
+        let regular = serde_json::to_value(node.heartbeat().await).unwrap();
+        assert!(regular.get("shutdown_reason").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_requests_are_rejected() {
+        let config = NodeConfig {
+            max_request_types: 2,
+            max_request_items: 10,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        // Rejections go to the summary topic the client listens on
+        let summary_topic = format!("data/summary/{}/client-1", node.node_info.node_id);
+        let rejected = |publishes: Vec<rumqttc::Publish>| {
+            assert_eq!(publishes.len(), 1);
+            assert_eq!(publishes[0].topic, summary_topic);
+            let summary: FulfillmentSummary =
+                serde_json::from_slice(&publishes[0].payload).unwrap();
+            assert_eq!(summary.error.as_deref(), Some("request too large"));
+        };
+
+        node.enqueue_data_request(data_request("client-1", &["text", "sensor", "number"]))
+            .await;
+        rejected(published(&mut eventloop));
+        node.enqueue_data_request(DataRequest {
+            max_items: Some(11),
+            ..data_request("client-1", &["text"])
+        })
+        .await;
+        rejected(published(&mut eventloop));
+        // ...without taking up room in the queue
+        assert!(node.request_queue.lock().await.is_empty());
+
+        // At the caps the request is served, up to max_items packets
+        node.handle_data_request(&DataRequest {
+            max_items: Some(1),
+            ..data_request("client-1", &["text", "sensor"])
+        })
+        .await;
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        assert!(published[0].topic.starts_with("data/response/"));
+        let packet: DataPacket = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(packet.data_type, "text");
+    }
+
+    #[tokio::test]
+    async fn test_max_items_caps_generated_packets() {
+        let (node, mut eventloop) = test_node(&test_config());
+        node.handle_data_request(&DataRequest {
+            max_items: Some(2),
+            ..data_request("client-1", &GENERATED_TYPES)
+        })
+        .await;
+        let packets: usize = published(&mut eventloop)
+            .iter()
+            .filter(|p| p.topic.starts_with("data/response/"))
+            .map(|p| mqtt_common::decode_data_packets(&p.payload).unwrap().len())
+            .sum();
+        assert_eq!(packets, 2);
+    }
+
+    #[tokio::test]
+    async fn test_supported_types_limit_what_is_served() {
+        let env = |key: &str| (key == "SUPPORTED_TYPES").then(String::new);
+        let config = NodeConfig::load(&config::NodeConfig::default(), env).unwrap();
+        assert_eq!(config.supported_types, GENERATED_TYPES.to_vec());
+
+        let config = NodeConfig {
+            supported_types: vec!["text".to_string()],
+            ..test_config()
+        };
+        let (node, _eventloop) = test_node(&config);
+        assert_eq!(node.node_info.supported_data_types, vec!["text"]);
+        let prepared = node
+            .prepare_packets(&data_request("client-1", &["text", "sensor"]))
+            .await;
+        assert_eq!(prepared.packets.len(), 1);
+        assert_eq!(prepared.summary.unwrap().skipped[0].data_type, "sensor");
+    }
+
+    #[test]
+    fn test_classify_publish_error() {
+        let publish = || {
+            rumqttc::Request::Publish(rumqttc::Publish::new(
+                "data/response/node-1/client-1",
+                QoS::AtLeastOnce,
+                Vec::new(),
+            ))
+        };
+        assert_eq!(
+            classify_publish_error(&ClientError::TryRequest(publish())),
+            PublishErrorKind::BrokerFull
+        );
+        assert_eq!(
+            classify_publish_error(&ClientError::Request(publish())),
+            PublishErrorKind::Disconnected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_data_retries_while_queue_is_full() {
+        let mqtt_options = MqttOptions::new("test-node", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 1);
+        let node = Node::build(
+            NodeInfo::new(NodeType::Node, 10),
+            client.clone(),
+            &test_config(),
+            None,
+        );
+        client
+            .try_publish("filler", QoS::AtLeastOnce, false, Vec::new())
+            .unwrap();
+
+        let publisher = node.clone();
+        let publish =
+            tokio::spawn(async move { publisher.publish_data("data/processed/1", vec![1]).await });
+        time::sleep(Duration::from_millis(20)).await;
+        // Draining the queue makes room for the retried publish
+        assert_eq!(published(&mut eventloop).len(), 1);
+        publish.await.unwrap().unwrap();
+        assert_eq!(published(&mut eventloop)[0].topic, "data/processed/1");
+
+        // Once the event loop is gone the publish fails after its retries
+        drop(eventloop);
+        assert_eq!(
+            classify_publish_error(
+                &node
+                    .publish_data("data/processed/2", vec![2])
+                    .await
+                    .unwrap_err()
+            ),
+            PublishErrorKind::Disconnected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_metadata_only_tightens_quotas() {
+        let config = NodeConfig {
+            type_quotas: HashMap::from([("log".to_string(), 2)]),
+            ..test_config()
+        };
+        let (node, _eventloop) = test_node(&config);
+        let mut request = routing_request("client-1");
+        for (key, limit) in [("quota.image", "1"), ("quota.log", "50")] {
+            request
+                .node_info
+                .metadata
+                .insert(key.to_string(), limit.to_string());
+        }
+        node.handle_routing_request(&request).await;
+
+        let mut quotas = node.type_quotas.lock().await;
+        let now = Instant::now();
+        assert!(quotas.try_consume("client-1", "image", now));
+        assert!(!quotas.try_consume("client-1", "image", now));
+        assert!(quotas.try_consume("client-1", "text", now));
+
+        // Asking for more than the node's default gets the default
+        assert!(quotas.try_consume("client-1", "log", now));
+        assert!(quotas.try_consume("client-1", "log", now));
+        assert!(!quotas.try_consume("client-1", "log", now));
+    }
+
+    /// Reads one MQTT packet: its first header byte and its body
+    async fn read_packet(stream: &mut tokio::net::TcpStream) -> Option<(u8, Vec<u8>)> {
+        use tokio::io::AsyncReadExt;
+        let header = stream.read_u8().await.ok()?;
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    /// A broker that accepts connections one after another. The first
+    /// `dropped` of them are closed at their first SUBSCRIBE; the rest answer
+    /// every SUBSCRIBE with `return_code`. Counts the connections made.
+    async fn scripted_broker(dropped: usize, return_code: u8) -> (u16, Arc<AtomicU32>) {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let index = counted.fetch_add(1, Ordering::SeqCst) as usize;
+                tokio::spawn(async move {
+                    while let Some((header, body)) = read_packet(&mut stream).await {
+                        let reply = match header >> 4 {
+                            // CONNECT: accept, without a stored session
+                            1 => vec![0x20, 2, 0, 0],
+                            8 if index < dropped => return,
+                            8 => vec![0x90, 3, body[0], body[1], return_code],
+                            // PINGREQ
+                            12 => vec![0xd0, 0],
+                            _ => continue,
+                        };
+                        if stream.write_all(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (port, connections)
+    }
+
+    fn subscriptions() -> Vec<(String, QoS)> {
+        vec![
+            ("data/request/+/+".to_string(), QoS::AtLeastOnce),
+            ("probe/node-1".to_string(), QoS::AtMostOnce),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_are_resent_after_losing_the_connection() {
+        let (port, connections) = scripted_broker(1, 0x01).await;
+        let options = rumqttc::MqttOptions::new("test-node", "127.0.0.1", port);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        wait_for_broker(&mut eventloop, 0, Duration::from_millis(1))
+            .await
+            .unwrap();
+
+        subscribe_all(
+            &client,
+            &mut eventloop,
+            &subscriptions(),
+            3,
+            Duration::from_millis(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_retries_are_bounded() {
+        let (port, connections) = scripted_broker(usize::MAX, 0x01).await;
+        let options = rumqttc::MqttOptions::new("test-node", "127.0.0.1", port);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        wait_for_broker(&mut eventloop, 0, Duration::from_millis(1))
+            .await
+            .unwrap();
+
+        let result = subscribe_all(
+            &client,
+            &mut eventloop,
+            &subscriptions(),
+            2,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert!(matches!(result, Err(PoolError::Connection(_))));
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_refused_subscription_fails_startup() {
+        let (port, _) = scripted_broker(0, 0x80).await;
+        let options = rumqttc::MqttOptions::new("test-node", "127.0.0.1", port);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        wait_for_broker(&mut eventloop, 0, Duration::from_millis(1))
+            .await
+            .unwrap();
+
+        let result = subscribe_all(
+            &client,
+            &mut eventloop,
+            &subscriptions(),
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert!(matches!(result, Err(PoolError::Other(_))));
+    }
+
+    #[test]
+    fn test_publish_latency_is_exponentially_smoothed() {
+        let mut latency = LatencyEwma::new(0.5);
+        assert_eq!(latency.average_ms(), None);
+        for millis in [100, 200, 200, 40] {
+            latency.record(Duration::from_millis(millis));
+        }
+        // 100 -> 150 -> 175 -> 107.5
+        assert_eq!(latency.average_ms(), Some(108));
+    }
+
+    #[test]
+    fn test_publish_latency_spans_until_the_broker_acks() {
+        let mut latency = AckLatency::new(1.0);
+        let start = Instant::now();
+        latency.sent(0, start);
+        latency.sent(7, start);
+        latency.acked(0, start + Duration::from_millis(500));
+        assert_eq!(latency.average_ms(), None);
+
+        latency.acked(7, start + Duration::from_millis(30));
+        assert_eq!(latency.average_ms(), Some(30));
+        // A duplicate ack times nothing
+        latency.acked(7, start + Duration::from_millis(90));
+        assert_eq!(latency.average_ms(), Some(30));
+
+        latency.sent(8, start);
+        latency.reconnected();
+        latency.acked(8, start + Duration::from_millis(90));
+        assert_eq!(latency.average_ms(), Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_report_processed_packets() {
+        let (node, _eventloop) = test_node(&test_config());
+        for _ in 0..3 {
+            node.handle_data_packet("client-1", &packet(DataPayload::Number(1.0)))
+                .await;
+        }
+
+        let first = node.heartbeat().await;
+        assert_eq!(first.processed_total, 3);
+        assert_eq!(first.processed_since_last_heartbeat, 3);
+
+        node.handle_data_packet("client-1", &packet(DataPayload::Number(2.0)))
+            .await;
+        let second = node.heartbeat().await;
+        assert_eq!(second.processed_total, 4);
+        assert_eq!(second.processed_since_last_heartbeat, 1);
+        // Nothing processed since the last heartbeat
+        assert_eq!(node.heartbeat().await.processed_since_last_heartbeat, 0);
+    }
+
+    #[tokio::test]
+    async fn test_processed_packet_delivers_response_on_channel() {
+        let mqtt_options = MqttOptions::new("test-node", "localhost", 1883);
+        let (client, _eventloop) = AsyncClient::new(mqtt_options, 100);
+        let (results_tx, mut results_rx) = mpsc::channel(10);
+        let node = Node::build(
+            NodeInfo::new(NodeType::Node, 100),
+            client,
+            &test_config(),
+            Some(results_tx),
+        );
+
+        let packet = packet(DataPayload::Number(42.5));
+        node.handle_data_packet("client-1", &packet).await;
+
+        let response = results_rx.recv().await.unwrap();
+        assert_eq!(response.packet_id, packet.id);
+        assert_eq!(response.status, ProcessingStatus::Processed);
+        assert!(response.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_processed_packet_publishes_timed_response() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let packet = packet(DataPayload::Text("hello".to_string()));
+        node.handle_data_packet("client-1", &packet).await;
+
+        let published = published(&mut eventloop);
+        let response_topic = format!("data/response/{}", node.node_info.node_id);
+        let publish = published
+            .iter()
+            .find(|p| p.topic == response_topic)
+            .expect("a DataResponse should be published");
+        let response: DataResponse = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(response.packet_id, packet.id);
+        assert_eq!(response.status, ProcessingStatus::Processed);
+        assert!(response.processing_time_ms >= 100);
+        assert!(response.errors.is_empty());
+        assert!(response.received_at.parse::<u64>().is_ok());
+        assert_eq!(response.processor_info.node_id, node.node_info.node_id);
+    }
+
+    #[tokio::test]
+    async fn test_slow_processing_is_answered_with_timeout() {
+        let config = NodeConfig {
+            processing_timeout_ms: 50,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        // Images take 500 ms to process
+        let packet = packet(DataPayload::ImageData {
+            width: 1,
+            height: 1,
+            format: "PNG".to_string(),
+            data: vec![0],
+        });
+        node.handle_data_packet("client-1", &packet).await;
+
+        let packets = published(&mut eventloop);
+        assert!(!packets
+            .iter()
+            .any(|p| p.topic.starts_with("data/processed/")));
+        let response: DataResponse = serde_json::from_slice(&packets[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::Timeout);
+        assert!(response.processing_time_ms < 500);
+        assert_eq!(node.current_load.load(Ordering::Relaxed), 0);
+
+        // The same budget is advertised to routed clients
+        node.handle_routing_request(&routing_request("client-1"))
+            .await;
+        let response: RoutingResponse =
+            serde_json::from_slice(&published(&mut eventloop)[0].payload).unwrap();
+        assert_eq!(response.configuration.unwrap().processing_timeout_ms, 50);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_metadata_truncated_or_rejected() {
+        let limits = |policy| MetadataLimits {
+            max_value_len: 16,
+            policy,
+            ..MetadataLimits::default()
+        };
+        let mut oversized = packet(DataPayload::Number(1.0));
+        oversized
+            .metadata
+            .insert("note".to_string(), "x".repeat(1000));
+
+        let config = NodeConfig {
+            metadata_limits: limits(OversizePolicy::Truncate),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        node.handle_data_packet("client-1", &oversized).await;
+        let processed = published_processed(&mut eventloop).unwrap();
+        assert_eq!(processed.metadata["note"], "x".repeat(16));
+
+        let config = NodeConfig {
+            metadata_limits: limits(OversizePolicy::Reject),
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        node.handle_data_packet("client-1", &oversized).await;
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::InvalidInput);
+        assert_eq!(
+            response.errors,
+            vec!["metadata value too long for key: note".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_is_reported_to_the_client() {
+        let config = NodeConfig {
+            mqtt: MqttSettings {
+                payload_limit: PayloadLimit {
+                    max_bytes: 16,
+                    ..PayloadLimit::default()
+                },
+                ..MqttSettings::default()
+            },
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let request = data_request("client-1", &["text"]);
+        node.handle_data_request(&request).await;
+
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        assert_eq!(
+            published[0].topic,
+            format!("data/summary/{}/client-1", node.node_info.node_id)
+        );
+        let summary: FulfillmentSummary = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(summary.request_id, request.request_id);
+        assert!(summary.error.unwrap().contains("exceeds the 16 byte limit"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_processed_packet_is_refused() {
+        let config = NodeConfig {
+            mqtt: MqttSettings {
+                payload_limit: PayloadLimit {
+                    max_bytes: 4096,
+                    ..PayloadLimit::default()
+                },
+                ..MqttSettings::default()
+            },
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+        let image = |bytes| DataPacket {
+            data_type: "image".to_string(),
+            ..packet(DataPayload::ImageData {
+                width: 64,
+                height: 64,
+                format: "png".to_string(),
+                data: vec![7; bytes],
+            })
+        };
+
+        node.handle_data_packet("client-1", &image(16)).await;
+        assert!(published_processed(&mut eventloop).is_some());
+
+        node.handle_data_packet("client-1", &image(8192)).await;
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::InvalidInput);
+        assert!(response.errors[0].contains("exceeds the 4096 byte limit"));
+    }
+
+    #[tokio::test]
+    async fn test_registered_handler_processes_its_data_type() {
+        struct Rejecting {
+            calls: Arc<AtomicU32>,
+        }
+
+        #[async_trait::async_trait]
+        impl PayloadHandler for Rejecting {
+            fn data_type(&self) -> &str {
+                "number"
+            }
+
+            async fn process(&self, _packet: &DataPacket) -> Result<(), handlers::ProcessingError> {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                Err(handlers::ProcessingError("out of range".to_string()))
+            }
+        }
+
+        let (node, mut eventloop) = test_node(&test_config());
+        let calls = Arc::new(AtomicU32::new(0));
+        node.register_handler(Box::new(Rejecting {
+            calls: Arc::clone(&calls),
+        }))
+        .await;
+
+        node.handle_data_packet("client-1", &packet(DataPayload::Number(1.0)))
+            .await;
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::Failed);
+        assert_eq!(response.errors, vec!["out of range".to_string()]);
+
+        // Other types keep their default handler
+        node.handle_data_packet("client-1", &packet(DataPayload::Text("hi".to_string())))
+            .await;
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(published_processed(&mut eventloop).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_registering_a_handler_does_not_wait_for_processing() {
+        struct Stalled {
+            release: Arc<Notify>,
+        }
+
+        #[async_trait::async_trait]
+        impl PayloadHandler for Stalled {
+            fn data_type(&self) -> &str {
+                "number"
+            }
+
+            async fn process(&self, _packet: &DataPacket) -> Result<(), handlers::ProcessingError> {
+                self.release.notified().await;
+                Ok(())
+            }
+        }
+
+        let (node, _eventloop) = test_node(&test_config());
+        let release = Arc::new(Notify::new());
+        node.register_handler(Box::new(Stalled {
+            release: Arc::clone(&release),
+        }))
+        .await;
+
+        let packet = packet(DataPayload::Number(1.0));
+        let ((), registered) = tokio::join!(node.handle_data_packet("client-1", &packet), async {
+            let registered = time::timeout(
+                Duration::from_secs(1),
+                node.register_handler(Box::new(handlers::Simulated::new("text", Duration::ZERO))),
+            )
+            .await;
+            release.notify_one();
+            registered
+        });
+        assert!(registered.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batch_items_processed_in_turn_and_deep_nesting_rejected() {
+        struct Counting {
+            calls: Arc<AtomicU32>,
+        }
+
+        #[async_trait::async_trait]
+        impl PayloadHandler for Counting {
+            fn data_type(&self) -> &str {
+                "number"
+            }
+
+            async fn process(&self, _packet: &DataPacket) -> Result<(), handlers::ProcessingError> {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let (node, mut eventloop) = test_node(&test_config());
+        let calls = Arc::new(AtomicU32::new(0));
+        node.register_handler(Box::new(Counting {
+            calls: Arc::clone(&calls),
+        }))
+        .await;
+
+        let batch = DataPayload::Batch(vec![
+            DataPayload::Number(1.0),
+            DataPayload::Number(2.0).compress(),
+        ]);
+        node.handle_data_packet("client-1", &packet(batch)).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let response: DataResponse = published(&mut eventloop)
+            .iter()
+            .find(|publish| publish.topic.starts_with("data/response/"))
+            .map(|publish| serde_json::from_slice(&publish.payload).unwrap())
+            .unwrap();
+        assert_eq!(response.status, ProcessingStatus::Processed);
+
+        let mut nested = DataPayload::Number(1.0);
+        for _ in 0..=MAX_BATCH_DEPTH {
+            nested = DataPayload::Batch(vec![nested]);
+        }
+        node.handle_data_packet("client-1", &packet(nested)).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_packet_is_processed_once() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let packet = packet(DataPayload::Number(1.0));
+        node.handle_data_packet("client-1", &packet).await;
+        node.handle_data_packet("client-1", &packet).await;
+
+        let published = published(&mut eventloop);
+        let count = |prefix: &str| {
+            published
+                .iter()
+                .filter(|p| p.topic.starts_with(prefix))
+                .count()
+        };
+        assert_eq!(count("data/response/"), 1);
+        assert_eq!(count("data/processed/"), 1);
+        assert_eq!(node.current_load.load(Ordering::Relaxed), 0);
+    }
+
+    fn packet_at(timestamp: &str) -> DataPacket {
+        DataPacket {
+            timestamp: timestamp.to_string(),
+            ..packet(DataPayload::Number(1.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_timestamp_order_rejects_regression() {
+        let config = NodeConfig {
+            timestamp_order: TimestampOrder::Strict,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+
+        node.handle_data_packet("client-1", &packet_at("100")).await;
+        published(&mut eventloop);
+        node.handle_data_packet("client-1", &packet_at("50")).await;
+
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::InvalidInput);
+        assert_eq!(response.errors, vec!["timestamp regression".to_string()]);
+
+        // Other streams are unaffected
+        node.handle_data_packet("client-2", &packet_at("50")).await;
+        assert!(published_processed(&mut eventloop).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lenient_timestamp_order_tags_regression() {
+        let config = NodeConfig {
+            timestamp_order: TimestampOrder::Lenient,
+            ..test_config()
+        };
+        let (node, mut eventloop) = test_node(&config);
+
+        node.handle_data_packet("client-1", &packet_at("100")).await;
+        let first = published_processed(&mut eventloop).unwrap();
+        assert!(!first.metadata.contains_key("out_of_order"));
+
+        node.handle_data_packet("client-1", &packet_at("50")).await;
+        let second = published_processed(&mut eventloop).unwrap();
+        assert_eq!(
+            second.metadata.get("out_of_order").map(String::as_str),
+            Some("true")
+        );
+    }
+
+    /// The packet published on `data/processed/...`, if any
+    fn published_processed(eventloop: &mut EventLoop) -> Option<DataPacket> {
+        published(eventloop)
+            .iter()
+            .find(|p| p.topic.starts_with("data/processed/"))
+            .map(|p| serde_json::from_slice(&p.payload).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_packets_are_processed_as_their_inner_payload() {
+        let (node, mut eventloop) = test_node(&test_config());
+        let compressed = packet(DataPayload::Text("hello".to_string()).compress());
+        node.handle_data_packet("client-1", &compressed).await;
+        let processed = published_processed(&mut eventloop).unwrap();
+        assert!(matches!(processed.payload, DataPayload::Text(ref text) if text == "hello"));
+
+        let unknown = packet(DataPayload::Compressed {
+            inner_type: "text".to_string(),
+            algorithm: "zstd".to_string(),
+            data: vec![1, 2, 3],
+        });
+        node.handle_data_packet("client-1", &unknown).await;
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        let response: DataResponse = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(response.status, ProcessingStatus::InvalidInput);
+        assert_eq!(
+            response.errors,
+            vec!["unknown compression algorithm: zstd".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_response_cache_expires_after_ttl() {
+        let mut cache = ResponseCache::new(Duration::from_secs(10));
+        let start = Instant::now();
+        cache.insert(
+            "client-1/key".to_string(),
+            PreparedResponse::default(),
+            start,
+        );
+
+        assert!(cache
+            .get("client-1/key", start + Duration::from_secs(5))
+            .is_some());
+        assert!(cache
+            .get("client-1/key", start + Duration::from_secs(11))
+            .is_none());
+    }
+
+    #[test]
+    fn test_processing_slots_use_concurrency_not_capacity() {
+        let config = NodeConfig {
+            node_capacity: 100,
+            processing_concurrency: 4,
+            ..test_config()
+        };
+        let (node, _eventloop) = test_node(&config);
+
+        assert_eq!(node.node_info.capacity, 100);
+        assert_eq!(node.processing_slots.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_response_delay_jitter_bounds() {
+        let delay = ResponseDelay::new(100, 20);
+        for _ in 0..100 {
+            let sampled = delay.sample();
+            assert!(sampled >= Duration::from_millis(100));
+            assert!(sampled <= Duration::from_millis(120));
+        }
+        assert_eq!(ResponseDelay::default().sample(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_change_tracker_suppresses_small_changes() {
+        let mut tracker = ChangeTracker::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(tracker.should_send("client-1", "sensor", &[23.5, 45.0], 1.0, start));
+        // Below threshold is suppressed
+        assert!(!tracker.should_send("client-1", "sensor", &[23.9, 45.2], 1.0, start));
+        // Above threshold is sent
+        assert!(tracker.should_send("client-1", "sensor", &[25.0, 45.2], 1.0, start));
+        // Other clients are tracked independently
+        assert!(tracker.should_send("client-2", "sensor", &[25.0, 45.2], 1.0, start));
+        // Keepalive re-sends unchanged values
+        let later = start + Duration::from_secs(61);
+        assert!(tracker.should_send("client-1", "sensor", &[25.0, 45.2], 1.0, later));
     }
 }
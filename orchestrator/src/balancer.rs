@@ -0,0 +1,534 @@
+use mqtt_common::{NodeInfo, NodeStatus, NodeType, RoutingRequest, MAX_LOAD_COST};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Policy for picking the node a client is routed to.
+///
+/// `candidates` are the nodes able to take the request right now (active,
+/// with room for another client, serving the requested types), in node
+/// id order. Returns the id of the chosen node. Deployments can plug in their
+/// own selection logic by implementing this trait.
+pub trait NodeSelector {
+    fn select(&self, candidates: &[&NodeInfo], request: &RoutingRequest) -> Option<String>;
+}
+
+/// Names `from_name` knows
+pub const NAMES: [&str; 6] = [
+    "least_loaded",
+    "least_loaded_low_latency",
+    "round_robin",
+    "weighted_round_robin",
+    "random",
+    "weighted_random",
+];
+
+/// Picks a selector by name (one of `NAMES`), falling back to least loaded. Random selectors draw
+/// from `seed` when given, so their picks can be replayed.
+pub fn from_name(name: &str, seed: Option<u64>) -> Box<dyn NodeSelector + Send + Sync> {
+    match name {
+        "least_loaded_low_latency" => Box::new(LeastLoadedLowLatency),
+        "round_robin" => Box::new(RoundRobin::default()),
+        "weighted_round_robin" => Box::new(WeightedRoundRobin::default()),
+        "random" => Box::new(Random::seeded(seed)),
+        "weighted_random" => Box::new(WeightedRandom::seeded(seed)),
+        _ => Box::new(LeastLoaded),
+    }
+}
+
+fn seeded_rng(seed: Option<u64>) -> Mutex<StdRng> {
+    Mutex::new(match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    })
+}
+
+/// Whether the node has a free client slot and, going by the packet load it
+/// last reported, headroom for the most expensive packet. The headroom is at
+/// most the node's capacity, so idle nodes smaller than `MAX_LOAD_COST` can
+/// still be routed to.
+pub fn has_room_for_client(info: &NodeInfo) -> bool {
+    let headroom = MAX_LOAD_COST.min(info.capacity);
+    info.has_capacity(1)
+        && info
+            .reported_load
+            .is_none_or(|load| load.saturating_add(headroom) <= info.capacity)
+}
+
+/// Active nodes serving the requested types, with room for one more client
+fn is_eligible(info: &NodeInfo, request: &RoutingRequest) -> bool {
+    info.status == NodeStatus::Active
+        && has_room_for_client(info)
+        && info.node_type == NodeType::Node
+        && info.supports_all(&request.data_type)
+}
+
+/// The nodes a selector may choose from, in a stable (node id) order
+pub fn eligible_candidates<'a>(
+    nodes: &'a HashMap<String, NodeInfo>,
+    request: &RoutingRequest,
+) -> Vec<&'a NodeInfo> {
+    let mut eligible: Vec<&NodeInfo> = nodes
+        .values()
+        .filter(|info| is_eligible(info, request))
+        .collect();
+    eligible.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    eligible
+}
+
+/// Clients to move off the busiest active node, as `(node id, client id)`
+/// pairs, when its utilization exceeds the idlest active node's by more than
+/// `threshold`. Each client's destination is picked by `selector` from the
+/// nodes eligible for its routing request in `requests`, as a move would
+/// pick it; clients no other node can take stay put. Stops after
+/// `max_moves`, or once the gap is within the threshold, counting each
+/// planned client as already moved.
+pub fn plan_rebalance(
+    nodes: &HashMap<String, NodeInfo>,
+    routing_table: &HashMap<String, Vec<String>>,
+    requests: &HashMap<String, RoutingRequest>,
+    selector: &dyn NodeSelector,
+    threshold: f32,
+    max_moves: usize,
+) -> Vec<(String, String)> {
+    let is_active = |info: &NodeInfo| {
+        info.node_type == NodeType::Node && info.status == NodeStatus::Active && info.capacity > 0
+    };
+    let coolest_utilization = |nodes: &HashMap<String, NodeInfo>| {
+        nodes
+            .values()
+            .filter(|info| is_active(info))
+            .map(NodeInfo::utilization)
+            .min_by(f32::total_cmp)
+    };
+    let mut active: Vec<&NodeInfo> = nodes.values().filter(|info| is_active(info)).collect();
+    active.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    let Some(hottest) = active
+        .into_iter()
+        .max_by(|a, b| a.utilization().total_cmp(&b.utilization()))
+        .map(|info| info.node_id.clone())
+    else {
+        return Vec::new();
+    };
+
+    let mut clients: Vec<&String> = routing_table
+        .iter()
+        .filter(|(_, node_ids)| node_ids.contains(&hottest))
+        .map(|(client_id, _)| client_id)
+        .collect();
+    clients.sort();
+
+    // Loads as they will be once the planned moves are made
+    let mut planned = nodes.clone();
+    let mut moves = Vec::new();
+    for client_id in clients {
+        let gap = planned[&hottest].utilization() - coolest_utilization(&planned).unwrap_or(0.0);
+        if moves.len() == max_moves || gap <= threshold {
+            break;
+        }
+        let Some(request) = requests.get(client_id) else {
+            continue;
+        };
+        let candidates: Vec<&NodeInfo> = eligible_candidates(&planned, request)
+            .into_iter()
+            .filter(|info| info.node_id != hottest)
+            .collect();
+        let Some(target) = selector
+            .select(&candidates, request)
+            .filter(|id| candidates.iter().any(|info| &info.node_id == id))
+        else {
+            continue;
+        };
+        if let Some(info) = planned.get_mut(&target) {
+            info.current_load += 1;
+        }
+        if let Some(info) = planned.get_mut(&hottest) {
+            info.current_load = info.current_load.saturating_sub(1);
+        }
+        moves.push((hottest.clone(), client_id.clone()));
+    }
+    moves
+}
+
+/// Node with the lowest load relative to its capacity
+pub struct LeastLoaded;
+
+impl NodeSelector for LeastLoaded {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        candidates
+            .iter()
+            .min_by(|a, b| a.utilization().total_cmp(&b.utilization()))
+            .map(|info| info.node_id.clone())
+    }
+}
+
+/// Like `LeastLoaded`, but between equally loaded nodes prefers the one with
+/// the lowest `avg_publish_latency_ms`. Nodes that haven't reported a latency
+/// yet come after those that have.
+pub struct LeastLoadedLowLatency;
+
+impl NodeSelector for LeastLoadedLowLatency {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        let latency = |info: &NodeInfo| info.avg_publish_latency_ms.unwrap_or(u32::MAX);
+        candidates
+            .iter()
+            .min_by(|a, b| {
+                a.utilization()
+                    .total_cmp(&b.utilization())
+                    .then(latency(a).cmp(&latency(b)))
+            })
+            .map(|info| info.node_id.clone())
+    }
+}
+
+/// Cycles through candidates in node id order
+#[derive(Default)]
+pub struct RoundRobin {
+    cursor: AtomicUsize,
+}
+
+impl NodeSelector for RoundRobin {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = self.cursor.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Some(candidates[index].node_id.clone())
+    }
+}
+
+/// Cycles through candidates in proportion to their capacity, so a node with
+/// twice the capacity is picked twice as often. Uses smooth weighted
+/// round-robin: every pick, each candidate earns its capacity in credit, and
+/// the richest one is chosen and pays back the total.
+#[derive(Default)]
+pub struct WeightedRoundRobin {
+    credits: Mutex<HashMap<String, i64>>,
+}
+
+impl NodeSelector for WeightedRoundRobin {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        let mut credits = self.credits.lock().unwrap();
+        // Nodes that left forfeit their credit; newcomers start from zero
+        credits.retain(|node_id, _| candidates.iter().any(|info| &info.node_id == node_id));
+
+        let total: i64 = candidates.iter().map(|info| i64::from(info.capacity)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut best: Option<(&str, i64)> = None;
+        for info in candidates {
+            let credit = credits.entry(info.node_id.clone()).or_insert(0);
+            *credit += i64::from(info.capacity);
+            if best.is_none_or(|(_, richest)| *credit > richest) {
+                best = Some((&info.node_id, *credit));
+            }
+        }
+        let (node_id, _) = best?;
+        *credits.get_mut(node_id)? -= total;
+        Some(node_id.to_string())
+    }
+}
+
+/// Uniformly random candidate
+pub struct Random {
+    rng: Mutex<StdRng>,
+}
+
+impl Random {
+    pub fn seeded(seed: Option<u64>) -> Self {
+        Random {
+            rng: seeded_rng(seed),
+        }
+    }
+}
+
+impl NodeSelector for Random {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = self.rng.lock().unwrap().gen_range(0..candidates.len());
+        Some(candidates[index].node_id.clone())
+    }
+}
+
+/// Random candidate, weighted by how much spare capacity it has
+pub struct WeightedRandom {
+    rng: Mutex<StdRng>,
+}
+
+impl WeightedRandom {
+    pub fn seeded(seed: Option<u64>) -> Self {
+        WeightedRandom {
+            rng: seeded_rng(seed),
+        }
+    }
+}
+
+impl NodeSelector for WeightedRandom {
+    fn select(&self, candidates: &[&NodeInfo], _request: &RoutingRequest) -> Option<String> {
+        let free = |info: &NodeInfo| u64::from(info.capacity.saturating_sub(info.current_load));
+        let total: u64 = candidates.iter().map(|info| free(info)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = self.rng.lock().unwrap().gen_range(0..total);
+        candidates
+            .iter()
+            .find(|info| {
+                if point < free(info) {
+                    true
+                } else {
+                    point -= free(info);
+                    false
+                }
+            })
+            .map(|info| info.node_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_common::PROTOCOL_VERSION;
+
+    fn node(node_id: &str, capacity: u32, current_load: u32) -> NodeInfo {
+        let mut info = NodeInfo::new(NodeType::Node, capacity);
+        info.node_id = node_id.to_string();
+        info.current_load = current_load;
+        info.supported_data_types = vec!["text".to_string()];
+        info
+    }
+
+    fn nodes(list: &[(&str, u32, u32)]) -> HashMap<String, NodeInfo> {
+        list.iter()
+            .map(|(id, capacity, load)| (id.to_string(), node(id, *capacity, *load)))
+            .collect()
+    }
+
+    fn request() -> RoutingRequest {
+        RoutingRequest {
+            client_id: "client-1".to_string(),
+            data_type: vec!["text".to_string()],
+            node_info: NodeInfo::new(NodeType::Client, 10),
+            preferred_node: None,
+            timestamp: 0,
+            request_id: None,
+            fan_out: None,
+            schema_version: PROTOCOL_VERSION,
+            logical_id: None,
+        }
+    }
+
+    fn pick(selector: &dyn NodeSelector, nodes: &HashMap<String, NodeInfo>) -> Option<String> {
+        selector.select(&eligible_candidates(nodes, &request()), &request())
+    }
+
+    #[test]
+    fn test_rebalance_moves_clients_only_past_threshold() {
+        let routing_table: HashMap<String, Vec<String>> = (0..10)
+            .map(|i| (format!("client-{}", i), vec!["hot".to_string()]))
+            .chain([("client-x".to_string(), vec!["cool".to_string()])])
+            .collect();
+
+        let requests: HashMap<String, RoutingRequest> = routing_table
+            .keys()
+            .map(|client_id| {
+                let request = RoutingRequest {
+                    client_id: client_id.clone(),
+                    ..request()
+                };
+                (client_id.clone(), request)
+            })
+            .collect();
+        let plan = |nodes: &HashMap<String, NodeInfo>, selector: &dyn NodeSelector, max_moves| {
+            plan_rebalance(nodes, &routing_table, &requests, selector, 0.5, max_moves)
+        };
+
+        // 95% against 10%: moves are capped by `max_moves`
+        let drifted = nodes(&[("hot", 20, 19), ("cool", 20, 2), ("mid", 20, 10)]);
+        let moves = plan(&drifted, &LeastLoaded, 3);
+        assert_eq!(
+            moves,
+            vec![
+                ("hot".to_string(), "client-0".to_string()),
+                ("hot".to_string(), "client-1".to_string()),
+                ("hot".to_string(), "client-2".to_string()),
+            ]
+        );
+        // ...or by the gap closing first
+        assert_eq!(plan(&drifted, &LeastLoaded, 10).len(), 4);
+
+        let even = nodes(&[("hot", 20, 12), ("cool", 20, 6)]);
+        assert!(plan(&even, &LeastLoaded, 3).is_empty());
+        assert!(plan(&nodes(&[("hot", 20, 19)]), &LeastLoaded, 3).is_empty());
+    }
+
+    #[test]
+    fn test_rebalance_plans_moves_with_the_selector() {
+        let routing_table: HashMap<String, Vec<String>> = (0..10)
+            .map(|i| (format!("client-{}", i), vec!["hot".to_string()]))
+            .collect();
+        let requests: HashMap<String, RoutingRequest> = routing_table
+            .keys()
+            .map(|client_id| (client_id.clone(), request()))
+            .collect();
+
+        /// Only ever picks "mid", which doesn't close the gap to "cool"
+        struct Mid;
+        impl NodeSelector for Mid {
+            fn select(&self, candidates: &[&NodeInfo], _: &RoutingRequest) -> Option<String> {
+                candidates
+                    .iter()
+                    .find(|info| info.node_id == "mid")
+                    .map(|info| info.node_id.clone())
+            }
+        }
+        // "mid" takes clients up to its last free slot, filling up after 5 moves
+        let drifted = nodes(&[("hot", 20, 19), ("cool", 20, 2), ("mid", 20, 15)]);
+        let moves = plan_rebalance(&drifted, &routing_table, &requests, &Mid, 0.5, 10);
+        assert_eq!(moves.len(), 5);
+
+        // Clients whose types no other node serves aren't planned
+        let mut text_only = drifted.clone();
+        for node_id in ["cool", "mid"] {
+            text_only.get_mut(node_id).unwrap().supported_data_types = vec!["json".to_string()];
+        }
+        assert!(
+            plan_rebalance(&text_only, &routing_table, &requests, &LeastLoaded, 0.5, 10).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_least_loaded_picks_lowest_load_fraction() {
+        let candidates = nodes(&[("a", 10, 5), ("b", 100, 10), ("c", 10, 10)]);
+        assert_eq!(pick(&LeastLoaded, &candidates).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_least_loaded_survives_zero_capacity_candidates() {
+        let zero = node("zero", 0, 0);
+        let half = node("half", 10, 5);
+        let candidates = [&zero, &half];
+        assert_eq!(
+            LeastLoaded.select(&candidates, &request()).as_deref(),
+            Some("half")
+        );
+    }
+
+    #[test]
+    fn test_low_latency_breaks_load_ties() {
+        let mut candidates = nodes(&[("a", 10, 5), ("b", 10, 5), ("c", 10, 5), ("d", 10, 2)]);
+        for (node_id, latency) in [("a", 40), ("b", 15), ("d", 90)] {
+            candidates.get_mut(node_id).unwrap().avg_publish_latency_ms = Some(latency);
+        }
+        // Load still comes first
+        assert_eq!(
+            pick(&LeastLoadedLowLatency, &candidates).as_deref(),
+            Some("d")
+        );
+        candidates.get_mut("d").unwrap().current_load = 5;
+        assert_eq!(
+            pick(&LeastLoadedLowLatency, &candidates).as_deref(),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn test_round_robin_cycles_through_eligible_nodes() {
+        let candidates = nodes(&[("a", 10, 0), ("b", 10, 0), ("full", 10, 10)]);
+        let selector = RoundRobin::default();
+        let picks: Vec<String> = (0..4)
+            .map(|_| pick(&selector, &candidates).unwrap())
+            .collect();
+        assert_eq!(picks, vec!["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn test_weighted_round_robin_follows_capacity_ratios() {
+        let candidates = nodes(&[("small", 100, 0), ("medium", 200, 0), ("large", 300, 0)]);
+        let selector = WeightedRoundRobin::default();
+        let mut picks: HashMap<String, usize> = HashMap::new();
+        for _ in 0..6000 {
+            *picks
+                .entry(pick(&selector, &candidates).unwrap())
+                .or_default() += 1;
+        }
+        for (node_id, expected) in [("small", 1000), ("medium", 2000), ("large", 3000)] {
+            let got = picks[node_id];
+            assert!(
+                got.abs_diff(expected) <= 50,
+                "{} picked {} times",
+                node_id,
+                got
+            );
+        }
+
+        // A node joining later is folded in without starving the others
+        let mut joined = candidates.clone();
+        joined.insert("new".to_string(), node("new", 100, 0));
+        let mut picks: HashMap<String, usize> = HashMap::new();
+        for _ in 0..700 {
+            *picks.entry(pick(&selector, &joined).unwrap()).or_default() += 1;
+        }
+        assert!(
+            picks["new"].abs_diff(100) <= 5,
+            "new picked {}",
+            picks["new"]
+        );
+        assert!(picks["large"].abs_diff(300) <= 5);
+
+        // One leaving is simply dropped
+        joined.remove("large");
+        for _ in 0..10 {
+            assert_ne!(pick(&selector, &joined).as_deref(), Some("large"));
+        }
+    }
+
+    #[test]
+    fn test_random_only_picks_eligible_nodes() {
+        let candidates = nodes(&[("a", 10, 0), ("full", 10, 10)]);
+        let random = Random::seeded(None);
+        for _ in 0..20 {
+            assert_eq!(pick(&random, &candidates).as_deref(), Some("a"));
+        }
+
+        let all_full = nodes(&[("full", 10, 10)]);
+        assert!(pick(&random, &all_full).is_none());
+    }
+
+    #[test]
+    fn test_candidates_need_room_for_costliest_packet() {
+        let mut candidates = nodes(&[
+            ("roomy", 10, 9),
+            ("tight", 10, 0),
+            ("full", 10, 10),
+            ("small", 3, 0),
+        ]);
+        // One client slot left, and packet headroom as reported
+        candidates.get_mut("roomy").unwrap().reported_load = Some(10 - MAX_LOAD_COST);
+        candidates.get_mut("tight").unwrap().reported_load = Some(10 - MAX_LOAD_COST + 1);
+        // Nodes smaller than the costliest packet are routable while idle
+        candidates.get_mut("small").unwrap().reported_load = Some(0);
+        let eligible: Vec<&str> = eligible_candidates(&candidates, &request())
+            .iter()
+            .map(|info| info.node_id.as_str())
+            .collect();
+        assert_eq!(eligible, vec!["roomy", "small"]);
+    }
+
+    #[test]
+    fn test_weighted_random_favors_spare_capacity() {
+        let candidates = nodes(&[("busy", 100, 90), ("idle", 100, 0), ("full", 100, 96)]);
+        let selector = WeightedRandom::seeded(Some(7));
+        let idle_picks = (0..1000)
+            .filter(|_| pick(&selector, &candidates).as_deref() == Some("idle"))
+            .count();
+        // Expect roughly 10 of every 11 picks to land on the idle node
+        assert!(idle_picks > 850, "idle picked {} times", idle_picks);
+        assert!(idle_picks < 1000);
+    }
+}
@@ -0,0 +1,607 @@
+use bytes::BytesMut;
+use mqtt_common::DEFAULT_MAX_PAYLOAD_BYTES;
+pub use rumqttc::mqttbytes::v4::Login;
+use rumqttc::mqttbytes::{self, v4};
+use rumqttc::{
+    matches, valid_filter, valid_topic, ConnAck, ConnectReturnCode, LastWill, Packet, PingResp,
+    PubAck, PubComp, PubRec, PubRel, Publish, QoS, SubAck, SubscribeReasonCode, UnsubAck,
+};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{self, Instant};
+use tracing::{error, warn};
+
+/// Largest packet accepted from a connection, enough for the largest
+/// payload the pool publishes by default
+const MAX_PACKET_SIZE: usize = 2 * DEFAULT_MAX_PAYLOAD_BYTES;
+
+/// Publishes queued for a subscriber that isn't reading; later ones are
+/// dropped rather than buffered without limit
+const SESSION_QUEUE_CAP: usize = 1024;
+
+/// QoS 1 and 2 publishes sent to one subscriber and not yet acknowledged;
+/// past this, further ones are dropped like those of a full queue
+const MAX_INFLIGHT: usize = 1024;
+
+/// Pause after a failed `accept`, so e.g. running out of file descriptors
+/// doesn't turn into a busy loop
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// One connected client and the filters it subscribed to
+struct Session {
+    /// Each filter with the QoS granted for it
+    filters: Vec<(String, QoS)>,
+    outgoing: mpsc::Sender<Publish>,
+}
+
+type Sessions = Mutex<HashMap<u64, Session>>;
+
+/// One connection's QoS 1 and 2 exchanges still under way
+#[derive(Default)]
+struct Inflight {
+    /// Packet ids of publishes sent and not yet acknowledged: by PUBACK at
+    /// QoS 1, by PUBCOMP at QoS 2
+    sent: HashSet<u16>,
+    last_pkid: u16,
+    /// Packet ids of QoS 2 publishes received and not yet released, so a
+    /// redelivery isn't routed twice
+    received: HashSet<u16>,
+}
+
+impl Inflight {
+    /// A free packet id for the next publish sent, or `None` when too many
+    /// are unacknowledged
+    fn next_pkid(&mut self) -> Option<u16> {
+        if self.sent.len() >= MAX_INFLIGHT {
+            return None;
+        }
+        loop {
+            // Packet id 0 is reserved for QoS 0
+            self.last_pkid = self.last_pkid.wrapping_add(1).max(1);
+            if self.sent.insert(self.last_pkid) {
+                return Some(self.last_pkid);
+            }
+        }
+    }
+}
+
+/// Serves a minimal MQTT 3.1.1 broker on `listener` until the process exits.
+///
+/// Meant for single-binary deployments where the orchestrator, nodes and
+/// clients share one host: it keeps no state across connections. Publishes
+/// are delivered at the lower of their QoS and the one each subscriber was
+/// granted, QoS 1 and 2 included. Last wills are published when a client
+/// goes away without a DISCONNECT, including when it stays silent past
+/// one and a half keep-alive intervals. With `login` set, connections must
+/// present those credentials; otherwise any login is accepted. Deployments
+/// needing persistence or retained messages should run a standalone broker.
+pub async fn serve(listener: TcpListener, login: Option<Login>) {
+    let sessions: Arc<Sessions> = Arc::default();
+    let login = Arc::new(login);
+    let mut next_id = 0;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Embedded broker failed to accept a connection: {}", e);
+                time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        next_id += 1;
+        let id = next_id;
+        let sessions = Arc::clone(&sessions);
+        let login = Arc::clone(&login);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(id, stream, &sessions, (*login).as_ref()).await {
+                error!("Embedded broker dropped {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Runs one connection from its CONNECT until the peer disconnects,
+/// publishing its last will unless it said DISCONNECT first
+async fn handle_connection(
+    id: u64,
+    mut stream: TcpStream,
+    sessions: &Sessions,
+    login: Option<&Login>,
+) -> io::Result<()> {
+    let mut incoming = BytesMut::with_capacity(4096);
+    let mut outgoing = BytesMut::new();
+
+    let connect = match next_packet(&mut stream, &mut incoming).await? {
+        Some(Packet::Connect(connect)) => connect,
+        Some(packet) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected CONNECT, got {:?}", packet),
+            ))
+        }
+        None => return Ok(()),
+    };
+    if login.is_some_and(|login| connect.login.as_ref() != Some(login)) {
+        ConnAck::new(ConnectReturnCode::BadUserNamePassword, false)
+            .write(&mut outgoing)
+            .map_err(invalid_data)?;
+        stream.write_all_buf(&mut outgoing).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("refused login for {}", connect.client_id),
+        ));
+    }
+    ConnAck::new(ConnectReturnCode::Success, false)
+        .write(&mut outgoing)
+        .map_err(invalid_data)?;
+    stream.write_all_buf(&mut outgoing).await?;
+
+    let (sender, deliveries) = mpsc::channel(SESSION_QUEUE_CAP);
+    sessions.lock().await.insert(
+        id,
+        Session {
+            filters: Vec::new(),
+            outgoing: sender,
+        },
+    );
+    // Clients must send something at least every keep-alive interval; half
+    // as long again is allowed for, as MQTT 3.1.1 asks of brokers
+    let keep_alive = (connect.keep_alive > 0)
+        .then(|| Duration::from_secs(u64::from(connect.keep_alive)) * 3 / 2);
+    let session = run_session(
+        id,
+        &mut stream,
+        (&mut incoming, &mut outgoing),
+        sessions,
+        deliveries,
+        keep_alive,
+    )
+    .await;
+
+    let mut sessions = sessions.lock().await;
+    sessions.remove(&id);
+    if !matches!(session, Ok(true)) {
+        if let Some(will) = connect.last_will {
+            publish_will(will, &sessions);
+        }
+    }
+    session.map(|_| ())
+}
+
+/// Exchanges packets with a connected client. Returns true when it ended
+/// with a DISCONNECT, false when the client just went away.
+async fn run_session(
+    id: u64,
+    stream: &mut TcpStream,
+    (incoming, outgoing): (&mut BytesMut, &mut BytesMut),
+    sessions: &Sessions,
+    mut deliveries: mpsc::Receiver<Publish>,
+    keep_alive: Option<Duration>,
+) -> io::Result<bool> {
+    let mut inflight = Inflight::default();
+    let mut deadline = keep_alive.map(|grace| Instant::now() + grace);
+    loop {
+        tokio::select! {
+            packet = next_packet(stream, incoming) => match packet? {
+                None => return Ok(false),
+                Some(Packet::Disconnect) => return Ok(true),
+                Some(packet) => {
+                    deadline = keep_alive.map(|grace| Instant::now() + grace);
+                    respond(id, packet, sessions, &mut inflight, outgoing)
+                        .await
+                        .map_err(invalid_data)?;
+                }
+            },
+            Some(mut publish) = deliveries.recv() => {
+                if publish.qos != QoS::AtMostOnce {
+                    let Some(pkid) = inflight.next_pkid() else {
+                        warn!(
+                            "Embedded broker dropped a publish on {} for session {} with {} unacknowledged",
+                            publish.topic, id, MAX_INFLIGHT
+                        );
+                        continue;
+                    };
+                    publish.pkid = pkid;
+                }
+                publish.write(outgoing).map_err(invalid_data)?;
+            }
+            () = expire(deadline) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "keep-alive interval passed without a packet",
+                ));
+            }
+        }
+        stream.write_all_buf(outgoing).await?;
+    }
+}
+
+/// Completes at `deadline`, or never without one
+async fn expire(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Next complete packet from `stream`, or `None` once the peer hangs up
+async fn next_packet(stream: &mut TcpStream, buffer: &mut BytesMut) -> io::Result<Option<Packet>> {
+    loop {
+        match v4::read(buffer, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => return Err(invalid_data(e)),
+        }
+        if stream.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Handles one packet from session `id`, queueing any reply in `outgoing`
+async fn respond(
+    id: u64,
+    packet: Packet,
+    sessions: &Sessions,
+    inflight: &mut Inflight,
+    outgoing: &mut BytesMut,
+) -> Result<(), mqttbytes::Error> {
+    match packet {
+        Packet::Publish(publish) => {
+            let first_delivery = match publish.qos {
+                QoS::AtMostOnce => true,
+                QoS::AtLeastOnce => {
+                    PubAck::new(publish.pkid).write(outgoing)?;
+                    true
+                }
+                QoS::ExactlyOnce => {
+                    PubRec::new(publish.pkid).write(outgoing)?;
+                    inflight.received.insert(publish.pkid)
+                }
+            };
+            if first_delivery && valid_topic(&publish.topic) {
+                route(publish, &*sessions.lock().await);
+            }
+        }
+        Packet::PubRel(release) => {
+            inflight.received.remove(&release.pkid);
+            PubComp::new(release.pkid).write(outgoing)?;
+        }
+        Packet::PubAck(PubAck { pkid }) | Packet::PubComp(PubComp { pkid }) => {
+            inflight.sent.remove(&pkid);
+        }
+        Packet::PubRec(received) => {
+            PubRel::new(received.pkid).write(outgoing)?;
+        }
+        Packet::Subscribe(subscribe) => {
+            let mut sessions = sessions.lock().await;
+            let Some(session) = sessions.get_mut(&id) else {
+                return Ok(());
+            };
+            let return_codes = subscribe
+                .filters
+                .into_iter()
+                .map(|filter| {
+                    if !valid_filter(&filter.path) {
+                        return SubscribeReasonCode::Failure;
+                    }
+                    session.filters.retain(|(path, _)| *path != filter.path);
+                    session.filters.push((filter.path, filter.qos));
+                    SubscribeReasonCode::Success(filter.qos)
+                })
+                .collect();
+            SubAck::new(subscribe.pkid, return_codes).write(outgoing)?;
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            if let Some(session) = sessions.lock().await.get_mut(&id) {
+                session
+                    .filters
+                    .retain(|(filter, _)| !unsubscribe.topics.contains(filter));
+            }
+            UnsubAck::new(unsubscribe.pkid).write(outgoing)?;
+        }
+        Packet::PingReq => {
+            PingResp.write(outgoing)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Publishes the will of a client that went away without a DISCONNECT
+fn publish_will(will: LastWill, sessions: &HashMap<u64, Session>) {
+    if !valid_topic(&will.topic) {
+        return;
+    }
+    route(
+        Publish {
+            dup: false,
+            qos: will.qos,
+            retain: false,
+            topic: will.topic,
+            pkid: 0,
+            payload: will.message,
+        },
+        sessions,
+    );
+}
+
+/// Hands `publish` to every session with a matching filter, once per
+/// session, at the lower of its QoS and the highest one the session was
+/// granted for a matching filter
+fn route(mut publish: Publish, sessions: &HashMap<u64, Session>) {
+    publish.pkid = 0;
+    publish.dup = false;
+    publish.retain = false;
+    for (id, session) in sessions {
+        let Some(granted) = session
+            .filters
+            .iter()
+            .filter(|(filter, _)| matches(&publish.topic, filter))
+            .map(|(_, qos)| *qos)
+            .reduce(|a, b| if b > a { b } else { a })
+        else {
+            continue;
+        };
+        let mut delivery = publish.clone();
+        if granted < delivery.qos {
+            delivery.qos = granted;
+        }
+        // A closed channel means the session is being torn down
+        if let Err(mpsc::error::TrySendError::Full(_)) = session.outgoing.try_send(delivery) {
+            warn!(
+                "Embedded broker dropped a publish on {} for slow session {}",
+                publish.topic, id
+            );
+        }
+    }
+}
+
+fn invalid_data(e: mqttbytes::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_common::{NodeInfo, NodeType};
+    use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// Polls `eventloop` until it yields an incoming packet accepted by `want`
+    async fn wait_for<T>(eventloop: &mut EventLoop, want: impl Fn(Packet) -> Option<T>) -> T {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::Incoming(packet) = eventloop.poll().await.unwrap() {
+                    if let Some(found) = want(packet) {
+                        return found;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for the broker")
+    }
+
+    #[tokio::test]
+    async fn test_node_heartbeat_reaches_orchestrator_through_embedded_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, None));
+
+        let (orchestrator, mut orchestrator_loop) =
+            AsyncClient::new(MqttOptions::new("orchestrator", "127.0.0.1", port), 10);
+        orchestrator
+            .subscribe("heartbeat/master/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        wait_for(&mut orchestrator_loop, |packet| {
+            matches!(packet, Packet::SubAck(_)).then_some(())
+        })
+        .await;
+
+        let (node, mut node_loop) =
+            AsyncClient::new(MqttOptions::new("node", "127.0.0.1", port), 10);
+        let heartbeat = NodeInfo::new(NodeType::Node, 10);
+        node.publish(
+            format!("heartbeat/master/{}", heartbeat.node_id),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&heartbeat).unwrap(),
+        )
+        .await
+        .unwrap();
+        wait_for(&mut node_loop, |packet| {
+            matches!(packet, Packet::PubAck(_)).then_some(())
+        })
+        .await;
+
+        let received = wait_for(&mut orchestrator_loop, |packet| match packet {
+            Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await;
+        assert_eq!(
+            received.topic,
+            format!("heartbeat/master/{}", heartbeat.node_id)
+        );
+        let received: NodeInfo = serde_json::from_slice(&received.payload).unwrap();
+        assert_eq!(received.node_id, heartbeat.node_id);
+    }
+
+    #[tokio::test]
+    async fn test_configured_login_is_enforced() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, Some(Login::new("pool", "secret"))));
+
+        let connect = |password: Option<&str>| {
+            let mut options = MqttOptions::new("node", "127.0.0.1", port);
+            if let Some(password) = password {
+                options.set_credentials("pool", password);
+            }
+            AsyncClient::new(options, 10).1
+        };
+        for password in [None, Some("guess")] {
+            let mut eventloop = connect(password);
+            let refused = timeout(Duration::from_secs(5), eventloop.poll())
+                .await
+                .unwrap();
+            assert!(refused.is_err(), "{:?}", password);
+        }
+
+        let mut eventloop = connect(Some("secret"));
+        let code = wait_for(&mut eventloop, |packet| match packet {
+            Packet::ConnAck(ack) => Some(ack.code),
+            _ => None,
+        })
+        .await;
+        assert_eq!(code, ConnectReturnCode::Success);
+    }
+
+    /// Connects `client_id` and polls its event loop in the background
+    async fn connect(port: u16, client_id: &str) -> AsyncClient {
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new(client_id, "127.0.0.1", port), 10);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        client
+    }
+
+    #[tokio::test]
+    async fn test_will_fires_only_for_clients_that_vanish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, None));
+
+        let (orchestrator, mut orchestrator_loop) =
+            AsyncClient::new(MqttOptions::new("orchestrator", "127.0.0.1", port), 10);
+        orchestrator
+            .subscribe("heartbeat/master/+", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        wait_for(&mut orchestrator_loop, |packet| {
+            matches!(packet, Packet::SubAck(_)).then_some(())
+        })
+        .await;
+
+        let with_will = |node_id: &str| {
+            let mut options = MqttOptions::new(node_id, "127.0.0.1", port);
+            options.set_keep_alive(Duration::from_secs(1));
+            options.set_last_will(LastWill::new(
+                format!("heartbeat/master/{}", node_id),
+                "offline",
+                QoS::AtLeastOnce,
+                false,
+            ));
+            AsyncClient::new(options, 10)
+        };
+        let connected = |packet| matches!(packet, Packet::ConnAck(_)).then_some(());
+
+        // A clean DISCONNECT discards the will
+        let (leaving, mut leaving_loop) = with_will("leaving");
+        wait_for(&mut leaving_loop, connected).await;
+        leaving.disconnect().await.unwrap();
+        while leaving_loop.poll().await.is_ok() {}
+
+        // Going silent past the keep-alive publishes it
+        let (_silent, mut silent_loop) = with_will("silent");
+        wait_for(&mut silent_loop, connected).await;
+
+        let will = wait_for(&mut orchestrator_loop, |packet| match packet {
+            Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await;
+        assert_eq!(will.topic, "heartbeat/master/silent");
+        assert_eq!(&will.payload[..], b"offline");
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+    }
+
+    #[tokio::test]
+    async fn test_qos2_publishes_are_delivered_exactly_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, None));
+
+        let (client, mut client_loop) =
+            AsyncClient::new(MqttOptions::new("client", "127.0.0.1", port), 10);
+        client
+            .subscribe("routing/response/+", QoS::ExactlyOnce)
+            .await
+            .unwrap();
+        let granted = wait_for(&mut client_loop, |packet| match packet {
+            Packet::SubAck(ack) => Some(ack.return_codes),
+            _ => None,
+        })
+        .await;
+        assert_eq!(
+            granted,
+            vec![SubscribeReasonCode::Success(QoS::ExactlyOnce)]
+        );
+
+        let orchestrator = connect(port, "orchestrator").await;
+        orchestrator
+            .publish(
+                "routing/response/client",
+                QoS::ExactlyOnce,
+                false,
+                "accepted",
+            )
+            .await
+            .unwrap();
+
+        let delivered = wait_for(&mut client_loop, |packet| match packet {
+            Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await;
+        assert_eq!(delivered.qos, QoS::ExactlyOnce);
+        assert_ne!(delivered.pkid, 0);
+        // The broker completes its side of the handshake...
+        let released = wait_for(&mut client_loop, |packet| match packet {
+            Packet::PubRel(release) => Some(release.pkid),
+            _ => None,
+        })
+        .await;
+        assert_eq!(released, delivered.pkid);
+        // ...and sends nothing more
+        assert!(timeout(Duration::from_millis(200), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(_)) = client_loop.poll().await.unwrap() {
+                    return;
+                }
+            }
+        })
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn test_slow_sessions_drop_publishes_past_their_queue() {
+        let (outgoing, mut deliveries) = mpsc::channel(SESSION_QUEUE_CAP);
+        let sessions = HashMap::from([(
+            1,
+            Session {
+                filters: vec![("data/#".to_string(), QoS::AtMostOnce)],
+                outgoing,
+            },
+        )]);
+        for _ in 0..SESSION_QUEUE_CAP + 10 {
+            route(
+                Publish::new("data/processed", QoS::AtLeastOnce, "payload"),
+                &sessions,
+            );
+        }
+        let mut queued = 0;
+        while deliveries.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, SESSION_QUEUE_CAP);
+    }
+}
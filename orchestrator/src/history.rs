@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// How a client's routing changed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum RoutingChange {
+    /// The client was routed for the first time
+    Added,
+    /// The client was routed again, away from `from`
+    Migrated { from: Vec<String> },
+    /// The routing was dropped, e.g. because its node stopped heartbeating
+    Removed { reason: String },
+}
+
+/// One change to the routing table
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoutingEvent {
+    pub timestamp: u64,
+    pub client_id: String,
+    /// Nodes the client is routed to after the change; empty once removed
+    pub node_ids: Vec<String>,
+    pub change: RoutingChange,
+}
+
+/// Body of an `orchestrator/query/routing_history` request. An empty payload
+/// asks for the whole retained window.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RoutingHistoryQuery {
+    /// Only the most recent `limit` events
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Bounded log of routing changes, oldest first
+pub struct RoutingHistory {
+    events: VecDeque<RoutingEvent>,
+    capacity: usize,
+}
+
+impl RoutingHistory {
+    pub fn new(capacity: usize) -> Self {
+        RoutingHistory {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends an event, dropping the oldest once `capacity` is reached
+    pub fn record(&mut self, event: RoutingEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The most recent events, oldest first; all of them when `limit` is `None`
+    pub fn recent(&self, limit: Option<usize>) -> Vec<RoutingEvent> {
+        let limit = limit.unwrap_or(self.events.len()).min(self.events.len());
+        self.events
+            .iter()
+            .skip(self.events.len() - limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(timestamp: u64) -> RoutingEvent {
+        RoutingEvent {
+            timestamp,
+            client_id: format!("client-{}", timestamp),
+            node_ids: vec!["node-a".to_string()],
+            change: RoutingChange::Added,
+        }
+    }
+
+    #[test]
+    fn test_history_keeps_most_recent_window() {
+        let mut history = RoutingHistory::new(3);
+        for timestamp in 0..5 {
+            history.record(added(timestamp));
+        }
+        let timestamps = |events: Vec<RoutingEvent>| -> Vec<u64> {
+            events.iter().map(|event| event.timestamp).collect()
+        };
+        assert_eq!(timestamps(history.recent(None)), vec![2, 3, 4]);
+        assert_eq!(timestamps(history.recent(Some(2))), vec![3, 4]);
+        assert_eq!(timestamps(history.recent(Some(10))), vec![2, 3, 4]);
+    }
+}
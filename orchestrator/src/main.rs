@@ -1,357 +1,3642 @@
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+mod balancer;
+mod broker;
+mod history;
+mod metrics;
+mod recorder;
+mod webhook;
+
+use balancer::NodeSelector;
+use history::{RoutingChange, RoutingEvent, RoutingHistory, RoutingHistoryQuery};
+use recorder::Recorder;
+use rumqttc::{AsyncClient, Event, Packet};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio::time;
+use tracing::{debug, error, info, instrument, warn};
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-
 // Import the common types
+use mqtt_common::config::{self, ConfigFile};
+use mqtt_common::integrity::{SharedSecret, Signed};
 use mqtt_common::{
-    NodeInfo, NodeStatus, NodeType, RoutingRequest, RoutingResponse, RoutingStatus,
-    ClientConfiguration,
+    NodeInfo, NodeStatus, NodeType, PoolControl, ProbeAck, ProbeRequest, RegionSummary, RoutingRequest, RoutingResponse,
+    RoutingStatus, ClientConfiguration, WireFormat, Backoff, requested_compression,
+    mqtt_client_id, MqttSettings, decode_or_log, NodeAssignment, RoutingIssuer, PROTOCOL_VERSION,
+    ROUTED_CLIENTS_METADATA_KEY, FEATURE_COMPRESSION, topics, HeartbeatMessage, advertised_processing_timeout,
+    RoutingTableSnapshot, PoolError, MqttTransport, QosPolicy, health, RoutingAck,
 };
 
-#[derive(Clone)]
-struct OrchestrationService {
-    nodes: Arc<Mutex<HashMap<String, NodeInfo>>>,
-    routing_table: Arc<Mutex<HashMap<String, String>>>,
-    client: Arc<AsyncClient>,
-}
+/// Region summaries older than this are not used for routing
+const REGION_TIMEOUT_SECS: u64 = 15;
 
-impl OrchestrationService {
-    async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let mut mqtt_options = MqttOptions::new(
-            format!("orchestrator-{}", Uuid::new_v4()),
-            "localhost",
-            1883,
-        );
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
+/// How often nodes publish heartbeats
+const EXPECTED_HEARTBEAT_INTERVAL_SECS: u64 = 5;
 
-        let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
-        let client = Arc::new(client);
+/// How long a fresh routing is exempt from reconciliation: a node only
+/// reports a client in the heartbeats after it took up the routing
+const RECONCILE_GRACE: Duration = Duration::from_secs(2 * EXPECTED_HEARTBEAT_INTERVAL_SECS);
 
-        let nodes = Arc::new(Mutex::new(HashMap::new()));
-        let routing_table = Arc::new(Mutex::new(HashMap::new()));
+/// Processing timeout handed to clients of nodes that don't advertise theirs
+const DEFAULT_PROCESSING_TIMEOUT_MS: u64 = 30000;
 
-        let service = OrchestrationService {
-            nodes: Arc::clone(&nodes),
-            routing_table: Arc::clone(&routing_table),
-            client: Arc::clone(&client),
-        };
+/// Timing settings, read from the environment. Intervals are at least one
+/// second, as `time::interval` panics on zero.
+#[derive(Debug, Clone, PartialEq)]
+struct OrchestratorConfig {
+    /// Broker credentials, keep-alive and packet size
+    mqtt: MqttSettings,
+    /// Nodes silent for longer than this are removed
+    heartbeat_timeout_secs: u64,
+    /// How often inactive nodes are cleaned up
+    cleanup_interval_secs: u64,
+    /// How often the status table is printed
+    status_print_interval_secs: u64,
+    /// Optional `http://` endpoint receiving periodic utilization reports
+    webhook_url: Option<String>,
+    /// How often the utilization report is posted
+    webhook_interval_secs: u64,
+    /// Port serving Prometheus metrics on `/metrics`; no server when unset
+    metrics_port: Option<u16>,
+    /// Port serving the `/health` readiness probe; no server when unset
+    health_port: Option<u16>,
+    /// Prepended to the MQTT client id so broker logs show the host or pod
+    client_id_prefix: Option<String>,
+    /// Namespaces every topic so several pools can share a broker
+    topic_prefix: String,
+    /// Routing changes kept for `orchestrator/query/routing_history`
+    routing_history_size: usize,
+    /// Topic every routing change is also published to; none when unset
+    routing_audit_topic: Option<String>,
+    /// Address of the broker hosted in-process, loopback unless
+    /// `EMBEDDED_BROKER_ADDR` says otherwise; an external broker is used when unset
+    embedded_broker: Option<SocketAddr>,
+    /// Whether routing divergences reported by node heartbeats are fixed in
+    /// the routing table, rather than only logged
+    correct_routing_divergence: bool,
+    /// Retry hint sent with `Pending` while no node is active; such routing
+    /// requests are rejected outright when unset
+    unavailable_retry_after_ms: Option<u64>,
+    /// File receiving every routing request and response as JSON lines; no
+    /// recording when unset
+    record_file: Option<String>,
+    /// Signs outgoing and verifies incoming messages when set
+    shared_secret: Option<SharedSecret>,
+    /// QoS of routing traffic and of everything else
+    qos: QosPolicy,
+    /// Utilization gap (0.0-1.0) between the busiest and idlest active node
+    /// above which clients are moved off the busiest; no rebalancing when unset
+    rebalance_threshold: Option<f32>,
+    /// Most clients moved in one rebalancing pass
+    rebalance_max_moves: usize,
+    /// Minimum time between rebalancing passes that moved clients
+    rebalance_cooldown_secs: u64,
+    /// How long an accepted routing waits for the client's `RoutingAck`
+    /// before it is rolled back; routings are final right away when unset,
+    /// as clients predating acks need
+    routing_ack_timeout_secs: Option<u64>,
+}
 
-        // Subscribe to required topics
-        client
-            .subscribe("heartbeat/master/+", QoS::AtLeastOnce)
-            .await?;
-        client
-            .subscribe("routing/request", QoS::AtLeastOnce)
-            .await?;
-        client
-            .subscribe("master/status/+", QoS::AtLeastOnce)
-            .await?;
+impl Default for OrchestratorConfig {
+    fn default() -> Self {
+        OrchestratorConfig {
+            mqtt: MqttSettings::default(),
+            heartbeat_timeout_secs: 15,
+            cleanup_interval_secs: 15,
+            status_print_interval_secs: 10,
+            webhook_url: None,
+            webhook_interval_secs: 60,
+            metrics_port: None,
+            health_port: None,
+            client_id_prefix: None,
+            topic_prefix: String::new(),
+            routing_history_size: 1000,
+            routing_audit_topic: None,
+            embedded_broker: None,
+            correct_routing_divergence: false,
+            unavailable_retry_after_ms: Some(5000),
+            record_file: None,
+            shared_secret: None,
+            qos: QosPolicy::default(),
+            rebalance_threshold: None,
+            rebalance_max_moves: 2,
+            rebalance_cooldown_secs: 60,
+            routing_ack_timeout_secs: None,
+        }
+    }
+}
 
-        // Start event loop handler
-        service.start_event_loop(eventloop).await;
+impl OrchestratorConfig {
+    /// Builds the config from `var` lookups, falling back to the
+    /// `[orchestrator]` table of the config file and then to the defaults.
+    /// Invalid variables are ignored; invalid file values are an error.
+    fn load(
+        file: &config::OrchestratorConfig,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, PoolError> {
+        let defaults = OrchestratorConfig::default();
+        let embedded_broker = config::flag(&var, "EMBEDDED_BROKER", file.embedded_broker)
+            .unwrap_or(false)
+            .then(|| {
+                config::parsed(
+                    &var,
+                    "EMBEDDED_BROKER_ADDR",
+                    file.embedded_broker_addr.as_deref(),
+                    |addr| addr.parse().ok(),
+                )
+            })
+            .transpose()?
+            .map(|addr| addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 1883))));
+        let usable_threshold = |threshold: &f32| *threshold > 0.0 && *threshold <= 1.0;
+        let rebalance_threshold = match file.rebalance_threshold {
+            Some(threshold) if !usable_threshold(&threshold) => {
+                return Err(config::invalid("REBALANCE_THRESHOLD", threshold));
+            }
+            file => config::setting(&var, "REBALANCE_THRESHOLD", None)
+                .filter(usable_threshold)
+                .or(file),
+        };
+        let config = OrchestratorConfig {
+            mqtt: MqttSettings::load(&file.mqtt(), &var)?,
+            heartbeat_timeout_secs: config::setting(
+                &var,
+                "HEARTBEAT_TIMEOUT_SECS",
+                file.heartbeat_timeout_secs,
+            )
+            .unwrap_or(defaults.heartbeat_timeout_secs),
+            cleanup_interval_secs: config::bounded(
+                &var,
+                "CLEANUP_INTERVAL_SECS",
+                file.cleanup_interval_secs,
+                1..=u64::MAX,
+            )?
+            .unwrap_or(defaults.cleanup_interval_secs),
+            status_print_interval_secs: config::bounded(
+                &var,
+                "STATUS_PRINT_INTERVAL_SECS",
+                file.status_print_interval_secs,
+                1..=u64::MAX,
+            )?
+            .unwrap_or(defaults.status_print_interval_secs),
+            webhook_url: config::setting(&var, "WEBHOOK_URL", file.webhook_url.clone())
+                .filter(|url| !url.is_empty()),
+            webhook_interval_secs: config::bounded(
+                &var,
+                "WEBHOOK_INTERVAL_SECS",
+                file.webhook_interval_secs,
+                1..=u64::MAX,
+            )?
+            .unwrap_or(defaults.webhook_interval_secs),
+            metrics_port: config::setting(&var, "METRICS_PORT", file.metrics_port),
+            health_port: config::setting(&var, "HEALTH_PORT", file.health_port),
+            client_id_prefix: config::setting(
+                &var,
+                "CLIENT_ID_PREFIX",
+                file.client_id_prefix.clone(),
+            )
+            .filter(|prefix| !prefix.is_empty()),
+            topic_prefix: config::setting(&var, "TOPIC_PREFIX", file.topic_prefix.clone())
+                .unwrap_or_default()
+                .trim_matches('/')
+                .to_string(),
+            routing_history_size: config::setting(
+                &var,
+                "ROUTING_HISTORY_SIZE",
+                file.routing_history_size,
+            )
+            .unwrap_or(defaults.routing_history_size),
+            routing_audit_topic: config::setting(
+                &var,
+                "ROUTING_AUDIT_TOPIC",
+                file.routing_audit_topic.clone(),
+            )
+            .filter(|topic| !topic.is_empty()),
+            embedded_broker,
+            correct_routing_divergence: config::flag(
+                &var,
+                "CORRECT_ROUTING_DIVERGENCE",
+                file.correct_routing_divergence,
+            )
+            .unwrap_or(defaults.correct_routing_divergence),
+            // 0 turns the pending answer off
+            unavailable_retry_after_ms: config::setting(
+                &var,
+                "UNAVAILABLE_RETRY_AFTER_MS",
+                file.unavailable_retry_after_ms,
+            )
+            .or(defaults.unavailable_retry_after_ms)
+            .filter(|ms| *ms > 0),
+            record_file: config::setting(&var, "RECORD_FILE", file.record_file.clone())
+                .filter(|path| !path.is_empty()),
+            shared_secret: SharedSecret::load(file.shared_secret.as_deref(), &var),
+            qos: QosPolicy::load(file.mqtt_qos, file.routing_qos, &var)?,
+            rebalance_threshold,
+            rebalance_max_moves: config::setting(
+                &var,
+                "REBALANCE_MAX_MOVES",
+                file.rebalance_max_moves,
+            )
+            .unwrap_or(defaults.rebalance_max_moves),
+            rebalance_cooldown_secs: config::setting(
+                &var,
+                "REBALANCE_COOLDOWN_SECS",
+                file.rebalance_cooldown_secs,
+            )
+            .unwrap_or(defaults.rebalance_cooldown_secs),
+            // Unset or 0 makes routings final without waiting for an ack
+            routing_ack_timeout_secs: config::setting(
+                &var,
+                "ROUTING_ACK_TIMEOUT_SECS",
+                file.routing_ack_timeout_secs,
+            )
+            .or(defaults.routing_ack_timeout_secs)
+            .filter(|secs| *secs > 0),
+        };
+        if !config.timeout_covers_heartbeats() {
+            warn!(
+                "HEARTBEAT_TIMEOUT_SECS={} is less than twice the {}s heartbeat interval; healthy nodes may be dropped",
+                config.heartbeat_timeout_secs, EXPECTED_HEARTBEAT_INTERVAL_SECS
+            );
+        }
+        Ok(config)
+    }
 
-        Ok(service)
+    /// Whether a node can miss one heartbeat without being timed out
+    fn timeout_covers_heartbeats(&self) -> bool {
+        self.heartbeat_timeout_secs >= 2 * EXPECTED_HEARTBEAT_INTERVAL_SECS
     }
+}
 
-    async fn handle_routing_request(
-        &self,
-        request: RoutingRequest,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut nodes_guard = self.nodes.lock().await;
-        let selected_node = nodes_guard
-            .iter_mut() // Note: Using iter_mut() to allow updating the load
-            .filter(|(_, info)| {
-                info.status == NodeStatus::Active
-                    && info.current_load + 1 <= info.capacity
-                    && info.node_type == NodeType::Node
-            })
-            .min_by_key(|(_, info)| {
-                ((info.current_load as f32 / info.capacity as f32) * 100.0) as u32
-            });
+/// Role this orchestrator plays in a hierarchical deployment
+#[derive(Debug, Clone, PartialEq)]
+enum OrchestrationMode {
+    /// Routes clients directly to its own nodes
+    Standalone,
+    /// Routes clients to regional orchestrators by their aggregate capacity
+    Parent,
+    /// Manages the nodes of one region and reports its capacity to the parent
+    Regional(String),
+}
 
-        if let Some((node_id, master_info)) = selected_node {
-            // Update the master's load before releasing the lock
-            master_info.current_load += 1;
-            let node_id = node_id.clone();
+impl OrchestrationMode {
+    /// Reads `ORCHESTRATOR_MODE` (`standalone`, `parent` or `regional`) and
+    /// `ORCHESTRATOR_REGION` from `var`, else the config file
+    fn load(
+        file: &config::OrchestratorConfig,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, PoolError> {
+        let mode = config::parsed(
+            &var,
+            "ORCHESTRATOR_MODE",
+            file.orchestrator_mode.as_deref(),
+            |mode| match mode {
+                "standalone" | "parent" | "regional" => Some(mode.to_string()),
+                _ => None,
+            },
+        )?;
+        Ok(match mode.as_deref() {
+            Some("parent") => OrchestrationMode::Parent,
+            Some("regional") => OrchestrationMode::Regional(
+                config::setting(
+                    &var,
+                    "ORCHESTRATOR_REGION",
+                    file.orchestrator_region.clone(),
+                )
+                .unwrap_or_else(|| "default".to_string()),
+            ),
+            _ => OrchestrationMode::Standalone,
+        })
+    }
+}
 
-            // Update routing table
-            self.routing_table
-                .lock()
-                .await
-                .insert(request.client_id.clone(), node_id.clone());
-
-            // Create slave configuration
-            let slave_config = ClientConfiguration {
-                subscribe_topics: vec![
-                    format!("data/input/{}", request.client_id),
-                    format!("control/{}", request.client_id),
-                ],
-                publish_topic: format!("data/processed/{}", request.client_id),
-                qos: 1,
-                max_batch_size: 100,
-                processing_timeout_ms: 30000,
-            };
+/// Where a node's own list of routed clients disagrees with the routing table
+#[derive(Debug, Default, PartialEq)]
+struct RoutingDivergence {
+    /// Routed to the node by the orchestrator, but unknown to the node
+    missing_on_node: Vec<String>,
+    /// Accepted by the node, but not routed anywhere by the orchestrator.
+    /// Clients the orchestrator routed to other nodes are not counted: every
+    /// node answers routing requests, so nodes also know clients they lost.
+    unknown_to_orchestrator: Vec<String>,
+}
 
-            let response = RoutingResponse {
-                node_id: node_id.clone(),
-                client_id: request.client_id.clone(),
-                status: RoutingStatus::Accepted,
-                rejection_reason: None,
-                configuration: Some(slave_config),
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
+impl RoutingDivergence {
+    /// Compares `node_id`'s entries in `routing_table` with the clients it reported
+    fn between(
+        routing_table: &HashMap<String, Vec<String>>,
+        node_id: &str,
+        reported: &HashSet<String>,
+    ) -> Self {
+        let mut divergence = RoutingDivergence {
+            missing_on_node: routing_table
+                .iter()
+                .filter(|(client_id, node_ids)| {
+                    node_ids.iter().any(|id| id == node_id) && !reported.contains(*client_id)
+                })
+                .map(|(client_id, _)| client_id.clone())
+                .collect(),
+            unknown_to_orchestrator: reported
+                .iter()
+                .filter(|client_id| !routing_table.contains_key(*client_id))
+                .cloned()
+                .collect(),
+        };
+        divergence.missing_on_node.sort();
+        divergence.unknown_to_orchestrator.sort();
+        divergence
+    }
 
-            if let Ok(response_payload) = serde_json::to_string(&response) {
-                self.client
-                    .publish(
-                        format!("routing/response/{}", request.client_id),
-                        QoS::AtLeastOnce,
-                        false,
-                        response_payload.as_bytes(),
-                    )
-                    .await?;
+    fn is_empty(&self) -> bool {
+        self.missing_on_node.is_empty() && self.unknown_to_orchestrator.is_empty()
+    }
+}
 
-                println!(
-                    "Assigned Node [{}] to Client [{}] (Current load: {}/{})",
-                    node_id, request.client_id, master_info.current_load, master_info.capacity
-                );
-            }
-        } else {
-            // Send rejection response if no suitable master found
-            let response = RoutingResponse {
-                node_id: String::from("none"),
-                client_id: request.client_id.clone(),
-                status: RoutingStatus::Rejected,
-                rejection_reason: Some("No available master nodes".to_string()),
-                configuration: None,
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
+/// Picks the region with the most free capacity from reasonably fresh summaries
+fn select_region(regions: &HashMap<String, RegionSummary>, current_time: u64) -> Option<String> {
+    regions
+        .values()
+        .filter(|summary| {
+            current_time.saturating_sub(summary.timestamp) <= REGION_TIMEOUT_SECS
+                && summary.available_capacity() > 0
+        })
+        .max_by_key(|summary| summary.available_capacity())
+        .map(|summary| summary.region.clone())
+}
 
-            if let Ok(response_payload) = serde_json::to_string(&response) {
-                self.client
-                    .publish(
-                        format!("routing/response/{}", request.client_id),
-                        QoS::AtLeastOnce,
-                        false,
-                        response_payload.as_bytes(),
-                    )
-                    .await?;
-            }
-            println!("No available Nodes for client {}", request.client_id);
+/// The client's preferred node, when it is an active node with room for one
+/// more client, by the same rule the balancer applies. Otherwise
+/// logs why the preference can't be honored and leaves the choice to the
+/// balancer.
+fn usable_preferred_node(
+    nodes: &HashMap<String, NodeInfo>,
+    request: &RoutingRequest,
+) -> Option<String> {
+    let preferred = request.preferred_node.as_ref()?;
+    let reason = match nodes.get(preferred) {
+        None => "unknown node",
+        Some(info) if info.node_type != NodeType::Node || info.status != NodeStatus::Active => {
+            "not active"
         }
-        Ok(())
+        Some(info) if !balancer::has_room_for_client(info) => "at capacity",
+        Some(info) if !info.supports_all(&request.data_type) => "missing requested types",
+        Some(_) => return Some(preferred.clone()),
+    };
+    info!(
+        "Preferred node {} of client {} is unavailable ({}); falling back to the balancer",
+        preferred, request.client_id, reason
+    );
+    None
+}
+
+/// Webhook attempts per report before it is dropped
+const WEBHOOK_RETRIES: u32 = 3;
+
+/// Utilization summary posted to the external webhook
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct UtilizationReport {
+    node_count: u32,
+    total_capacity: u32,
+    used_capacity: u32,
+    /// `used_capacity / total_capacity`, 0 when there is no capacity
+    utilization: f64,
+    /// Routing requests rejected since startup
+    rejected_routings: u64,
+    timestamp: u64,
+}
+
+/// Whether the pool as a whole is accepting new routings
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PoolMode {
+    Serving,
+    /// Pool-wide maintenance: every routing request is rejected
+    Draining,
+}
+
+/// Body of an `orchestrator/reassign` command: move the client off its
+/// current nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReassignRequest {
+    client_id: String,
+}
+
+#[derive(Clone)]
+struct OrchestrationService<T = AsyncClient> {
+    nodes: Arc<Mutex<HashMap<String, NodeInfo>>>,
+    /// Client id -> assigned node ids; more than one for fan-out clients
+    routing_table: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    regions: Arc<Mutex<HashMap<String, RegionSummary>>>,
+    pool_mode: Arc<Mutex<PoolMode>>,
+    /// Nodes the current pool drain put into maintenance, released on resume
+    drained_nodes: Arc<Mutex<Vec<String>>>,
+    selector: Arc<dyn NodeSelector + Send + Sync>,
+    /// Probes awaiting an ack: probe id -> (node id, sent at)
+    pending_probes: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+    /// Round-trip time of the latest answered probe per node
+    probe_latencies: Arc<Mutex<HashMap<String, Duration>>>,
+    /// Payload encodings each node advertised in its heartbeat metadata
+    wire_formats: Arc<Mutex<HashMap<String, Vec<WireFormat>>>>,
+    /// Packets per second each node processed between its last two heartbeats
+    throughput: Arc<Mutex<HashMap<String, f64>>>,
+    /// Routing requests rejected since startup
+    rejected_routings: Arc<AtomicU64>,
+    /// Recent routing adds, migrations and removals
+    routing_history: Arc<Mutex<RoutingHistory>>,
+    /// Clients heartbeating on `heartbeat/slave/+`, by client id
+    slaves: Arc<Mutex<HashMap<String, NodeInfo>>>,
+    /// When the rebalancer last moved clients
+    last_rebalance: Arc<Mutex<Option<Instant>>>,
+    /// Latest accepted routing request per routed client, replayed when the
+    /// client is reassigned
+    routed_requests: Arc<Mutex<HashMap<String, RoutingRequest>>>,
+    /// Accepted routings the client hasn't acknowledged yet: client id ->
+    /// when the routing was sent
+    unacked_routings: Arc<Mutex<HashMap<String, Instant>>>,
+    /// When each routed client was last assigned its nodes
+    routed_at: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Nodes each logical client id was last routed to, kept after the
+    /// client leaves so a restarted client can return to them
+    logical_routes: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Tees routing traffic to `config.record_file`
+    recorder: Option<Arc<Recorder>>,
+    /// Whether the broker connection is up, as reported on `/health`
+    connected: Arc<AtomicBool>,
+    mode: OrchestrationMode,
+    config: OrchestratorConfig,
+    /// Signs everything published when the pool shares a secret
+    client: Arc<Signed<T>>,
+}
+
+impl OrchestrationService {
+    async fn new(
+        mode: OrchestrationMode,
+        selector: Box<dyn NodeSelector + Send + Sync>,
+        config: OrchestratorConfig,
+    ) -> Result<Self, PoolError> {
+        let mqtt_options = config.mqtt.options(
+            mqtt_client_id(
+                config.client_id_prefix.as_deref(),
+                &format!("orchestrator-{}", Uuid::new_v4()),
+            ),
+            "localhost",
+            config
+                .embedded_broker
+                .map_or(1883, |address| address.port()),
+        );
+        let channel_cap = mqtt_options.request_channel_capacity();
+        let (client, eventloop) = AsyncClient::new(mqtt_options, channel_cap);
+        let service = OrchestrationService::build(client, mode, selector, config);
+
+        service.subscribe_topics().await?;
+
+        // Start event loop handler
+        service.start_event_loop(eventloop).await;
+
+        Ok(service)
     }
 
     async fn start_event_loop(&self, mut eventloop: rumqttc::EventLoop) {
-        let nodes = Arc::clone(&self.nodes);
-        let client = Arc::clone(&self.client);
         let service = self.clone();
 
         tokio::spawn(async move {
+            let mut backoff = Backoff::default();
             loop {
                 match eventloop.poll().await {
                     Ok(notification) => {
+                        backoff.reset();
                         match notification {
                             Event::Incoming(Packet::Publish(publish)) => {
-                                match publish.topic.as_str() {
-                                    topic if topic.starts_with("heartbeat/master/") => {
-                                        let node_id = topic.split('/').last().unwrap_or("unknown");
-                                        if let Ok(mut node_info) =
-                                            serde_json::from_slice::<NodeInfo>(&publish.payload)
-                                        {
-                                            // Preserve current load when updating heartbeat
-                                            let current_load = nodes
-                                                .lock()
-                                                .await
-                                                .get(node_id)
-                                                .map(|info| info.current_load)
-                                                .unwrap_or(0);
-
-                                            node_info.current_load = current_load;
-                                            node_info.last_heartbeat = SystemTime::now()
-                                                .duration_since(UNIX_EPOCH)
-                                                .unwrap()
-                                                .as_secs();
-
-                                            nodes
-                                                .lock()
-                                                .await
-                                                .insert(node_id.to_string(), node_info);
-                                        }
-                                    }
-                                    "routing/request" => {
-                                        if let Ok(request) = serde_json::from_slice::<RoutingRequest>(
-                                            &publish.payload,
-                                        ) {
-                                            if let Err(e) =
-                                                service.handle_routing_request(request).await
-                                            {
-                                                eprintln!(
-                                                    "Failed to handle routing request: {}",
-                                                    e
-                                                );
-                                            }
-                                        }
-                                    }
-                                    _ => {}
-                                }
+                                service
+                                    .handle_publish(&publish.topic, &publish.payload)
+                                    .await;
                             }
                             Event::Incoming(Packet::ConnAck(_)) => {
-                                println!("Connected to MQTT broker");
+                                service.connected.store(true, Ordering::Relaxed);
+                                info!("Connected to MQTT broker");
                             }
                             Event::Incoming(Packet::SubAck(_)) => {
-                                println!("Subscribed to topics");
+                                info!("Subscribed to topics");
                             }
                             _ => {}
                         }
                     }
                     Err(e) => {
-                        eprintln!("Connection error: {}", e);
-                        time::sleep(Duration::from_secs(5)).await;
+                        service.connected.store(false, Ordering::Relaxed);
+                        let delay = backoff.next();
+                        error!("Connection error: {}; retrying in {:?}", e, delay);
+                        time::sleep(delay).await;
                     }
                 }
             }
         });
     }
+}
 
-    async fn cleanup_inactive_nodes(&self) {
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+impl<T: MqttTransport> OrchestrationService<T> {
+    /// Subscribes to the topics this orchestrator's mode listens on
+    async fn subscribe_topics(&self) -> Result<(), rumqttc::ClientError> {
+        let prefix = self.config.topic_prefix.as_str();
+        let mut filters = vec![topics::pool_control(prefix)];
+        let mut routing_filters = vec![match &self.mode {
+            OrchestrationMode::Parent => topics::routing_request(prefix),
+            // Regional orchestrators only see requests the parent forwards to them
+            OrchestrationMode::Regional(region) => topics::regional_routing_request(prefix, region),
+            OrchestrationMode::Standalone => topics::routing_request(prefix),
+        }];
+        match &self.mode {
+            OrchestrationMode::Parent => {
+                filters.push(topics::region_summary(prefix, "+"));
+            }
+            _ => {
+                filters.extend([
+                    topics::heartbeat_master(prefix, "+"),
+                    topics::heartbeat_slave(prefix, "+"),
+                    topics::probe_ack(prefix, "+"),
+                    topics::admin_probe(prefix, "+"),
+                ]);
+                filters.extend([
+                    topics::master_status(prefix, "+"),
+                    topics::routing_history_query(prefix),
+                    topics::routings_query(prefix),
+                    topics::reassign(prefix),
+                ]);
+                routing_filters.push(topics::routing_ack(prefix, "+"));
+            }
+        }
+        for filter in filters {
+            self.client
+                .subscribe(filter, self.config.qos.default)
+                .await?;
+        }
+        for filter in routing_filters {
+            self.client
+                .subscribe(filter, self.config.qos.routing)
+                .await?;
+        }
+        Ok(())
+    }
 
-        let timeout = 15; // seconds
+    fn build(
+        client: T,
+        mode: OrchestrationMode,
+        selector: Box<dyn NodeSelector + Send + Sync>,
+        config: OrchestratorConfig,
+    ) -> Self {
+        OrchestrationService {
+            nodes: Arc::new(Mutex::new(HashMap::new())),
+            routing_table: Arc::new(Mutex::new(HashMap::new())),
+            regions: Arc::new(Mutex::new(HashMap::new())),
+            pool_mode: Arc::new(Mutex::new(PoolMode::Serving)),
+            drained_nodes: Arc::new(Mutex::new(Vec::new())),
+            selector: Arc::from(selector),
+            pending_probes: Arc::new(Mutex::new(HashMap::new())),
+            probe_latencies: Arc::new(Mutex::new(HashMap::new())),
+            wire_formats: Arc::new(Mutex::new(HashMap::new())),
+            throughput: Arc::new(Mutex::new(HashMap::new())),
+            rejected_routings: Arc::new(AtomicU64::new(0)),
+            routing_history: Arc::new(Mutex::new(RoutingHistory::new(config.routing_history_size))),
+            slaves: Arc::new(Mutex::new(HashMap::new())),
+            routed_requests: Arc::new(Mutex::new(HashMap::new())),
+            unacked_routings: Arc::new(Mutex::new(HashMap::new())),
+            routed_at: Arc::new(Mutex::new(HashMap::new())),
+            logical_routes: Arc::new(Mutex::new(HashMap::new())),
+            last_rebalance: Arc::new(Mutex::new(None)),
+            recorder: config
+                .record_file
+                .as_ref()
+                .and_then(|path| match Recorder::open(path) {
+                    Ok(recorder) => {
+                        info!("Recording routing traffic to {}", path);
+                        Some(Arc::new(recorder))
+                    }
+                    Err(e) => {
+                        error!("Failed to open record file {}: {}", path, e);
+                        None
+                    }
+                }),
+            connected: Arc::new(AtomicBool::new(false)),
+            client: Arc::new(Signed::new(client, config.shared_secret.clone())),
+            mode,
+            config,
+        }
+    }
 
-        let mut nodes = self.nodes.lock().await;
-        let inactive_nodes: Vec<String> = nodes
-            .iter()
-            .filter(|(_, info)| current_time - info.last_heartbeat > timeout)
-            .map(|(id, _)| id.clone())
-            .collect();
+    /// Sends a rejected `RoutingResponse` to the client
+    async fn reject_routing(
+        &self,
+        request: &RoutingRequest,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.rejected_routings.fetch_add(1, Ordering::Relaxed);
+        self.decline_routing(request, RoutingStatus::Rejected, reason, None)
+            .await
+    }
 
-        // for id in inactive_masters {
-        //     masters.remove(&id);
-        //     println!("Removed inactive master: {}", id);
-
-        //     // Update master status to inactive
-        //     let status_update = serde_json::json!({
-        //         "status": NodeStatus::Inactive,
-        //         "timestamp": current_time
-        //     });
-
-        //     if let Ok(payload) = serde_json::to_string(&status_update) {
-        //         let _ = self.client.publish(
-        //             format!("master/status/{}", id),
-        //             QoS::AtLeastOnce,
-        //             false,
-        //             payload.as_bytes(),
-        //         ).await;
-        //     }
-        // }
-
-        // Clean up routing table and notify affected slaves
-        let mut routing_table = self.routing_table.lock().await;
-        let mut affected_slaves = Vec::new();
+    /// Rejects a client because every capable node is full, hinting when to
+    /// retry: node load is only refreshed by heartbeats, so asking again
+    /// sooner cannot get a different answer, and spacing the retries out
+    /// keeps turned-away clients from crowding the next freed slot
+    async fn reject_until_capacity_frees(
+        &self,
+        request: &RoutingRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.rejected_routings.fetch_add(1, Ordering::Relaxed);
+        self.decline_routing(
+            request,
+            RoutingStatus::Rejected,
+            "No available master nodes",
+            Some(EXPECTED_HEARTBEAT_INTERVAL_SECS * 1000),
+        )
+        .await
+    }
+
+    /// Asks the client to try again after `retry_after_ms` rather than
+    /// rejecting it, so it waits instead of retrying as fast as it can
+    async fn defer_routing(
+        &self,
+        request: &RoutingRequest,
+        reason: &str,
+        retry_after_ms: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.decline_routing(
+            request,
+            RoutingStatus::Pending,
+            reason,
+            Some(retry_after_ms),
+        )
+        .await
+    }
+
+    /// Answers a routing request without assigning a node
+    async fn decline_routing(
+        &self,
+        request: &RoutingRequest,
+        status: RoutingStatus,
+        reason: &str,
+        retry_after_ms: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client_id = &request.client_id;
+        let response = RoutingResponse {
+            node_id: String::from("none"),
+            client_id: client_id.to_string(),
+            request_id: request.request_id.clone(),
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms,
+            status,
+            rejection_reason: Some(reason.to_string()),
+            configuration: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+
+        self.send_routing_response(&response).await?;
+        Ok(())
+    }
+
+    /// Publishes `response` to its client, recording it first if enabled
+    async fn send_routing_response(
+        &self,
+        response: &RoutingResponse,
+    ) -> Result<(), rumqttc::ClientError> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_response(response);
+        }
+        if let Ok(response_payload) = serde_json::to_string(response) {
+            self.client
+                .publish(
+                    topics::routing_response(&self.config.topic_prefix, &response.client_id),
+                    self.config.qos.routing,
+                    false,
+                    response_payload.as_bytes(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
 
-        routing_table.retain(|client_id, node_id| {
-            let keep = nodes.contains_key(node_id);
-            if !keep {
-                affected_slaves.push(client_id.clone());
+    async fn handle_pool_control(
+        &self,
+        control: PoolControl,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match control {
+            PoolControl::PoolDrain { drain_nodes } => {
+                *self.pool_mode.lock().await = PoolMode::Draining;
+                info!("Pool drain started, rejecting all new routings");
+
+                if drain_nodes {
+                    let node_ids: Vec<String> = self.nodes.lock().await.keys().cloned().collect();
+                    self.send_node_control(&node_ids, &control).await?;
+                    self.drained_nodes.lock().await.extend(node_ids);
+                }
             }
-            keep
-        });
+            PoolControl::PoolResume => {
+                *self.pool_mode.lock().await = PoolMode::Serving;
+                info!("Pool resumed, routing new clients again");
 
-        // Notify affected slaves about master failure
-        for client_id in affected_slaves {
-            let response = RoutingResponse {
-                node_id: String::from("none"),
-                client_id: client_id.clone(),
-                status: RoutingStatus::Rejected,
-                rejection_reason: Some("Node failed to connect".to_string()),
-                configuration: None,
-                timestamp: current_time,
-            };
+                let node_ids = std::mem::take(&mut *self.drained_nodes.lock().await);
+                self.send_node_control(&node_ids, &control).await?;
+            }
+        }
+        Ok(())
+    }
 
-            if let Ok(payload) = serde_json::to_string(&response) {
-                let _ = self
-                    .client
-                    .publish(
-                        format!("routing/response/{}", client_id),
-                        QoS::AtLeastOnce,
-                        false,
-                        payload.as_bytes(),
-                    )
+    /// Puts nodes into or out of maintenance for a pool command
+    async fn send_node_control(
+        &self,
+        node_ids: &[String],
+        control: &PoolControl,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = serde_json::to_string(&control.node_control())?;
+        for node_id in node_ids {
+            self.client
+                .publish(
+                    topics::control(&self.config.topic_prefix, node_id),
+                    self.config.qos.default,
+                    false,
+                    payload.as_bytes(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Actively checks one node's health; the ack is recorded by
+    /// `handle_probe_ack`. Returns the probe id.
+    async fn probe_node(&self, node_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let probe = ProbeRequest {
+            probe_id: Uuid::new_v4().to_string(),
+            sent_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+
+        self.pending_probes.lock().await.insert(
+            probe.probe_id.clone(),
+            (node_id.to_string(), Instant::now()),
+        );
+        self.client
+            .publish(
+                topics::probe(&self.config.topic_prefix, node_id),
+                self.config.qos.default,
+                false,
+                serde_json::to_string(&probe)?.as_bytes(),
+            )
+            .await?;
+        Ok(probe.probe_id)
+    }
+
+    async fn handle_probe_ack(&self, ack: ProbeAck) {
+        let Some((node_id, sent_at)) = self.pending_probes.lock().await.remove(&ack.probe_id)
+        else {
+            return;
+        };
+        let latency = sent_at.elapsed();
+        info!(
+            "Probe of node {}: {:?} round trip, status {:?}, load {}",
+            node_id, latency, ack.status, ack.current_load
+        );
+
+        if let Some(info) = self.nodes.lock().await.get_mut(&node_id) {
+            info.status = ack.status;
+        }
+        self.probe_latencies.lock().await.insert(node_id, latency);
+    }
+
+    #[instrument(skip_all, fields(
+        client_id = %request.client_id,
+        request_id = %request.request_id.as_deref().unwrap_or_default(),
+    ))]
+    async fn handle_routing_request(
+        &self,
+        request: RoutingRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if *self.pool_mode.lock().await == PoolMode::Draining {
+            return self.reject_routing(&request, "pool maintenance").await;
+        }
+        let previous_nodes = match &request.logical_id {
+            Some(logical_id) => {
+                self.drop_replaced_clients(logical_id, &request.client_id)
                     .await;
+                self.logical_routes
+                    .lock()
+                    .await
+                    .get(logical_id)
+                    .cloned()
+                    .unwrap_or_default()
             }
+            None => Vec::new(),
+        };
+
+        let mut nodes_guard = self.nodes.lock().await;
+        // With no active node at all, clients are told to wait rather than
+        // rejected; routing resumes as soon as a node heartbeats again
+        let any_active = nodes_guard
+            .values()
+            .any(|info| info.node_type == NodeType::Node && info.status == NodeStatus::Active);
+        if let (false, Some(retry_after_ms)) = (any_active, self.config.unavailable_retry_after_ms)
+        {
+            drop(nodes_guard);
+            info!(
+                "No active nodes; asking client {} to retry in {}ms",
+                request.client_id, retry_after_ms
+            );
+            return self
+                .defer_routing(&request, "no active nodes", retry_after_ms)
+                .await;
+        }
+        let capable = nodes_guard
+            .values()
+            .any(|info| info.node_type == NodeType::Node && info.supports_all(&request.data_type));
+        if !capable {
+            drop(nodes_guard);
+            info!(
+                "No node supports {:?} for client {}",
+                request.data_type, request.client_id
+            );
+            return self
+                .reject_routing(&request, "no node supports requested types")
+                .await;
         }
-    }
 
-    async fn print_status(&self) {
-        let nodes = self.nodes.lock().await;
-        let routing_table = self.routing_table.lock().await;
+        let fan_out = request.fan_out.unwrap_or(1).max(1) as usize;
+        let (assigned, assigned_features) =
+            self.pick_nodes(&mut nodes_guard, &request, &[], &previous_nodes);
+        drop(nodes_guard);
 
-        println!("\n=== System Status =============");
-        println!("Active Nodes:");
-        for (id, info) in nodes.iter() {
-            println!(
-                "- {} (Load: {}/{}, Status: {:?})",
-                id, info.current_load, info.capacity, info.status
+        if assigned.is_empty() {
+            // Send rejection response if no suitable master found
+            self.reject_until_capacity_frees(&request).await?;
+            info!("No available Nodes for client {}", request.client_id);
+            return Ok(());
+        }
+        if assigned.len() < fan_out {
+            info!(
+                "Client {} asked for {} nodes; only {} available",
+                request.client_id,
+                fan_out,
+                assigned.len()
             );
-            println!("  Version: {}", info.version);
-            if !info.metadata.is_empty() {
-                println!("  Metadata: {:?}", info.metadata);
-            }
         }
+        self.accept_routing(&request, assigned, assigned_features)
+            .await
+    }
+
+    /// Drops the routings of clients other than `client_id` that were routed
+    /// under `logical_id`: the same logical client from before a restart,
+    /// whose capacity would otherwise stay reserved until it times out
+    async fn drop_replaced_clients(&self, logical_id: &str, client_id: &str) {
+        let replaced: Vec<String> = self
+            .routed_requests
+            .lock()
+            .await
+            .values()
+            .filter(|request| {
+                request.client_id != client_id && request.logical_id.as_deref() == Some(logical_id)
+            })
+            .map(|request| request.client_id.clone())
+            .collect();
+        for old_client_id in replaced {
+            info!(
+                "Client {} replaces {} as logical client {}",
+                client_id, old_client_id, logical_id
+            );
+            self.drop_routing(
+                &old_client_id,
+                "replaced by a client with the same logical id",
+            )
+            .await;
+        }
+    }
 
-        println!("\nActive Routings:");
-        for (client_id, node_id) in routing_table.iter() {
-            println!("- Client [{}] →  Node [{}]", client_id, node_id);
+    /// Picks up to the request's `fan_out` distinct nodes, one at a time,
+    /// skipping `excluded`, and reserves capacity on each. After the
+    /// client's preferred node, nodes in `previous` are taken while they are
+    /// eligible; the selector picks the rest. Returns the node ids and the
+    /// features each advertised, in the same order.
+    fn pick_nodes(
+        &self,
+        nodes: &mut HashMap<String, NodeInfo>,
+        request: &RoutingRequest,
+        excluded: &[String],
+        previous: &[String],
+    ) -> (Vec<String>, Vec<Vec<String>>) {
+        let fan_out = request.fan_out.unwrap_or(1).max(1) as usize;
+        let mut assigned: Vec<String> = Vec::new();
+        let mut assigned_features: Vec<Vec<String>> = Vec::new();
+        // The client's preferred node, when usable, is its first pick
+        let mut preferred =
+            usable_preferred_node(nodes, request).filter(|id| !excluded.contains(id));
+        while assigned.len() < fan_out {
+            let candidates: Vec<&NodeInfo> = balancer::eligible_candidates(nodes, request)
+                .into_iter()
+                .filter(|info| {
+                    !assigned.contains(&info.node_id) && !excluded.contains(&info.node_id)
+                })
+                .collect();
+            let offered = |id: &String| candidates.iter().any(|info| &info.node_id == id);
+            // Only trust a pick that was actually offered to the selector
+            let Some(node_id) = preferred
+                .take()
+                .or_else(|| previous.iter().find(|id| offered(id)).cloned())
+                .or_else(|| self.selector.select(&candidates, request).filter(offered))
+            else {
+                break;
+            };
+            // Reserve the node's capacity before releasing the lock
+            if let Some(info) = nodes.get_mut(&node_id) {
+                info.current_load += 1;
+                assigned_features.push(info.features.clone());
+                info!(
+                    "Assigned Node [{}] to Client [{}] (Current load: {}/{})",
+                    node_id, request.client_id, info.current_load, info.capacity
+                );
+            }
+            assigned.push(node_id);
         }
-        println!("================================\n");
+        (assigned, assigned_features)
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting Orchestration Service...");
+    /// Routes the client to the nodes `pick_nodes` chose and sends it the
+    /// accepted `RoutingResponse`
+    async fn accept_routing(
+        &self,
+        request: &RoutingRequest,
+        assigned: Vec<String>,
+        assigned_features: Vec<Vec<String>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(node_id) = assigned.first().cloned() else {
+            return Ok(());
+        };
+        self.routed_requests
+            .lock()
+            .await
+            .insert(request.client_id.clone(), request.clone());
+
+        // Update routing table
+        let previous = self
+            .routing_table
+            .lock()
+            .await
+            .insert(request.client_id.clone(), assigned.clone());
+        let change = match previous {
+            Some(from) if from != assigned => Some(RoutingChange::Migrated { from }),
+            Some(_) => None,
+            None => Some(RoutingChange::Added),
+        };
+        if let Some(change) = change {
+            self.record_routing_change(&request.client_id, assigned.clone(), change)
+                .await;
+        }
+        self.routed_at
+            .lock()
+            .await
+            .insert(request.client_id.clone(), Instant::now());
+        if let Some(logical_id) = &request.logical_id {
+            self.logical_routes
+                .lock()
+                .await
+                .insert(logical_id.clone(), assigned.clone());
+        }
+        if self.config.routing_ack_timeout_secs.is_some() {
+            self.unacked_routings
+                .lock()
+                .await
+                .insert(request.client_id.clone(), Instant::now());
+        }
 
-    let service = OrchestrationService::new().await?;
-    println!("Orchestration Service initialized");
+        // Data responses are bincode from nodes that share it with the client
+        let wire_formats: Vec<WireFormat> = {
+            let client_formats = WireFormat::advertised_by(&request.node_info);
+            let known = self.wire_formats.lock().await;
+            assigned
+                .iter()
+                .map(|node_id| {
+                    let node_formats = known.get(node_id).map_or(&[][..], Vec::as_slice);
+                    WireFormat::shared(node_formats, &client_formats)
+                })
+                .collect()
+        };
+        let shared_format = if wire_formats.iter().all(|f| *f == WireFormat::Bincode) {
+            WireFormat::Bincode
+        } else {
+            WireFormat::Json
+        };
 
-    // Start periodic cleanup of inactive nodes
-    let service_clone = service.clone();
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(15));
-        loop {
-            interval.tick().await;
-            service_clone.cleanup_inactive_nodes().await;
+        // Each assignment carries the processing timeout its node enforces;
+        // the shared configuration carries the longest of them
+        let timeouts: Vec<u64> = {
+            let nodes = self.nodes.lock().await;
+            assigned
+                .iter()
+                .map(|node_id| {
+                    nodes
+                        .get(node_id)
+                        .and_then(advertised_processing_timeout)
+                        .unwrap_or(DEFAULT_PROCESSING_TIMEOUT_MS)
+                })
+                .collect()
+        };
+        let shared_timeout = timeouts
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(DEFAULT_PROCESSING_TIMEOUT_MS);
+
+        // Create slave configuration, enabling only features the nodes support
+        let slave_config = |node_features: Vec<String>, wire_format, timeout| ClientConfiguration {
+            subscribe_topics: vec![
+                topics::data_input(&self.config.topic_prefix, &request.client_id),
+                topics::control(&self.config.topic_prefix, &request.client_id),
+            ],
+            publish_topic: topics::data_processed(&self.config.topic_prefix, &request.client_id),
+            qos: 1,
+            max_batch_size: 100,
+            processing_timeout_ms: timeout,
+            compression_level: if node_features.iter().any(|f| f == FEATURE_COMPRESSION) {
+                requested_compression(&request.node_info)
+            } else {
+                0
+            },
+            wire_format,
+            node_features,
+        };
+        let shared_features: Vec<String> = assigned_features
+            .first()
+            .into_iter()
+            .flatten()
+            .filter(|feature| {
+                assigned_features
+                    .iter()
+                    .all(|features| features.contains(feature))
+            })
+            .cloned()
+            .collect();
+
+        let response = RoutingResponse {
+            node_id,
+            client_id: request.client_id.clone(),
+            status: RoutingStatus::Accepted,
+            rejection_reason: None,
+            configuration: Some(slave_config(shared_features, shared_format, shared_timeout)),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            request_id: request.request_id.clone(),
+            assignments: assigned
+                .into_iter()
+                .zip(assigned_features)
+                .zip(wire_formats)
+                .zip(timeouts)
+                .map(
+                    |(((node_id, features), wire_format), timeout)| NodeAssignment {
+                        node_id,
+                        configuration: slave_config(features, wire_format, timeout),
+                    },
+                )
+                .collect(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        };
+
+        self.send_routing_response(&response).await?;
+        Ok(())
+    }
+
+    /// Routes the client of `request` afresh, avoiding the nodes in `avoid`,
+    /// and releases the capacity it held on `current`, the nodes it is on
+    /// now. Returns false, leaving the routing as it was, when no other node
+    /// can take it.
+    async fn move_client(
+        &self,
+        request: &RoutingRequest,
+        current: &[String],
+        avoid: &[String],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut nodes = self.nodes.lock().await;
+        let (assigned, assigned_features) = self.pick_nodes(&mut nodes, request, avoid, &[]);
+        if assigned.is_empty() {
+            return Ok(false);
         }
-    });
+        for node_id in current {
+            if let Some(info) = nodes.get_mut(node_id) {
+                info.current_load = info.current_load.saturating_sub(1);
+            }
+        }
+        drop(nodes);
 
-    // Start periodic status printing
-    let service_clone = service.clone();
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            service_clone.print_status().await;
+        // Answering the original request id lets the client take it as an
+        // update of its current routing
+        self.accept_routing(request, assigned, assigned_features)
+            .await?;
+        Ok(true)
+    }
+
+    /// Moves a bounded number of clients off the busiest node when its
+    /// utilization has drifted more than `rebalance_threshold` above the
+    /// idlest one's. After moving any, waits out `rebalance_cooldown_secs`
+    /// so heartbeats catch up before the loads are compared again. Returns
+    /// how many clients were moved.
+    async fn rebalance(&self) -> usize {
+        let Some(threshold) = self.config.rebalance_threshold else {
+            return 0;
+        };
+        let cooldown = Duration::from_secs(self.config.rebalance_cooldown_secs);
+        if self
+            .last_rebalance
+            .lock()
+            .await
+            .is_some_and(|last| last.elapsed() < cooldown)
+        {
+            return 0;
         }
-    });
 
-    // Keep the main task running
-    loop {
-        time::sleep(Duration::from_secs(1)).await;
+        let plan = {
+            let nodes = self.nodes.lock().await;
+            let routing_table = self.routing_table.lock().await;
+            let requests = self.routed_requests.lock().await;
+            balancer::plan_rebalance(
+                &nodes,
+                &routing_table,
+                &requests,
+                self.selector.as_ref(),
+                threshold,
+                self.config.rebalance_max_moves,
+            )
+        };
+        let Some(hottest) = plan.first().map(|(node_id, _)| node_id.clone()) else {
+            return 0;
+        };
+        info!(
+            "Node {} is running hotter than the rest; moving up to {} clients off it",
+            hottest,
+            plan.len()
+        );
+        *self.last_rebalance.lock().await = Some(Instant::now());
+
+        let mut moved = 0;
+        for (node_id, client_id) in plan {
+            let Some(current) = self.routing_table.lock().await.get(&client_id).cloned() else {
+                continue;
+            };
+            let Some(request) = self.routed_requests.lock().await.get(&client_id).cloned() else {
+                continue;
+            };
+            match self.move_client(&request, &current, &[node_id]).await {
+                Ok(true) => moved += 1,
+                Ok(false) => break,
+                Err(e) => error!("Failed to move client {}: {}", client_id, e),
+            }
+        }
+        moved
+    }
+
+    /// Moves `client_id` off the nodes it is routed to, e.g. because one is
+    /// degraded. The client is re-routed to other nodes as if it had asked
+    /// again, or rejected when no other node can take it; either way the
+    /// `RoutingResponse` tells it where to subscribe next.
+    async fn reassign_client(&self, client_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(previous) = self.routing_table.lock().await.get(client_id).cloned() else {
+            warn!("cannot reassign client {}: it is not routed", client_id);
+            return Ok(());
+        };
+        let Some(request) = self.routed_requests.lock().await.get(client_id).cloned() else {
+            warn!(
+                "cannot reassign client {}: its routing request is unknown",
+                client_id
+            );
+            return Ok(());
+        };
+
+        if self.move_client(&request, &previous, &previous).await? {
+            info!("Reassigned client {} away from {:?}", client_id, previous);
+            return Ok(());
+        }
+
+        info!(
+            "No other node can take client {}; dropping its routing",
+            client_id
+        );
+        self.drop_routing(client_id, "reassigned with no other node available")
+            .await;
+        // The nodes it was moved off are no place to send it back to
+        if let Some(logical_id) = &request.logical_id {
+            self.logical_routes.lock().await.remove(logical_id);
+        }
+        self.revoke_routing(client_id, "No other node available for reassignment")
+            .await
+    }
+
+    /// Tells a client its routing is gone, so it stops using its nodes and
+    /// asks to be routed again
+    async fn revoke_routing(
+        &self,
+        client_id: &str,
+        reason: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let response = RoutingResponse {
+            node_id: String::from("none"),
+            client_id: client_id.to_string(),
+            status: RoutingStatus::Rejected,
+            rejection_reason: Some(reason.to_string()),
+            configuration: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            // Without a request id the client drops its current routing
+            request_id: None,
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        };
+        self.send_routing_response(&response).await?;
+        Ok(())
+    }
+
+    /// Removes `client_id`'s routing and releases the capacity it held on
+    /// its nodes, without telling the client
+    async fn drop_routing(&self, client_id: &str, reason: &str) {
+        self.routed_requests.lock().await.remove(client_id);
+        self.unacked_routings.lock().await.remove(client_id);
+        self.routed_at.lock().await.remove(client_id);
+        let Some(assigned) = self.routing_table.lock().await.remove(client_id) else {
+            return;
+        };
+        let mut nodes = self.nodes.lock().await;
+        for node_id in &assigned {
+            if let Some(info) = nodes.get_mut(node_id) {
+                info.current_load = info.current_load.saturating_sub(1);
+            }
+        }
+        drop(nodes);
+        self.record_routing_change(
+            client_id,
+            Vec::new(),
+            RoutingChange::Removed {
+                reason: reason.to_string(),
+            },
+        )
+        .await;
+    }
+
+    /// Finalizes the routing a client confirmed on `routing/ack/{node_id}`,
+    /// or rolls it back when the client could not take it up. Acks for a
+    /// routing that has since been replaced are ignored.
+    async fn handle_routing_ack(&self, ack: RoutingAck) {
+        let primary = self
+            .routing_table
+            .lock()
+            .await
+            .get(&ack.client_id)
+            .and_then(|assigned| assigned.first().cloned());
+        if primary.as_deref() != Some(ack.node_id.as_str()) {
+            return;
+        }
+        if ack.accepted {
+            if self
+                .unacked_routings
+                .lock()
+                .await
+                .remove(&ack.client_id)
+                .is_some()
+            {
+                info!(
+                    "Client {} confirmed its routing to {}",
+                    ack.client_id, ack.node_id
+                );
+            }
+        } else {
+            info!(
+                "Client {} could not take up its routing to {}; rolling it back",
+                ack.client_id, ack.node_id
+            );
+            self.drop_routing(&ack.client_id, "client declined routing")
+                .await;
+            if let Err(e) = self
+                .revoke_routing(&ack.client_id, "routing rolled back")
+                .await
+            {
+                error!("Failed to revoke routing of {}: {}", ack.client_id, e);
+            }
+        }
+    }
+
+    /// Rolls back accepted routings their client didn't acknowledge within
+    /// `routing_ack_timeout_secs`, most likely because it died right after
+    /// being routed
+    async fn expire_unacked_routings(&self) {
+        let Some(timeout) = self.config.routing_ack_timeout_secs else {
+            return;
+        };
+        let timeout = Duration::from_secs(timeout);
+        let expired: Vec<String> = self
+            .unacked_routings
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, sent_at)| sent_at.elapsed() > timeout)
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            info!(
+                "Client {} never acknowledged its routing; rolling it back",
+                client_id
+            );
+            self.drop_routing(&client_id, "routing not acknowledged")
+                .await;
+            if let Err(e) = self
+                .revoke_routing(&client_id, "routing not acknowledged")
+                .await
+            {
+                error!("Failed to revoke routing of {}: {}", client_id, e);
+            }
+        }
+    }
+
+    /// Records a heartbeat from `heartbeat/master/{node_id}`. Only `Node`
+    /// heartbeats become routing candidates; anything else published there by
+    /// mistake is ignored.
+    async fn handle_node_heartbeat(&self, node_id: &str, mut node_info: NodeInfo) {
+        if node_info.node_type != NodeType::Node {
+            warn!(
+                "ignoring {} heartbeat from {} on the node heartbeat topic",
+                node_info.node_type, node_id
+            );
+            return;
+        }
+        if let Some(reason) = &node_info.shutdown_reason {
+            info!("Node {} shut down: {}", node_id, reason);
+        }
+
+        let mut nodes = self.nodes.lock().await;
+        // Preserve current load when updating heartbeat, keeping the node's
+        // own packet load aside
+        node_info.reported_load = Some(node_info.current_load);
+        node_info.current_load = nodes
+            .get(node_id)
+            .map(|info| info.current_load)
+            .unwrap_or(0);
+        node_info.last_heartbeat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        self.wire_formats
+            .lock()
+            .await
+            .insert(node_id.to_string(), WireFormat::advertised_by(&node_info));
+        if let Some(rate) = nodes
+            .get(node_id)
+            .and_then(|previous| messages_per_sec(previous, &node_info))
+        {
+            self.throughput
+                .lock()
+                .await
+                .insert(node_id.to_string(), rate);
+        }
+        let reported: Option<HashSet<String>> = node_info
+            .metadata
+            .get(ROUTED_CLIENTS_METADATA_KEY)
+            .map(|clients| {
+                clients
+                    .split(',')
+                    .filter(|client_id| !client_id.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            });
+        nodes.insert(node_id.to_string(), node_info);
+        drop(nodes);
+
+        // Nodes that predate routed client reports are not cross-checked
+        if let Some(reported) = reported {
+            self.reconcile_routing(node_id, &reported).await;
+        }
+    }
+
+    /// Records a heartbeat from `heartbeat/slave/{client_id}`; a client
+    /// reporting itself offline is forgotten along with its routing
+    async fn handle_slave_heartbeat(&self, client_id: &str, mut info: NodeInfo) {
+        if info.node_type != NodeType::Client {
+            warn!(
+                "ignoring {} heartbeat from {} on the client heartbeat topic",
+                info.node_type, client_id
+            );
+            return;
+        }
+        if info.status == NodeStatus::Offline {
+            self.remove_slaves(&[client_id.to_string()], "client shut down")
+                .await;
+            return;
+        }
+        info.last_heartbeat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.slaves.lock().await.insert(client_id.to_string(), info);
+    }
+
+    /// Forgets `client_ids` and drops their routings
+    async fn remove_slaves(&self, client_ids: &[String], reason: &str) {
+        for client_id in client_ids {
+            if self.slaves.lock().await.remove(client_id).is_some() {
+                info!("Removed client {}: {}", client_id, reason);
+            }
+            self.drop_routing(client_id, reason).await;
+        }
+    }
+
+    /// Cross-checks the routing table against the clients `node_id` reported
+    /// in its heartbeat, logging any divergence. When correction is enabled,
+    /// stale routings to the node are dropped, and their clients told, and
+    /// clients the orchestrator lost track of are routed to the node that
+    /// still serves them, provided they are connected clients. Routings
+    /// younger than `RECONCILE_GRACE` are left alone, as the node may not
+    /// have reported them yet. The node's load follows either change.
+    async fn reconcile_routing(&self, node_id: &str, reported: &HashSet<String>) {
+        let divergence =
+            RoutingDivergence::between(&*self.routing_table.lock().await, node_id, reported);
+        if divergence.is_empty() {
+            return;
+        }
+        warn!(
+            "routing views of node {} diverge; routed but unknown to the node: {:?}, accepted by the node but unrouted: {:?}",
+            node_id, divergence.missing_on_node, divergence.unknown_to_orchestrator
+        );
+        if !self.config.correct_routing_divergence {
+            return;
+        }
+
+        // Only clients we know can be routed; anything else the node reports
+        // may be a stale id or one that belongs to another pool
+        let adopted: Vec<&String> = {
+            let slaves = self.slaves.lock().await;
+            divergence
+                .unknown_to_orchestrator
+                .iter()
+                .filter(|client_id| {
+                    let known = slaves.contains_key(*client_id);
+                    if !known {
+                        debug!(
+                            "not routing unknown client {} to node {}",
+                            client_id, node_id
+                        );
+                    }
+                    known
+                })
+                .collect()
+        };
+
+        let stale: Vec<&String> = {
+            let routed_at = self.routed_at.lock().await;
+            divergence
+                .missing_on_node
+                .iter()
+                .filter(|client_id| {
+                    routed_at
+                        .get(*client_id)
+                        .is_none_or(|at| at.elapsed() >= RECONCILE_GRACE)
+                })
+                .collect()
+        };
+        let reason = format!("node {} does not know the client", node_id);
+        for client_id in stale {
+            self.drop_routing(client_id, &reason).await;
+            if let Err(e) = self.revoke_routing(client_id, &reason).await {
+                error!("Failed to revoke routing of {}: {}", client_id, e);
+            }
+        }
+
+        let mut routing_table = self.routing_table.lock().await;
+        let mut changes = Vec::new();
+        let mut added = 0;
+        for client_id in adopted {
+            // Routed elsewhere since the divergence was computed
+            if routing_table.contains_key(client_id) {
+                continue;
+            }
+            routing_table.insert(client_id.clone(), vec![node_id.to_string()]);
+            self.routed_at
+                .lock()
+                .await
+                .insert(client_id.clone(), Instant::now());
+            added += 1;
+            changes.push((
+                client_id.clone(),
+                vec![node_id.to_string()],
+                RoutingChange::Added,
+            ));
+        }
+        drop(routing_table);
+
+        if let Some(info) = self.nodes.lock().await.get_mut(node_id) {
+            info.current_load += added;
+        }
+
+        for (client_id, node_ids, change) in changes {
+            self.record_routing_change(&client_id, node_ids, change)
+                .await;
+        }
+    }
+
+    /// Forwards a routing request to the regional orchestrator with the most
+    /// free capacity, or rejects it if no region can take it
+    #[instrument(skip_all, fields(
+        client_id = %request.client_id,
+        request_id = %request.request_id.as_deref().unwrap_or_default(),
+    ))]
+    async fn forward_to_region(
+        &self,
+        request: RoutingRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if *self.pool_mode.lock().await == PoolMode::Draining {
+            return self.reject_routing(&request, "pool maintenance").await;
+        }
+
+        let current_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut regions = self.regions.lock().await;
+        if let Some(region) = select_region(&regions, current_time) {
+            // Count the client against the region until its next summary arrives
+            if let Some(summary) = regions.get_mut(&region) {
+                summary.total_load += 1;
+            }
+
+            if let Ok(payload) = serde_json::to_string(&request) {
+                self.client
+                    .publish(
+                        topics::regional_routing_request(&self.config.topic_prefix, &region),
+                        self.config.qos.routing,
+                        false,
+                        payload.as_bytes(),
+                    )
+                    .await?;
+            }
+            info!(
+                "Forwarded Client [{}] to Region [{}]",
+                request.client_id, region
+            );
+        } else {
+            self.reject_routing(&request, "No available regions")
+                .await?;
+            info!("No available regions for client {}", request.client_id);
+        }
+        Ok(())
+    }
+
+    /// Aggregates the capacity of the active nodes managed by this orchestrator
+    async fn region_summary(&self, region: &str) -> RegionSummary {
+        let nodes = self.nodes.lock().await;
+        let active = nodes
+            .values()
+            .filter(|info| info.status == NodeStatus::Active && info.node_type == NodeType::Node);
+
+        let mut summary = RegionSummary {
+            region: region.to_string(),
+            node_count: 0,
+            total_capacity: 0,
+            total_load: 0,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+        for info in active {
+            summary.node_count += 1;
+            summary.total_capacity += info.capacity;
+            summary.total_load += info.current_load;
+        }
+        summary
+    }
+
+    async fn publish_region_summary(&self, region: &str) {
+        let summary = self.region_summary(region).await;
+        if let Ok(payload) = serde_json::to_string(&summary) {
+            if let Err(e) = self
+                .client
+                .publish(
+                    topics::region_summary(&self.config.topic_prefix, region),
+                    self.config.qos.default,
+                    false,
+                    payload.as_bytes(),
+                )
+                .await
+            {
+                error!("Failed to publish region summary: {}", e);
+            }
+        }
+    }
+
+    /// Dispatches a message received on one of the subscribed topics
+    async fn handle_publish(&self, topic: &str, payload: &[u8]) {
+        let Some(payload) = self.client.open(topic, payload) else {
+            warn!(
+                "dropping message on {} with a missing or invalid signature",
+                topic
+            );
+            return;
+        };
+        let Some(topic) = topics::strip(&self.config.topic_prefix, topic) else {
+            return;
+        };
+        match topic {
+            topic if topic.starts_with("heartbeat/master/") => {
+                let node_id = topic.rsplit('/').next().unwrap_or("unknown");
+                match decode_or_log::<HeartbeatMessage>(topic, payload) {
+                    Some(HeartbeatMessage::Live(node_info)) => {
+                        self.handle_node_heartbeat(node_id, *node_info).await;
+                    }
+                    // The broker sends the will once the node's
+                    // connection drops; no need to wait for the timeout
+                    Some(HeartbeatMessage::Offline(notice))
+                        if notice.status == NodeStatus::Offline =>
+                    {
+                        self.remove_nodes(&[node_id.to_string()], "connection lost")
+                            .await;
+                    }
+                    _ => {}
+                }
+            }
+            topic if topic.starts_with("heartbeat/slave/") => {
+                let client_id = topic.rsplit('/').next().unwrap_or_default();
+                match decode_or_log::<HeartbeatMessage>(topic, payload) {
+                    Some(HeartbeatMessage::Live(info)) => {
+                        self.handle_slave_heartbeat(client_id, *info).await;
+                    }
+                    Some(HeartbeatMessage::Offline(_)) => {
+                        self.remove_slaves(&[client_id.to_string()], "client connection lost")
+                            .await;
+                    }
+                    None => {}
+                }
+            }
+            topic if topic.starts_with("routing/ack/") => {
+                if let Some(ack) = decode_or_log::<RoutingAck>(topic, payload) {
+                    self.handle_routing_ack(ack).await;
+                }
+            }
+            "control/pool" => {
+                if let Some(control) = decode_or_log::<PoolControl>(topic, payload) {
+                    if let Err(e) = self.handle_pool_control(control).await {
+                        error!("Failed to handle pool control: {}", e);
+                    }
+                }
+            }
+            topic if topic.starts_with("admin/probe/") => {
+                let node_id = topic.rsplit('/').next().unwrap_or("unknown");
+                if let Err(e) = self.probe_node(node_id).await {
+                    error!("Failed to probe node {}: {}", node_id, e);
+                }
+            }
+            "orchestrator/query/routing_history" => {
+                // An empty payload asks for everything retained
+                let query = if payload.is_empty() {
+                    Some(RoutingHistoryQuery::default())
+                } else {
+                    decode_or_log(topic, payload)
+                };
+                if let Some(query) = query {
+                    if let Err(e) = self.answer_routing_history_query(query).await {
+                        error!("Failed to answer routing history query: {}", e);
+                    }
+                }
+            }
+            "orchestrator/reassign" => {
+                if let Some(reassign) = decode_or_log::<ReassignRequest>(topic, payload) {
+                    if let Err(e) = self.reassign_client(&reassign.client_id).await {
+                        error!("Failed to reassign client {}: {}", reassign.client_id, e);
+                    }
+                }
+            }
+            // Any payload asks for the current snapshot
+            "orchestrator/query/routings" => {
+                if let Err(e) = self.answer_routings_query().await {
+                    error!("Failed to answer routings query: {}", e);
+                }
+            }
+            topic if topic.starts_with("probe/ack/") => {
+                if let Some(ack) = decode_or_log::<ProbeAck>(topic, payload) {
+                    self.handle_probe_ack(ack).await;
+                }
+            }
+            topic if topic.starts_with("orchestrator/region/") => {
+                if let Some(summary) = decode_or_log::<RegionSummary>(topic, payload) {
+                    self.regions
+                        .lock()
+                        .await
+                        .insert(summary.region.clone(), summary);
+                }
+            }
+            topic if topic.starts_with("routing/request") => {
+                if let Some(request) = decode_or_log::<RoutingRequest>(topic, payload) {
+                    if let Some(recorder) = &self.recorder {
+                        recorder.record_request(topic, &request);
+                    }
+                    let result = if self.mode == OrchestrationMode::Parent {
+                        self.forward_to_region(request).await
+                    } else {
+                        self.handle_routing_request(request).await
+                    };
+                    if let Err(e) = result {
+                        error!("Failed to handle routing request: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn cleanup_inactive_nodes(&self) {
+        let current_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let timeout = self.config.heartbeat_timeout_secs;
+
+        let inactive_nodes: Vec<String> = self
+            .nodes
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, info)| current_time.saturating_sub(info.last_heartbeat) > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        self.remove_nodes(&inactive_nodes, "node timed out").await;
+
+        let stale_slaves: Vec<String> = self
+            .slaves
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, info)| current_time.saturating_sub(info.last_heartbeat) > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        self.remove_slaves(&stale_slaves, "client timed out").await;
+    }
+
+    /// Drops `node_ids` from the pool and tells the clients routed to them
+    /// to find another node
+    async fn remove_nodes(&self, node_ids: &[String], reason: &str) {
+        let current_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut nodes = self.nodes.lock().await;
+        for id in node_ids {
+            if nodes.remove(id).is_none() {
+                continue;
+            }
+            self.wire_formats.lock().await.remove(id);
+            self.throughput.lock().await.remove(id);
+            info!("Removed node {}: {}", id, reason);
+
+            // Update node status to inactive
+            let status_update = serde_json::json!({
+                "status": NodeStatus::Inactive,
+                "timestamp": current_time
+            });
+
+            if let Ok(payload) = serde_json::to_string(&status_update) {
+                let _ = self
+                    .client
+                    .publish(
+                        topics::master_status(&self.config.topic_prefix, id),
+                        self.config.qos.default,
+                        false,
+                        payload.as_bytes(),
+                    )
+                    .await;
+            }
+        }
+
+        // Find the slaves routed through any removed node, including
+        // fan-out routings whose other nodes are still up
+        let affected_slaves: Vec<String> = self
+            .routing_table
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, node_ids)| node_ids.iter().any(|node_id| !nodes.contains_key(node_id)))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        self.logical_routes.lock().await.retain(|_, node_ids| {
+            node_ids.retain(|node_id| nodes.contains_key(node_id));
+            !node_ids.is_empty()
+        });
+        drop(nodes);
+
+        // Drop their routings, releasing the load held on surviving nodes,
+        // and notify them about master failure
+        for client_id in affected_slaves {
+            self.drop_routing(&client_id, reason).await;
+            let _ = self
+                .revoke_routing(&client_id, "Node failed to connect")
+                .await;
+        }
+    }
+
+    /// Appends to the routing history and, if configured, the audit topic
+    async fn record_routing_change(
+        &self,
+        client_id: &str,
+        node_ids: Vec<String>,
+        change: RoutingChange,
+    ) {
+        let event = RoutingEvent {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            client_id: client_id.to_string(),
+            node_ids,
+            change,
+        };
+        if let Some(topic) = &self.config.routing_audit_topic {
+            if let Ok(payload) = serde_json::to_string(&event) {
+                if let Err(e) = self
+                    .client
+                    .publish(topic, self.config.qos.default, false, payload.as_bytes())
+                    .await
+                {
+                    error!("Failed to publish routing audit event: {}", e);
+                }
+            }
+        }
+        self.routing_history.lock().await.record(event);
+    }
+
+    /// Publishes the recent routing history on `orchestrator/query/routing_history/response`
+    async fn answer_routing_history_query(
+        &self,
+        query: RoutingHistoryQuery,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let events = self.routing_history.lock().await.recent(query.limit);
+        let payload = serde_json::to_string(&events)?;
+        self.client
+            .publish(
+                topics::routing_history_response(&self.config.topic_prefix),
+                self.config.qos.default,
+                false,
+                payload.as_bytes(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Current nodes and routing table
+    async fn routing_snapshot(&self) -> RoutingTableSnapshot {
+        let mut nodes: Vec<NodeInfo> = self.nodes.lock().await.values().cloned().collect();
+        nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        RoutingTableSnapshot {
+            nodes,
+            routings: self.routing_table.lock().await.clone(),
+            generated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Publishes a `RoutingTableSnapshot` on `orchestrator/response/routings`
+    async fn answer_routings_query(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = serde_json::to_string(&self.routing_snapshot().await)?;
+        self.client
+            .publish(
+                topics::routings_response(&self.config.topic_prefix),
+                self.config.qos.default,
+                false,
+                payload.as_bytes(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn utilization_report(&self) -> UtilizationReport {
+        // Same aggregation a regional orchestrator reports to its parent
+        let summary = self.region_summary("").await;
+        UtilizationReport {
+            node_count: summary.node_count,
+            total_capacity: summary.total_capacity,
+            used_capacity: summary.total_load,
+            utilization: if summary.total_capacity == 0 {
+                0.0
+            } else {
+                summary.total_load as f64 / summary.total_capacity as f64
+            },
+            rejected_routings: self.rejected_routings.load(Ordering::Relaxed),
+            timestamp: summary.timestamp,
+        }
+    }
+
+    /// Posts the current utilization report, retrying with backoff. Runs on
+    /// its own task so a slow or failing webhook never delays routing.
+    async fn post_utilization(&self, url: &str, initial_backoff: Duration) {
+        let report = self.utilization_report().await;
+        let body = match serde_json::to_string(&report) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize utilization report: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = webhook::post_with_retry(url, &body, WEBHOOK_RETRIES, initial_backoff).await
+        {
+            error!("Giving up on utilization webhook: {}", e);
+        }
+    }
+
+    async fn print_status(&self) {
+        let nodes = self.nodes.lock().await;
+        let slaves = self.slaves.lock().await;
+        let routing_table = self.routing_table.lock().await;
+        let throughput = self.throughput.lock().await;
+
+        info!(
+            nodes = nodes.len(),
+            clients = slaves.len(),
+            routings = routing_table.len(),
+            "System status"
+        );
+        for (id, info) in nodes.iter() {
+            let rate = throughput
+                .get(id)
+                .map_or_else(|| "n/a".to_string(), |rate| format!("{:.1} msg/s", rate));
+            info!(
+                node_id = %id,
+                load = info.current_load,
+                capacity = info.capacity,
+                status = ?info.status,
+                throughput = %rate,
+                version = %info.version,
+                metadata = ?info.metadata,
+                "Active node"
+            );
+        }
+        for (id, info) in slaves.iter() {
+            info!(
+                client_id = %id,
+                load = info.current_load,
+                status = ?info.status,
+                "Active client"
+            );
+        }
+        for (client_id, node_ids) in routing_table.iter() {
+            info!(client_id = %client_id, nodes = %node_ids.join(", "), "Active routing");
+        }
+    }
+}
+
+/// Packets per second processed between two consecutive heartbeats of one
+/// node, timed by when they were received. `None` when they arrived within
+/// the same second. A node that restarted in between reports only what it
+/// processed since.
+fn messages_per_sec(previous: &NodeInfo, current: &NodeInfo) -> Option<f64> {
+    let elapsed = current
+        .last_heartbeat
+        .checked_sub(previous.last_heartbeat)
+        .filter(|&elapsed| elapsed > 0)?;
+    let processed = current
+        .processed_total
+        .checked_sub(previous.processed_total)
+        .unwrap_or(u64::from(current.processed_since_last_heartbeat));
+    Some(processed as f64 / elapsed as f64)
+}
+
+/// Re-publishes the requests recorded in `path` (or `RECORD_FILE`) to the
+/// broker, at their original spacing, then exits
+async fn replay_recording(path: Option<String>) -> Result<(), PoolError> {
+    let file = ConfigFile::from_env()?;
+    let config = OrchestratorConfig::load(&file.orchestrator, config::env_var)?;
+    let path = path.or(config.record_file.clone()).ok_or_else(|| {
+        PoolError::Config("replay needs a recording: pass a path or set RECORD_FILE".into())
+    })?;
+    let messages = recorder::read_recording(&path)?;
+    info!(
+        "Replaying {} recorded messages from {}",
+        messages.len(),
+        path
+    );
+
+    let mqtt_options = config.mqtt.options(
+        mqtt_client_id(
+            config.client_id_prefix.as_deref(),
+            &format!("orchestrator-replay-{}", Uuid::new_v4()),
+        ),
+        "localhost",
+        config
+            .embedded_broker
+            .map_or(1883, |address| address.port()),
+    );
+    let channel_cap = mqtt_options.request_channel_capacity();
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options, channel_cap);
+    let client = Signed::new(client, config.shared_secret.clone());
+
+    let replay = tokio::spawn(async move {
+        let replayed = recorder::replay(&messages, &config.topic_prefix, &client).await;
+        // Disconnecting once everything is queued lets the event loop drain
+        let _ = client.inner().disconnect().await;
+        replayed
+    });
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let replayed = replay
+        .await
+        .map_err(|e| PoolError::Other(format!("replay task failed: {}", e)))??;
+    info!("Replayed {} routing requests", replayed);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), PoolError> {
+    // RUST_LOG filters, at info by default; messages `mqtt-common` reports
+    // through `log` are forwarded too
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    // `replay [file]` feeds a recording back to the broker instead of orchestrating
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        return replay_recording(std::env::args().nth(2)).await;
+    }
+
+    info!("Starting Orchestration Service...");
+
+    // Environment variables win over the config file
+    let file = ConfigFile::from_env()?;
+    let var = config::env_var;
+    let mode = OrchestrationMode::load(&file.orchestrator, var)?;
+    let routing_seed = config::setting(&var, "ROUTING_SEED", file.orchestrator.routing_seed);
+    let balancer = config::parsed(
+        &var,
+        "BALANCER",
+        file.orchestrator.balancer.as_deref(),
+        |name| balancer::NAMES.contains(&name).then(|| name.to_string()),
+    )?;
+    let selector = balancer::from_name(balancer.as_deref().unwrap_or_default(), routing_seed);
+    let config = OrchestratorConfig::load(&file.orchestrator, var)?;
+
+    // Single-binary deployments host the broker themselves
+    if let Some(address) = config.embedded_broker {
+        // It takes the same credentials the pool uses to log in
+        let login = match (&config.mqtt.username, &config.mqtt.password) {
+            (Some(username), Some(password)) => Some(broker::Login::new(username, password)),
+            _ => None,
+        };
+        if login.is_none() && !address.ip().is_loopback() {
+            warn!(
+                "Embedded MQTT broker on {} accepts any login; set MQTT_USERNAME and MQTT_PASSWORD",
+                address
+            );
+        }
+        let listener = TcpListener::bind(address).await?;
+        info!("Embedded MQTT broker listening on {}", address);
+        tokio::spawn(broker::serve(listener, login));
+    }
+
+    let service = OrchestrationService::new(mode.clone(), selector, config.clone()).await?;
+    info!("Orchestration Service initialized ({:?})", mode);
+
+    // Regional orchestrators report their aggregate capacity to the parent
+    if let OrchestrationMode::Regional(region) = mode {
+        let service_clone = service.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                service_clone.publish_region_summary(&region).await;
+            }
+        });
+    }
+
+    // Start periodic cleanup of inactive nodes and unacknowledged routings,
+    // then rebalancing of the rest
+    let service_clone = service.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(config.cleanup_interval_secs));
+        loop {
+            interval.tick().await;
+            service_clone.cleanup_inactive_nodes().await;
+            service_clone.expire_unacked_routings().await;
+            service_clone.rebalance().await;
+        }
+    });
+
+    // Start periodic status printing
+    let service_clone = service.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(config.status_print_interval_secs));
+        loop {
+            interval.tick().await;
+            service_clone.print_status().await;
+        }
+    });
+
+    // Report utilization to an external dashboard
+    if let Some(url) = config.webhook_url.clone() {
+        let service_clone = service.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(config.webhook_interval_secs));
+            loop {
+                interval.tick().await;
+                service_clone
+                    .post_utilization(&url, Duration::from_secs(1))
+                    .await;
+            }
+        });
+    }
+
+    // Expose pool state for Prometheus to scrape
+    if let Some(port) = config.metrics_port {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        info!("Serving metrics on port {}", port);
+        tokio::spawn(metrics::serve(
+            listener,
+            Arc::clone(&service.nodes),
+            Arc::clone(&service.routing_table),
+            Arc::clone(&service.rejected_routings),
+        ));
+    }
+
+    if let Some(port) = config.health_port {
+        health::start(port, Arc::clone(&service.connected)).await?;
+    }
+
+    // Keep the main task running
+    loop {
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_common::testkit::MemoryBroker;
+    use mqtt_common::{
+        MaintenanceControl, OfflineNotice, MAX_LOAD_COST, WIRE_FORMATS, WIRE_FORMATS_METADATA_KEY,
+    };
+    use rumqttc::{EventLoop, MqttOptions, QoS};
+    use tracing_test::traced_test;
+
+    /// Builds a service whose publishes queue up in the returned event loop
+    fn test_service(mode: OrchestrationMode) -> (OrchestrationService, EventLoop) {
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(mqtt_options, 100);
+        (
+            OrchestrationService::build(
+                client,
+                mode,
+                Box::new(balancer::LeastLoaded),
+                OrchestratorConfig::default(),
+            ),
+            eventloop,
+        )
+    }
+
+    /// Drains everything the service has published so far
+    fn published(eventloop: &mut EventLoop) -> Vec<rumqttc::Publish> {
+        eventloop.clean();
+        std::mem::take(&mut eventloop.pending)
+            .into_iter()
+            .filter_map(|request| match request {
+                rumqttc::Request::Publish(publish) => Some(publish),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn routing_responses(eventloop: &mut EventLoop) -> Vec<RoutingResponse> {
+        published(eventloop)
+            .iter()
+            .filter(|p| p.topic.starts_with("routing/response/"))
+            .map(|p| serde_json::from_slice(&p.payload).unwrap())
+            .collect()
+    }
+
+    fn routing_request(client_id: &str) -> RoutingRequest {
+        RoutingRequest {
+            client_id: client_id.to_string(),
+            data_type: vec!["text".to_string()],
+            node_info: NodeInfo::new(NodeType::Client, 10),
+            preferred_node: None,
+            timestamp: 0,
+            request_id: None,
+            fan_out: None,
+            schema_version: PROTOCOL_VERSION,
+            logical_id: None,
+        }
+    }
+
+    async fn add_node(service: &OrchestrationService, capacity: u32) -> String {
+        let mut info = NodeInfo::new(NodeType::Node, capacity);
+        info.supported_data_types = vec!["text".to_string()];
+        let node_id = info.node_id.clone();
+        service.nodes.lock().await.insert(node_id.clone(), info);
+        node_id
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_reissues_routings_then_cools_down() {
+        let (client, mut eventloop) = AsyncClient::new(
+            MqttOptions::new("test-orchestrator", "localhost", 1883),
+            100,
+        );
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig {
+                rebalance_threshold: Some(0.5),
+                rebalance_max_moves: 2,
+                rebalance_cooldown_secs: 60,
+                ..OrchestratorConfig::default()
+            },
+        );
+        let hot = add_node(&service, 10).await;
+        for i in 0..4 {
+            let request = RoutingRequest {
+                request_id: Some(format!("request-{}", i)),
+                ..routing_request(&format!("client-{}", i))
+            };
+            service.handle_routing_request(request).await.unwrap();
+        }
+        routing_responses(&mut eventloop);
+        let cool = add_node(&service, 10).await;
+        let set_hot_load = |load| {
+            let service = &service;
+            let hot = &hot;
+            async move {
+                service
+                    .nodes
+                    .lock()
+                    .await
+                    .get_mut(hot)
+                    .unwrap()
+                    .current_load = load;
+            }
+        };
+        set_hot_load(9).await;
+
+        assert_eq!(service.rebalance().await, 2);
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses.len(), 2);
+        for response in &responses {
+            assert_eq!(response.status, RoutingStatus::Accepted);
+            assert_eq!(response.node_id, cool);
+            // Reissued under the client's original request id
+            let client = response.client_id.trim_start_matches("client-");
+            assert_eq!(response.request_id, Some(format!("request-{}", client)));
+            assert_eq!(
+                service.routing_table.lock().await[&response.client_id],
+                vec![cool.clone()]
+            );
+        }
+
+        // Still drifted, but nothing moves until the cooldown has passed
+        set_hot_load(9).await;
+        assert_eq!(service.rebalance().await, 0);
+        assert!(routing_responses(&mut eventloop).is_empty());
+
+        *service.last_rebalance.lock().await = Instant::now().checked_sub(Duration::from_secs(61));
+        // 90% against 20%: one move closes the gap this time
+        assert_eq!(service.rebalance().await, 1);
+        assert_eq!(routing_responses(&mut eventloop).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_routing_matches_supported_data_types() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let text_node = add_node(&service, 10).await;
+        let sensor_node = add_node(&service, 10).await;
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&sensor_node)
+            .unwrap()
+            .supported_data_types = vec!["text".to_string(), "sensor".to_string()];
+        let route = |client_id: &str, types: &[&str]| RoutingRequest {
+            data_type: types.iter().map(|t| t.to_string()).collect(),
+            ..routing_request(client_id)
+        };
+
+        // Full match: only the node serving both types qualifies
+        service
+            .handle_routing_request(route("client-1", &["text", "sensor"]))
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        assert_eq!(response.node_id, sensor_node);
+
+        // Partial match: no node serves every requested type
+        service
+            .handle_routing_request(route("client-2", &["text", "image"]))
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Rejected);
+        assert_eq!(
+            response.rejection_reason.as_deref(),
+            Some("no node supports requested types")
+        );
+
+        // No requested types: any node will do
+        service
+            .handle_routing_request(route("client-3", &[]))
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        assert!(response.node_id == text_node || response.node_id == sensor_node);
+    }
+
+    #[tokio::test]
+    async fn test_routing_history_records_adds_migrations_and_evictions() {
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 100);
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig {
+                routing_audit_topic: Some("audit/routing".to_string()),
+                ..OrchestratorConfig::default()
+            },
+        );
+        let mut node_ids = [add_node(&service, 10).await, add_node(&service, 10).await];
+        node_ids.sort();
+        let (first, second) = (node_ids[0].clone(), node_ids[1].clone());
+
+        // Least loaded picks the first node, then the second once the first has load
+        for _ in 0..2 {
+            service
+                .handle_routing_request(routing_request("client-1"))
+                .await
+                .unwrap();
+        }
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&second)
+            .unwrap()
+            .last_heartbeat = 0;
+        service.cleanup_inactive_nodes().await;
+
+        let history = service.routing_history.lock().await.recent(None);
+        let changes: Vec<(&[String], &RoutingChange)> = history
+            .iter()
+            .map(|event| (event.node_ids.as_slice(), &event.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (&[first.clone()][..], &RoutingChange::Added),
+                (
+                    &[second.clone()][..],
+                    &RoutingChange::Migrated {
+                        from: vec![first.clone()]
+                    }
+                ),
+                (
+                    &[][..],
+                    &RoutingChange::Removed {
+                        reason: "node timed out".to_string()
+                    }
+                ),
+            ]
+        );
+        assert!(history.iter().all(|event| event.client_id == "client-1"));
+
+        service
+            .answer_routing_history_query(RoutingHistoryQuery { limit: Some(2) })
+            .await
+            .unwrap();
+        let published = published(&mut eventloop);
+        let audited: Vec<RoutingEvent> = published
+            .iter()
+            .filter(|p| p.topic == "audit/routing")
+            .map(|p| serde_json::from_slice(&p.payload).unwrap())
+            .collect();
+        assert_eq!(audited, history);
+        let answer = published
+            .iter()
+            .find(|p| p.topic == "orchestrator/query/routing_history/response")
+            .unwrap();
+        let recent: Vec<RoutingEvent> = serde_json::from_slice(&answer.payload).unwrap();
+        assert_eq!(recent, history[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_routings_query_publishes_snapshot_of_shared_state() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let mut node_ids = [add_node(&service, 10).await, add_node(&service, 20).await];
+        node_ids.sort();
+        service
+            .routing_table
+            .lock()
+            .await
+            .insert("client-1".to_string(), vec![node_ids[1].clone()]);
+
+        service.answer_routings_query().await.unwrap();
+
+        let packets = published(&mut eventloop);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].topic, "orchestrator/response/routings");
+        let snapshot: RoutingTableSnapshot = serde_json::from_slice(&packets[0].payload).unwrap();
+        let snapshot_ids: Vec<&str> = snapshot
+            .nodes
+            .iter()
+            .map(|info| info.node_id.as_str())
+            .collect();
+        assert_eq!(snapshot_ids, node_ids);
+        assert_eq!(
+            snapshot.routings,
+            HashMap::from([("client-1".to_string(), vec![node_ids[1].clone()])])
+        );
+        assert!(snapshot.generated_at > 0);
+    }
+
+    #[tokio::test]
+    async fn test_assignments_use_bincode_only_where_node_and_client_share_it() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let bincode_node = add_node(&service, 10).await;
+        let json_node = add_node(&service, 10).await;
+        service.wire_formats.lock().await.extend([
+            (bincode_node.clone(), WIRE_FORMATS.to_vec()),
+            (json_node.clone(), vec![WireFormat::Json]),
+        ]);
+
+        let mut request = RoutingRequest {
+            fan_out: Some(2),
+            ..routing_request("client-1")
+        };
+        request.node_info.metadata.insert(
+            WIRE_FORMATS_METADATA_KEY.to_string(),
+            WireFormat::join(&WIRE_FORMATS),
+        );
+        service.handle_routing_request(request).await.unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        let formats: HashMap<String, WireFormat> = response
+            .assignments
+            .iter()
+            .map(|assignment| {
+                (
+                    assignment.node_id.clone(),
+                    assignment.configuration.wire_format,
+                )
+            })
+            .collect();
+        assert_eq!(formats[&bincode_node], WireFormat::Bincode);
+        assert_eq!(formats[&json_node], WireFormat::Json);
+        assert_eq!(
+            response.configuration.unwrap().wire_format,
+            WireFormat::Json
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_assigns_distinct_nodes_and_reserves_each() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        for _ in 0..4 {
+            add_node(&service, 10).await;
+        }
+
+        service
+            .handle_routing_request(RoutingRequest {
+                fan_out: Some(3),
+                ..routing_request("client-1")
+            })
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        let assigned: Vec<String> = response
+            .assignments
+            .iter()
+            .map(|assignment| assignment.node_id.clone())
+            .collect();
+        assert_eq!(assigned.len(), 3);
+        assert_eq!(assigned[0], response.node_id);
+        assert_eq!(
+            assigned
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            3
+        );
+
+        let nodes = service.nodes.lock().await;
+        for (node_id, info) in nodes.iter() {
+            let expected = if assigned.contains(node_id) { 1 } else { 0 };
+            assert_eq!(info.current_load, expected, "load on {}", node_id);
+        }
+        assert_eq!(
+            service.routing_table.lock().await.get("client-1"),
+            Some(&assigned)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clients_get_the_processing_timeout_nodes_advertise() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        for timeout in ["2000", "8000"] {
+            let node_id = add_node(&service, 10).await;
+            service
+                .nodes
+                .lock()
+                .await
+                .get_mut(&node_id)
+                .unwrap()
+                .metadata
+                .insert("processing_timeout_ms".to_string(), timeout.to_string());
+        }
+
+        service
+            .handle_routing_request(RoutingRequest {
+                fan_out: Some(2),
+                ..routing_request("client-1")
+            })
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        let nodes = service.nodes.lock().await;
+        for assignment in &response.assignments {
+            assert_eq!(
+                Some(assignment.configuration.processing_timeout_ms),
+                advertised_processing_timeout(&nodes[&assignment.node_id])
+            );
+        }
+        assert_eq!(response.configuration.unwrap().processing_timeout_ms, 8000);
+    }
+
+    #[tokio::test]
+    async fn test_configured_qos_used_per_message_class() {
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 100);
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig {
+                qos: QosPolicy {
+                    default: QoS::AtMostOnce,
+                    routing: QoS::ExactlyOnce,
+                },
+                ..OrchestratorConfig::default()
+            },
+        );
+        add_node(&service, 10).await;
+
+        service
+            .handle_routing_request(routing_request("client-1"))
+            .await
+            .unwrap();
+        service.answer_routings_query().await.unwrap();
+
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].topic, "routing/response/client-1");
+        assert_eq!(published[0].qos, QoS::ExactlyOnce);
+        assert_eq!(published[1].topic, "orchestrator/response/routings");
+        assert_eq!(published[1].qos, QoS::AtMostOnce);
+    }
+
+    #[tokio::test]
+    async fn test_reassign_moves_client_to_another_node_or_rejects() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let first = add_node(&service, 10).await;
+        let second = add_node(&service, 10).await;
+        service
+            .handle_routing_request(RoutingRequest {
+                request_id: Some("req-1".to_string()),
+                ..routing_request("client-1")
+            })
+            .await
+            .unwrap();
+        let original = routing_responses(&mut eventloop).remove(0).node_id;
+        let other = if original == first { &second } else { &first };
+
+        let reassign = serde_json::to_vec(&ReassignRequest {
+            client_id: "client-1".to_string(),
+        })
+        .unwrap();
+        service
+            .handle_publish("orchestrator/reassign", &reassign)
+            .await;
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        assert_eq!(&response.node_id, other);
+        // Same request id, so the client treats it as an update
+        assert_eq!(response.request_id.as_deref(), Some("req-1"));
+        assert_eq!(
+            service.routing_table.lock().await.get("client-1"),
+            Some(&vec![other.clone()])
+        );
+        {
+            let nodes = service.nodes.lock().await;
+            assert_eq!(nodes[&original].current_load, 0);
+            assert_eq!(nodes[other].current_load, 1);
+        }
+
+        // Only the node it is on now is left to take it
+        service.nodes.lock().await.remove(&original);
+        service
+            .handle_publish("orchestrator/reassign", &reassign)
+            .await;
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Rejected);
+        assert_eq!(response.request_id, None);
+        assert!(service.routing_table.lock().await.get("client-1").is_none());
+        assert_eq!(service.nodes.lock().await[other].current_load, 0);
+        let history = service.routing_history.lock().await.recent(None);
+        assert!(matches!(
+            history.last().map(|event| &event.change),
+            Some(RoutingChange::Removed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ack_finalizes_routing_and_silence_rolls_it_back() {
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 100);
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig {
+                routing_ack_timeout_secs: Some(30),
+                ..OrchestratorConfig::default()
+            },
+        );
+        let node_id = add_node(&service, 10).await;
+        for client_id in ["client-1", "client-2", "client-3"] {
+            service
+                .handle_routing_request(routing_request(client_id))
+                .await
+                .unwrap();
+        }
+        routing_responses(&mut eventloop);
+
+        let ack = |client_id: &str, accepted: bool| {
+            serde_json::to_vec(&RoutingAck {
+                client_id: client_id.to_string(),
+                node_id: node_id.clone(),
+                accepted,
+            })
+            .unwrap()
+        };
+        let ack_topic = format!("routing/ack/{}", node_id);
+        service
+            .handle_publish(&ack_topic, &ack("client-1", true))
+            .await;
+        assert!(!service
+            .unacked_routings
+            .lock()
+            .await
+            .contains_key("client-1"));
+
+        // client-3 could not subscribe; its routing is revoked
+        service
+            .handle_publish(&ack_topic, &ack("client-3", false))
+            .await;
+        let revoked = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(revoked.client_id, "client-3");
+        assert_eq!(revoked.status, RoutingStatus::Rejected);
+        assert_eq!(revoked.request_id, None);
+
+        // client-2 never answers; age its routing past the ack timeout
+        service.unacked_routings.lock().await.insert(
+            "client-2".to_string(),
+            Instant::now() - Duration::from_secs(60),
+        );
+        service.expire_unacked_routings().await;
+        let revoked = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(revoked.client_id, "client-2");
+        assert_eq!(revoked.request_id, None);
+
+        let routing_table = service.routing_table.lock().await;
+        assert_eq!(routing_table.get("client-1"), Some(&vec![node_id.clone()]));
+        assert!(routing_table.get("client-2").is_none());
+        assert!(routing_table.get("client-3").is_none());
+        drop(routing_table);
+        assert_eq!(service.nodes.lock().await[&node_id].current_load, 1);
+        assert!(service.unacked_routings.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_routing_logs_are_correlated_by_request_span() {
+        let (service, _eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+        let request = RoutingRequest {
+            request_id: Some("req-7".to_string()),
+            ..routing_request("client-1")
+        };
+        service.handle_routing_request(request).await.unwrap();
+
+        let span = "handle_routing_request{client_id=client-1 request_id=req-7}";
+        let assigned = format!("Assigned Node [{}] to Client [client-1]", node_id);
+        logs_assert(|lines| {
+            lines
+                .iter()
+                .any(|line| line.contains(span) && line.contains(&assigned))
+                .then_some(())
+                .ok_or_else(|| format!("no assignment logged within {}", span))
+        });
+    }
+
+    #[tokio::test]
+    async fn test_same_logical_id_is_routed_back_to_its_node() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        for _ in 0..3 {
+            add_node(&service, 10).await;
+        }
+        let route = |client_id: &str, logical_id: &str| RoutingRequest {
+            logical_id: Some(logical_id.to_string()),
+            ..routing_request(client_id)
+        };
+
+        service
+            .handle_routing_request(route("client-1", "gateway-7"))
+            .await
+            .unwrap();
+        let first = routing_responses(&mut eventloop).remove(0).node_id;
+
+        // The same logical client after a restart, under a new client id,
+        // returns to its node even though idler ones exist
+        service
+            .handle_routing_request(route("client-2", "gateway-7"))
+            .await
+            .unwrap();
+        assert_eq!(routing_responses(&mut eventloop).remove(0).node_id, first);
+        // ...and the routing of its old client id is dropped
+        assert!(!service.routing_table.lock().await.contains_key("client-1"));
+        assert_eq!(service.nodes.lock().await[&first].current_load, 1);
+
+        service
+            .handle_routing_request(route("client-3", "gateway-8"))
+            .await
+            .unwrap();
+        assert_ne!(routing_responses(&mut eventloop).remove(0).node_id, first);
+
+        // Moving the client moves where its logical id routes back to
+        service.reassign_client("client-2").await.unwrap();
+        let moved = routing_responses(&mut eventloop).remove(0).node_id;
+        assert_ne!(moved, first);
+        assert_eq!(
+            service.logical_routes.lock().await["gateway-7"],
+            vec![moved.clone()]
+        );
+
+        // Routes to nodes that leave the pool are forgotten
+        service.remove_nodes(&[moved], "node timed out").await;
+        assert!(!service
+            .logical_routes
+            .lock()
+            .await
+            .contains_key("gateway-7"));
+    }
+
+    #[tokio::test]
+    async fn test_pool_drain_rejects_routing_until_resume() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+
+        service
+            .handle_pool_control(PoolControl::PoolDrain { drain_nodes: true })
+            .await
+            .unwrap();
+        // Nodes are put into maintenance
+        let node_controls = |eventloop: &mut EventLoop| -> Vec<(String, bool)> {
+            published(eventloop)
+                .into_iter()
+                .filter(|p| p.topic.starts_with("control/"))
+                .map(|p| {
+                    let control: MaintenanceControl = serde_json::from_slice(&p.payload).unwrap();
+                    (p.topic, control.maintenance)
+                })
+                .collect()
+        };
+        let control_topic = format!("control/{}", node_id);
+        assert_eq!(
+            node_controls(&mut eventloop),
+            vec![(control_topic.clone(), true)]
+        );
+
+        for client_id in ["client-1", "client-2"] {
+            service
+                .handle_routing_request(routing_request(client_id))
+                .await
+                .unwrap();
+        }
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses.len(), 2);
+        for response in &responses {
+            assert_eq!(response.status, RoutingStatus::Rejected);
+            assert_eq!(
+                response.rejection_reason.as_deref(),
+                Some("pool maintenance")
+            );
+        }
+
+        service
+            .handle_pool_control(PoolControl::PoolResume)
+            .await
+            .unwrap();
+        assert_eq!(node_controls(&mut eventloop), vec![(control_topic, false)]);
+        service
+            .handle_routing_request(routing_request("client-3"))
+            .await
+            .unwrap();
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses[0].status, RoutingStatus::Accepted);
+        assert_eq!(responses[0].node_id, node_id);
+    }
+
+    #[tokio::test]
+    async fn test_full_pool_rejection_hints_when_to_retry() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&node_id)
+            .unwrap()
+            .current_load = 10;
+
+        service
+            .handle_routing_request(routing_request("client-1"))
+            .await
+            .unwrap();
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses[0].status, RoutingStatus::Rejected);
+        assert_eq!(
+            responses[0].rejection_reason.as_deref(),
+            Some("No available master nodes")
+        );
+        assert_eq!(
+            responses[0].retry_after_ms,
+            Some(EXPECTED_HEARTBEAT_INTERVAL_SECS * 1000)
+        );
+        assert_eq!(service.rejected_routings.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_preferred_node_is_used_while_it_has_room() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let idle = add_node(&service, 10).await;
+        let busy = add_node(&service, 10).await;
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&busy)
+            .unwrap()
+            .current_load = 5;
+        let prefer = |client_id: &str, node_id: &str| RoutingRequest {
+            preferred_node: Some(node_id.to_string()),
+            ..routing_request(client_id)
+        };
+
+        // Chosen over the less loaded node
+        service
+            .handle_routing_request(prefer("client-1", &busy))
+            .await
+            .unwrap();
+        assert_eq!(routing_responses(&mut eventloop)[0].node_id, busy);
+
+        // A preferred node without room for the costliest packet falls back
+        // to the balancer, even with some capacity left
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&busy)
+            .unwrap()
+            .reported_load = Some(10 - MAX_LOAD_COST + 1);
+        service
+            .handle_routing_request(prefer("client-2", &busy))
+            .await
+            .unwrap();
+        assert_eq!(routing_responses(&mut eventloop)[0].node_id, idle);
+
+        // So does one the orchestrator doesn't know
+        service
+            .handle_routing_request(prefer("client-3", "node-gone"))
+            .await
+            .unwrap();
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        assert_eq!(response.node_id, idle);
+    }
+
+    #[tokio::test]
+    async fn test_routing_is_pending_while_no_node_is_active() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+        service.nodes.lock().await.get_mut(&node_id).unwrap().status = NodeStatus::Inactive;
+
+        service
+            .handle_routing_request(routing_request("client-1"))
+            .await
+            .unwrap();
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses[0].status, RoutingStatus::Pending);
+        assert_eq!(responses[0].retry_after_ms, Some(5000));
+        assert_eq!(service.rejected_routings.load(Ordering::Relaxed), 0);
+
+        // Serving resumes once the node is back
+        service.nodes.lock().await.get_mut(&node_id).unwrap().status = NodeStatus::Active;
+        service
+            .handle_routing_request(routing_request("client-1"))
+            .await
+            .unwrap();
+        let responses = routing_responses(&mut eventloop);
+        assert_eq!(responses[0].status, RoutingStatus::Accepted);
+        assert_eq!(responses[0].node_id, node_id);
+    }
+
+    #[tokio::test]
+    async fn test_probe_ack_records_latency() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+
+        let probe_id = service.probe_node(&node_id).await.unwrap();
+        let published = published(&mut eventloop);
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic, format!("probe/{}", node_id));
+        let probe: ProbeRequest = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(probe.probe_id, probe_id);
+
+        time::sleep(Duration::from_millis(10)).await;
+        service
+            .handle_probe_ack(ProbeAck {
+                probe_id,
+                node_id: node_id.clone(),
+                status: NodeStatus::Active,
+                current_load: 3,
+                sent_at_ms: probe.sent_at_ms,
+                acked_at_ms: probe.sent_at_ms,
+            })
+            .await;
+
+        let latency = service.probe_latencies.lock().await[&node_id];
+        assert!(latency >= Duration::from_millis(10));
+        assert!(service.pending_probes.lock().await.is_empty());
+    }
+
+    /// Serves one HTTP request per status in `statuses`, answering with that
+    /// status, and returns the server's url and the request bodies it received
+    async fn mock_webhook(statuses: Vec<u16>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/utilization", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                // Read the headers, then as much body as Content-Length says
+                let body = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("Content-Length: "))
+                            .and_then(|value| value.parse().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                bodies.push(body);
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            bodies
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_removes_inactive_nodes_and_routings() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let stale = add_node(&service, 10).await;
+        let live = add_node(&service, 10).await;
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&stale)
+            .unwrap()
+            .last_heartbeat = 0;
+        {
+            let mut routing_table = service.routing_table.lock().await;
+            routing_table.insert("client-1".to_string(), vec![stale.clone()]);
+            routing_table.insert("client-2".to_string(), vec![live.clone()]);
+        }
+
+        service.cleanup_inactive_nodes().await;
+
+        let nodes = service.nodes.lock().await;
+        assert!(!nodes.contains_key(&stale));
+        assert!(nodes.contains_key(&live));
+        let routing_table = service.routing_table.lock().await;
+        assert!(!routing_table.contains_key("client-1"));
+        assert_eq!(routing_table.get("client-2"), Some(&vec![live.clone()]));
+
+        let topics: Vec<String> = published(&mut eventloop)
+            .into_iter()
+            .map(|p| p.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                format!("master/status/{}", stale),
+                "routing/response/client-1".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_removing_a_node_drops_fan_out_routings_through_it() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let first = add_node(&service, 10).await;
+        let second = add_node(&service, 10).await;
+        service
+            .handle_routing_request(RoutingRequest {
+                fan_out: Some(2),
+                ..routing_request("client-1")
+            })
+            .await
+            .unwrap();
+        routing_responses(&mut eventloop);
+
+        service
+            .remove_nodes(std::slice::from_ref(&first), "test")
+            .await;
+
+        // The surviving node gets its capacity back and the client is told
+        assert_eq!(service.nodes.lock().await[&second].current_load, 0);
+        assert!(service.routing_table.lock().await.is_empty());
+        assert!(service.routed_requests.lock().await.is_empty());
+        assert!(service.unacked_routings.lock().await.is_empty());
+        let response = routing_responses(&mut eventloop).remove(0);
+        assert_eq!(response.status, RoutingStatus::Rejected);
+        assert_eq!(response.request_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_slave_heartbeats_tracked_and_stale_slaves_cleaned() {
+        let (service, _eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+        let heartbeat = NodeInfo::new(NodeType::Client, 10);
+        let client_id = heartbeat.node_id.clone();
+        service.handle_slave_heartbeat(&client_id, heartbeat).await;
+        assert!(service.slaves.lock().await[&client_id].last_heartbeat > 0);
+        service
+            .handle_routing_request(routing_request(&client_id))
+            .await
+            .unwrap();
+        let load = || async { service.nodes.lock().await[&node_id].current_load };
+        assert_eq!(load().await, 1);
+
+        // Still fresh: kept
+        service.cleanup_inactive_nodes().await;
+        assert!(service.slaves.lock().await.contains_key(&client_id));
+
+        service
+            .slaves
+            .lock()
+            .await
+            .get_mut(&client_id)
+            .unwrap()
+            .last_heartbeat = 0;
+        service.cleanup_inactive_nodes().await;
+        assert!(service.slaves.lock().await.is_empty());
+        assert!(service.routing_table.lock().await.is_empty());
+        // The node itself is untouched, with the client's load released
+        assert_eq!(load().await, 0);
+
+        // A client's last will releases its load the same way
+        service
+            .handle_routing_request(routing_request("client-2"))
+            .await
+            .unwrap();
+        assert_eq!(load().await, 1);
+        let notice = serde_json::to_vec(&OfflineNotice {
+            node_id: "client-2".to_string(),
+            status: NodeStatus::Offline,
+        })
+        .unwrap();
+        service
+            .handle_publish("heartbeat/slave/client-2", &notice)
+            .await;
+        assert!(service.routing_table.lock().await.is_empty());
+        assert_eq!(load().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_last_will_removes_node_without_waiting_for_timeout() {
+        let (service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        let crashed = add_node(&service, 10).await;
+        service
+            .routing_table
+            .lock()
+            .await
+            .insert("client-1".to_string(), vec![crashed.clone()]);
+
+        // The heartbeat is fresh, so only the will can have removed it
+        service
+            .remove_nodes(std::slice::from_ref(&crashed), "connection lost")
+            .await;
+
+        assert!(!service.nodes.lock().await.contains_key(&crashed));
+        assert!(service.routing_table.lock().await.is_empty());
+        let events = service.routing_history.lock().await.recent(None);
+        assert_eq!(
+            events.last().unwrap().change,
+            RoutingChange::Removed {
+                reason: "connection lost".to_string()
+            }
+        );
+        assert!(published(&mut eventloop)
+            .iter()
+            .any(|p| p.topic == "routing/response/client-1"));
+    }
+
+    #[tokio::test]
+    async fn test_utilization_report_posted_to_webhook() {
+        let (service, _eventloop) = test_service(OrchestrationMode::Standalone);
+        let node_id = add_node(&service, 10).await;
+        service
+            .nodes
+            .lock()
+            .await
+            .get_mut(&node_id)
+            .unwrap()
+            .current_load = 4;
+        service
+            .handle_pool_control(PoolControl::PoolDrain { drain_nodes: false })
+            .await
+            .unwrap();
+        service
+            .handle_routing_request(routing_request("client-1"))
+            .await
+            .unwrap();
+
+        // The first attempt fails and is retried
+        let (url, server) = mock_webhook(vec![500, 200]).await;
+        service
+            .post_utilization(&url, Duration::from_millis(10))
+            .await;
+
+        let bodies = server.await.unwrap();
+        assert_eq!(bodies.len(), 2);
+        let report: UtilizationReport = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(report.node_count, 1);
+        assert_eq!(report.total_capacity, 10);
+        assert_eq!(report.used_capacity, 4);
+        assert_eq!(report.utilization, 0.4);
+        assert_eq!(report.rejected_routings, 1);
+    }
+
+    #[test]
+    fn test_config_defaults_and_overrides() {
+        let file = config::OrchestratorConfig::default();
+        let config = OrchestratorConfig::load(&file, |_| None).unwrap();
+        assert_eq!(config, OrchestratorConfig::default());
+        assert_eq!(config.heartbeat_timeout_secs, 15);
+        assert_eq!(config.cleanup_interval_secs, 15);
+        assert_eq!(config.status_print_interval_secs, 10);
+
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("HEARTBEAT_TIMEOUT_SECS", "30"),
+            ("CLEANUP_INTERVAL_SECS", "not a number"),
+            ("STATUS_PRINT_INTERVAL_SECS", "2"),
+            ("WEBHOOK_INTERVAL_SECS", "0"),
+            ("CLIENT_ID_PREFIX", "pod-7"),
+            ("METRICS_PORT", "9100"),
+            ("HEALTH_PORT", "9101"),
+            ("ROUTING_HISTORY_SIZE", "50"),
+            ("EMBEDDED_BROKER", "1"),
+            ("EMBEDDED_BROKER_ADDR", "127.0.0.1:1884"),
+        ]);
+        let config =
+            OrchestratorConfig::load(&file, |key| env.get(key).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.heartbeat_timeout_secs, 30);
+        assert_eq!(config.cleanup_interval_secs, 15);
+        assert_eq!(config.status_print_interval_secs, 2);
+        assert_eq!(config.webhook_interval_secs, 1);
+        assert_eq!(config.client_id_prefix.as_deref(), Some("pod-7"));
+        assert_eq!(config.metrics_port, Some(9100));
+        assert_eq!(config.health_port, Some(9101));
+        assert_eq!(config.routing_history_size, 50);
+        assert_eq!(
+            config.embedded_broker,
+            Some(SocketAddr::from(([127, 0, 0, 1], 1884)))
+        );
+        assert!(config.timeout_covers_heartbeats());
+
+        let short = OrchestratorConfig {
+            heartbeat_timeout_secs: 6,
+            ..OrchestratorConfig::default()
+        };
+        assert!(!short.timeout_covers_heartbeats());
+
+        // Unusable file values are errors rather than silently defaulted
+        for table in [
+            "rebalance_threshold = 1.5",
+            "embedded_broker = true\nembedded_broker_addr = \"nowhere\"",
+            "orchestrator_mode = \"regionl\"",
+            "mqtt_channel_cap = 0",
+            "cleanup_interval_secs = 0",
+        ] {
+            let file = ConfigFile::parse(&format!("[orchestrator]\n{}", table)).unwrap();
+            let loaded = OrchestratorConfig::load(&file.orchestrator, |_| None)
+                .and_then(|_| OrchestrationMode::load(&file.orchestrator, |_| None));
+            assert!(loaded.is_err(), "{}", table);
+        }
+        let file = ConfigFile::parse("[orchestrator]\nmqtt_username = \"orchestrator\"").unwrap();
+        let config = OrchestratorConfig::load(&file.orchestrator, |_| None).unwrap();
+        assert_eq!(config.mqtt.username.as_deref(), Some("orchestrator"));
+    }
+
+    fn region(name: &str, total_capacity: u32, total_load: u32, timestamp: u64) -> RegionSummary {
+        RegionSummary {
+            region: name.to_string(),
+            node_count: 1,
+            total_capacity,
+            total_load,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_select_region_with_available_capacity() {
+        let mut regions = HashMap::new();
+        regions.insert("eu".to_string(), region("eu", 100, 100, 1000));
+        regions.insert("us".to_string(), region("us", 100, 40, 1000));
+        regions.insert("ap".to_string(), region("ap", 100, 80, 1000));
+
+        assert_eq!(select_region(&regions, 1005), Some("us".to_string()));
+    }
+
+    #[test]
+    fn test_select_region_ignores_full_and_stale_regions() {
+        let mut regions = HashMap::new();
+        regions.insert("eu".to_string(), region("eu", 100, 100, 1000));
+        regions.insert("us".to_string(), region("us", 100, 0, 900));
+
+        assert_eq!(select_region(&regions, 1005), None);
+    }
+
+    #[test]
+    fn test_messages_per_sec_from_consecutive_heartbeats() {
+        let heartbeat =
+            |last_heartbeat, processed_total, processed_since_last_heartbeat| NodeInfo {
+                last_heartbeat,
+                processed_total,
+                processed_since_last_heartbeat,
+                ..NodeInfo::new(NodeType::Node, 10)
+            };
+        let previous = heartbeat(1000, 40, 10);
+        assert_eq!(
+            messages_per_sec(&previous, &heartbeat(1005, 90, 50)),
+            Some(10.0)
+        );
+        assert_eq!(messages_per_sec(&previous, &heartbeat(1000, 90, 50)), None);
+        // A restarted node's counters start over
+        assert_eq!(
+            messages_per_sec(&previous, &heartbeat(1005, 5, 5)),
+            Some(1.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_node_heartbeats_become_routable() {
+        let (service, _eventloop) = test_service(OrchestrationMode::Standalone);
+
+        let client = NodeInfo::new(NodeType::Client, 10);
+        service
+            .handle_node_heartbeat(&client.node_id.clone(), client)
+            .await;
+        let monitor = NodeInfo::new(NodeType::Monitor, 0);
+        service
+            .handle_node_heartbeat(&monitor.node_id.clone(), monitor)
+            .await;
+        assert!(service.nodes.lock().await.is_empty());
+
+        let node = NodeInfo::new(NodeType::Node, 10);
+        let node_id = node.node_id.clone();
+        service.handle_node_heartbeat(&node_id, node).await;
+        assert!(service.nodes.lock().await.contains_key(&node_id));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_detects_and_corrects_routing_divergence() {
+        let heartbeat = |clients: &str| {
+            let mut node = NodeInfo::new(NodeType::Node, 10);
+            node.node_id = "node-a".to_string();
+            node.metadata
+                .insert(ROUTED_CLIENTS_METADATA_KEY.to_string(), clients.to_string());
+            node
+        };
+        let table = || {
+            HashMap::from([
+                ("client-1".to_string(), vec!["node-a".to_string()]),
+                ("client-2".to_string(), vec!["node-a".to_string()]),
+                ("client-3".to_string(), vec!["node-b".to_string()]),
+            ])
+        };
+
+        // client-2 was forgotten by the node; client-4 by the orchestrator
+        let reported: HashSet<String> = ["client-1", "client-3", "client-4"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            RoutingDivergence::between(&table(), "node-a", &reported),
+            RoutingDivergence {
+                missing_on_node: vec!["client-2".to_string()],
+                unknown_to_orchestrator: vec!["client-4".to_string()],
+            }
+        );
+
+        // Without correction, the divergence is only logged
+        let (service, _eventloop) = test_service(OrchestrationMode::Standalone);
+        *service.routing_table.lock().await = table();
+        service
+            .handle_node_heartbeat("node-a", heartbeat("client-1,client-3,client-4"))
+            .await;
+        assert_eq!(*service.routing_table.lock().await, table());
+
+        // With correction, client-1 and client-2 are dropped from node-a and
+        // client-4 is routed to it; client-5 is no client we know, so it is
+        // left alone, and client-6 was routed too recently to be reported
+        let (mut service, mut eventloop) = test_service(OrchestrationMode::Standalone);
+        service.config.correct_routing_divergence = true;
+        *service.routing_table.lock().await = table();
+        service
+            .routing_table
+            .lock()
+            .await
+            .insert("client-6".to_string(), vec!["node-a".to_string()]);
+        service
+            .routed_at
+            .lock()
+            .await
+            .insert("client-6".to_string(), Instant::now());
+        service.nodes.lock().await.insert(
+            "node-a".to_string(),
+            NodeInfo {
+                current_load: 3,
+                ..heartbeat("")
+            },
+        );
+        service
+            .slaves
+            .lock()
+            .await
+            .insert("client-4".to_string(), NodeInfo::new(NodeType::Client, 10));
+        service
+            .handle_node_heartbeat("node-a", heartbeat("client-3,client-4,client-5"))
+            .await;
+        let routing_table = service.routing_table.lock().await.clone();
+        assert!(!routing_table.contains_key("client-1"));
+        assert!(!routing_table.contains_key("client-2"));
+        assert_eq!(routing_table["client-3"], vec!["node-b".to_string()]);
+        assert_eq!(routing_table["client-4"], vec!["node-a".to_string()]);
+        assert!(!routing_table.contains_key("client-5"));
+        assert_eq!(routing_table["client-6"], vec!["node-a".to_string()]);
+        assert_eq!(service.nodes.lock().await["node-a"].current_load, 2);
+
+        // The dropped clients are told to route again
+        let mut revoked: Vec<String> = routing_responses(&mut eventloop)
+            .into_iter()
+            .filter(|response| response.status == RoutingStatus::Rejected)
+            .map(|response| response.client_id)
+            .collect();
+        revoked.sort();
+        assert_eq!(revoked, vec!["client-1", "client-2"]);
+
+        let changes: Vec<RoutingChange> = service
+            .routing_history
+            .lock()
+            .await
+            .recent(None)
+            .into_iter()
+            .map(|event| event.change)
+            .collect();
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(changes[0], RoutingChange::Removed { .. }));
+        assert!(matches!(changes[1], RoutingChange::Removed { .. }));
+        assert_eq!(changes[2], RoutingChange::Added);
+    }
+
+    #[tokio::test]
+    async fn test_custom_selector_is_used_for_routing() {
+        /// Always routes to the node with the most capacity
+        struct HighestCapacity;
+
+        impl NodeSelector for HighestCapacity {
+            fn select(
+                &self,
+                candidates: &[&NodeInfo],
+                _request: &RoutingRequest,
+            ) -> Option<String> {
+                candidates
+                    .iter()
+                    .max_by_key(|info| info.capacity)
+                    .map(|info| info.node_id.clone())
+            }
+        }
+
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 100);
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(HighestCapacity),
+            OrchestratorConfig::default(),
+        );
+        add_node(&service, 10).await;
+        let big = add_node(&service, 500).await;
+        add_node(&service, 50).await;
+
+        for client_id in ["client-1", "client-2", "client-3"] {
+            service
+                .handle_routing_request(routing_request(client_id))
+                .await
+                .unwrap();
+        }
+        let assigned: Vec<String> = routing_responses(&mut eventloop)
+            .into_iter()
+            .map(|response| response.node_id)
+            .collect();
+        assert_eq!(assigned, vec![big.clone(), big.clone(), big.clone()]);
+        assert_eq!(service.nodes.lock().await[&big].current_load, 3);
+    }
+
+    #[tokio::test]
+    async fn test_same_routing_seed_gives_same_assignments() {
+        async fn assignments(seed: u64) -> Vec<String> {
+            let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+            let (client, mut eventloop) = AsyncClient::new(mqtt_options, 500);
+            let service = OrchestrationService::build(
+                client,
+                OrchestrationMode::Standalone,
+                balancer::from_name("weighted_random", Some(seed)),
+                OrchestratorConfig::default(),
+            );
+            for (node_id, capacity) in [("node-a", 1000), ("node-b", 500), ("node-c", 200)] {
+                let mut info = NodeInfo::new(NodeType::Node, capacity);
+                info.node_id = node_id.to_string();
+                info.supported_data_types = vec!["text".to_string()];
+                service.nodes.lock().await.insert(node_id.to_string(), info);
+            }
+
+            for i in 0..200 {
+                service
+                    .handle_routing_request(routing_request(&format!("client-{}", i)))
+                    .await
+                    .unwrap();
+            }
+            routing_responses(&mut eventloop)
+                .into_iter()
+                .map(|response| response.node_id)
+                .collect()
+        }
+
+        let first = assignments(42).await;
+        assert_eq!(first.len(), 200);
+        assert_eq!(first, assignments(42).await);
+        // Every node gets a share, so the match isn't trivial
+        for node_id in ["node-a", "node-b", "node-c"] {
+            assert!(first.iter().any(|assigned| assigned == node_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing_handshake_over_memory_broker() {
+        let broker = MemoryBroker::new();
+        let (client, mut eventloop) = broker.connect();
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig::default(),
+        );
+        service.subscribe_topics().await.unwrap();
+        let running = service.clone();
+        tokio::spawn(async move {
+            while let Some(publish) = eventloop.recv().await {
+                running
+                    .handle_publish(&publish.topic, &publish.payload)
+                    .await;
+            }
+        });
+
+        let (node, _) = broker.connect();
+        let mut node_info = NodeInfo::new(NodeType::Node, 10);
+        node_info.supported_data_types = vec!["text".to_string()];
+        node.publish(
+            topics::heartbeat_master("", &node_info.node_id),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&node_info).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (slave, mut slave_events) = broker.connect();
+        let response_topic = topics::routing_response("", "client-1");
+        slave
+            .subscribe(response_topic.as_str(), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        slave
+            .publish(
+                "routing/request",
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_vec(&routing_request("client-1")).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let publish = slave_events
+            .expect(&response_topic, Duration::from_secs(2))
+            .await;
+        let response: RoutingResponse = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(response.status, RoutingStatus::Accepted);
+        assert_eq!(response.node_id, node_info.node_id);
+        assert!(response.configuration.is_some());
+        assert_eq!(
+            service.routing_table.lock().await.get("client-1"),
+            Some(&vec![node_info.node_id.clone()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_topic_prefix_isolates_pools() {
+        let mqtt_options = MqttOptions::new("test-orchestrator", "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 100);
+        let service = OrchestrationService::build(
+            client,
+            OrchestrationMode::Standalone,
+            Box::new(balancer::LeastLoaded),
+            OrchestratorConfig {
+                topic_prefix: "pool-a".to_string(),
+                ..OrchestratorConfig::default()
+            },
+        );
+        add_node(&service, 10).await;
+        let payload = serde_json::to_vec(&routing_request("client-1")).unwrap();
+
+        for foreign in ["routing/request", "pool-b/routing/request"] {
+            service.handle_publish(foreign, &payload).await;
+        }
+        assert!(published(&mut eventloop).is_empty());
+
+        service
+            .handle_publish("pool-a/routing/request", &payload)
+            .await;
+        let topics: Vec<String> = published(&mut eventloop)
+            .into_iter()
+            .map(|p| p.topic)
+            .collect();
+        assert_eq!(topics, vec!["pool-a/routing/response/client-1"]);
     }
 }
@@ -0,0 +1,241 @@
+use mqtt_common::{NodeInfo, NodeStatus, NodeType};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+/// Largest request head read from a scraper before giving up on it
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Pause after a failed `accept`, so e.g. running out of file descriptors
+/// doesn't turn into a busy loop
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Point-in-time pool state exposed on `/metrics`
+#[derive(Debug, Default, PartialEq)]
+pub struct PoolMetrics {
+    pub active_nodes: u64,
+    pub total_capacity: u64,
+    pub total_load: u64,
+    pub routings_active: u64,
+    pub routing_rejections_total: u64,
+}
+
+impl PoolMetrics {
+    /// Aggregates active `Node`s the same way region summaries do
+    pub fn collect(
+        nodes: &HashMap<String, NodeInfo>,
+        routing_table: &HashMap<String, Vec<String>>,
+        routing_rejections_total: u64,
+    ) -> Self {
+        let mut metrics = PoolMetrics {
+            routings_active: routing_table.len() as u64,
+            routing_rejections_total,
+            ..PoolMetrics::default()
+        };
+        for info in nodes
+            .values()
+            .filter(|info| info.status == NodeStatus::Active && info.node_type == NodeType::Node)
+        {
+            metrics.active_nodes += 1;
+            metrics.total_capacity += u64::from(info.capacity);
+            metrics.total_load += u64::from(info.current_load);
+        }
+        metrics
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "pool_active_nodes",
+                "gauge",
+                "Active nodes in the pool",
+                self.active_nodes,
+            ),
+            (
+                "pool_total_capacity",
+                "gauge",
+                "Combined capacity of active nodes",
+                self.total_capacity,
+            ),
+            (
+                "pool_total_load",
+                "gauge",
+                "Combined load of active nodes",
+                self.total_load,
+            ),
+            (
+                "pool_routings_active",
+                "gauge",
+                "Clients currently routed to a node",
+                self.routings_active,
+            ),
+            (
+                "pool_routing_rejections_total",
+                "counter",
+                "Routing requests rejected since startup",
+                self.routing_rejections_total,
+            ),
+        ];
+        metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+            })
+            .collect()
+    }
+}
+
+/// Serves `GET /metrics` on `listener` until the process exits. A failed
+/// `accept` is logged and the next connection awaited.
+pub async fn serve(
+    listener: TcpListener,
+    nodes: Arc<Mutex<HashMap<String, NodeInfo>>>,
+    routing_table: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    rejections: Arc<AtomicU64>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Error accepting metrics connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let nodes = Arc::clone(&nodes);
+        let routing_table = Arc::clone(&routing_table);
+        let rejections = Arc::clone(&rejections);
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &nodes, &routing_table, &rejections).await {
+                error!("Error serving metrics: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers one scrape, or a 404 for anything other than `GET /metrics`
+async fn respond(
+    mut stream: TcpStream,
+    nodes: &Mutex<HashMap<String, NodeInfo>>,
+    routing_table: &Mutex<HashMap<String, Vec<String>>>,
+    rejections: &AtomicU64,
+) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = PoolMetrics::collect(
+                &*nodes.lock().await,
+                &*routing_table.lock().await,
+                rejections.load(Ordering::Relaxed),
+            )
+            .render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(capacity: u32, current_load: u32, status: NodeStatus) -> (String, NodeInfo) {
+        let mut info = NodeInfo::new(NodeType::Node, capacity);
+        info.current_load = current_load;
+        info.status = status;
+        (info.node_id.clone(), info)
+    }
+
+    #[test]
+    fn test_metrics_text_from_node_map() {
+        let nodes: HashMap<String, NodeInfo> = [
+            node(100, 40, NodeStatus::Active),
+            node(50, 5, NodeStatus::Active),
+            node(80, 80, NodeStatus::Inactive),
+        ]
+        .into_iter()
+        .chain([{
+            let client = NodeInfo::new(NodeType::Client, 10);
+            (client.node_id.clone(), client)
+        }])
+        .collect();
+        let routing_table = HashMap::from([
+            ("client-1".to_string(), vec!["node-a".to_string()]),
+            ("client-2".to_string(), vec!["node-b".to_string()]),
+        ]);
+
+        let text = PoolMetrics::collect(&nodes, &routing_table, 7).render();
+        for line in [
+            "# TYPE pool_active_nodes gauge",
+            "pool_active_nodes 2",
+            "pool_total_capacity 150",
+            "pool_total_load 45",
+            "pool_routings_active 2",
+            "# TYPE pool_routing_rejections_total counter",
+            "pool_routing_rejections_total 7",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            Arc::new(Mutex::new(HashMap::from([node(10, 3, NodeStatus::Active)]))),
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(AtomicU64::new(0)),
+        ));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\npool_total_load 3\n"));
+        assert!(get("/other").await.starts_with("HTTP/1.1 404"));
+    }
+}
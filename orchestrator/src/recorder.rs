@@ -0,0 +1,225 @@
+use mqtt_common::{topics, MqttTransport, RoutingRequest, RoutingResponse};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{error, warn};
+
+/// Lines waiting to be written before new ones are dropped
+const RECORD_QUEUE_CAP: usize = 1024;
+
+/// Routing message seen or sent by the orchestrator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedEntry {
+    /// A request received on `topic`, relative to the pool's topic prefix
+    Request {
+        topic: String,
+        request: RoutingRequest,
+    },
+    /// A response published to the requesting client
+    Response { response: RoutingResponse },
+}
+
+/// One line of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Milliseconds since the Unix epoch when the message was recorded
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub entry: RecordedEntry,
+}
+
+/// Appends routing traffic to a file as newline-delimited JSON, so it can be
+/// inspected or replayed against another broker later. Lines are handed to a
+/// writer task, keeping file I/O off the routing path.
+pub struct Recorder {
+    lines: mpsc::Sender<String>,
+}
+
+impl Recorder {
+    /// Opens `path` for appending, creating it if needed, and starts the
+    /// writer task, which runs until the recorder is dropped
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, queued) = mpsc::channel(RECORD_QUEUE_CAP);
+        tokio::spawn(write_lines(tokio::fs::File::from_std(file), queued));
+        Ok(Recorder { lines })
+    }
+
+    pub fn record_request(&self, topic: &str, request: &RoutingRequest) {
+        self.record(RecordedEntry::Request {
+            topic: topic.to_string(),
+            request: request.clone(),
+        });
+    }
+
+    pub fn record_response(&self, response: &RoutingResponse) {
+        self.record(RecordedEntry::Response {
+            response: response.clone(),
+        });
+    }
+
+    /// Writes one line; failures are logged rather than interrupting routing
+    fn record(&self, entry: RecordedEntry) {
+        let message = RecordedMessage {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            entry,
+        };
+        let Ok(mut line) = serde_json::to_string(&message) else {
+            return;
+        };
+        line.push('\n');
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.lines.try_send(line) {
+            warn!("Dropped a routing record; the record file is falling behind");
+        }
+    }
+}
+
+/// Writes each queued line to `file`; failures are logged and the line lost
+async fn write_lines(mut file: tokio::fs::File, mut queued: mpsc::Receiver<String>) {
+    while let Some(line) = queued.recv().await {
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(e) = written.await {
+            error!("Failed to record routing traffic: {}", e);
+        }
+    }
+}
+
+/// Reads a recording back, skipping lines that do not parse
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<RecordedMessage>> {
+    let mut messages = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(message) => messages.push(message),
+            Err(e) => error!("Skipping recorded line {}: {}", number + 1, e),
+        }
+    }
+    Ok(messages)
+}
+
+/// Re-publishes the recorded requests on their original topics under
+/// `topic_prefix`, keeping the spacing between them. Responses are left out;
+/// the live orchestrator produces its own.
+pub async fn replay(
+    messages: &[RecordedMessage],
+    topic_prefix: &str,
+    client: &impl MqttTransport,
+) -> Result<usize, rumqttc::ClientError> {
+    let mut previous: Option<u64> = None;
+    let mut replayed = 0;
+    for message in messages {
+        let RecordedEntry::Request { topic, request } = &message.entry else {
+            continue;
+        };
+        if let Some(previous) = previous {
+            let gap = message.timestamp_ms.saturating_sub(previous);
+            time::sleep(Duration::from_millis(gap)).await;
+        }
+        previous = Some(message.timestamp_ms);
+        if let Ok(payload) = serde_json::to_vec(request) {
+            client
+                .publish(
+                    topics::prefixed(topic_prefix, topic),
+                    QoS::AtLeastOnce,
+                    false,
+                    payload,
+                )
+                .await?;
+            replayed += 1;
+        }
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_common::{NodeInfo, NodeType, RoutingIssuer, RoutingStatus, PROTOCOL_VERSION};
+
+    #[tokio::test]
+    async fn test_recorded_lines_parse_back_into_routing_messages() {
+        let path =
+            std::env::temp_dir().join(format!("routing-recording-{}.jsonl", uuid::Uuid::new_v4()));
+        let request = RoutingRequest {
+            client_id: "client-1".to_string(),
+            data_type: vec!["text".to_string()],
+            node_info: NodeInfo::new(NodeType::Client, 10),
+            preferred_node: None,
+            timestamp: 1,
+            request_id: Some("req-1".to_string()),
+            fan_out: None,
+            schema_version: PROTOCOL_VERSION,
+            logical_id: None,
+        };
+        let response = RoutingResponse {
+            node_id: "node-a".to_string(),
+            client_id: "client-1".to_string(),
+            status: RoutingStatus::Rejected,
+            rejection_reason: Some("No available master nodes".to_string()),
+            configuration: None,
+            timestamp: 2,
+            request_id: Some("req-1".to_string()),
+            assignments: Vec::new(),
+            issuer: RoutingIssuer::Orchestrator,
+            schema_version: PROTOCOL_VERSION,
+            retry_after_ms: None,
+        };
+
+        let recorder = Recorder::open(&path).unwrap();
+        recorder.record_request("routing/request", &request);
+        recorder.record_response(&response);
+        drop(recorder);
+
+        // Written in the background
+        let messages = time::timeout(Duration::from_secs(1), async {
+            loop {
+                let messages = read_recording(&path).unwrap();
+                if messages.len() >= 2 {
+                    break messages;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(messages.len(), 2);
+        match &messages[0].entry {
+            RecordedEntry::Request {
+                topic,
+                request: recorded,
+            } => {
+                assert_eq!(topic, "routing/request");
+                assert_eq!(recorded.client_id, request.client_id);
+                assert_eq!(recorded.request_id, request.request_id);
+                assert_eq!(recorded.data_type, request.data_type);
+            }
+            other => panic!("expected a request, got {:?}", other),
+        }
+        match &messages[1].entry {
+            RecordedEntry::Response { response: recorded } => {
+                assert_eq!(recorded.client_id, response.client_id);
+                assert_eq!(recorded.status, response.status);
+                assert_eq!(recorded.rejection_reason, response.rejection_reason);
+            }
+            other => panic!("expected a response, got {:?}", other),
+        }
+        assert!(messages[0].timestamp_ms <= messages[1].timestamp_ms);
+    }
+}
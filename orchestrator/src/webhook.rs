@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::error;
+
+/// Upper bound for the delay between webhook attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long connecting, sending the request or reading the response may
+/// each take before the attempt fails
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Splits an `http://host[:port][/path]` URL into its address and path
+fn parse_url(url: &str) -> io::Result<(String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::other(format!("unsupported webhook url: {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(io::Error::other(format!(
+            "missing host in webhook url: {}",
+            url
+        )));
+    }
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((address, path.to_string()))
+}
+
+/// Runs one step of a webhook call, failing it with `TimedOut` after `limit`
+async fn within<T>(
+    step: &str,
+    limit: Duration,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    time::timeout(limit, future).await.unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("webhook {} timed out after {:?}", step, limit),
+        ))
+    })
+}
+
+/// POSTs `body` as JSON and fails unless the server answers with a 2xx status
+pub async fn post_json(url: &str, body: &str) -> io::Result<()> {
+    post_json_within(url, body, STEP_TIMEOUT).await
+}
+
+/// `post_json`, giving each of connect, send and receive at most `limit`
+async fn post_json_within(url: &str, body: &str, limit: Duration) -> io::Result<()> {
+    let (address, path) = parse_url(url)?;
+    let host = address.trim_end_matches(":80");
+
+    let mut stream = within("connect", limit, TcpStream::connect(&address)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    within("request", limit, stream.write_all(request.as_bytes())).await?;
+
+    let mut response = Vec::new();
+    within("response", limit, stream.read_to_end(&mut response)).await?;
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "webhook answered: {}",
+            status_line
+        )))
+    }
+}
+
+/// Retries `post_json` up to `retries` times, doubling the delay after each
+/// failure, timeouts included
+pub async fn post_with_retry(
+    url: &str,
+    body: &str,
+    retries: u32,
+    initial_backoff: Duration,
+) -> io::Result<()> {
+    let mut backoff = initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match post_json(url, body).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                error!(
+                    "Webhook attempt {}/{} failed: {}; retrying in {:?}",
+                    attempt, retries, e, backoff
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://localhost:8080/hooks/pool").unwrap(),
+            ("localhost:8080".to_string(), "/hooks/pool".to_string())
+        );
+        assert_eq!(
+            parse_url("http://example.com").unwrap(),
+            ("example.com:80".to_string(), "/".to_string())
+        );
+        assert!(parse_url("https://example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_webhook_times_out() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+
+        let err = post_json_within(&url, "{}", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("response"));
+        server.abort();
+    }
+}